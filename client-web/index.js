@@ -117,7 +117,12 @@ var sndNoise = (function() {
 /*
  * Input
  */
-var input = { x: 0.0, y: 0.0, r: 0.0, fire: false, mouse: [100, 100] };
+var input = {
+  x: 0.0, y: 0.0, r: 0.0, fire: 0, tractorBeam: false, dampeners: false,
+  boost: false, matchVelocity: false, mouse: [100, 100],
+};
+// Keys firing each weapon group, as bits of input.fire
+var fireKeys = { Space: 0x01, KeyF: 0x02, KeyR: 0x04 };
 function kbInput(evt, down) {
   if(down && evt.repeat) {
     return;
@@ -134,8 +139,20 @@ function kbInput(evt, down) {
     input.r = down ? 1.0 : 0.0;
   } else if(evt.code === 'KeyD') {
     input.r = down ? -1.0 : 0.0;
-  } else if(evt.code === 'Space') {
-    input.fire = down;
+  } else if(evt.code === 'KeyT') {
+    input.tractorBeam = down;
+  } else if(evt.code === 'KeyZ') {
+    input.dampeners = down;
+  } else if(evt.code === 'ShiftLeft') {
+    input.boost = down;
+  } else if(evt.code === 'KeyV') {
+    input.matchVelocity = down;
+  } else if(evt.code in fireKeys) {
+    if(down) {
+      input.fire |= fireKeys[evt.code];
+    } else {
+      input.fire &= ~fireKeys[evt.code];
+    }
   }
 }
 document.addEventListener('keydown', function(e) { kbInput(e, true); });
@@ -193,7 +210,8 @@ function render(now) {
   // Call WebAssembly
   client_web.update(
     delta, gl.drawingBufferWidth, gl.drawingBufferHeight,
-    input.x, input.y, input.r, input.fire,
+    input.x, input.y, input.r, input.fire, input.tractorBeam,
+    input.dampeners, input.boost, input.matchVelocity,
     input.mouse[0], input.mouse[1],
   );
 
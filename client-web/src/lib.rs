@@ -3,7 +3,7 @@ mod primitives;
 mod render;
 
 use game::Game;
-use game::input::{Input, Press};
+use game::input::{Inputs, Press};
 use log::{error, info, warn};
 use specs::WorldExt;
 use std::cell::{RefCell, RefMut};
@@ -44,6 +44,15 @@ pub extern "C" fn start() {
     render::init();
 }
 
+/// The new status of a key, from whether it is down.
+fn key_press(down: bool, previous: Press) -> Press {
+    match (down, previous) {
+        (false, _) => Press::UP,
+        (true, Press::UP) => Press::PRESSED,
+        (true, _) => Press::KEPT,
+    }
+}
+
 #[wasm_bindgen]
 pub extern "C" fn update(
     // Simulation delta
@@ -51,7 +60,13 @@ pub extern "C" fn update(
     // Canvas size
     width: u32, height: u32,
     // Input
-    x: f32, y: f32, r: f32, fire: bool,
+    x: f32, y: f32, r: f32,
+    // Bitmask of the weapon groups to fire
+    fire: u32,
+    tractor_beam: bool,
+    dampeners: bool,
+    boost: bool,
+    match_velocity: bool,
     mouse_x: f32, mouse_y: f32,
 ) {
     let mut app = match get_app() {
@@ -68,10 +83,22 @@ pub extern "C" fn update(
 
     // Set input
     {
-        let mut input = app.game.world.write_resource::<Input>();
+        let mut inputs = app.game.world.write_resource::<Inputs>();
+        let input = &mut inputs[0];
         input.movement = [x, y];
         input.rotation = r;
-        input.fire = if fire { Press::PRESSED } else { Press::UP };
+        for (i, press) in input.fire.iter_mut().enumerate() {
+            *press = if fire & (1 << i) != 0 {
+                Press::PRESSED
+            } else {
+                Press::UP
+            };
+        }
+        input.tractor_beam = key_press(tractor_beam, input.tractor_beam);
+        input.dampeners = key_press(dampeners, input.dampeners);
+        input.boost = key_press(boost, input.boost);
+        input.match_velocity =
+            key_press(match_velocity, input.match_velocity);
         input.mouse = app.render_app.project_cursor([mouse_x, mouse_y]);
     }
 
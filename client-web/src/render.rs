@@ -1,7 +1,14 @@
 use game::blocks::{BlockInner, Blocky};
-use game::guns::{Projectile, ProjectileType};
+use game::capture::CaptureZone;
+use game::economy::OrePickup;
+use game::guns::{Beam, Projectile, ProjectileType};
+use game::medium::MediumZone;
 use game::particles::{Particle, ParticleType};
-use game::physics::{LocalControl, Position};
+use game::physics::{CircleCollider, LocalControl, Position};
+use game::planet::Planet;
+use game::sector::{SectorId, SectorManager};
+use game::station::Station;
+use game::teams::Team;
 use log::info;
 use specs::{Entity, Join};
 use specs::world::WorldExt;
@@ -36,11 +43,20 @@ const EXTRA_BUFS_BASE: f64 = (1u64 << 40) as f64;
 const BUF_BOUNDS: f64 = EXTRA_BUFS_BASE + 0.0;
 const BUF_PLASMA: f64 = EXTRA_BUFS_BASE + 1.0;
 const BUF_RAIL: f64 = EXTRA_BUFS_BASE + 2.0;
+const BUF_EMP: f64 = EXTRA_BUFS_BASE + 3.0;
+const BUF_NEBULA: f64 = EXTRA_BUFS_BASE + 4.0;
+const BUF_BEAMS: f64 = EXTRA_BUFS_BASE + 5.0;
+const BUF_FLAK: f64 = EXTRA_BUFS_BASE + 6.0;
+const BUF_CHARGE: f64 = EXTRA_BUFS_BASE + 7.0;
+const BUF_ZONE: f64 = EXTRA_BUFS_BASE + 8.0;
+const BUF_ORE: f64 = EXTRA_BUFS_BASE + 9.0;
 
 const BUF_SPARK: f64 = EXTRA_BUFS_BASE + 20.0;
 const BUF_EXHAUST: f64 = EXTRA_BUFS_BASE + 21.0;
 const BUF_EXPLOSION: f64 = EXTRA_BUFS_BASE + 22.0;
 const BUF_LASER_HIT: f64 = EXTRA_BUFS_BASE + 23.0;
+const BUF_EMP_HIT: f64 = EXTRA_BUFS_BASE + 24.0;
+const BUF_BOOST_EXHAUST: f64 = EXTRA_BUFS_BASE + 25.0;
 
 // IDs for entities' buffers
 const BUFFERS_PER_ENTITY:u32 = 2;
@@ -51,6 +67,19 @@ fn entity_buffer(entity_id: u32, buffer_id: u32) -> f64 {
 // "Default color", white (no modulation)
 const DEF_COLOR: &[f32] = &[1.0, 1.0, 1.0, 1.0];
 
+// Colors ships are tinted with, by team
+const TEAM_COLORS: [&[f32]; 2] = [
+    &[0.5, 0.7, 1.0, 1.0],
+    &[1.0, 0.55, 0.45, 1.0],
+];
+
+fn team_color(team: Option<&Team>) -> &'static [f32] {
+    match team {
+        Some(t) => TEAM_COLORS[t.0 as usize % TEAM_COLORS.len()],
+        None => DEF_COLOR,
+    }
+}
+
 /// Global information kept by the render module
 #[derive(Default)]
 pub struct RenderApp {
@@ -122,6 +151,31 @@ pub fn init() {
         [1.0, 1.0, 1.0, 1.0],
     );
     rail.store(BUF_RAIL, BufType::STATIC);
+    let mut emp = VertexVecs::default();
+    emp.filled_rect(
+        [-0.4, -0.4], [0.4, 0.4],
+        [0.4, 0.6, 1.0, 1.0],
+    );
+    emp.store(BUF_EMP, BufType::STATIC);
+    let mut flak = VertexVecs::default();
+    flak.filled_rect(
+        [-0.3, -0.3], [0.3, 0.3],
+        [1.0, 0.7, 0.2, 1.0],
+    );
+    flak.store(BUF_FLAK, BufType::STATIC);
+    let mut charge = VertexVecs::default();
+    charge.line(
+        [-0.6, 0.0], [0.6, 0.0],
+        0.6,
+        [1.0, 0.9, 0.3, 1.0],
+    );
+    charge.store(BUF_CHARGE, BufType::STATIC);
+    let mut ore = VertexVecs::default();
+    ore.filled_rect(
+        [-0.25, -0.25], [0.25, 0.25],
+        [0.9, 0.7, 0.4, 1.0],
+    );
+    ore.store(BUF_ORE, BufType::STATIC);
     let mut spark = VertexVecs::default();
     spark.filled_rect(
         [-0.05, -0.05], [0.05, 0.05],
@@ -134,6 +188,12 @@ pub fn init() {
         [1.0, 1.0, 1.0, 1.0],
     );
     exhaust.store(BUF_EXHAUST, BufType::STATIC);
+    let mut boost_exhaust = VertexVecs::default();
+    boost_exhaust.filled_rect(
+        [-0.4, -0.4], [0.4, 0.4],
+        [1.0, 0.6, 0.2, 1.0],
+    );
+    boost_exhaust.store(BUF_BOOST_EXHAUST, BufType::STATIC);
     let mut explosion = VertexVecs::default();
     explosion.filled_rect(
         [-1.2, -1.2], [1.2, 1.2],
@@ -151,6 +211,34 @@ pub fn init() {
         [0.0, 1.0, 0.0, 1.0],
     );
     laser_hit.store(BUF_LASER_HIT, BufType::STATIC);
+    let mut emp_hit = VertexVecs::default();
+    let mut points = Vec::new();
+    for i in 0..32 {
+        let (s, c) = (i as f32 * 2.0 * PI / 32.0).sin_cos();
+        points.push([4.0 * c, 4.0 * s]);
+    }
+    emp_hit.filled_convex_polygon(
+        &points,
+        [0.4, 0.6, 1.0, 1.0],
+    );
+    emp_hit.store(BUF_EMP_HIT, BufType::STATIC);
+    let mut nebula = VertexVecs::default();
+    let mut points = Vec::new();
+    for i in 0..64 {
+        let (s, c) = (i as f32 * 2.0 * PI / 64.0).sin_cos();
+        points.push([c, s]);
+    }
+    nebula.filled_convex_polygon(
+        &points,
+        [0.6, 0.3, 0.8, 1.0],
+    );
+    nebula.store(BUF_NEBULA, BufType::STATIC);
+    let mut zone = VertexVecs::default();
+    zone.filled_convex_polygon(
+        &points,
+        [1.0, 1.0, 1.0, 1.0],
+    );
+    zone.store(BUF_ZONE, BufType::STATIC);
 }
 
 /// Render everything
@@ -162,11 +250,25 @@ pub fn render(app: &mut App, viewport: [u32; 2]) {
     let blocky = world.read_component::<Blocky>();
     let projectile = world.read_component::<Projectile>();
     let particle = world.read_component::<Particle>();
+    let medium = world.read_component::<MediumZone>();
+    let beam = world.read_component::<Beam>();
+    let team = world.read_component::<Team>();
+    let capture = world.read_component::<CaptureZone>();
+    let ore = world.read_component::<OrePickup>();
+    let station = world.read_component::<Station>();
+    let planet = world.read_component::<Planet>();
+    let circle = world.read_component::<CircleCollider>();
 
-    // Update camera location
+    // Update camera location, between the local ships
     app.render_app.set_viewport(viewport);
+    let mut center = [0.0, 0.0];
+    let mut count = 0;
     for (pos, _) in (&pos, &local).join() {
-        app.render_app.camera = pos.pos;
+        center = vec2_add(center, pos.pos);
+        count += 1;
+    }
+    if count > 0 {
+        app.render_app.camera = vec2_scale(center, 1.0 / count as f32);
     }
     set_camera(
         app.render_app.camera[0], app.render_app.camera[1],
@@ -177,11 +279,105 @@ pub fn render(app: &mut App, viewport: [u32; 2]) {
         1.0 / app.render_app.scale[1] + 30.0,
     ]);
 
-    // TODO: Background
+    // Draw the backdrop of the sector, fixed on screen
+    let sectors = world.read_resource::<SectorManager>();
+    let backdrop = sectors
+        .get(SectorId::at(app.render_app.camera))
+        .and_then(|s| s.backdrop.as_ref());
+    if let Some(backdrop) = backdrop {
+        let scale = app.render_app.scale;
+        let camera = app.render_app.camera;
+        let at = |p: [f32; 2]| {
+            [camera[0] + p[0] / scale[0], camera[1] + p[1] / scale[1]]
+        };
+        let size = |s: f32| s * 2.0 / scale[1];
+        let sun = at(backdrop.sun_pos);
+        let c = backdrop.sun_color;
+        draw(
+            sun[0], sun[1],
+            0.0, size(backdrop.sun_size),
+            &[c[0], c[1], c[2], 1.0],
+            BUF_ZONE,
+        );
+        for planet in &backdrop.planets {
+            let p = at(planet.pos);
+            let c = planet.color;
+            draw(
+                p[0], p[1],
+                0.0, size(planet.size),
+                &[c[0], c[1], c[2], 1.0],
+                BUF_ZONE,
+            );
+        }
+    }
 
     // Bounds
     draw(0.0, 0.0, 0.0, 1.0, DEF_COLOR, BUF_BOUNDS);
 
+    // Draw nebulae
+    for (pos, zone) in (&pos, &medium).join() {
+        draw(
+            pos.pos[0], pos.pos[1],
+            0.0, zone.radius,
+            &[1.0, 1.0, 1.0, 0.2],
+            BUF_NEBULA,
+        );
+    }
+
+    // Draw control points in their owner's color, with the capture
+    // progress growing from the center
+    for (pos, zone) in (&pos, &capture).join() {
+        let color = team_color(zone.owner.as_ref());
+        draw(
+            pos.pos[0], pos.pos[1],
+            0.0, zone.radius,
+            &[color[0], color[1], color[2], 0.15],
+            BUF_ZONE,
+        );
+        if let Some((t, progress)) = zone.capturing {
+            let color = team_color(Some(&t));
+            draw(
+                pos.pos[0], pos.pos[1],
+                0.0, zone.radius * progress,
+                &[color[0], color[1], color[2], 0.2],
+                BUF_ZONE,
+            );
+        }
+    }
+
+    // Draw planets
+    for (pos, _, circle) in (&pos, &planet, &circle).join() {
+        draw(
+            pos.pos[0], pos.pos[1],
+            0.0, circle.radius,
+            &[0.35, 0.45, 0.6, 1.0],
+            BUF_ZONE,
+        );
+    }
+
+    // Draw the safe zones of stations
+    for (pos, station) in (&pos, &station).join() {
+        draw(
+            pos.pos[0], pos.pos[1],
+            0.0, station.safe_radius,
+            &[0.3, 0.9, 0.4, 0.08],
+            BUF_ZONE,
+        );
+    }
+
+    // Draw ore, as small diamonds
+    for (pos, _) in (&pos, &ore).join() {
+        if vec2_square_len(vec2_sub(pos.pos, app.render_app.camera)) > sq_radius {
+            continue;
+        }
+        draw(
+            pos.pos[0], pos.pos[1],
+            0.25 * PI, 1.0,
+            DEF_COLOR,
+            BUF_ORE,
+        );
+    }
+
     // Draw blocks
     let mut blocky_seen: HashSet<u32> = HashSet::new();
     for (ent, pos, blocky) in (&*entities, &pos, &blocky).join() {
@@ -219,16 +415,17 @@ pub fn render(app: &mut App, viewport: [u32; 2]) {
         generate_blocky_buffers(ent.id(), blocky, changed);
 
         // Draw
+        let color = team_color(team.get(ent));
         draw(
             pos.pos[0], pos.pos[1],
             pos.rot, 1.0,
-            DEF_COLOR,
+            color,
             entity_buffer(ent.id(), 0),
         );
         draw(
             pos.pos[0], pos.pos[1],
             pos.rot, 1.0,
-            DEF_COLOR,
+            color,
             entity_buffer(ent.id(), 1),
         );
     }
@@ -268,9 +465,49 @@ pub fn render(app: &mut App, viewport: [u32; 2]) {
                     BUF_RAIL,
                 );
             }
+            ProjectileType::Emp => {
+                draw(
+                    pos.pos[0], pos.pos[1],
+                    pos.rot, 1.0,
+                    DEF_COLOR,
+                    BUF_EMP,
+                );
+            }
+            ProjectileType::Flak => {
+                draw(
+                    pos.pos[0], pos.pos[1],
+                    pos.rot, 1.0,
+                    DEF_COLOR,
+                    BUF_FLAK,
+                );
+            }
+            ProjectileType::Charge(_) => {
+                draw(
+                    pos.pos[0], pos.pos[1],
+                    pos.rot, 1.0,
+                    DEF_COLOR,
+                    BUF_CHARGE,
+                );
+            }
         }
     }
 
+    // Draw beams
+    let mut beams = VertexVecs::default();
+    for beam in (&beam).join() {
+        let (s, c) = beam.rot.sin_cos();
+        let end = vec2_add(beam.start, vec2_scale([c, s], beam.length));
+        beams.line(beam.start, end, 0.2, [1.0, 0.3, 0.2, 0.8]);
+        if beam.hitting {
+            beams.filled_rect(
+                [end[0] - 0.3, end[1] - 0.3], [end[0] + 0.3, end[1] + 0.3],
+                [1.0, 0.8, 0.4, 1.0],
+            );
+        }
+    }
+    beams.store(BUF_BEAMS, BufType::STREAM);
+    draw(0.0, 0.0, 0.0, 1.0, DEF_COLOR, BUF_BEAMS);
+
     // Draw particles
     for (pos, particle) in (&pos, &particle).join() {
         // Check position is within visible area
@@ -298,6 +535,15 @@ pub fn render(app: &mut App, viewport: [u32; 2]) {
                     BUF_EXHAUST,
                 );
             }
+            ParticleType::BoostExhaust => {
+                let alpha = (particle.lifetime * 1.5).min(0.7);
+                draw(
+                    pos.pos[0], pos.pos[1],
+                    pos.rot, 1.0,
+                    &[1.0, 1.0, 1.0, alpha],
+                    BUF_BOOST_EXHAUST,
+                );
+            }
             ParticleType::Explosion => {
                 let alpha = (particle.lifetime as f32 * 1.6).min(0.8);
                 draw(
@@ -317,6 +563,16 @@ pub fn render(app: &mut App, viewport: [u32; 2]) {
                     BUF_LASER_HIT,
                 );
             }
+            ParticleType::EmpHit => {
+                let alpha = (particle.lifetime * 2.0).min(0.5);
+                let size = 1.0 - particle.lifetime * 2.0;
+                draw(
+                    pos.pos[0], pos.pos[1],
+                    pos.rot, size,
+                    &[1.0, 1.0, 1.0, alpha],
+                    BUF_EMP_HIT,
+                );
+            }
         }
     }
 }
@@ -385,7 +641,10 @@ fn generate_blocky_buffers(ent_id: u32, blocky: &Blocky, base_changed: bool) {
                         [0.8, 0.8, 1.0, 1.0],
                     );
                 }
-                BlockInner::RailGun { .. } => {
+                BlockInner::RailGun { .. }
+                | BlockInner::EmpGun { .. }
+                | BlockInner::FlakCannon { .. }
+                | BlockInner::ChargeGun { .. } => {
                     buf_base.polygon(
                         &[
                             [-0.35, -0.35],
@@ -401,6 +660,93 @@ fn generate_blocky_buffers(ent_id: u32, blocky: &Blocky, base_changed: bool) {
                         [0.8, 0.8, 1.0, 1.0],
                     );
                 }
+                BlockInner::BeamLaser { .. } => {
+                    buf_base.polygon(
+                        &[
+                            [-0.35, -0.35],
+                            [0.0, -0.45],
+                            [0.35, -0.35],
+                            [0.45, 0.0],
+                            [0.35, 0.35],
+                            [0.0, 0.45],
+                            [-0.35, 0.35],
+                            [-0.45, 0.0],
+                        ],
+                        0.05,
+                        [1.0, 0.6, 0.5, 1.0],
+                    );
+                }
+                BlockInner::BoardingClamp => {
+                    buf_base.hollow_rect(
+                        [-0.4, -0.4],
+                        [0.0, 0.4],
+                        0.1,
+                        [0.8, 0.8, 0.8, 1.0],
+                    );
+                    for y in &[-0.3, 0.3] {
+                        buf_base.line(
+                            [0.0, *y],
+                            [0.45, *y * 0.5],
+                            0.1,
+                            [1.0, 0.8, 0.2, 1.0],
+                        );
+                    }
+                }
+                BlockInner::SalvageBeam => {
+                    buf_base.hollow_rect(
+                        [-0.4, -0.4],
+                        [0.4, 0.4],
+                        0.05,
+                        [0.8, 0.8, 0.8, 1.0],
+                    );
+                    buf_base.filled_convex_polygon(
+                        &[[-0.2, -0.2], [0.3, 0.0], [-0.2, 0.2]],
+                        [0.2, 1.0, 0.4, 1.0],
+                    );
+                }
+                BlockInner::DroneBay { drones, .. } => {
+                    buf_base.hollow_rect(
+                        [-0.4, -0.4],
+                        [0.4, 0.4],
+                        0.1,
+                        [0.8, 0.8, 0.8, 1.0],
+                    );
+                    for i in 0..drones {
+                        let y = -0.15 + 0.3 * i as f32;
+                        buf_base.filled_rect(
+                            [-0.2, y - 0.08],
+                            [0.2, y + 0.08],
+                            [0.4, 0.7, 1.0, 1.0],
+                        );
+                    }
+                }
+                BlockInner::FuelTank { .. } => {
+                    buf_base.hollow_rect(
+                        [-0.4, -0.4],
+                        [0.4, 0.4],
+                        0.05,
+                        [0.8, 0.8, 0.8, 1.0],
+                    );
+                    buf_base.filled_rect(
+                        [-0.3, -0.2],
+                        [0.3, 0.2],
+                        [0.9, 0.6, 0.1, 1.0],
+                    );
+                }
+                BlockInner::DockingPort => {
+                    buf_base.hollow_rect(
+                        [-0.4, -0.4],
+                        [0.4, 0.4],
+                        0.1,
+                        [0.8, 0.8, 0.8, 1.0],
+                    );
+                    buf_base.hollow_rect(
+                        [-0.25, -0.25],
+                        [0.25, 0.25],
+                        0.05,
+                        [0.3, 0.9, 0.4, 1.0],
+                    );
+                }
                 BlockInner::Armor => {
                     buf_base.hollow_rect(
                         [-0.4, -0.4],
@@ -445,6 +791,40 @@ fn generate_blocky_buffers(ent_id: u32, blocky: &Blocky, base_changed: bool) {
                         [0.8, 0.8, 1.0, 1.0],
                     );
                 }
+                BlockInner::EmpGun { angle, .. } => {
+                    buf_dyn.rotate(angle).filled_rect(
+                        [-0.2, -0.2], [0.55, 0.2],
+                        [0.4, 0.6, 1.0, 1.0],
+                    );
+                }
+                BlockInner::FlakCannon { angle, .. } => {
+                    let mut buf_dyn = buf_dyn.rotate(angle);
+                    for y in &[-0.15, 0.15] {
+                        buf_dyn.filled_rect(
+                            [0.0, *y - 0.07], [0.6, *y + 0.07],
+                            [1.0, 0.7, 0.2, 1.0],
+                        );
+                    }
+                }
+                BlockInner::ChargeGun { angle, charge, .. } => {
+                    let mut buf_dyn = buf_dyn.rotate(angle);
+                    buf_dyn.filled_rect(
+                        [-0.2, -0.2], [0.6, 0.2],
+                        [0.8, 0.8, 1.0, 1.0],
+                    );
+                    if charge > 0.0 {
+                        buf_dyn.filled_rect(
+                            [-0.15, -0.15], [-0.15 + 0.7 * charge, 0.15],
+                            [1.0, 0.9, 0.3, 1.0],
+                        );
+                    }
+                }
+                BlockInner::BeamLaser { angle } => {
+                    buf_dyn.rotate(angle).filled_rect(
+                        [0.0, -0.1], [0.7, 0.1],
+                        [1.0, 0.3, 0.2, 1.0],
+                    );
+                }
                 _ => {}
             }
         }
@@ -1,23 +1,161 @@
 //! Entrypoint and eventloop for server.
 
-use game::Game;
-use game::net::udp::UdpServer;
+use game::{Game, GameBuilder};
+#[cfg(feature = "encryption")]
+use game::net::crypto::{EncryptedServer, ServerIdentity};
+use game::net::tcp::TcpServer;
+use game::net::udp::{LanAnnouncer, UdpServer};
+use game::net::Server;
+use game::players::Players;
+use game::profiles::FileStore;
+use game::rules::Rules;
 use log::{info, warn};
 use std::thread::sleep;
 use std::time::{Duration, SystemTime};
 
 const TIME_STEP: f32 = 0.080;
 
+/// Port the server listens on.
+const PORT: u16 = 34244;
+
+/// Duration of a match, after which the results are sent to the players.
+const MATCH_LENGTH: f32 = 600.0;
+
+/// Time for players to get ready before each match.
+const WARMUP: f32 = 15.0;
+
+/// Time the results of a match stay up before the next one.
+const INTERMISSION: f32 = 10.0;
+
 fn to_secs(dt: Duration) -> f32 {
     dt.as_secs() as f32 + dt.subsec_nanos() as f32 * 0.000_000_001
 }
 
+/// Starts the game on a transport, encrypting it if a key is set.
+fn serve<S: Server>(builder: GameBuilder, server: S) -> Game {
+    #[cfg(feature = "encryption")]
+    {
+        if let Ok(path) = std::env::var("IDENTITY_KEY") {
+            let identity = ServerIdentity::load_or_generate(&path)
+                .expect("Couldn't load the identity key");
+            info!("Encrypting, server key is {}", identity.public_key_hex());
+            return builder.server(EncryptedServer::new(server, identity));
+        }
+    }
+    builder.server(server)
+}
+
 /// Entrypoint for server.
 fn main() {
     color_logger::init(log::Level::Info).unwrap();
     info!("Starting up");
 
-    let mut game = Game::new_server(UdpServer::new(34244));
+    let builder = GameBuilder::new().rules(Rules {
+        match_length: Some(MATCH_LENGTH),
+        warmup: WARMUP,
+        intermission: INTERMISSION,
+        ..Default::default()
+    });
+    #[cfg(feature = "webhook")]
+    let builder = match std::env::var("WEBHOOK_URL") {
+        Ok(url) => {
+            info!("Posting events to webhook");
+            builder.webhook(url)
+        }
+        Err(_) => builder,
+    };
+    let builder = match std::env::var("PROFILE_DIR") {
+        Ok(dir) => {
+            info!("Keeping player profiles in {}", dir);
+            builder.profiles(FileStore::new(dir))
+        }
+        Err(_) => builder,
+    };
+    let name = std::env::var("SERVER_NAME")
+        .unwrap_or_else(|_| "Vigilant Steel".to_owned());
+    #[cfg(feature = "master")]
+    let builder = match (
+        std::env::var("MASTER_SERVER"),
+        std::env::var("PUBLIC_ADDRESS"),
+    ) {
+        (Ok(url), Ok(address)) => {
+            info!("Registering with master server {} as {}", url, address);
+            builder.master_server(url, name.clone(), address)
+        }
+        (Ok(_), Err(_)) => {
+            warn!("Set PUBLIC_ADDRESS to register with the master server");
+            builder
+        }
+        _ => builder,
+    };
+    let builder = match std::env::var("BAN_LIST") {
+        Ok(path) => {
+            info!("Keeping bans in {}", path);
+            builder.ban_list(path)
+        }
+        Err(_) => builder,
+    };
+    let builder = match std::env::var("CLIENT_BANDWIDTH") {
+        Ok(bytes) => match bytes.parse() {
+            Ok(bytes) => {
+                info!("Sending up to {} bytes/s to each client", bytes);
+                builder.client_bandwidth(bytes)
+            }
+            Err(_) => {
+                warn!("Invalid CLIENT_BANDWIDTH {:?}", bytes);
+                builder
+            }
+        },
+        Err(_) => builder,
+    };
+    let max_clients = match std::env::var("MAX_CLIENTS") {
+        Ok(max) => match max.parse() {
+            Ok(max) => {
+                info!("Taking up to {} players", max);
+                Some(max)
+            }
+            Err(_) => {
+                warn!("Invalid MAX_CLIENTS {:?}", max);
+                None
+            }
+        },
+        Err(_) => None,
+    };
+    let builder = match max_clients {
+        Some(max) => builder.max_clients(max),
+        None => builder,
+    };
+    let builder = match std::env::var("CAPTURE_FILE") {
+        Ok(path) => builder.capture(path),
+        Err(_) => builder,
+    };
+    #[cfg(feature = "metrics")]
+    let builder = match std::env::var("METRICS_ADDRESS") {
+        Ok(address) => builder.metrics(address),
+        Err(_) => builder,
+    };
+    let transport = std::env::var("TRANSPORT").unwrap_or_default();
+    let mut game = match transport.as_str() {
+        "tcp" => {
+            info!("Taking clients over TCP");
+            let mut server = TcpServer::new(PORT);
+            if let Some(max) = max_clients {
+                server = server.max_connections(max);
+            }
+            serve(builder, server)
+        }
+        "" | "udp" => serve(builder, UdpServer::new(PORT)),
+        _ => panic!("Unknown TRANSPORT {:?}", transport),
+    };
+
+    // Announce the server to clients on the local network
+    let mut announcer = match LanAnnouncer::new(&name, PORT) {
+        Ok(a) => Some(a),
+        Err(e) => {
+            warn!("Can't announce the server on the local network: {}", e);
+            None
+        }
+    };
 
     let mut previous = SystemTime::now();
     let mut timer = 0.0;
@@ -38,6 +176,10 @@ fn main() {
                     game.update(TIME_STEP);
                     timer -= TIME_STEP;
                 }
+                if let Some(ref mut announcer) = announcer {
+                    let players = game.world.fetch::<Players>();
+                    announcer.announce(players.players.len());
+                }
 
                 if TIME_STEP - timer > 0.001 {
                     sleep(Duration::new(
@@ -0,0 +1,176 @@
+//! Achievements, milestones players reach over a session.
+//!
+//! `SysAchievements` follows the game events to track each player's
+//! progress in the `Achievements` resource: kills, survival waves, blocks
+//! destroyed. When a player reaches a milestone, it sends a
+//! `GameEvent::Achievement`, which the server forwards to that player's
+//! client. Frontends can read those events to show popups.
+
+use specs::shrev::ReaderId;
+use specs::{Entities, Join, ReadStorage, System, World, Write};
+use std::collections::HashMap;
+
+use crate::events::{GameEvent, GameEvents};
+use crate::physics::{pilot, LocalControl, RemoteControl};
+use crate::ship::Ship;
+
+/// Numbers of survival waves that earn `Achievement::Survivor`.
+const WAVE_MILESTONES: [u32; 3] = [5, 10, 20];
+
+/// Numbers of blocks that earn `Achievement::Demolition`.
+const BLOCK_MILESTONES: [u32; 3] = [100, 500, 2000];
+
+/// A milestone a player reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "webhook", derive(serde::Serialize))]
+pub enum Achievement {
+    /// Destroyed another player's ship for the first time.
+    FirstKill,
+    /// Survived this many waves in survival mode, see `survival.rs`.
+    Survivor(u32),
+    /// Destroyed this many blocks.
+    Demolition(u32),
+}
+
+impl Achievement {
+    /// Short text for the achievement, for popups.
+    pub fn describe(self) -> String {
+        match self {
+            Achievement::FirstKill => "First kill".to_owned(),
+            Achievement::Survivor(waves) => {
+                format!("Survived {} waves", waves)
+            }
+            Achievement::Demolition(blocks) => {
+                format!("Destroyed {} blocks", blocks)
+            }
+        }
+    }
+}
+
+/// A player's progress towards achievements.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    pub kills: u32,
+    /// Most survival waves this player was around for.
+    pub waves: u32,
+    pub blocks_destroyed: u32,
+    pub earned: Vec<Achievement>,
+}
+
+impl Progress {
+    /// Records an achievement, returning whether it is new.
+    fn earn(&mut self, achievement: Achievement) -> bool {
+        if self.earned.contains(&achievement) {
+            false
+        } else {
+            self.earned.push(achievement);
+            true
+        }
+    }
+}
+
+/// Progress of each player, available as a resource.
+///
+/// Players are identified as in `physics::pilot()`. On clients, this only
+/// has the achievements of the local player, received from the server.
+#[derive(Debug, Default)]
+pub struct Achievements {
+    pub players: HashMap<u64, Progress>,
+}
+
+/// Achievements system, tracks progress from the game events.
+pub struct SysAchievements {
+    reader: ReaderId<GameEvent>,
+}
+
+impl SysAchievements {
+    pub fn new(world: &World) -> SysAchievements {
+        SysAchievements {
+            reader: world.fetch_mut::<GameEvents>().register_reader(),
+        }
+    }
+}
+
+impl<'a> System<'a> for SysAchievements {
+    type SystemData = (
+        Write<'a, GameEvents>,
+        Write<'a, Achievements>,
+        Entities<'a>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            mut events,
+            mut achievements,
+            entities,
+            ship,
+            local,
+            remote,
+        ): Self::SystemData,
+    ) {
+        let mut earned = Vec::new();
+        for event in events.read(&mut self.reader) {
+            match *event {
+                GameEvent::ShipDestroyed {
+                    player: Some(player),
+                    killer: Some(killer),
+                } if killer != player => {
+                    let progress =
+                        achievements.players.entry(killer).or_default();
+                    progress.kills += 1;
+                    if progress.earn(Achievement::FirstKill) {
+                        earned.push((killer, Achievement::FirstKill));
+                    }
+                }
+                GameEvent::WaveStart { wave } => {
+                    // Everyone still flying got through the last wave
+                    let survived = wave - 1;
+                    for (ent, _) in (&*entities, &ship).join() {
+                        let player = match pilot(ent, &local, &remote) {
+                            Some(p) => p,
+                            None => continue,
+                        };
+                        let progress =
+                            achievements.players.entry(player).or_default();
+                        progress.waves = progress.waves.max(survived);
+                        for &milestone in &WAVE_MILESTONES {
+                            let achievement = Achievement::Survivor(milestone);
+                            if survived >= milestone
+                                && progress.earn(achievement)
+                            {
+                                earned.push((player, achievement));
+                            }
+                        }
+                    }
+                }
+                GameEvent::BlocksDestroyed {
+                    attacker: Some(attacker),
+                    count,
+                } => {
+                    let progress =
+                        achievements.players.entry(attacker).or_default();
+                    progress.blocks_destroyed += count;
+                    for &milestone in &BLOCK_MILESTONES {
+                        let achievement = Achievement::Demolition(milestone);
+                        if progress.blocks_destroyed >= milestone
+                            && progress.earn(achievement)
+                        {
+                            earned.push((attacker, achievement));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (player, achievement) in earned {
+            events.single_write(GameEvent::Achievement {
+                player,
+                achievement,
+            });
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Behavior trees for AI pilots.
+//!
+//! A tree is plain data, a `Node` built out of composites, conditions and
+//! actions, so that scenarios can put together their own behaviors without
+//! touching the systems. `SysAi` ticks each pilot's tree once per frame,
+//! starting from idle controls and the target from the previous frame; the
+//! actions that run set the ship's controls.
+
+use specs::{Entity, ReadStorage};
+use vecmath::*;
+
+use crate::faction::{hostile, Faction};
+use crate::math::{atan2, sin_cos};
+use crate::physics::{Position, Velocity};
+use crate::ship::{Ship, WEAPON_GROUPS};
+use crate::utils::{angle_wrap, clamp};
+
+/// How fast pilots turn towards their target, in radians per second per
+/// radian of error.
+const TURN_RATE: f32 = 3.0;
+
+/// A node of a behavior tree.
+#[derive(Debug, Clone)]
+pub enum Node {
+    /// Runs the children in order, until one fails.
+    Sequence(Vec<Node>),
+    /// Runs the children in order, until one succeeds.
+    Selector(Vec<Node>),
+    /// Runs the child, and succeeds whatever happens.
+    Optional(Box<Node>),
+    /// Runs the child, and succeeds if it fails.
+    Not(Box<Node>),
+    Condition(Condition),
+    Action(Action),
+}
+
+/// A test on the situation, succeeds if true.
+#[derive(Debug, Clone)]
+pub enum Condition {
+    /// Whether a target was picked.
+    HasTarget,
+    /// Whether the target is closer than this.
+    Within(f32),
+    /// Whether the ship is facing the target, within this angle in radians.
+    Aimed(f32),
+}
+
+/// Something the pilot does, fails if it can't.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Targets the nearest hostile ship closer than this.
+    AcquireEnemy(f32),
+    /// Targets the nearest asteroid closer than this.
+    AcquireAsteroid(f32),
+    /// Turns towards the target, and points the guns at it.
+    Face,
+    /// Thrusts forward at this throttle, from 0 to 1.
+    Advance(f32),
+    /// Thrusts backward at this throttle, from 0 to 1.
+    Retreat(f32),
+    /// Boosts the thrusters.
+    Boost,
+    /// Fires all weapon groups.
+    Fire,
+    /// Uses the thrusters to come to a stop.
+    Stop,
+}
+
+/// Result of ticking a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Success,
+    Failure,
+}
+
+/// What a tree sees of the world, and the ship it flies.
+pub struct Context<'a, 'b> {
+    pub ent: Entity,
+    pub pos: &'a Position,
+    pub vel: &'a Velocity,
+    pub ship: &'a mut Ship,
+    /// The current target and its position.
+    pub target: Option<(Entity, [f32; 2])>,
+    /// Ships that can be targeted, those the pilot's sensors pick up, with
+    /// their positions.
+    pub ships: &'a [(Entity, [f32; 2])],
+    /// Asteroids that can be targeted, with their positions.
+    pub asteroids: &'a [(Entity, [f32; 2])],
+    pub faction: &'a ReadStorage<'b, Faction>,
+}
+
+impl<'a, 'b> Context<'a, 'b> {
+    /// Distance to the target, and angle between the ship's heading and it.
+    fn to_target(&self) -> Option<(f32, f32)> {
+        self.target.map(|(_, target)| {
+            let to = vec2_sub(target, self.pos.pos);
+            let angle = atan2(to[1], to[0]) - self.pos.rot;
+            (vec2_len(to), angle_wrap(angle))
+        })
+    }
+
+    /// Picks the nearest of `candidates` in range, and makes it the target.
+    fn acquire<F>(
+        &mut self,
+        candidates: &[(Entity, [f32; 2])],
+        range: f32,
+        filter: F,
+    ) -> Status
+    where
+        F: Fn(Entity) -> bool,
+    {
+        let pos = self.pos.pos;
+        self.target = candidates
+            .iter()
+            .filter(|&&(e, _)| filter(e))
+            .map(|&(e, p)| (e, p, vec2_square_len(vec2_sub(p, pos))))
+            .filter(|&(_, _, sq_dist)| sq_dist <= range * range)
+            .min_by(|a, b| a.2.partial_cmp(&b.2).unwrap())
+            .map(|(e, p, _)| (e, p));
+        status(self.target.is_some())
+    }
+}
+
+fn status(success: bool) -> Status {
+    if success {
+        Status::Success
+    } else {
+        Status::Failure
+    }
+}
+
+impl Node {
+    /// Runs this node, setting the ship's controls.
+    pub fn tick(&self, ctx: &mut Context) -> Status {
+        match *self {
+            Node::Sequence(ref children) => {
+                for child in children {
+                    if child.tick(ctx) == Status::Failure {
+                        return Status::Failure;
+                    }
+                }
+                Status::Success
+            }
+            Node::Selector(ref children) => {
+                for child in children {
+                    if child.tick(ctx) == Status::Success {
+                        return Status::Success;
+                    }
+                }
+                Status::Failure
+            }
+            Node::Optional(ref child) => {
+                child.tick(ctx);
+                Status::Success
+            }
+            Node::Not(ref child) => match child.tick(ctx) {
+                Status::Success => Status::Failure,
+                Status::Failure => Status::Success,
+            },
+            Node::Condition(ref condition) => condition.check(ctx),
+            Node::Action(ref action) => action.run(ctx),
+        }
+    }
+}
+
+impl Condition {
+    fn check(&self, ctx: &Context) -> Status {
+        match *self {
+            Condition::HasTarget => status(ctx.target.is_some()),
+            Condition::Within(range) => match ctx.to_target() {
+                Some((dist, _)) => status(dist < range),
+                None => Status::Failure,
+            },
+            Condition::Aimed(tolerance) => match ctx.to_target() {
+                Some((_, angle)) => status(angle.abs() < tolerance),
+                None => Status::Failure,
+            },
+        }
+    }
+}
+
+impl Action {
+    fn run(&self, ctx: &mut Context) -> Status {
+        match *self {
+            Action::AcquireEnemy(range) => {
+                let (ent, faction, ships) = (ctx.ent, ctx.faction, ctx.ships);
+                ctx.acquire(ships, range, |e| hostile(faction, ent, e))
+            }
+            Action::AcquireAsteroid(range) => {
+                let asteroids = ctx.asteroids;
+                ctx.acquire(asteroids, range, |_| true)
+            }
+            Action::Face => match (ctx.target, ctx.to_target()) {
+                (Some((_, target)), Some((_, angle))) => {
+                    ctx.ship.want_thrust_rot =
+                        clamp(angle * TURN_RATE - ctx.vel.rot, -1.0, 1.0);
+                    ctx.ship.want_target = vec2_sub(target, ctx.pos.pos);
+                    Status::Success
+                }
+                _ => Status::Failure,
+            },
+            Action::Advance(throttle) => {
+                ctx.ship.want_thrust = [throttle, 0.0];
+                Status::Success
+            }
+            Action::Retreat(throttle) => {
+                ctx.ship.want_thrust = [-throttle, 0.0];
+                Status::Success
+            }
+            Action::Boost => {
+                ctx.ship.want_boost = true;
+                Status::Success
+            }
+            Action::Fire => {
+                ctx.ship.want_fire = [true; WEAPON_GROUPS];
+                Status::Success
+            }
+            Action::Stop => {
+                let (s, c) = sin_cos(ctx.pos.rot);
+                let vel = ctx.vel.vel;
+                ctx.ship.want_thrust = [
+                    -(vel[0] * c + vel[1] * s),
+                    vel[0] * s - vel[1] * c,
+                ];
+                ctx.ship.want_thrust_rot = clamp(-ctx.vel.rot, -1.0, 1.0);
+                Status::Success
+            }
+        }
+    }
+}
@@ -0,0 +1,421 @@
+//! Computer-controlled pilots.
+//!
+//! A ship with an `AiPilot` component gets its controls from `SysAi` instead
+//! of a player, by ticking the pilot's behavior tree, see `bt.rs`. The
+//! default trees come from the pilot's `Personality`: go after the nearest
+//! hostile ship, see `faction::hostile()`, and shoot at it once in range.
+//! Pilots only know of the ships their sensors pick up, see `sensors.rs`.
+//! This runs before `SysAutopilot` and `SysShip`, like player input.
+//!
+//! Ships spawned as a group have a `Wing`: they hold formation around their
+//! leader with the autopilot, until enemies get close.
+//!
+//! On top of what the tree wants, pilots steer around obstacles in the way of
+//! their velocity, see `look_ahead()`.
+
+pub mod bt;
+
+use specs::{Component, Entities, Entity, Join, LazyUpdate, Read,
+            HashMapStorage, ReadStorage, System, WriteStorage};
+use vecmath::*;
+
+use crate::asteroid::Asteroid;
+use crate::autopilot::Autopilot;
+use crate::blocks::Blocky;
+use crate::faction::{hostile, Faction};
+use crate::math::sin_cos;
+use crate::medium::MediumZone;
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{raycast, Position, Velocity};
+use crate::sensors::{can_see, SENSOR_RANGE};
+use crate::ship::{Ship, ShipClass, WEAPON_GROUPS};
+use crate::utils::clamp;
+
+use self::bt::{Action, Condition, Context, Node};
+
+/// Aiming error, in radians, under which AI pilots fire and thrust forward.
+const AIM_TOLERANCE: f32 = 0.3;
+
+/// Distance of the nearest enemy under which wingmen break formation.
+const BREAK_RANGE: f32 = 40.0;
+
+/// How far ahead AI pilots look for obstacles, in seconds at their current
+/// velocity.
+const AVOID_LOOKAHEAD: f32 = 2.0;
+
+/// Distance ahead AI pilots always look for obstacles, even when slow.
+const AVOID_MIN_RANGE: f32 = 6.0;
+
+/// Speed under which AI pilots don't look for obstacles.
+const AVOID_MIN_SPEED: f32 = 0.5;
+
+/// How hard AI pilots brake in front of obstacles, relative to the
+/// sideways thrust that takes them around.
+const AVOID_BRAKE: f32 = 0.5;
+
+/// The behavior of an AI pilot, picked when it spawns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Personality {
+    /// Rushes at enemies and fights up close, boosting to catch them.
+    Interceptor,
+    /// Keeps its distance and fires from afar.
+    Sniper,
+    /// Shoots asteroids for their ore, and leaves everyone else alone.
+    Miner,
+    /// Doesn't fight at all, leaving the flying to its `Autopilot`.
+    Trader,
+    /// Goes after the target its carrier gives it, see `drones.rs`.
+    Drone,
+}
+
+impl Personality {
+    /// Distance the pilot tries to keep from its target. It backs off when
+    /// closer than half this.
+    fn engage_distance(self) -> f32 {
+        match self {
+            Personality::Interceptor => 10.0,
+            Personality::Sniper => 35.0,
+            Personality::Drone => 8.0,
+            Personality::Miner | Personality::Trader => 15.0,
+        }
+    }
+
+    /// Distance under which the pilot opens fire.
+    fn fire_range(self) -> f32 {
+        match self {
+            Personality::Interceptor => 25.0,
+            Personality::Sniper => 55.0,
+            Personality::Drone => 20.0,
+            Personality::Miner | Personality::Trader => 25.0,
+        }
+    }
+
+    /// Fraction of the thrusters' power the pilot uses.
+    fn throttle(self) -> f32 {
+        match self {
+            Personality::Interceptor | Personality::Drone => 1.0,
+            Personality::Sniper => 0.6,
+            Personality::Miner | Personality::Trader => 0.5,
+        }
+    }
+
+    /// Whether the pilot boosts when far from its target.
+    fn boosts(self) -> bool {
+        self == Personality::Interceptor
+    }
+
+    /// The behavior tree for this personality.
+    ///
+    /// The pilot picks a target, turns towards it, closes in while facing
+    /// it or backs off when too close, and fires once aimed and in range.
+    /// With nothing to target, it comes to a stop. Traders do nothing, and
+    /// drones only attack the target they were given, leaving the rest to
+    /// the autopilot.
+    pub fn tree(self) -> Node {
+        use self::Action::*;
+        use self::Condition::*;
+        use self::Node::{Action as Do, Condition as If, Not, Optional,
+                         Selector, Sequence};
+
+        if self == Personality::Trader {
+            return Sequence(Vec::new());
+        }
+
+        let engage = self.engage_distance();
+        let fire_range = self.fire_range();
+        let throttle = self.throttle();
+        let acquire = match self {
+            Personality::Miner => Do(AcquireAsteroid(SENSOR_RANGE)),
+            Personality::Drone => If(HasTarget),
+            _ => Do(AcquireEnemy(SENSOR_RANGE)),
+        };
+        let mut advance = vec![
+            Not(Box::new(If(Within(engage)))),
+            If(Aimed(AIM_TOLERANCE)),
+            Do(Advance(throttle)),
+        ];
+        if self.boosts() {
+            advance.push(Optional(Box::new(Sequence(vec![
+                Not(Box::new(If(Within(fire_range)))),
+                Do(Boost),
+            ]))));
+        }
+        let attack = Sequence(vec![
+            acquire,
+            Do(Face),
+            Optional(Box::new(Selector(vec![
+                Sequence(vec![
+                    If(Within(engage * 0.5)),
+                    Do(Retreat(throttle)),
+                ]),
+                Sequence(advance),
+            ]))),
+            Optional(Box::new(Sequence(vec![
+                If(Within(fire_range)),
+                If(Aimed(AIM_TOLERANCE)),
+                Do(Fire),
+            ]))),
+        ]);
+        if self == Personality::Drone {
+            return attack;
+        }
+        Selector(vec![attack, Do(Stop)])
+    }
+}
+
+/// Marks a ship as flown by the computer.
+pub struct AiPilot {
+    pub personality: Personality,
+    /// The behavior tree flying the ship, from the personality unless a
+    /// scenario replaced it.
+    pub tree: Node,
+    /// The ship being attacked, or asteroid being mined.
+    pub target: Option<Entity>,
+}
+
+impl AiPilot {
+    pub fn new(personality: Personality) -> AiPilot {
+        AiPilot {
+            personality,
+            tree: personality.tree(),
+            target: None,
+        }
+    }
+
+    /// Creates a ship flown by the computer.
+    pub fn spawn(
+        entities: &Entities,
+        lazy: &Read<LazyUpdate>,
+        class: ShipClass,
+        pos: [f32; 2],
+        angle: f32,
+        personality: Personality,
+    ) -> Entity {
+        let ent = Ship::create_at(entities, lazy, class, pos, angle);
+        lazy.insert(ent, AiPilot::new(personality));
+        ent
+    }
+}
+
+impl Component for AiPilot {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Member of a group of AI ships, flying in formation with its leader.
+pub struct Wing {
+    pub leader: Entity,
+    /// Place in the formation, in the leader's coordinate system.
+    pub offset: [f32; 2],
+}
+
+impl Component for Wing {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Looks for obstacles in the way of a ship, other than its target.
+///
+/// This casts rays ahead of the ship's velocity, from its center and its
+/// sides. Returns the direction to thrust in to avoid the closest obstacle,
+/// in world coordinates, scaled by how urgent it is, and that urgency from 0
+/// to 1.
+fn look_ahead(
+    ent: Entity,
+    pos: &Position,
+    vel: &Velocity,
+    target: Option<Entity>,
+    entities: &Entities,
+    position: &ReadStorage<Position>,
+    blocky: &ReadStorage<Blocky>,
+) -> Option<([f32; 2], f32)> {
+    let speed = vec2_len(vel.vel);
+    if speed < AVOID_MIN_SPEED {
+        return None;
+    }
+    let dir = vec2_scale(vel.vel, 1.0 / speed);
+    let side = [-dir[1], dir[0]];
+    let range = (speed * AVOID_LOOKAHEAD).max(AVOID_MIN_RANGE);
+    let radius = blocky.get(ent).map(|b| b.radius).unwrap_or(1.0);
+
+    let mut closest: Option<(Entity, f32)> = None;
+    for &offset in &[-radius, 0.0, radius] {
+        let start = vec2_add(pos.pos, vec2_scale(side, offset));
+        let hit = raycast(
+            entities,
+            position,
+            blocky,
+            start,
+            dir,
+            range,
+            |e| e != ent && Some(e) != target,
+        );
+        if let Some(hit) = hit {
+            match closest {
+                Some((_, dist)) if dist <= hit.distance => {}
+                _ => closest = Some((hit.entity, hit.distance)),
+            }
+        }
+    }
+    let (obstacle, dist) = closest?;
+
+    // Go around it, on the side away from its center, and slow down
+    let weight = 1.0 - dist / range;
+    let to_obstacle = vec2_sub(position.get(obstacle)?.pos, pos.pos);
+    let away = if vec2_dot(to_obstacle, side) > 0.0 {
+        vec2_scale(side, -1.0)
+    } else {
+        side
+    };
+    let avoid = vec2_sub(away, vec2_scale(dir, AVOID_BRAKE));
+    Some((vec2_scale(avoid, weight), weight))
+}
+
+/// AI system, sets the controls of ships with an `AiPilot`.
+pub struct SysAi;
+
+impl<'a> System<'a> for SysAi {
+    type SystemData = (
+        Read<'a, LazyUpdate>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        ReadStorage<'a, Faction>,
+        ReadStorage<'a, Asteroid>,
+        ReadStorage<'a, Blocky>,
+        ReadStorage<'a, Wing>,
+        ReadStorage<'a, MediumZone>,
+        WriteStorage<'a, Ship>,
+        WriteStorage<'a, AiPilot>,
+        WriteStorage<'a, Autopilot>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            lazy,
+            entities,
+            position,
+            vel,
+            faction,
+            asteroid,
+            blocky,
+            wing,
+            medium,
+            mut ship,
+            mut ai,
+            mut autopilot,
+        ): Self::SystemData,
+    ) {
+        let ships = (&*entities, &position, &ship)
+            .join()
+            .map(|(e, p, _)| (e, p.pos))
+            .collect::<Vec<_>>();
+        let asteroids = (&*entities, &position, &asteroid)
+            .join()
+            .map(|(e, p, _)| (e, p.pos))
+            .collect::<Vec<_>>();
+        let zones = (&position, &medium)
+            .join()
+            .map(|(pos, zone)| (pos.pos, zone.clone()))
+            .collect::<Vec<_>>();
+
+        for (ent, pos, vel, ship, ai) in
+            (&*entities, &position, &vel, &mut ship, &mut ai).join()
+        {
+            // Start from idle controls, the tree's actions set them
+            ship.want_thrust = [0.0, 0.0];
+            ship.want_thrust_rot = 0.0;
+            ship.want_fire = [false; WEAPON_GROUPS];
+            ship.want_boost = false;
+
+            // Only consider the ships the sensors pick up
+            let visible = ships
+                .iter()
+                .filter(|&&(e, p)| e == ent || can_see(pos.pos, p, &zones))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            // Wingmen hold formation until enemies get close
+            if let Some(wing) = wing.get(ent) {
+                let leader = wing.leader;
+                let following = matches!(
+                    autopilot.get(ent),
+                    Some(&Autopilot::Follow { .. })
+                );
+                let engaged = visible.iter().any(|&(e, p)| {
+                    hostile(&faction, ent, e)
+                        && vec2_len(vec2_sub(p, pos.pos)) < BREAK_RANGE
+                });
+                if !ships.iter().any(|&(e, _)| e == leader) {
+                    // Leader is gone, every ship for itself
+                    lazy.remove::<Wing>(ent);
+                } else if !engaged {
+                    if !following {
+                        let follow = Autopilot::Follow {
+                            leader,
+                            offset: wing.offset,
+                        };
+                        autopilot.insert(ent, follow).unwrap();
+                    }
+                    ai.target = None;
+                    continue;
+                }
+                if following {
+                    autopilot.remove(ent);
+                }
+            }
+
+            // Keep the last target if it's still around
+            let target = ai.target.and_then(|t| {
+                visible
+                    .iter()
+                    .chain(asteroids.iter())
+                    .find(|&&(e, _)| e == t)
+                    .cloned()
+            });
+            let mut ctx = Context {
+                ent,
+                pos,
+                vel,
+                ship,
+                target,
+                ships: &visible,
+                asteroids: &asteroids,
+                faction: &faction,
+            };
+            ai.tree.tick(&mut ctx);
+            ai.target = ctx.target.map(|(e, _)| e);
+
+            // Steer clear of obstacles, unless the autopilot is flying
+            if autopilot.get(ent).is_none() {
+                let obstacle = look_ahead(
+                    ent,
+                    pos,
+                    vel,
+                    ai.target,
+                    &entities,
+                    &position,
+                    &blocky,
+                );
+                if let Some((avoid, weight)) = obstacle {
+                    let (s, c) = sin_cos(pos.rot);
+                    let avoid = [
+                        avoid[0] * c + avoid[1] * s,
+                        -avoid[0] * s + avoid[1] * c,
+                    ];
+                    for (want, avoid) in
+                        ship.want_thrust.iter_mut().zip(&avoid)
+                    {
+                        *want =
+                            clamp(*want * (1.0 - weight) + avoid, -1.0, 1.0);
+                    }
+                }
+            }
+
+            #[cfg(feature = "network")]
+            lazy.insert(ent, net::Dirty);
+        }
+        #[cfg(not(feature = "network"))]
+        let _ = lazy;
+    }
+}
@@ -1,19 +1,21 @@
 //! Asteroid objects, floating around for the user to collide with or shoot.
 //!
 //! Asteroids are not really special now. The components only marks the objects
-//! so they are removed when falling off the screen, and more asteroids spawned
-//! when their number is low.
+//! so they are removed when drifting away from the players, and more asteroids
+//! spawned when their number is low, as set by each sector's `Layout`.
 
 use rand::prelude::*;
-use specs::{Component, Entities, Read, ReadExpect, Join, LazyUpdate,
-            NullStorage, ReadStorage, System};
+use specs::{Component, Entities, Entity, Read, ReadExpect, Join, LazyUpdate,
+            NullStorage, ReadStorage, System, Write};
+use std::collections::HashMap;
 use std::f32::consts::PI;
 
-use crate::Role;
+use crate::{GameRng, Role};
 use crate::blocks::{Block, BlockInner, Blocky};
 #[cfg(feature = "network")]
 use crate::net;
 use crate::physics::{delete_entity, Position, Velocity};
+use crate::sector::{SectorId, SectorManager, SECTOR_SIZE};
 
 /// An asteroid
 #[derive(Default)]
@@ -23,16 +25,69 @@ impl Component for Asteroid {
     type Storage = NullStorage<Self>;
 }
 
+/// Creates an asteroid of random shape.
+pub fn create<R: Rng>(
+    entities: &Entities,
+    lazy: &LazyUpdate,
+    rng: &mut R,
+    pos: [f32; 2],
+    vel: [f32; 2],
+) -> Entity {
+    // Generate blocks in an ellipse
+    let mut blocks = Vec::new();
+    let a = rng.gen_range(3.0, 4.0);
+    let ai = a as i32 + 1;
+    let b = rng.gen_range(2.0, 3.0);
+    let bi = b as i32 + 1;
+    for y in -ai..ai {
+        for x in -bi..bi {
+            let x = x as f32;
+            let y = y as f32;
+            if x * x * a * a + y * y * b * b <= a * a * b * b {
+                blocks.push(([x, y], Block::new(BlockInner::Rock)));
+            }
+        }
+    }
+    let (blocky, _) = Blocky::new(blocks);
+
+    let entity = entities.create();
+    lazy.insert(
+        entity,
+        Position {
+            pos,
+            rot: rng.gen_range(0.0, 2.0 * PI),
+        },
+    );
+    lazy.insert(
+        entity,
+        Velocity {
+            vel,
+            rot: rng.gen_range(-2.0, 2.0),
+        },
+    );
+    lazy.insert(entity, Asteroid);
+    lazy.insert(entity, blocky);
+    #[cfg(feature = "network")]
+    {
+        lazy.insert(entity, net::Replicated::new());
+        lazy.insert(entity, net::Dirty);
+    }
+    entity
+}
+
 /// Asteroid spawning and removing.
 ///
-/// Asteroids are spawned after a delay when not enough exist, and removed on
-/// collision or when outside the screen.
+/// Asteroids are spawned at the edge of the active sectors (see
+/// `SectorManager::active()`) when not enough exist there, and removed on
+/// collision or when outside the active sectors.
 pub struct SysAsteroid;
 
 impl<'a> System<'a> for SysAsteroid {
     type SystemData = (
         ReadExpect<'a, Role>,
         Read<'a, LazyUpdate>,
+        Read<'a, SectorManager>,
+        Write<'a, GameRng>,
         Entities<'a>,
         ReadStorage<'a, Position>,
         ReadStorage<'a, Asteroid>,
@@ -40,78 +95,60 @@ impl<'a> System<'a> for SysAsteroid {
 
     fn run(
         &mut self,
-        (role, lazy, entities, pos, asteroid): Self::SystemData,
+        (
+            role,
+            lazy,
+            sectors,
+            mut rng,
+            entities,
+            pos,
+            asteroid,
+        ): Self::SystemData,
     ) {
         assert!(role.authoritative());
 
-        // Remove asteroids gone from the screen
-        let mut count = 0;
+        // Remove asteroids gone from the active sectors
+        let active = sectors.active();
+        let mut counts = HashMap::new();
         for (entity, pos, _) in (&*entities, &pos, &asteroid).join() {
-            count += 1;
-
-            let pos = pos.pos;
-            if pos[0] < -150.0 || pos[0] > 150.0 || pos[1] < -150.0
-                || pos[1] > 150.0
-            {
+            let sector = SectorId::at(pos.pos);
+            if !active.contains(&sector) {
                 delete_entity(*role, &entities, &lazy, entity);
                 continue;
             }
+            *counts.entry(sector).or_insert(0) += 1;
         }
 
-        if count < 60 {
+        for &sector in &active {
+            let layout = sectors.get(sector).and_then(|s| s.layout.as_ref());
+            let wanted = match layout {
+                Some(layout) => layout.asteroids,
+                None => continue,
+            };
+            if counts.get(&sector).cloned().unwrap_or(0) >= wanted {
+                continue;
+            }
+
             // Choose position
-            let mut rng = rand::thread_rng();
+            let rng = &mut *rng;
             let &(xpos, ypos) = [
                 (-1.0, 0.0), // left
                 (1.0, 0.0),  // right
                 (0.0, -1.0), // bottom
                 (0.0, 1.0),  // top
-            ].choose(&mut rng).unwrap();
-            // Generate blocks in an ellipse
-            let mut blocks = Vec::new();
-            let a = rng.gen_range(3.0, 4.0);
-            let ai = a as i32 + 1;
-            let b = rng.gen_range(2.0, 3.0);
-            let bi = b as i32 + 1;
-            for y in -ai..ai {
-                for x in -bi..bi {
-                    let x = x as f32;
-                    let y = y as f32;
-                    if x * x * a * a + y * y * b * b <= a * a * b * b {
-                        blocks.push(([x, y], Block::new(BlockInner::Rock)));
-                    }
-                }
-            }
-            let (blocky, _) = Blocky::new(blocks);
-
-            let entity = entities.create();
-            lazy.insert(
-                entity,
-                Position {
-                    pos: [
-                        xpos * 145.0 + ypos * rng.gen_range(-140.0, 140.0),
-                        ypos * 145.0 + xpos * rng.gen_range(-140.0, 140.0),
-                    ],
-                    rot: rng.gen_range(0.0, 2.0 * PI),
-                },
-            );
-            lazy.insert(
-                entity,
-                Velocity {
-                    vel: [
-                        rng.gen_range(-4.0, 4.0) - xpos * 10.0,
-                        rng.gen_range(-4.0, 4.0) - ypos * 10.0,
-                    ],
-                    rot: rng.gen_range(-2.0, 2.0),
-                },
-            );
-            lazy.insert(entity, Asteroid);
-            lazy.insert(entity, blocky);
-            #[cfg(feature = "network")]
-            {
-                lazy.insert(entity, net::Replicated::new());
-                lazy.insert(entity, net::Dirty);
-            }
+            ].choose(rng).unwrap();
+            let center = sector.center();
+            let edge = 0.5 * SECTOR_SIZE - 5.0;
+            let along = rng.gen_range(-edge + 5.0, edge - 5.0);
+            let pos = [
+                center[0] + xpos * edge + ypos * along,
+                center[1] + ypos * edge + xpos * along,
+            ];
+            let vel = [
+                rng.gen_range(-4.0, 4.0) - xpos * 10.0,
+                rng.gen_range(-4.0, 4.0) - ypos * 10.0,
+            ];
+            create(&entities, &lazy, rng, pos, vel);
         }
     }
 }
@@ -0,0 +1,254 @@
+//! Autopilot, flying ships to a point, matching another's velocity, or
+//! holding a place in formation.
+//!
+//! A ship with an `Autopilot` component ignores its pilot's movement controls:
+//! `SysAutopilot` sets them instead, until the autopilot is done and it
+//! removes the component. This runs before `SysShip`, which turns the
+//! controls into thrust.
+//!
+//! Pilots engage velocity matching by holding the key while pointing at
+//! something, see `Ship::want_match`.
+
+use specs::{Component, Entities, Entity, Join, LazyUpdate, Read,
+            HashMapStorage, ReadStorage, System, WriteStorage};
+use vecmath::*;
+
+use crate::blocks::Blocky;
+use crate::math::{atan2, sin_cos};
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{Position, Velocity};
+use crate::ship::Ship;
+use crate::utils::{angle_wrap, clamp};
+
+/// Distance to the target under which the ship has arrived.
+const ARRIVE_DISTANCE: f32 = 1.0;
+
+/// Speed under which the ship has stopped.
+const STOP_SPEED: f32 = 0.5;
+
+/// Cruise speed, the autopilot doesn't go faster than this.
+const MAX_SPEED: f32 = 20.0;
+
+/// Velocity error under which the autopilot doesn't thrust.
+const SPEED_TOLERANCE: f32 = 0.5;
+
+/// How fast the autopilot turns towards the target, in radians per second
+/// per radian of error.
+const TURN_RATE: f32 = 2.0;
+
+/// How far from an object's edge the cursor can be to pick it.
+const PICK_DISTANCE: f32 = 2.0;
+
+/// How fast ships move back to their place in formation, in units per
+/// second per unit of distance.
+const FORMATION_GAIN: f32 = 0.5;
+
+/// Takes over the controls of a ship.
+#[derive(Debug, Clone)]
+pub enum Autopilot {
+    /// Flies the ship to a point, in world coordinates, and stops there.
+    FlyTo([f32; 2]),
+    /// Matches the velocity of another object, as long as the pilot wants
+    /// it.
+    MatchVelocity(Entity),
+    /// Holds a place in formation: `offset` from the leader, in the leader's
+    /// coordinate system, facing the same way.
+    Follow { leader: Entity, offset: [f32; 2] },
+}
+
+impl Component for Autopilot {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Autopilot system, sets the controls of ships with an `Autopilot`.
+pub struct SysAutopilot;
+
+impl<'a> System<'a> for SysAutopilot {
+    type SystemData = (
+        Read<'a, LazyUpdate>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        ReadStorage<'a, Blocky>,
+        WriteStorage<'a, Ship>,
+        WriteStorage<'a, Autopilot>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            lazy,
+            entities,
+            pos,
+            vel,
+            blocky,
+            mut ship,
+            mut autopilot,
+        ): Self::SystemData,
+    ) {
+        // Engage and disengage velocity matching
+        let mut matching = Vec::new();
+        for (ent, ship_pos, ship) in (&*entities, &pos, &ship).join() {
+            match (ship.want_match, autopilot.get(ent)) {
+                (true, None) => {
+                    let cursor = vec2_add(ship_pos.pos, ship.want_target);
+                    let target = pick(ent, cursor, &entities, &pos, &blocky);
+                    if target.is_some() {
+                        matching.push((ent, target));
+                    }
+                }
+                (false, Some(&Autopilot::MatchVelocity(_))) => {
+                    matching.push((ent, None));
+                }
+                _ => {}
+            }
+        }
+        for (ent, target) in matching {
+            match target {
+                Some(target) => {
+                    autopilot
+                        .insert(ent, Autopilot::MatchVelocity(target))
+                        .unwrap();
+                }
+                None => {
+                    autopilot.remove(ent);
+                }
+            }
+        }
+
+        let mut done = Vec::new();
+        for (ent, own_pos, blk, ship, autopilot) in
+            (&*entities, &pos, &blocky, &mut ship, &autopilot).join()
+        {
+            let own_vel = vel.get(ent).unwrap();
+            let (wanted_vel, wanted_rot) = match *autopilot {
+                Autopilot::FlyTo(target) => {
+                    match fly_to(target, own_pos, own_vel, blk, ship) {
+                        Some(c) => c,
+                        None => {
+                            done.push(ent);
+                            ([0.0, 0.0], 0.0)
+                        }
+                    }
+                }
+                Autopilot::MatchVelocity(target) => match vel.get(target) {
+                    Some(v) => (v.vel, 0.0),
+                    None => {
+                        done.push(ent);
+                        (own_vel.vel, 0.0)
+                    }
+                },
+                Autopilot::Follow { leader, offset } => {
+                    match (pos.get(leader), vel.get(leader)) {
+                        (Some(p), Some(v)) => follow(p, v, offset, own_pos),
+                        _ => {
+                            done.push(ent);
+                            (own_vel.vel, 0.0)
+                        }
+                    }
+                }
+            };
+
+            // Thrust to correct the velocity, in the ship's frame
+            let error = vec2_sub(wanted_vel, own_vel.vel);
+            ship.want_thrust = if vec2_len(error) > SPEED_TOLERANCE {
+                let (s, c) = sin_cos(own_pos.rot);
+                [
+                    error[0] * c + error[1] * s,
+                    -error[0] * s + error[1] * c,
+                ]
+            } else {
+                [0.0, 0.0]
+            };
+            ship.want_thrust_rot = clamp(wanted_rot - own_vel.rot, -1.0, 1.0);
+
+            #[cfg(feature = "network")]
+            lazy.insert(ent, net::Dirty);
+        }
+        #[cfg(not(feature = "network"))]
+        let _ = lazy;
+
+        for ent in done {
+            autopilot.remove(ent);
+        }
+    }
+}
+
+/// Computes the velocity and rotation speed to fly to a point.
+///
+/// Returns `None` once the ship got there and stopped.
+fn fly_to(
+    target: [f32; 2],
+    pos: &Position,
+    vel: &Velocity,
+    blk: &Blocky,
+    ship: &Ship,
+) -> Option<([f32; 2], f32)> {
+    let to = vec2_sub(target, pos.pos);
+    let dist = vec2_len(to);
+    if dist < ARRIVE_DISTANCE && vec2_len(vel.vel) < STOP_SPEED {
+        return None;
+    }
+
+    // Fastest speed from which the ship can still stop in time
+    let accel = ship.nominal_thrust[0] * ship.authority[0] / blk.mass;
+    let max_speed = (2.0 * accel * dist).sqrt().min(MAX_SPEED);
+    let wanted_vel = if dist > 0.0 {
+        vec2_scale(to, max_speed / dist)
+    } else {
+        [0.0, 0.0]
+    };
+
+    // Turn towards the target, or just stop turning once close
+    let wanted_rot = if dist > ARRIVE_DISTANCE {
+        let heading = atan2(to[1], to[0]);
+        angle_wrap(heading - pos.rot) * TURN_RATE
+    } else {
+        0.0
+    };
+    Some((wanted_vel, wanted_rot))
+}
+
+/// Computes the velocity and rotation speed to hold a place in formation.
+fn follow(
+    leader_pos: &Position,
+    leader_vel: &Velocity,
+    offset: [f32; 2],
+    pos: &Position,
+) -> ([f32; 2], f32) {
+    let (s, c) = sin_cos(leader_pos.rot);
+    let slot = vec2_add(
+        leader_pos.pos,
+        [offset[0] * c - offset[1] * s, offset[0] * s + offset[1] * c],
+    );
+
+    // Move along with the leader, catching up with the slot
+    let mut catch_up = vec2_scale(vec2_sub(slot, pos.pos), FORMATION_GAIN);
+    if vec2_len(catch_up) > MAX_SPEED {
+        catch_up = vec2_scale(catch_up, MAX_SPEED / vec2_len(catch_up));
+    }
+    let wanted_vel = vec2_add(leader_vel.vel, catch_up);
+
+    // Face the same way as the leader
+    let wanted_rot =
+        leader_vel.rot + angle_wrap(leader_pos.rot - pos.rot) * TURN_RATE;
+    (wanted_vel, wanted_rot)
+}
+
+/// Finds the object under a point, other than the ship itself.
+fn pick<'a>(
+    ent: Entity,
+    point: [f32; 2],
+    entities: &Entities<'a>,
+    pos: &ReadStorage<'a, Position>,
+    blocky: &ReadStorage<'a, Blocky>,
+) -> Option<Entity> {
+    (&**entities, pos, blocky)
+        .join()
+        .filter(|&(e, _, _)| e != ent)
+        .map(|(e, p, blk)| (e, vec2_len(vec2_sub(p.pos, point)) - blk.radius))
+        .filter(|&(_, dist)| dist <= PICK_DISTANCE)
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map(|(e, _)| e)
+}
@@ -12,11 +12,18 @@
 // TODO: Refactor some blocky behavior out of SysShip, into a blocky system?
 
 use specs::{Component, Entities, Read, LazyUpdate, VecStorage};
+use std::f32::consts::PI;
 use std::num::Wrapping;
 use vecmath::*;
 
 use crate::tree::Tree;
 
+/// Fuel held by a full `FuelTank`, in seconds of burn of a thruster.
+pub const TANK_CAPACITY: f32 = 100.0;
+
+/// Drones held by a full `DroneBay`.
+pub const BAY_CAPACITY: u32 = 2;
+
 /// Active component of the block.
 #[derive(Debug, Clone)]
 pub enum BlockInner {
@@ -25,11 +32,42 @@ pub enum BlockInner {
     Cockpit,
     /// Allows a ship to move. A ship needs multiple of this to be able to
     /// move and rotate.
+    ///
+    /// Thrusters burn fuel from the ship's `FuelTank`s.
     Thruster { angle: f32 },
     /// This shoots explosive energy projectiles.
-    PlasmaGun { angle: f32, cooldown: f32 },
+    ///
+    /// Like all guns, it fires from a magazine holding `ammo` rounds, and
+    /// reloads from the ship's `Cargo` once it is empty.
+    PlasmaGun { angle: f32, cooldown: f32, ammo: u32 },
     /// This shoots heavy projectiles.
-    RailGun { angle: f32, cooldown: f32 },
+    RailGun { angle: f32, cooldown: f32, ammo: u32 },
+    /// This shoots electromagnetic pulses, that disable blocks instead of
+    /// damaging them.
+    EmpGun { angle: f32, cooldown: f32, ammo: u32 },
+    /// This shoots flak, that bursts close to its targets.
+    FlakCannon { angle: f32, cooldown: f32, ammo: u32 },
+    /// This charges up while its weapon group is firing, and shoots when
+    /// released, harder the longer it charged (`charge` goes from 0 to 1).
+    ChargeGun {
+        angle: f32,
+        cooldown: f32,
+        ammo: u32,
+        charge: f32,
+    },
+    /// This fires a continuous beam, burning whatever is in front of it.
+    BeamLaser { angle: f32 },
+    /// Grabs onto derelict ships, allowing the pilot to take them over.
+    BoardingClamp,
+    /// Deconstructs blocks of wrecks, so they can be carried as cargo.
+    SalvageBeam,
+    /// Launches companion drones, see `drones.rs`. `drones` is the number
+    /// of drones docked, up to `BAY_CAPACITY`.
+    DroneBay { drones: u32, cooldown: f32 },
+    /// Stores fuel for the thrusters, up to `TANK_CAPACITY`.
+    FuelTank { fuel: f32 },
+    /// Where ships dock to a station, see `station.rs`.
+    DockingPort,
     /// An armor block does nothing, it is only there to take damage (and
     /// weigh you down).
     Armor,
@@ -66,11 +104,56 @@ impl BlockInner {
             BlockInner::Thruster { .. } => 0.8,
             BlockInner::PlasmaGun { .. } => 0.2,
             BlockInner::RailGun { .. } => 0.8,
+            BlockInner::EmpGun { .. } => 0.6,
+            BlockInner::FlakCannon { .. } => 0.7,
+            BlockInner::ChargeGun { .. } => 0.8,
+            BlockInner::BeamLaser { .. } => 0.5,
+            BlockInner::BoardingClamp => 0.6,
+            BlockInner::SalvageBeam => 0.4,
+            BlockInner::DroneBay { .. } => 0.9,
+            BlockInner::FuelTank { .. } => 0.7,
+            BlockInner::DockingPort => 1.0,
             BlockInner::Armor => 0.6,
             BlockInner::Rock => 0.6,
         }
     }
 
+    /// The magazine size and reload time, for guns that use ammunition.
+    pub fn magazine(&self) -> Option<(u32, f32)> {
+        match *self {
+            BlockInner::PlasmaGun { .. } => Some((20, 2.0)),
+            BlockInner::RailGun { .. } => Some((4, 3.0)),
+            BlockInner::EmpGun { .. } => Some((2, 4.0)),
+            BlockInner::FlakCannon { .. } => Some((8, 2.5)),
+            BlockInner::ChargeGun { .. } => Some((6, 3.0)),
+            _ => None,
+        }
+    }
+
+    /// How far a turret can turn to either side of the front of the ship,
+    /// for guns that aim at the target.
+    pub fn firing_arc(&self) -> Option<f32> {
+        match *self {
+            BlockInner::PlasmaGun { .. } => Some(0.5 * PI),
+            BlockInner::FlakCannon { .. } => Some(0.75 * PI),
+            BlockInner::ChargeGun { .. } => Some(0.25 * PI),
+            BlockInner::BeamLaser { .. } => Some(PI / 3.0),
+            _ => None,
+        }
+    }
+
+    /// Half-angle of the cone in which the shots of a gun spread.
+    pub fn spread(&self) -> f32 {
+        match *self {
+            BlockInner::PlasmaGun { .. } => 0.05,
+            BlockInner::RailGun { .. } => 0.02,
+            BlockInner::EmpGun { .. } => 0.05,
+            BlockInner::FlakCannon { .. } => 0.1,
+            BlockInner::ChargeGun { .. } => 0.03,
+            _ => 0.0,
+        }
+    }
+
     /// The starting health of this block.
     pub fn max_health(&self) -> f32 {
         match *self {
@@ -78,16 +161,55 @@ impl BlockInner {
             BlockInner::Thruster { .. } => 0.6,
             BlockInner::PlasmaGun { .. } => 0.4,
             BlockInner::RailGun { .. } => 0.4,
+            BlockInner::EmpGun { .. } => 0.4,
+            BlockInner::FlakCannon { .. } => 0.4,
+            BlockInner::ChargeGun { .. } => 0.4,
+            BlockInner::BeamLaser { .. } => 0.4,
+            BlockInner::BoardingClamp => 0.6,
+            BlockInner::SalvageBeam => 0.4,
+            BlockInner::DroneBay { .. } => 0.5,
+            BlockInner::FuelTank { .. } => 0.3,
+            BlockInner::DockingPort => 0.8,
             BlockInner::Armor => 0.4,
             BlockInner::Rock => 0.3,
         }
     }
+
+    /// Ore it takes to build this block, see `economy.rs`.
+    pub fn cost(&self) -> u32 {
+        match *self {
+            BlockInner::Cockpit => 40,
+            BlockInner::Thruster { .. } => 12,
+            BlockInner::PlasmaGun { .. } => 15,
+            BlockInner::RailGun { .. } => 25,
+            BlockInner::EmpGun { .. } => 25,
+            BlockInner::FlakCannon { .. } => 20,
+            BlockInner::ChargeGun { .. } => 30,
+            BlockInner::BeamLaser { .. } => 35,
+            BlockInner::BoardingClamp => 20,
+            BlockInner::SalvageBeam => 20,
+            BlockInner::DroneBay { .. } => 40,
+            BlockInner::FuelTank { .. } => 8,
+            BlockInner::DockingPort => 30,
+            BlockInner::Armor => 5,
+            BlockInner::Rock => 2,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Block {
     /// Health of this blocks, starting at `inner.max_health()`.
     pub health: f32,
+    /// Time for which this block is disabled, for example by an EMP. A
+    /// disabled block doesn't function (thrusters don't thrust, guns don't
+    /// fire).
+    pub disabled: f32,
+    /// Weapon group this block fires with, see `Ship::want_fire`.
+    pub group: usize,
+    /// Automatic mode: the gun aims and fires on its own at incoming
+    /// projectiles and other ships, instead of following the pilot.
+    pub auto: bool,
     /// The state and behavior of this block, depending on its concrete
     /// type.
     pub inner: BlockInner,
@@ -98,9 +220,25 @@ impl Block {
     pub fn new(inner: BlockInner) -> Block {
         Block {
             health: inner.max_health(),
+            disabled: 0.0,
+            group: 0,
+            auto: false,
             inner: inner,
         }
     }
+
+    /// Whether this block is currently disabled.
+    pub fn is_disabled(&self) -> bool {
+        self.disabled > 0.0
+    }
+
+    /// Ore it takes to repair this block back to full health, a share of its
+    /// cost matching the health it lost.
+    pub fn repair_cost(&self) -> u32 {
+        let max = self.inner.max_health();
+        let missing = ((max - self.health) / max).max(0.0);
+        (self.inner.cost() as f32 * missing).ceil() as u32
+    }
 }
 
 // Entity is made of blocks
@@ -162,6 +300,37 @@ impl Blocky {
         center
     }
 
+    /// Whether this object has a cockpit, and is therefore a ship.
+    pub fn has_cockpit(&self) -> bool {
+        self.blocks
+            .iter()
+            .any(|(_, block)| matches!(block.inner, BlockInner::Cockpit))
+    }
+
+    /// The fuel left in all the tanks.
+    pub fn fuel(&self) -> f32 {
+        self.blocks
+            .iter()
+            .map(|(_, block)| match block.inner {
+                BlockInner::FuelTank { fuel } => fuel,
+                _ => 0.0,
+            })
+            .sum()
+    }
+
+    /// Takes some fuel from the tanks, returns how much there was.
+    pub fn burn_fuel(&mut self, mut amount: f32) -> f32 {
+        let wanted = amount;
+        for (_, block) in &mut self.blocks {
+            if let BlockInner::FuelTank { ref mut fuel } = block.inner {
+                let taken = amount.min(*fuel);
+                *fuel -= taken;
+                amount -= taken;
+            }
+        }
+        wanted - amount
+    }
+
     /// Called when some blocks are added or reach 0 health.
     ///
     /// Removes dead blocks, split the entity in multiple `Blocky` objects if
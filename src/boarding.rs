@@ -0,0 +1,186 @@
+//! Boarding of derelict ships.
+//!
+//! A derelict is a ship whose cockpit is intact but that no one is
+//! controlling, player or AI, for example a piece that broke off with the
+//! cockpit. A ship that keeps one of its `BoardingClamp` blocks in contact
+//! with a derelict for long enough takes it over: control moves to the
+//! derelict, and the old ship is left behind.
+
+use specs::{Component, Entities, Entity, Read, Join, HashMapStorage,
+            LazyUpdate, ReadStorage, System, Write, WriteStorage};
+use vecmath::*;
+
+use crate::ai::AiPilot;
+use crate::blocks::{BlockInner, Blocky};
+use crate::control::{controller, transfer_control};
+use crate::events::{GameEvent, GameEvents};
+use crate::faction::Faction;
+use crate::physics::{pilot, DeltaTime, HitEffect, Hits, LocalControl,
+                     RemoteControl};
+use crate::ship::{Ship, WEAPON_GROUPS};
+use crate::teams::Team;
+
+/// Time a clamp has to stay in contact with a derelict to take it over.
+pub const BOARDING_TIME: f32 = 3.0;
+
+/// Time without contact after which boarding is interrupted.
+///
+/// Colliding objects bounce off each other, so contact is not continuous.
+const BOARDING_GRACE: f32 = 0.5;
+
+/// Maximum distance between a clamp block and the contact point.
+const CLAMP_REACH: f32 = 1.0;
+
+/// Boarding in progress, attached to the boarding ship.
+pub struct Boarding {
+    /// The derelict being boarded.
+    pub target: Entity,
+    /// Time spent in contact so far.
+    pub progress: f32,
+    /// Time since the last contact.
+    since_contact: f32,
+}
+
+impl Component for Boarding {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Boarding system, tracks contact with derelicts and transfers control.
+pub struct SysBoarding;
+
+impl<'a> System<'a> for SysBoarding {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        Read<'a, LazyUpdate>,
+        Write<'a, GameEvents>,
+        Entities<'a>,
+        ReadStorage<'a, Blocky>,
+        ReadStorage<'a, Hits>,
+        WriteStorage<'a, Ship>,
+        WriteStorage<'a, Boarding>,
+        ReadStorage<'a, AiPilot>,
+        ReadStorage<'a, Faction>,
+        ReadStorage<'a, Team>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            lazy,
+            mut events,
+            entities,
+            blocky,
+            hits,
+            mut ship,
+            mut boarding,
+            ai,
+            faction,
+            team,
+            local,
+            remote,
+        ): Self::SystemData,
+    ) {
+        let dt = dt.0;
+
+        // Track contact between clamps and derelicts
+        let mut captures = Vec::new();
+        for (ent, blk, _) in (&*entities, &blocky, &ship).join() {
+            if pilot(ent, &local, &remote).is_none() {
+                continue;
+            }
+
+            let contact = hits.get(ent).and_then(|hits| {
+                hits.iter()
+                    .filter_map(|hit| match hit.effect {
+                        HitEffect::Collision(_, other) => {
+                            Some((hit.rel_location, other))
+                        }
+                        _ => None,
+                    })
+                    .find(|&(loc, other)| {
+                        ship.get(other).is_some()
+                            && pilot(other, &local, &remote).is_none()
+                            && ai.get(other).is_none()
+                            && has_clamp_near(blk, loc)
+                    })
+                    .map(|(_, other)| other)
+            });
+
+            let done = match (boarding.get_mut(ent), contact) {
+                (Some(b), Some(other)) if b.target == other => {
+                    b.progress += dt;
+                    b.since_contact = 0.0;
+                    b.progress >= BOARDING_TIME
+                }
+                (Some(b), _) => {
+                    b.since_contact += dt;
+                    if b.since_contact > BOARDING_GRACE {
+                        boarding.remove(ent);
+                    }
+                    false
+                }
+                (None, Some(other)) => {
+                    boarding
+                        .insert(
+                            ent,
+                            Boarding {
+                                target: other,
+                                progress: 0.0,
+                                since_contact: 0.0,
+                            },
+                        )
+                        .unwrap();
+                    false
+                }
+                (None, None) => false,
+            };
+            if done {
+                let target = boarding.remove(ent).unwrap().target;
+                captures.push((ent, target));
+            }
+        }
+
+        // Transfer control
+        let mut captured = Vec::new();
+        for (ent, target) in captures {
+            if !entities.is_alive(target) || captured.contains(&target) {
+                continue;
+            }
+            captured.push(target);
+            if let Some(player) = pilot(ent, &local, &remote) {
+                events.single_write(GameEvent::ShipCaptured { player });
+            }
+
+            let pilot = controller(ent, &local, &remote, &ai);
+            transfer_control(&lazy, target, pilot);
+            transfer_control(&lazy, ent, None);
+            if let Some(&side) = faction.get(ent) {
+                lazy.remove::<Faction>(ent);
+                lazy.insert(target, side);
+            }
+            if let Some(&side) = team.get(ent) {
+                lazy.remove::<Team>(ent);
+                lazy.insert(target, side);
+            }
+            // The ship left behind is now a derelict, release its controls
+            let old = ship.get_mut(ent).unwrap();
+            old.want_fire = [false; WEAPON_GROUPS];
+            old.want_thrust = [0.0, 0.0];
+            old.want_thrust_rot = 0.0;
+            old.want_tractor = false;
+        }
+    }
+}
+
+/// Whether a `BoardingClamp` block is within reach of a point.
+fn has_clamp_near(blocky: &Blocky, loc: [f32; 2]) -> bool {
+    blocky.blocks.iter().any(|&(pos, ref block)| match block.inner {
+        BlockInner::BoardingClamp => {
+            vec2_square_len(vec2_sub(pos, loc)) <= CLAMP_REACH * CLAMP_REACH
+        }
+        _ => false,
+    })
+}
@@ -0,0 +1,227 @@
+//! The dreadnought, a boss the pirates send late in the game.
+//!
+//! `BOSS_TIME` seconds into the game, `SysBoss` brings in a huge pirate ship,
+//! escorted by scouts flying in formation. Its turrets are in automatic mode:
+//! they shoot down incoming projectiles and fire at players on their own.
+//! Destroying it spills the rare blocks in its hold, and ends the match if
+//! `Rules::boss_ends_match` is set. Another one comes `BOSS_INTERVAL` seconds
+//! later. Like raids, this is turned off with `modes::Skirmish::pirates`.
+
+use rand::prelude::*;
+use specs::{Entities, Entity, Join, LazyUpdate, Read, ReadStorage, System,
+            Write, WriteStorage};
+use std::f32::consts::PI;
+
+use crate::GameRng;
+use crate::ai::{AiPilot, Personality, Wing};
+use crate::blocks::{BlockInner, TANK_CAPACITY};
+use crate::director::PIRATES;
+use crate::events::{GameEvent, GameEvents};
+use crate::math::{atan2, sin_cos};
+use crate::physics::{pilot, DeltaTime, LocalControl, Position,
+                     RemoteControl, Velocity};
+use crate::salvage::{spill_cargo, Cargo};
+use crate::ship::{build_blocks, Ship, ShipClass, STARTING_AMMO};
+
+/// Time before the first dreadnought.
+const BOSS_TIME: f32 = 300.0;
+
+/// Time between the destruction of a dreadnought and the next one.
+const BOSS_INTERVAL: f32 = 300.0;
+
+/// Distance from the center of the map where the dreadnought appears.
+const BOSS_EDGE: f32 = 95.0;
+
+/// Places of the escorts, in the dreadnought's coordinate system.
+const ESCORTS: [[f32; 2]; 3] = [[-14.0, -10.0], [-14.0, 10.0], [-18.0, 0.0]];
+
+/// Rare blocks, some of which the dreadnought carries as loot.
+const LOOT: [BlockInner; 4] = [
+    BlockInner::ChargeGun {
+        angle: 0.0,
+        cooldown: -1.0,
+        ammo: 6,
+        charge: 0.0,
+    },
+    BlockInner::BeamLaser { angle: 0.0 },
+    BlockInner::EmpGun {
+        angle: 0.0,
+        cooldown: -1.0,
+        ammo: 2,
+    },
+    BlockInner::DroneBay {
+        drones: 0,
+        cooldown: 0.0,
+    },
+];
+
+/// The blocks of the dreadnought, at integer positions.
+///
+/// This is an armored hull with engines at the back, a rail gun at the
+/// front, and turrets along the sides.
+fn blueprint() -> Vec<([i32; 2], BlockInner)> {
+    use crate::blocks::BlockInner::*;
+    let plasma = PlasmaGun {
+        angle: 0.0,
+        cooldown: -1.0,
+        ammo: 20,
+    };
+    let mut blocks = Vec::new();
+    for x in -6..7 {
+        for y in -3..4 {
+            let block = match (x, y) {
+                (0, 0) => Cockpit,
+                (-6, -3) | (-6, 3) | (6, -3) | (6, 3) => Armor,
+                (-6, _) => Thruster { angle: 0.0 },
+                (-5, -3) | (5, -3) => Thruster { angle: 0.5 * PI },
+                (-5, 3) | (5, 3) => Thruster { angle: -0.5 * PI },
+                (6, -2) | (6, 2) => Thruster { angle: PI },
+                (6, 0) => RailGun {
+                    angle: 0.0,
+                    cooldown: -1.0,
+                    ammo: 4,
+                },
+                (6, _) => plasma.clone(),
+                (0, -3) | (0, 3) => FlakCannon {
+                    angle: 0.0,
+                    cooldown: -1.0,
+                    ammo: 8,
+                },
+                (-3, -3) | (-3, 3) | (3, -3) | (3, 3) => plasma.clone(),
+                (-5, _) | (-4, _) if y > -3 && y < 3 => FuelTank {
+                    fuel: TANK_CAPACITY,
+                },
+                _ => Armor,
+            };
+            blocks.push(([x, y], block));
+        }
+    }
+    blocks
+}
+
+/// Boss system, sends the dreadnought and drops its loot.
+pub struct SysBoss {
+    /// Time until the next dreadnought.
+    next_boss: f32,
+    /// The current dreadnought.
+    boss: Option<Entity>,
+}
+
+impl Default for SysBoss {
+    fn default() -> SysBoss {
+        SysBoss {
+            next_boss: BOSS_TIME,
+            boss: None,
+        }
+    }
+}
+
+impl<'a> System<'a> for SysBoss {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        Read<'a, LazyUpdate>,
+        Write<'a, GameRng>,
+        Write<'a, GameEvents>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
+        WriteStorage<'a, Cargo>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            lazy,
+            mut rng,
+            mut events,
+            entities,
+            pos,
+            vel,
+            ship,
+            local,
+            remote,
+            mut cargo,
+        ): Self::SystemData,
+    ) {
+        let rng = &mut *rng;
+
+        if let Some(boss) = self.boss {
+            if entities.is_alive(boss) && ship.get(boss).is_some() {
+                return;
+            }
+
+            // Destroyed, drop the loot
+            if let (Some(pos), Some(vel), Some(cargo)) =
+                (pos.get(boss), vel.get(boss), cargo.get_mut(boss))
+            {
+                spill_cargo(&entities, &lazy, rng, pos, vel, cargo);
+            }
+            events.single_write(GameEvent::BossDestroyed);
+            self.boss = None;
+            self.next_boss = BOSS_INTERVAL;
+        }
+
+        self.next_boss -= dt.0;
+        if self.next_boss > 0.0 {
+            return;
+        }
+        let players = (&*entities, &ship)
+            .join()
+            .filter(|&(e, _)| pilot(e, &local, &remote).is_some())
+            .count();
+        if players == 0 {
+            return;
+        }
+
+        // Come in from a random direction, facing the center
+        let dir = rng.gen_range(0.0, 2.0 * PI);
+        let (s, c) = sin_cos(dir);
+        let start = [BOSS_EDGE * c, BOSS_EDGE * s];
+        let rot = atan2(-start[1], -start[0]);
+        let mut blocks = build_blocks(blueprint());
+        for &mut (_, ref mut block) in &mut blocks {
+            block.auto = block.inner.firing_arc().is_some();
+        }
+        let boss = Ship::create_from(&entities, &lazy, blocks, start, rot);
+        lazy.insert(boss, AiPilot::new(Personality::Sniper));
+        lazy.insert(boss, PIRATES);
+        let loot = LOOT.choose_multiple(rng, 2).cloned().collect();
+        lazy.insert(
+            boss,
+            Cargo {
+                blocks: loot,
+                ammo: STARTING_AMMO * 5,
+            },
+        );
+        self.boss = Some(boss);
+
+        // Escorts
+        let (s, c) = sin_cos(rot);
+        for &offset in &ESCORTS {
+            let pos = [
+                start[0] + offset[0] * c - offset[1] * s,
+                start[1] + offset[0] * s + offset[1] * c,
+            ];
+            let ent = AiPilot::spawn(
+                &entities,
+                &lazy,
+                ShipClass::Scout,
+                pos,
+                rot,
+                Personality::Interceptor,
+            );
+            lazy.insert(ent, PIRATES);
+            lazy.insert(
+                ent,
+                Wing {
+                    leader: boss,
+                    offset,
+                },
+            );
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Control points, for the control point game mode.
+//!
+//! With `modes::ControlPoints`, a few `CaptureZone`s are placed around the
+//! map. A team whose ships are alone in a zone captures it after
+//! `CAPTURE_TIME` seconds; while there are ships of several teams, the zone
+//! is contested and nothing moves. Each zone earns its owner a point every
+//! `POINT_INTERVAL` seconds, counted in the `Teams` scores until a team gets
+//! to the mode's `score_limit`. Zones are replicated so clients can show who
+//! holds them.
+
+use specs::{Component, Entities, Entity, Join, LazyUpdate, Read,
+            ReadStorage, System, VecStorage, Write, WriteStorage};
+use std::collections::HashSet;
+
+use crate::blocks::Blocky;
+use crate::events::{GameEvent, GameEvents};
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{query_area, DeltaTime, Position};
+use crate::rules::MatchState;
+use crate::ship::Ship;
+use crate::teams::{Team, Teams};
+
+/// Time a team needs to hold a zone alone to capture it.
+const CAPTURE_TIME: f32 = 10.0;
+
+/// Time between two points earned by holding a zone.
+const POINT_INTERVAL: f32 = 1.0;
+
+/// A zone teams fight over.
+#[derive(Debug, Clone)]
+pub struct CaptureZone {
+    pub radius: f32,
+    /// The team holding the zone.
+    pub owner: Option<Team>,
+    /// The team taking the zone over, and how far along it is from 0 to 1.
+    pub capturing: Option<(Team, f32)>,
+    /// Whether ships from several teams are in the zone.
+    pub contested: bool,
+    /// Time since the owner last earned a point.
+    held: f32,
+}
+
+impl Component for CaptureZone {
+    type Storage = VecStorage<Self>;
+}
+
+impl CaptureZone {
+    pub fn new(radius: f32) -> CaptureZone {
+        CaptureZone {
+            radius,
+            owner: None,
+            capturing: None,
+            contested: false,
+            held: 0.0,
+        }
+    }
+
+    pub fn create(
+        entities: &Entities,
+        lazy: &Read<LazyUpdate>,
+        pos: [f32; 2],
+        radius: f32,
+    ) -> Entity {
+        let entity = entities.create();
+        lazy.insert(entity, Position { pos, rot: 0.0 });
+        lazy.insert(entity, CaptureZone::new(radius));
+        #[cfg(feature = "network")]
+        {
+            lazy.insert(entity, net::Replicated::new());
+            lazy.insert(entity, net::Dirty);
+        }
+        entity
+    }
+}
+
+/// Creates the control points, in the middle of the map and on two sides.
+pub fn spawn_zones(entities: &Entities, lazy: &Read<LazyUpdate>) {
+    CaptureZone::create(entities, lazy, [0.0, 0.0], 15.0);
+    CaptureZone::create(entities, lazy, [-55.0, 45.0], 12.0);
+    CaptureZone::create(entities, lazy, [55.0, -45.0], 12.0);
+}
+
+/// Capture system, updates the zones from the ships in them.
+pub struct SysCapture;
+
+impl<'a> System<'a> for SysCapture {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        Read<'a, MatchState>,
+        Read<'a, LazyUpdate>,
+        Write<'a, GameEvents>,
+        Write<'a, Teams>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Blocky>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, Team>,
+        WriteStorage<'a, CaptureZone>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            match_state,
+            lazy,
+            mut events,
+            mut teams,
+            entities,
+            pos,
+            blocky,
+            ship,
+            team,
+            mut zone,
+        ): Self::SystemData,
+    ) {
+        // Zones are frozen between matches
+        if !match_state.playing() {
+            return;
+        }
+        let dt = dt.0;
+        for (ent, zone_pos, zone) in (&*entities, &pos, &mut zone).join() {
+            let present = query_area(
+                &entities,
+                &pos,
+                &blocky,
+                zone_pos.pos,
+                zone.radius,
+            ).into_iter()
+                .filter(|&e| ship.get(e).is_some())
+                .filter_map(|e| team.get(e).cloned())
+                .collect::<HashSet<_>>();
+
+            let before = (zone.owner, zone.capturing, zone.contested);
+            zone.contested = present.len() > 1;
+            if present.len() == 1 {
+                let side = *present.iter().next().unwrap();
+                if zone.owner == Some(side) {
+                    zone.capturing = None;
+                } else {
+                    let progress = match zone.capturing {
+                        Some((t, progress)) if t == side => progress,
+                        _ => 0.0,
+                    };
+                    let progress = progress + dt / CAPTURE_TIME;
+                    if progress >= 1.0 {
+                        zone.owner = Some(side);
+                        zone.capturing = None;
+                        zone.held = 0.0;
+                        events.single_write(GameEvent::ZoneCaptured {
+                            team: side.0,
+                        });
+                    } else {
+                        zone.capturing = Some((side, progress));
+                    }
+                }
+            } else if present.is_empty() {
+                // Capture progress fades away once the ships leave
+                zone.capturing = match zone.capturing {
+                    Some((t, progress)) if progress > dt / CAPTURE_TIME => {
+                        Some((t, progress - dt / CAPTURE_TIME))
+                    }
+                    _ => None,
+                };
+            }
+
+            // Earn points
+            if let Some(owner) = zone.owner {
+                zone.held += dt;
+                while zone.held >= POINT_INTERVAL {
+                    zone.held -= POINT_INTERVAL;
+                    teams.scores[owner.0 as usize] += 1;
+                }
+            }
+
+            #[cfg(feature = "network")]
+            {
+                if before != (zone.owner, zone.capturing, zone.contested) {
+                    lazy.insert(ent, net::Dirty);
+                }
+            }
+            #[cfg(not(feature = "network"))]
+            let _ = (ent, &lazy, before);
+        }
+    }
+}
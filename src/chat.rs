@@ -0,0 +1,90 @@
+//! Chat between the players of a multiplayer game.
+//!
+//! Frontends queue what the player types in the `ChatLog` resource. The
+//! client sends it to the server, which relays it to everyone along with the
+//! ID of the player who said it, and clients add it to their `ChatLog` for
+//! display. The server drops messages from players talking too fast (see
+//! `ChatLimit`).
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+use crate::utils::clean_text;
+
+/// Maximum length of a message, in bytes.
+pub const MAX_CHAT_LEN: usize = 200;
+
+/// Number of lines the log keeps.
+const MAX_LINES: usize = 100;
+
+/// Messages a player can send in a row.
+const CHAT_BURST: f32 = 5.0;
+
+/// Messages per second a player can keep sending after that.
+const CHAT_RATE: f32 = 0.5;
+
+/// A message, with the ID of the player who said it.
+#[derive(Debug, Clone)]
+pub struct ChatLine {
+    pub player: u64,
+    pub text: String,
+}
+
+/// Chat resource, with the messages received and the ones to send.
+#[derive(Default)]
+pub struct ChatLog {
+    /// Last messages, oldest first.
+    pub lines: VecDeque<ChatLine>,
+    /// Messages from the local player, that the client (or the host) sends
+    /// on its next update.
+    pub outgoing: Vec<String>,
+}
+
+impl ChatLog {
+    /// Adds a message, forgetting the oldest past `MAX_LINES`.
+    pub fn push(&mut self, player: u64, text: String) {
+        if self.lines.len() >= MAX_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(ChatLine { player, text });
+    }
+
+    /// Queues a message from the local player.
+    pub fn say(&mut self, text: &str) {
+        self.outgoing.push(text.to_owned());
+    }
+}
+
+/// Cleans up a message, see `clean_text()`.
+pub fn sanitize(text: &str) -> Option<String> {
+    clean_text(text, MAX_CHAT_LEN)
+}
+
+/// Flood control, on the server: how many messages each player can send.
+#[derive(Default)]
+pub struct ChatLimit {
+    budgets: HashMap<u64, (f32, Instant)>,
+}
+
+impl ChatLimit {
+    /// Charges a message to a player, returning whether they can send it.
+    pub fn allow(&mut self, player: u64) -> bool {
+        let now = Instant::now();
+        let &mut (ref mut budget, ref mut last) =
+            self.budgets.entry(player).or_insert((CHAT_BURST, now));
+        let elapsed = now.duration_since(*last).as_secs_f32();
+        *last = now;
+        *budget = (*budget + elapsed * CHAT_RATE).min(CHAT_BURST);
+        if *budget >= 1.0 {
+            *budget -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forgets a player who left.
+    pub fn forget(&mut self, player: u64) {
+        self.budgets.remove(&player);
+    }
+}
@@ -0,0 +1,80 @@
+//! Handing entities over from one pilot to another.
+//!
+//! An entity is flown by a local player (`LocalControl`), a network client
+//! (`net::ClientControlled`), the computer (`ai::AiPilot`), or no one.
+//! `transfer_control()` moves it from one to the other, for example when a
+//! player boards a derelict (see `boarding.rs`), or when the computer takes
+//! over the ship of a player who left (see `Rules::ai_takeover`). Servers
+//! tell clients about the entities they gain or lose, like for any change of
+//! control.
+
+use specs::{Entity, LazyUpdate, ReadStorage};
+
+use crate::ai::{AiPilot, Personality};
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{LocalControl, RemoteControl};
+
+/// Who flies an entity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    /// A local player, by index in `input::Inputs`.
+    Local(usize),
+    /// A network client, by client ID.
+    #[cfg(feature = "network")]
+    Remote(u64),
+    /// The computer, with a personality.
+    Ai(Personality),
+}
+
+/// Who flies an entity, if anyone.
+pub fn controller(
+    ent: Entity,
+    local: &ReadStorage<LocalControl>,
+    remote: &RemoteControl,
+    ai: &ReadStorage<AiPilot>,
+) -> Option<Controller> {
+    if let Some(&LocalControl(index)) = local.get(ent) {
+        return Some(Controller::Local(index));
+    }
+    #[cfg(feature = "network")]
+    {
+        if let Some(ctrl) = remote.get(ent) {
+            return Some(Controller::Remote(ctrl.client_id));
+        }
+    }
+    #[cfg(not(feature = "network"))]
+    let _ = remote;
+    ai.get(ent).map(|pilot| Controller::Ai(pilot.personality))
+}
+
+/// Hands an entity over to a new pilot, or to no one.
+///
+/// This goes through `LazyUpdate`, so it happens on the next
+/// `World::maintain()`.
+pub fn transfer_control(
+    lazy: &LazyUpdate,
+    ent: Entity,
+    to: Option<Controller>,
+) {
+    lazy.remove::<LocalControl>(ent);
+    lazy.remove::<AiPilot>(ent);
+    #[cfg(feature = "network")]
+    {
+        lazy.remove::<net::ClientControlled>(ent);
+        lazy.insert(ent, net::Dirty);
+    }
+    match to {
+        Some(Controller::Local(index)) => {
+            lazy.insert(ent, LocalControl(index))
+        }
+        #[cfg(feature = "network")]
+        Some(Controller::Remote(client_id)) => {
+            lazy.insert(ent, net::ClientControlled { client_id })
+        }
+        Some(Controller::Ai(personality)) => {
+            lazy.insert(ent, AiPilot::new(personality))
+        }
+        None => {}
+    }
+}
@@ -0,0 +1,152 @@
+//! The director, sending pirate raids after the players.
+//!
+//! Every `RAID_INTERVAL` seconds, `SysDirector` spawns a group of pirate
+//! ships at the edge of the map, flown by `SysAi` in formation. Raids grow
+//! bigger as the game goes on and as more players join. Later on, they also
+//! send a dreadnought, see `boss.rs`. This can be turned off with
+//! `modes::Skirmish::pirates`.
+
+use rand::prelude::*;
+use specs::{Entities, Join, LazyUpdate, Read, ReadStorage, System, Write};
+use vecmath::*;
+
+use crate::GameRng;
+use crate::ai::{AiPilot, Personality, Wing};
+use crate::drones::Drone;
+use crate::faction::Faction;
+use crate::math::{atan2, sin_cos};
+use crate::physics::{pilot, DeltaTime, LocalControl, RemoteControl};
+use crate::ship::{Ship, ShipClass};
+
+/// The pirates' side.
+pub const PIRATES: Faction = Faction(1);
+
+/// Time between raids.
+const RAID_INTERVAL: f32 = 60.0;
+
+/// Time before the first raid.
+const FIRST_RAID: f32 = 30.0;
+
+/// Time after which raids get one more ship.
+const RAID_ESCALATION: f32 = 180.0;
+
+const MAX_RAID_SIZE: usize = 6;
+
+/// No raid is sent while this many pirates are still around.
+const MAX_PIRATES: usize = 12;
+
+/// Distance from the center of the map where raids appear.
+const RAID_EDGE: f32 = 95.0;
+
+/// Space between the ships of a raid, in formation.
+const RAID_SPACING: f32 = 8.0;
+
+/// Director system, spawns pirate raids.
+pub struct SysDirector {
+    /// Time since the start of the game.
+    elapsed: f32,
+    /// Time until the next raid.
+    next_raid: f32,
+}
+
+impl Default for SysDirector {
+    fn default() -> SysDirector {
+        SysDirector {
+            elapsed: 0.0,
+            next_raid: FIRST_RAID,
+        }
+    }
+}
+
+impl<'a> System<'a> for SysDirector {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        Read<'a, LazyUpdate>,
+        Write<'a, GameRng>,
+        Entities<'a>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, AiPilot>,
+        ReadStorage<'a, Drone>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            lazy,
+            mut rng,
+            entities,
+            ship,
+            ai,
+            drone,
+            local,
+            remote,
+        ): Self::SystemData,
+    ) {
+        self.elapsed += dt.0;
+        self.next_raid -= dt.0;
+        if self.next_raid > 0.0 {
+            return;
+        }
+        self.next_raid = RAID_INTERVAL;
+
+        let players = (&*entities, &ship)
+            .join()
+            .filter(|&(e, _)| pilot(e, &local, &remote).is_some())
+            .count();
+        let pirates = (&ship, &ai, !&drone).join().count();
+        if players == 0 || pirates >= MAX_PIRATES {
+            return;
+        }
+        let size = 1 + (self.elapsed / RAID_ESCALATION) as usize + players / 2;
+        let size = size.min(MAX_RAID_SIZE).min(MAX_PIRATES - pirates);
+
+        // Come in from one side, lined up facing the center
+        let rng = &mut *rng;
+        let &(xpos, ypos) = [
+            (-1.0, 0.0), // left
+            (1.0, 0.0),  // right
+            (0.0, -1.0), // bottom
+            (0.0, 1.0),  // top
+        ].choose(rng).unwrap();
+        let along = rng.gen_range(-50.0, 50.0);
+        let center = [
+            xpos * RAID_EDGE + ypos * along,
+            ypos * RAID_EDGE + xpos * along,
+        ];
+        let rot = atan2(-center[1], -center[0]);
+        let (s, c) = sin_cos(rot);
+        let mut leader = None;
+        for i in 0..size {
+            // Fly in a V behind the leader
+            let rank = (i as f32 * 0.5).ceil();
+            let side = if i % 2 == 0 { 1.0 } else { -1.0 };
+            let offset = [-rank * RAID_SPACING, side * rank * RAID_SPACING];
+            let pos = vec2_add(
+                center,
+                [offset[0] * c - offset[1] * s, offset[0] * s + offset[1] * c],
+            );
+            // Interceptors, with a sniper in every third ship
+            let personality = if i % 3 == 2 {
+                Personality::Sniper
+            } else {
+                Personality::Interceptor
+            };
+            let ent = AiPilot::spawn(
+                &entities,
+                &lazy,
+                ShipClass::Scout,
+                pos,
+                rot,
+                personality,
+            );
+            lazy.insert(ent, PIRATES);
+            match leader {
+                Some(leader) => lazy.insert(ent, Wing { leader, offset }),
+                None => leader = Some(ent),
+            }
+        }
+    }
+}
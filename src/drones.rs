@@ -0,0 +1,324 @@
+//! Companion drones, launched from a ship's `DroneBay`.
+//!
+//! While its pilot fires the bay's weapon group, a `DroneBay` launches its
+//! docked drones one after the other. Drones are tiny ships flown by `SysAi`
+//! and their `Autopilot`: they circle their carrier, and go after the hostile
+//! ship closest to where the carrier is aiming. Once the pilot stops firing,
+//! or when they run low on fuel, they fly back to the bay and dock, ready to
+//! be launched again. Drones are lost with their carrier.
+
+use specs::{Component, Entities, Entity, HashMapStorage, Join, LazyUpdate,
+            Read, ReadExpect, ReadStorage, System, WriteStorage};
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::PI;
+use vecmath::*;
+
+use crate::Role;
+use crate::ai::{AiPilot, Personality};
+use crate::autopilot::Autopilot;
+use crate::blocks::{BlockInner, Blocky, BAY_CAPACITY, TANK_CAPACITY};
+use crate::faction::{hostile, Faction};
+use crate::math::{atan2, sin_cos};
+use crate::physics::{delete_entity, DeltaTime, Position, Velocity};
+use crate::ship::{build_blocks, Ship};
+
+/// Time between two launches from the same bay.
+const LAUNCH_INTERVAL: f32 = 1.0;
+
+/// Distance from the bay at which drones are launched, and dock.
+const LAUNCH_DISTANCE: f32 = 3.5;
+
+/// Distance from its launch point under which a returning drone docks.
+const DOCK_DISTANCE: f32 = 2.0;
+
+/// Distance from the carrier at which drones circle it.
+const ORBIT_RADIUS: f32 = 8.0;
+
+/// How fast drones circle their carrier, in radians per second.
+const ORBIT_SPEED: f32 = 0.5;
+
+/// Fuel under which drones go back to their bay.
+const RETURN_FUEL: f32 = 0.2 * TANK_CAPACITY;
+
+/// How far from the point the carrier aims at drones look for a target.
+const TARGET_RADIUS: f32 = 10.0;
+
+/// Distance from the carrier beyond which drones give up on their target.
+const LEASH: f32 = 50.0;
+
+/// The blocks of a drone, at integer positions.
+fn blueprint() -> Vec<([i32; 2], BlockInner)> {
+    use crate::blocks::BlockInner::*;
+    vec![
+        ([0, 0], Cockpit),
+        ([-2, 0], Thruster { angle: 0.0 }),
+        ([-1, 0], FuelTank { fuel: TANK_CAPACITY }),
+        ([-1, -1], Thruster { angle: 0.5 * PI }),
+        ([-1, 1], Thruster { angle: -0.5 * PI }),
+        ([0, -1], Thruster { angle: PI }),
+        ([0, 1], Thruster { angle: PI }),
+        ([1, -1], Thruster { angle: 0.5 * PI }),
+        ([1, 1], Thruster { angle: -0.5 * PI }),
+        (
+            [1, 0],
+            PlasmaGun {
+                angle: 0.0,
+                cooldown: -1.0,
+                ammo: 20,
+            },
+        ),
+    ]
+}
+
+/// A drone, flying for its carrier.
+pub struct Drone {
+    pub carrier: Entity,
+    /// Where the drone docks, in the carrier's coordinate system.
+    pub dock: [f32; 2],
+    /// Angle of the drone's place around the carrier.
+    pub orbit: f32,
+}
+
+impl Component for Drone {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// What the drones of a carrier should do.
+struct Orders {
+    /// Whether the pilot wants the drones out.
+    deployed: bool,
+    /// The ship to attack.
+    target: Option<Entity>,
+}
+
+/// Drone system, launches, directs and docks drones.
+///
+/// This sets the drones' targets and autopilots, so it runs before `SysAi`.
+pub struct SysDrones;
+
+impl<'a> System<'a> for SysDrones {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        ReadExpect<'a, Role>,
+        Read<'a, LazyUpdate>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        ReadStorage<'a, Faction>,
+        ReadStorage<'a, Ship>,
+        WriteStorage<'a, Blocky>,
+        WriteStorage<'a, Drone>,
+        WriteStorage<'a, AiPilot>,
+        WriteStorage<'a, Autopilot>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            role,
+            lazy,
+            entities,
+            pos,
+            vel,
+            faction,
+            ship,
+            mut blocky,
+            mut drone,
+            mut ai,
+            mut autopilot,
+        ): Self::SystemData,
+    ) {
+        let dt = dt.0;
+        let ships = (&*entities, &pos, &ship)
+            .join()
+            .map(|(e, p, _)| (e, p.pos))
+            .collect::<Vec<_>>();
+
+        let carriers = (&drone, &ship)
+            .join()
+            .map(|(d, _)| d.carrier)
+            .collect::<HashSet<_>>();
+
+        // Operate the bays
+        let mut orders = HashMap::new();
+        let mut launches = Vec::new();
+        for (ent, ship_pos, ship, blk) in
+            (&*entities, &pos, &ship, &mut blocky).join()
+        {
+            let mut deployed = false;
+            let mut has_bay = false;
+            for &mut (loc, ref mut block) in &mut blk.blocks {
+                let launching = ship.fires(block) && !block.is_disabled();
+                if let BlockInner::DroneBay {
+                    ref mut drones,
+                    ref mut cooldown,
+                } = block.inner
+                {
+                    has_bay = true;
+                    deployed |= launching;
+                    if *cooldown > 0.0 {
+                        *cooldown -= dt;
+                    } else if launching && *drones > 0 {
+                        *drones -= 1;
+                        *cooldown = LAUNCH_INTERVAL;
+                        launches.push((ent, loc));
+                    }
+                }
+            }
+            if !has_bay && !carriers.contains(&ent) {
+                continue;
+            }
+
+            // Attack the ship closest to where the pilot is aiming
+            let aim = vec2_add(ship_pos.pos, ship.want_target);
+            let target = ships
+                .iter()
+                .filter(|&&(e, _)| {
+                    hostile(&faction, ent, e)
+                        && drone.get(e).map(|d| d.carrier) != Some(ent)
+                })
+                .map(|&(e, p)| (e, vec2_square_len(vec2_sub(p, aim))))
+                .filter(|&(_, d)| d <= TARGET_RADIUS * TARGET_RADIUS)
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                .map(|(e, _)| e);
+            orders.insert(ent, Orders { deployed, target });
+        }
+
+        // Launch new drones, out of the bay
+        for (carrier, loc) in launches {
+            let carrier_pos = pos.get(carrier).unwrap();
+            let dock = dock_point(loc);
+            let (s, c) = sin_cos(carrier_pos.rot);
+            let start = vec2_add(
+                carrier_pos.pos,
+                [dock[0] * c - dock[1] * s, dock[0] * s + dock[1] * c],
+            );
+            let ent = Ship::create_from(
+                &entities,
+                &lazy,
+                build_blocks(blueprint()),
+                start,
+                carrier_pos.rot,
+            );
+            lazy.insert(
+                ent,
+                Velocity {
+                    vel: vel.get(carrier).unwrap().vel,
+                    rot: 0.0,
+                },
+            );
+            lazy.insert(ent, AiPilot::new(Personality::Drone));
+            lazy.insert(
+                ent,
+                Drone {
+                    carrier,
+                    dock,
+                    orbit: atan2(dock[1], dock[0]),
+                },
+            );
+            if let Some(&f) = faction.get(carrier) {
+                lazy.insert(ent, f);
+            }
+        }
+
+        // Direct the drones
+        let mut docked = Vec::new();
+        for (ent, drone_pos, _, drone, ai) in
+            (&*entities, &pos, &ship, &mut drone, &mut ai).join()
+        {
+            let orders = match orders.get(&drone.carrier) {
+                Some(o) => o,
+                None => {
+                    // Carrier is gone, and the drone with it
+                    delete_entity(*role, &entities, &lazy, ent);
+                    continue;
+                }
+            };
+            let carrier_pos = pos.get(drone.carrier).unwrap();
+
+            let fuel = blocky.get(ent).map(Blocky::fuel).unwrap_or(0.0);
+            if !orders.deployed || fuel < RETURN_FUEL {
+                // Fly back to the bay, to be refueled and launched again
+                ai.target = None;
+                let (s, c) = sin_cos(carrier_pos.rot);
+                let dock = vec2_add(
+                    carrier_pos.pos,
+                    [
+                        drone.dock[0] * c - drone.dock[1] * s,
+                        drone.dock[0] * s + drone.dock[1] * c,
+                    ],
+                );
+                if vec2_len(vec2_sub(dock, drone_pos.pos)) < DOCK_DISTANCE {
+                    docked.push((drone.carrier, drone.dock));
+                    delete_entity(*role, &entities, &lazy, ent);
+                } else {
+                    let follow = Autopilot::Follow {
+                        leader: drone.carrier,
+                        offset: drone.dock,
+                    };
+                    autopilot.insert(ent, follow).unwrap();
+                }
+                continue;
+            }
+
+            // Keep the current target if the carrier didn't pick a new one
+            let target = orders.target.or_else(|| {
+                ai.target.filter(|&t| {
+                    ships.iter().any(|&(e, p)| {
+                        e == t
+                            && vec2_len(vec2_sub(p, carrier_pos.pos)) < LEASH
+                    })
+                })
+            });
+            ai.target = target;
+            if target.is_some() {
+                autopilot.remove(ent);
+            } else {
+                // Circle the carrier
+                drone.orbit += ORBIT_SPEED * dt;
+                let (s, c) = sin_cos(drone.orbit);
+                let follow = Autopilot::Follow {
+                    leader: drone.carrier,
+                    offset: [ORBIT_RADIUS * c, ORBIT_RADIUS * s],
+                };
+                autopilot.insert(ent, follow).unwrap();
+            }
+        }
+
+        // Put docked drones back in a bay
+        for (carrier, dock) in docked {
+            let blk = match blocky.get_mut(carrier) {
+                Some(b) => b,
+                None => continue,
+            };
+            let bay = blk
+                .blocks
+                .iter_mut()
+                .filter_map(|&mut (loc, ref mut block)| match block.inner {
+                    BlockInner::DroneBay { ref mut drones, .. }
+                        if *drones < BAY_CAPACITY =>
+                    {
+                        let dist = vec2_square_len(vec2_sub(
+                            dock_point(loc),
+                            dock,
+                        ));
+                        Some((dist, drones))
+                    }
+                    _ => None,
+                })
+                .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            if let Some((_, drones)) = bay {
+                *drones += 1;
+            }
+        }
+    }
+}
+
+/// Where drones leave and join a bay, in the carrier's coordinate system.
+fn dock_point(bay: [f32; 2]) -> [f32; 2] {
+    if vec2_square_len(bay) < 0.01 {
+        return [LAUNCH_DISTANCE, 0.0];
+    }
+    vec2_add(bay, vec2_scale(vec2_normalized(bay), LAUNCH_DISTANCE))
+}
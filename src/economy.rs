@@ -0,0 +1,204 @@
+//! Ore economy, turning mining and salvage into something to spend.
+//!
+//! Rock blocks broken off asteroids drop `OrePickup`s, which ships collect by
+//! flying over them. Blocks salvaged into a ship's `Cargo` (see `salvage.rs`)
+//! get refined into ore, one at a time. Either way, the ore goes to the
+//! pilot's balance in the `Wallet` resource, to be spent on building blocks
+//! (`BlockInner::cost()`) and repairing them (`Block::repair_cost()`). The
+//! server sends each player their balance.
+
+use rand::Rng;
+use specs::{Component, Entities, Entity, Join, LazyUpdate, Read,
+            ReadExpect, ReadStorage, System, VecStorage, Write,
+            WriteStorage};
+use std::collections::HashMap;
+use vecmath::*;
+
+use crate::blocks::Blocky;
+#[cfg(feature = "network")]
+use crate::net;
+use crate::math::sin_cos;
+use crate::physics::{delete_entity, pilot, DeltaTime, Lifetime,
+                     LocalControl, Position, RemoteControl, Velocity};
+use crate::salvage::Cargo;
+use crate::ship::Ship;
+use crate::Role;
+
+/// Ore dropped by a rock block.
+const ORE_PER_ROCK: u32 = 3;
+
+/// Time before dropped ore disappears.
+const ORE_LIFETIME: f32 = 30.0;
+
+/// Speed at which dropped ore flies off.
+const ORE_SPEED: f32 = 2.0;
+
+/// Distance from a ship's blocks under which it collects ore.
+const PICKUP_RANGE: f32 = 2.0;
+
+/// Time it takes to refine a block from the cargo hold.
+const REFINE_TIME: f32 = 2.0;
+
+/// Share of a block's cost recovered by refining it.
+const REFINE_YIELD: f32 = 0.5;
+
+/// Ore of each player, available as a resource.
+///
+/// Players are identified as in `physics::pilot()`. On clients, this only
+/// has the balance of the local player, received from the server.
+#[derive(Debug, Default)]
+pub struct Wallet {
+    pub balances: HashMap<u64, u32>,
+}
+
+impl Wallet {
+    pub fn balance(&self, player: u64) -> u32 {
+        self.balances.get(&player).cloned().unwrap_or(0)
+    }
+
+    pub fn credit(&mut self, player: u64, amount: u32) {
+        *self.balances.entry(player).or_insert(0) += amount;
+    }
+
+    /// Takes ore from a player, if they have enough.
+    ///
+    /// Returns whether the ore was taken; nothing is taken otherwise.
+    pub fn spend(&mut self, player: u64, amount: u32) -> bool {
+        match self.balances.get_mut(&player) {
+            Some(balance) if *balance >= amount => {
+                *balance -= amount;
+                true
+            }
+            _ => amount == 0,
+        }
+    }
+}
+
+/// Ore floating in space, waiting to be collected.
+#[derive(Debug, Clone)]
+pub struct OrePickup {
+    pub amount: u32,
+}
+
+impl Component for OrePickup {
+    type Storage = VecStorage<Self>;
+}
+
+/// Drops the ore of a rock block that got destroyed.
+pub fn spawn_ore<R: Rng>(
+    entities: &Entities,
+    lazy: &Read<LazyUpdate>,
+    rng: &mut R,
+    pos: [f32; 2],
+    vel: [f32; 2],
+) -> Entity {
+    let dir = rng.gen_range(0.0, 2.0 * ::std::f32::consts::PI);
+    let (s, c) = sin_cos(dir);
+    let dir = [c, s];
+    let entity = entities.create();
+    lazy.insert(entity, Position { pos, rot: 0.0 });
+    lazy.insert(
+        entity,
+        Velocity {
+            vel: vec2_add(vel, vec2_scale(dir, ORE_SPEED)),
+            rot: 0.0,
+        },
+    );
+    lazy.insert(
+        entity,
+        OrePickup {
+            amount: ORE_PER_ROCK,
+        },
+    );
+    lazy.insert(entity, Lifetime(ORE_LIFETIME));
+    #[cfg(feature = "network")]
+    {
+        lazy.insert(entity, net::Replicated::new());
+        lazy.insert(entity, net::Dirty);
+    }
+    entity
+}
+
+/// Economy system, collects ore pickups and refines cargo into ore.
+#[derive(Default)]
+pub struct SysEconomy {
+    /// Time spent refining the current block, for each ship.
+    refining: HashMap<Entity, f32>,
+}
+
+impl<'a> System<'a> for SysEconomy {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        ReadExpect<'a, Role>,
+        Read<'a, LazyUpdate>,
+        Write<'a, Wallet>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Blocky>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, OrePickup>,
+        WriteStorage<'a, Cargo>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            role,
+            lazy,
+            mut wallet,
+            entities,
+            position,
+            blocky,
+            ship,
+            ore,
+            mut cargo,
+            local,
+            remote,
+        ): Self::SystemData,
+    ) {
+        assert!(role.authoritative());
+
+        // Collect ore
+        for (ent, pos, ore) in (&*entities, &position, &ore).join() {
+            let collector = (&*entities, &position, &blocky, &ship)
+                .join()
+                .filter(|&(_, ship_pos, blk, _)| {
+                    let range = blk.radius + PICKUP_RANGE;
+                    vec2_square_len(vec2_sub(ship_pos.pos, pos.pos))
+                        < range * range
+                })
+                .filter_map(|(e, _, _, _)| pilot(e, &local, &remote))
+                .next();
+            if let Some(player) = collector {
+                wallet.credit(player, ore.amount);
+                delete_entity(*role, &entities, &lazy, ent);
+                // Servers only delete it later, don't collect it twice
+                lazy.remove::<OrePickup>(ent);
+            }
+        }
+
+        // Refine cargo
+        self.refining.retain(|&e, _| entities.is_alive(e));
+        for (ent, cargo, _) in (&*entities, &mut cargo, &ship).join() {
+            let player = match pilot(ent, &local, &remote) {
+                Some(p) => p,
+                None => continue,
+            };
+            if cargo.blocks.is_empty() {
+                self.refining.remove(&ent);
+                continue;
+            }
+            let progress = self.refining.entry(ent).or_insert(0.0);
+            *progress += dt.0;
+            if *progress >= REFINE_TIME {
+                *progress = 0.0;
+                let block = cargo.blocks.pop().unwrap();
+                let value = block.cost() as f32 * REFINE_YIELD;
+                wallet.credit(player, value.ceil() as u32);
+            }
+        }
+    }
+}
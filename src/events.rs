@@ -0,0 +1,64 @@
+//! Game events.
+//!
+//! Systems write `GameEvent`s to the `GameEvents` resource when something
+//! noteworthy happens, so that other parts of the game (or integrations like
+//! the webhook) can react to them without being tied to the code that
+//! detects them.
+
+use specs::shrev::EventChannel;
+
+use crate::achievements::Achievement;
+use crate::stats::MatchSummary;
+
+/// Something that happened in the game.
+///
+/// Players are identified as in `physics::pilot()`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "webhook", derive(serde::Serialize))]
+#[cfg_attr(feature = "webhook", serde(tag = "type"))]
+pub enum GameEvent {
+    /// A client connected to the server.
+    PlayerJoined { player: u64 },
+    /// A client stopped answering the server, and was dropped.
+    PlayerLeft { player: u64 },
+    /// A ship lost its cockpit, `player` is who was controlling it and
+    /// `killer` who last damaged it.
+    ShipDestroyed {
+        player: Option<u64>,
+        killer: Option<u64>,
+    },
+    /// A player took over a derelict ship.
+    ShipCaptured { player: u64 },
+    /// A player's gun fired a projectile.
+    ShotFired { player: u64 },
+    /// A projectile fired by a player hit something.
+    ShotHit { player: u64 },
+    /// Blocks of a ship or asteroid were destroyed, by `attacker` if it was
+    /// a player.
+    BlocksDestroyed { attacker: Option<u64>, count: u32 },
+    /// Blocks were damaged by an explosion.
+    Damage {
+        attacker: Option<u64>,
+        victim: Option<u64>,
+        amount: f32,
+    },
+    /// The pirates' dreadnought was destroyed, see `boss.rs`.
+    BossDestroyed,
+    /// A new wave of enemies is coming, see `survival.rs`.
+    WaveStart { wave: u32 },
+    /// All players lost their ship in survival mode, after clearing `waves`
+    /// waves.
+    SurvivalOver { waves: u32 },
+    /// A team captured a control point, see `capture.rs`.
+    ZoneCaptured { team: u8 },
+    /// The match is over, see `rules.rs`.
+    MatchEnd { summary: MatchSummary },
+    /// A player reached a milestone, see `achievements.rs`.
+    Achievement {
+        player: u64,
+        achievement: Achievement,
+    },
+}
+
+/// Channel of game events, available as a resource.
+pub type GameEvents = EventChannel<GameEvent>;
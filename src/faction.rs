@@ -0,0 +1,30 @@
+//! Factions, telling friends from foes (IFF).
+//!
+//! Ships and the projectiles they fire can carry a `Faction`. Entities of the
+//! same faction are allies: damage between them doesn't score, and with
+//! `Rules::no_friendly_fire` their projectiles go through each other. Entities
+//! without a faction are hostile to everyone, which is the free-for-all that
+//! players get by default.
+
+use specs::{Component, Entity, ReadStorage, VecStorage};
+
+/// The side an entity is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Faction(pub u32);
+
+impl Component for Faction {
+    type Storage = VecStorage<Self>;
+}
+
+/// Whether two entities are enemies.
+///
+/// An entity is never hostile to itself.
+pub fn hostile(faction: &ReadStorage<Faction>, a: Entity, b: Entity) -> bool {
+    if a == b {
+        return false;
+    }
+    match (faction.get(a), faction.get(b)) {
+        (Some(fa), Some(fb)) => fa != fb,
+        _ => true,
+    }
+}
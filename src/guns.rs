@@ -1,20 +1,53 @@
-//! Guns and projectiles.
+//! Guns, projectiles, and beams.
 
 use specs::{Component, Entities, Entity, Read, ReadExpect, Join, LazyUpdate,
-            ReadStorage, System, VecStorage, WriteStorage};
+            ReadStorage, System, VecStorage, Write, WriteStorage};
 use vecmath::*;
 
 use crate::Role;
-use crate::blocks::Blocky;
+use crate::blocks::{BlockInner, Blocky};
+use crate::events::{GameEvent, GameEvents};
+use crate::math::sin_cos;
 #[cfg(feature = "network")]
 use crate::net;
 use crate::particles::{Effect, EffectInner};
-use crate::physics::{affect_area, delete_entity, AABox, DetectCollision,
-                     HitEffect, Hits, Position, Velocity};
+use crate::physics::{affect_area, delete_entity, find_collision_tree_ray,
+                     pilot, raycast, trace_blocks, AABox, DeltaTime,
+                     DetectCollision, Hit, HitEffect, Hits, Lifetime,
+                     LocalControl, Position, RemoteControl, Velocity};
+use crate::ship::Ship;
+use crate::station::InSafeZone;
+
+/// Radius of the area affected by an EMP projectile.
+const EMP_RADIUS: f32 = 4.0;
+/// How long blocks stay disabled after being hit by an EMP.
+const EMP_DURATION: f32 = 3.0;
+
+/// Time after which projectiles that didn't hit anything disappear.
+const PROJECTILE_LIFETIME: f32 = 4.0;
+
+/// Energy lost by piercing projectiles for each block they go through, on
+/// top of the block's health.
+const PIERCE_LOSS: f32 = 0.1;
+
+/// Time during which a projectile can't hit the ship that fired it.
+const SHOOTER_IMMUNITY: f32 = 0.3;
+
+/// Radius of the burst of flak projectiles.
+const FLAK_RADIUS: f32 = 2.0;
+
+/// Maximum length of a laser beam.
+const BEAM_RANGE: f32 = 25.0;
+/// Damage per second dealt by a laser beam to the block it hits.
+const BEAM_DAMAGE: f32 = 0.8;
 
 pub enum ProjectileType {
     Plasma,
     Rail,
+    Emp,
+    Flak,
+    /// Shot from a `ChargeGun`, with its charge from 0 to 1.
+    Charge(f32),
 }
 
 impl ProjectileType {
@@ -22,6 +55,9 @@ impl ProjectileType {
         match *self {
             ProjectileType::Plasma => 60.0,
             ProjectileType::Rail => 35.0,
+            ProjectileType::Emp => 40.0,
+            ProjectileType::Flak => 45.0,
+            ProjectileType::Charge(_) => 50.0,
         }
     }
 
@@ -29,6 +65,35 @@ impl ProjectileType {
         match *self {
             ProjectileType::Plasma => None,
             ProjectileType::Rail => Some(5.0),
+            ProjectileType::Emp => None,
+            ProjectileType::Flak => None,
+            ProjectileType::Charge(_) => None,
+        }
+    }
+
+    /// Energy for going through blocks, damaging them, instead of stopping
+    /// at the first one. It takes the block's health to get through.
+    pub fn penetration(&self) -> f32 {
+        match *self {
+            ProjectileType::Rail => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Time after which the projectile goes off on its own, if any.
+    pub fn fuse(&self) -> Option<f32> {
+        match *self {
+            ProjectileType::Flak => Some(1.2),
+            _ => None,
+        }
+    }
+
+    /// Distance from objects at which the projectile goes off, if it has a
+    /// proximity fuse.
+    pub fn proximity(&self) -> Option<f32> {
+        match *self {
+            ProjectileType::Flak => Some(2.5),
+            _ => None,
         }
     }
 
@@ -46,6 +111,24 @@ impl ProjectileType {
                 ymin: -0.6,
                 ymax: 0.6,
             },
+            ProjectileType::Emp => AABox {
+                xmin: -0.4,
+                xmax: 0.4,
+                ymin: -0.4,
+                ymax: 0.4,
+            },
+            ProjectileType::Flak => AABox {
+                xmin: -0.3,
+                xmax: 0.3,
+                ymin: -0.3,
+                ymax: 0.3,
+            },
+            ProjectileType::Charge(_) => AABox {
+                xmin: -0.6,
+                xmax: 0.6,
+                ymin: -0.3,
+                ymax: 0.3,
+            },
         }
     }
 }
@@ -53,10 +136,17 @@ impl ProjectileType {
 /// A projectile.
 ///
 /// This is a simple segment that goes in a straight line, and gets removed
-/// when it hits something or exits the screen.
+/// when it hits something or at the end of its `Lifetime`.
 pub struct Projectile {
     pub kind: ProjectileType,
     pub shooter: Entity,
+    /// Time left before the projectile can hit its shooter.
+    pub immunity: f32,
+    /// Time left before the projectile goes off on its own, if it has a
+    /// fuse.
+    pub fuse: Option<f32>,
+    /// Energy left to go through blocks, see `ProjectileType::penetration()`.
+    pub energy: f32,
 }
 
 impl Projectile {
@@ -65,11 +155,12 @@ impl Projectile {
         lazy: &Read<LazyUpdate>,
         pos: [f32; 2],
         rot: f32,
+        shooter_vel: [f32; 2],
         kind: ProjectileType,
         shooter: Entity,
     ) -> Entity {
         let entity = entities.create();
-        let (s, c) = rot.sin_cos();
+        let (s, c) = sin_cos(rot);
         lazy.insert(
             entity,
             Position {
@@ -80,7 +171,10 @@ impl Projectile {
         lazy.insert(
             entity,
             Velocity {
-                vel: [kind.speed() * c, kind.speed() * s],
+                vel: vec2_add(
+                    shooter_vel,
+                    [kind.speed() * c, kind.speed() * s],
+                ),
                 rot: 0.0,
             },
         );
@@ -92,10 +186,20 @@ impl Projectile {
                 bounding_box,
                 radius,
                 mass: kind.mass(),
-                ignore: None,
+                ignore: Some(shooter),
             },
         );
-        lazy.insert(entity, Projectile { kind, shooter });
+        lazy.insert(
+            entity,
+            Projectile {
+                fuse: kind.fuse(),
+                energy: kind.penetration(),
+                kind,
+                shooter,
+                immunity: SHOOTER_IMMUNITY,
+            },
+        );
+        lazy.insert(entity, Lifetime(PROJECTILE_LIFETIME));
         #[cfg(feature = "network")]
         {
             lazy.insert(entity, net::Replicated::new());
@@ -109,78 +213,141 @@ impl Component for Projectile {
     type Storage = VecStorage<Self>;
 }
 
-/// Deletes projectiles when they fall off.
+/// Makes projectiles go off on hits.
+///
+/// Projectiles ignore the ship that fired them for a short time, after which
+/// they can hit it too. Those with a fuse also go off when it runs out, or
+/// when getting close to an object.
 pub struct SysProjectile;
 
 impl<'a> System<'a> for SysProjectile {
     type SystemData = (
+        Read<'a, DeltaTime>,
         ReadExpect<'a, Role>,
         Read<'a, LazyUpdate>,
+        Write<'a, GameEvents>,
         Entities<'a>,
         WriteStorage<'a, Hits>,
         ReadStorage<'a, Position>,
         ReadStorage<'a, Blocky>,
-        ReadStorage<'a, Projectile>,
+        WriteStorage<'a, Projectile>,
+        WriteStorage<'a, DetectCollision>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
     );
 
     fn run(
         &mut self,
             (
+                dt,
                 role,
                 lazy,
+                mut events,
                 entities,
                 mut
                 hits,
                 position,
                 blocky,
-                projectile,
+                mut projectile,
+                mut detect,
+                local,
+                remote,
             ): Self::SystemData,
 ){
         assert!(role.authoritative());
 
-        for (entity, pos, proj) in (&*entities, &position, &projectile).join()
+        for (entity, pos, proj) in
+            (&*entities, &position, &mut projectile).join()
         {
-            // Remove projectiles gone from the screen
-            if pos.pos[0] < -150.0 || pos.pos[0] > 150.0 || pos.pos[1] < -150.0
-                || pos.pos[1] > 150.0
-            {
-                delete_entity(*role, &entities, &lazy, entity);
+            // Let the projectile hit its shooter after a while
+            if proj.immunity > 0.0 {
+                proj.immunity -= dt.0;
+                if proj.immunity <= 0.0 {
+                    if let Some(detect) = detect.get_mut(entity) {
+                        detect.ignore = None;
+                    }
+                }
             }
 
             // Hit projectiles go off and affect an area
-            let (mut delete, mut hit_loc) = (false, None);
+            let mut hit = None;
             match hits.get(entity) {
                 Some(v) => for h in &**v {
                     match h.effect {
                         HitEffect::Collision(_, e) => {
-                            delete = true;
-                            if e != proj.shooter {
-                                let (s, c) = pos.rot.sin_cos();
-                                hit_loc = Some(vec2_add(
-                                    pos.pos,
-                                    [
-                                        c * h.rel_location[0]
-                                            - s * h.rel_location[1],
-                                        s * h.rel_location[0]
-                                            + s * h.rel_location[1],
-                                    ],
-                                ));
-                                break;
-                            }
+                            let (s, c) = sin_cos(pos.rot);
+                            hit = Some((Some(e), vec2_add(
+                                pos.pos,
+                                [
+                                    c * h.rel_location[0]
+                                        - s * h.rel_location[1],
+                                    s * h.rel_location[0]
+                                        + c * h.rel_location[1],
+                                ],
+                            )));
+                            break;
                         }
                         _ => {}
                     }
                 },
                 None => {}
             };
-            if delete {
-                delete_entity(*role, &entities, &lazy, entity);
+
+            // Fuses make projectiles go off without hitting anything
+            if hit.is_none() {
+                if let Some(range) = proj.kind.proximity() {
+                    let target = find_nearby(
+                        &entities,
+                        &position,
+                        &blocky,
+                        pos.pos,
+                        range,
+                        proj.shooter,
+                    );
+                    if target.is_some() {
+                        hit = Some((target, pos.pos));
+                    }
+                }
+            }
+            if let Some(ref mut fuse) = proj.fuse {
+                *fuse -= dt.0;
+                if *fuse <= 0.0 && hit.is_none() {
+                    hit = Some((None, pos.pos));
+                }
             }
-            let hit_loc = match hit_loc {
+
+            let (target, hit_loc) = match hit {
                 None => continue,
-                Some(l) => l,
+                Some(h) => h,
             };
 
+            // Piercing projectiles that make it through keep going
+            if let Some(target) = target {
+                if proj.energy > 0.0
+                    && pierce(
+                        proj,
+                        target,
+                        hit_loc,
+                        pos.rot,
+                        &position,
+                        &blocky,
+                        &mut hits,
+                    )
+                {
+                    if let Some(detect) = detect.get_mut(entity) {
+                        detect.ignore = Some(target);
+                    }
+                    continue;
+                }
+            }
+
+            delete_entity(*role, &entities, &lazy, entity);
+            if target.is_some() && target != Some(proj.shooter) {
+                if let Some(player) = pilot(proj.shooter, &local, &remote) {
+                    events.single_write(GameEvent::ShotHit { player });
+                }
+            }
+
             match proj.kind {
                 ProjectileType::Plasma => {
                     // Affect entities in range with an Explosion
@@ -191,7 +358,7 @@ impl<'a> System<'a> for SysProjectile {
                         &mut hits,
                         hit_loc,
                         3.0,
-                        HitEffect::Explosion(3.0),
+                        HitEffect::Explosion(3.0, Some(proj.shooter)),
                     );
 
                     let new_effect = entities.create();
@@ -231,8 +398,333 @@ impl<'a> System<'a> for SysProjectile {
                     #[cfg(feature = "network")]
                     lazy.insert(new_effect, net::Dirty);
                 }
+                ProjectileType::Flak => {
+                    // Small burst
+                    affect_area(
+                        &entities,
+                        &position,
+                        &blocky,
+                        &mut hits,
+                        hit_loc,
+                        FLAK_RADIUS,
+                        HitEffect::Explosion(FLAK_RADIUS, Some(proj.shooter)),
+                    );
+
+                    let new_effect = entities.create();
+                    lazy.insert(
+                        new_effect,
+                        Position {
+                            pos: hit_loc,
+                            rot: 0.0,
+                        },
+                    );
+                    lazy.insert(
+                        new_effect,
+                        Effect {
+                            effect: EffectInner::Explosion(1.0),
+                            lifetime: -1.0,
+                        },
+                    );
+                    #[cfg(feature = "network")]
+                    lazy.insert(new_effect, net::Dirty);
+                }
+                ProjectileType::Charge(charge) => {
+                    // Explosion that grows with the charge
+                    let size = 1.0 + 4.0 * charge;
+                    affect_area(
+                        &entities,
+                        &position,
+                        &blocky,
+                        &mut hits,
+                        hit_loc,
+                        size,
+                        HitEffect::Explosion(size, Some(proj.shooter)),
+                    );
+
+                    let new_effect = entities.create();
+                    lazy.insert(
+                        new_effect,
+                        Position {
+                            pos: hit_loc,
+                            rot: 0.0,
+                        },
+                    );
+                    lazy.insert(
+                        new_effect,
+                        Effect {
+                            effect: EffectInner::Explosion(0.5 * size),
+                            lifetime: -1.0,
+                        },
+                    );
+                    #[cfg(feature = "network")]
+                    lazy.insert(new_effect, net::Dirty);
+                }
+                ProjectileType::Emp => {
+                    // Disable blocks in range
+                    affect_area(
+                        &entities,
+                        &position,
+                        &blocky,
+                        &mut hits,
+                        hit_loc,
+                        EMP_RADIUS,
+                        HitEffect::Emp(EMP_RADIUS, EMP_DURATION),
+                    );
+
+                    let new_effect = entities.create();
+                    lazy.insert(
+                        new_effect,
+                        Position {
+                            pos: pos.pos,
+                            rot: 0.0,
+                        },
+                    );
+                    lazy.insert(
+                        new_effect,
+                        Effect {
+                            effect: EffectInner::EmpHit,
+                            lifetime: -1.0,
+                        },
+                    );
+                    #[cfg(feature = "network")]
+                    lazy.insert(new_effect, net::Dirty);
+                }
             }
             continue;
         }
     }
 }
+
+/// Damages the blocks on the path of a piercing projectile through an object.
+///
+/// Returns whether it made it through, with energy to spare.
+fn pierce<'a>(
+    proj: &mut Projectile,
+    target: Entity,
+    hit_loc: [f32; 2],
+    rot: f32,
+    position: &ReadStorage<'a, Position>,
+    blocky: &ReadStorage<'a, Blocky>,
+    hits: &mut WriteStorage<'a, Hits>,
+) -> bool {
+    let (tpos, tblk) = match (position.get(target), blocky.get(target)) {
+        (Some(p), Some(b)) => (p, b),
+        _ => return false,
+    };
+    // Follow the path in the object's coordinate system
+    let (s, c) = sin_cos(tpos.rot);
+    let to_local = |v: [f32; 2]| [v[0] * c + v[1] * s, -v[0] * s + v[1] * c];
+    let dir = {
+        let (ds, dc) = sin_cos(rot);
+        to_local([dc, ds])
+    };
+    let start = vec2_sub(to_local(vec2_sub(hit_loc, tpos.pos)), dir);
+    for idx in trace_blocks(tblk, start, dir, 2.0 * tblk.radius + 1.0) {
+        let (loc, ref block) = tblk.blocks[idx];
+        let cost = block.health + PIERCE_LOSS;
+        let damage = cost.min(proj.energy);
+        proj.energy -= damage;
+        Hits::record(
+            hits,
+            target,
+            Hit {
+                rel_location: loc,
+                effect: HitEffect::Pierce(damage, proj.shooter),
+            },
+        );
+        if proj.energy <= 0.0 {
+            return false;
+        }
+    }
+    true
+}
+
+/// Finds an object with a block within `range` of a point.
+fn find_nearby<'a>(
+    entities: &Entities<'a>,
+    position: &ReadStorage<'a, Position>,
+    blocky: &ReadStorage<'a, Blocky>,
+    point: [f32; 2],
+    range: f32,
+    ignore: Entity,
+) -> Option<Entity> {
+    for (ent, pos, blk) in (&**entities, position, blocky).join() {
+        let rad = range + blk.radius;
+        if ent == ignore
+            || vec2_square_len(vec2_sub(pos.pos, point)) > rad * rad
+        {
+            continue;
+        }
+        let (s, c) = sin_cos(pos.rot);
+        let x = point[0] - pos.pos[0];
+        let y = point[1] - pos.pos[1];
+        let rel = [x * c + y * s, -x * s + y * c];
+        if blk.blocks.iter().any(|&(loc, _)| {
+            vec2_square_len(vec2_sub(loc, rel)) <= range * range
+        }) {
+            return Some(ent);
+        }
+    }
+    None
+}
+
+/// A laser beam, fired by a `BeamLaser` block for as long as the ship fires.
+///
+/// Unlike projectiles, beams hit instantly: they are cast as rays every
+/// frame, and burn the first block they meet.
+pub struct Beam {
+    /// The ship firing the beam (the beam itself on clients).
+    pub shooter: Entity,
+    /// Location of the `BeamLaser` block, in the shooter's coordinate system.
+    pub block: [f32; 2],
+    /// Start of the beam, in world coordinates.
+    pub start: [f32; 2],
+    /// Direction of the beam.
+    pub rot: f32,
+    /// Length of the beam, up to the block it hits or its maximum range.
+    pub length: f32,
+    /// Whether the beam is hitting something.
+    pub hitting: bool,
+}
+
+impl Component for Beam {
+    type Storage = VecStorage<Self>;
+}
+
+/// Beam system, casts the rays of lasers and keeps their `Beam` entities.
+///
+/// Like `SysSalvage`, this records hits on the blocks it burns, so it needs
+/// to run after `SysCollision` clears the hits.
+pub struct SysBeam;
+
+impl<'a> System<'a> for SysBeam {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        ReadExpect<'a, Role>,
+        Read<'a, LazyUpdate>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Blocky>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, InSafeZone>,
+        WriteStorage<'a, Beam>,
+        WriteStorage<'a, Hits>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            role,
+            lazy,
+            entities,
+            position,
+            blocky,
+            ships,
+            safe,
+            mut beams,
+            mut hits,
+        ): Self::SystemData,
+    ) {
+        assert!(role.authoritative());
+
+        // Cast the beams of the lasers that are firing
+        let mut firing = Vec::new();
+        for (ent, pos, blk, ship, _) in
+            (&*entities, &position, &blocky, &ships, !&safe).join()
+        {
+            let (s, c) = sin_cos(pos.rot);
+            for &(loc, ref block) in &blk.blocks {
+                let angle = match block.inner {
+                    BlockInner::BeamLaser { angle } => angle,
+                    _ => continue,
+                };
+                if !ship.fires(block) || block.is_disabled() {
+                    continue;
+                }
+                // Don't fire into our own blocks
+                let dir_loc = {
+                    let (ds, dc) = sin_cos(angle);
+                    [dc, ds]
+                };
+                if find_collision_tree_ray(
+                    vec2_add(loc, vec2_scale(dir_loc, 0.6)),
+                    dir_loc,
+                    &blk.tree,
+                ).is_some()
+                {
+                    continue;
+                }
+                let start = vec2_add(
+                    pos.pos,
+                    [loc[0] * c - loc[1] * s, loc[0] * s + loc[1] * c],
+                );
+                let rot = pos.rot + angle;
+                let dir = {
+                    let (ds, dc) = sin_cos(rot);
+                    [dc, ds]
+                };
+                let hit = raycast(
+                    &entities,
+                    &position,
+                    &blocky,
+                    start,
+                    dir,
+                    BEAM_RANGE,
+                    |e| e != ent,
+                );
+                let (length, hitting) = match hit {
+                    Some(hit) => {
+                        Hits::record(
+                            &mut hits,
+                            hit.entity,
+                            Hit {
+                                rel_location: hit.rel_location,
+                                effect: HitEffect::Pierce(
+                                    BEAM_DAMAGE * dt.0,
+                                    ent,
+                                ),
+                            },
+                        );
+                        (hit.distance, true)
+                    }
+                    None => (BEAM_RANGE, false),
+                };
+                firing.push(Beam {
+                    shooter: ent,
+                    block: loc,
+                    start,
+                    rot,
+                    length,
+                    hitting,
+                });
+            }
+        }
+
+        // Update existing beam entities, remove those no longer firing
+        for (ent, beam) in (&*entities, &mut beams).join() {
+            let idx = firing.iter().position(|b| {
+                b.shooter == beam.shooter && b.block == beam.block
+            });
+            match idx {
+                Some(idx) => {
+                    *beam = firing.swap_remove(idx);
+                    #[cfg(feature = "network")]
+                    lazy.insert(ent, net::Dirty);
+                }
+                None => delete_entity(*role, &entities, &lazy, ent),
+            }
+        }
+
+        // Create entities for new beams
+        for beam in firing {
+            let entity = entities.create();
+            lazy.insert(entity, beam);
+            #[cfg(feature = "network")]
+            {
+                lazy.insert(entity, net::Replicated::new());
+                lazy.insert(entity, net::Dirty);
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Pilot feedback.
+//!
+//! `SysHud` keeps the `HudState` resource up to date with the state of the
+//! locally-controlled ship, and sends `FeedbackEvent`s when something happens
+//! to it that the pilot should notice right away, such as losing thrusters.
+//! It also picks the local player's line from the `Scoreboard` and their ore
+//! from the `Wallet`, which clients get from the server.
+//!
+//! With several local players, this follows the first one's ship.
+
+use specs::shrev::EventChannel;
+use specs::{Join, Read, ReadStorage, System, Write};
+
+use crate::economy::Wallet;
+use crate::physics::LocalControl;
+use crate::ship::Ship;
+use crate::stats::{PlayerStats, Scoreboard};
+
+/// A drop in control authority at least this large triggers feedback.
+const AUTHORITY_DROP: f32 = 0.2;
+
+/// Control axes of a ship, in the order of `Ship::authority`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlAxis {
+    /// Forward and backward.
+    Longitudinal,
+    /// Sideways.
+    Lateral,
+    Rotation,
+}
+
+const AXES: [ControlAxis; 3] = [
+    ControlAxis::Longitudinal,
+    ControlAxis::Lateral,
+    ControlAxis::Rotation,
+];
+
+/// State of the local ship to show the pilot, available as a resource.
+#[derive(Debug, Clone)]
+pub struct HudState {
+    /// Control authority on each axis, see `Ship::authority`.
+    pub authority: [f32; 3],
+    /// Locations of the blocks disabled by an EMP, to gray out, see
+    /// `Ship::disabled_blocks`.
+    pub disabled_blocks: Vec<[f32; 2]>,
+    /// Kills, deaths and damage of the local player in the current match.
+    pub score: PlayerStats,
+    /// Ore of the local player, see `economy.rs`.
+    pub ore: u32,
+}
+
+impl Default for HudState {
+    fn default() -> HudState {
+        HudState {
+            authority: [1.0; 3],
+            disabled_blocks: Vec::new(),
+            score: Default::default(),
+            ore: 0,
+        }
+    }
+}
+
+/// The local player's key in the `Scoreboard`, available as a resource.
+///
+/// This is 0 for the first local player, and the client ID when connected to
+/// a server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalPlayer(pub u64);
+
+/// Something the pilot should be told about.
+#[derive(Debug, Clone)]
+pub enum FeedbackEvent {
+    /// Control authority dropped sharply on an axis, for example because
+    /// thrusters got destroyed or disabled.
+    AuthorityLost { axis: ControlAxis, authority: f32 },
+}
+
+/// Channel of feedback events, available as a resource.
+pub type FeedbackEvents = EventChannel<FeedbackEvent>;
+
+/// HUD system, updates `HudState` from the locally-controlled ship.
+pub struct SysHud;
+
+impl<'a> System<'a> for SysHud {
+    type SystemData = (
+        Write<'a, HudState>,
+        Write<'a, FeedbackEvents>,
+        Read<'a, Scoreboard>,
+        Read<'a, Wallet>,
+        Read<'a, LocalPlayer>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, LocalControl>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            mut hud,
+            mut events,
+            scoreboard,
+            wallet,
+            player,
+            ship,
+            local,
+        ): Self::SystemData,
+    ) {
+        let score = scoreboard
+            .players
+            .get(&player.0)
+            .cloned()
+            .unwrap_or_default();
+        let ore = wallet.balance(player.0);
+        let ship = (&ship, &local)
+            .join()
+            .find(|&(_, &LocalControl(index))| index == 0);
+        let ship = match ship {
+            Some((ship, _)) => ship,
+            None => {
+                *hud = HudState {
+                    score,
+                    ore,
+                    ..Default::default()
+                };
+                return;
+            }
+        };
+        hud.score = score;
+        hud.ore = ore;
+        hud.disabled_blocks.clear();
+        hud.disabled_blocks
+            .extend(ship.disabled_blocks.iter().map(|&(loc, _)| loc));
+
+        for (i, &axis) in AXES.iter().enumerate() {
+            let authority = ship.authority[i];
+            if hud.authority[i] - authority >= AUTHORITY_DROP {
+                events.single_write(FeedbackEvent::AuthorityLost {
+                    axis,
+                    authority,
+                });
+            }
+            hud.authority[i] = authority;
+        }
+    }
+}
@@ -1,7 +1,9 @@
 //! Keyboard input structure.
 //!
 //! This is a simple structure used as a specs resource to store input from the
-//! local player.
+//! local players.
+
+use std::ops::{Deref, DerefMut};
 
 /// A key status.
 ///
@@ -28,11 +30,20 @@ impl Press {
     }
 }
 
-/// Input resource, stores the local user's controls.
+/// The controls of one local player.
 pub struct Input {
     pub movement: [f32; 2],
     pub rotation: f32,
-    pub fire: Press,
+    /// Fire buttons, one for each weapon group.
+    pub fire: [Press; 3],
+    /// Turns the tractor beam on or off.
+    pub tractor_beam: Press,
+    /// Turns the inertia dampeners on or off.
+    pub dampeners: Press,
+    /// Afterburner, boosts the thrusters while held.
+    pub boost: Press,
+    /// Matches velocity with the object under the cursor while held.
+    pub match_velocity: Press,
     pub mouse: [f32; 2],
     pub buttons: [Press; 3],
 }
@@ -42,7 +53,11 @@ impl Default for Input {
         Input {
             movement: [0.0, 0.0],
             rotation: 0.0,
-            fire: Press::UP,
+            fire: [Press::UP; 3],
+            tractor_beam: Press::UP,
+            dampeners: Press::UP,
+            boost: Press::UP,
+            match_velocity: Press::UP,
             mouse: [0.0; 2],
             buttons: [Press::UP; 3],
         }
@@ -52,9 +67,56 @@ impl Default for Input {
 impl Input {
     /// Update status of keys, called once per frame.
     pub fn update(&mut self) {
-        self.fire.update();
+        for fire in &mut self.fire {
+            fire.update();
+        }
+        self.tractor_beam.update();
+        self.dampeners.update();
+        self.boost.update();
+        self.match_velocity.update();
         self.buttons[0].update();
         self.buttons[1].update();
         self.buttons[2].update();
     }
 }
+
+/// Input resource, stores the controls of each local player.
+///
+/// A ship with `LocalControl(index)` is controlled by the `Input` at that
+/// index, so that several players can share a screen, for example one on the
+/// keyboard and one on a gamepad.
+pub struct Inputs(Vec<Input>);
+
+impl Inputs {
+    /// Creates the controls for this number of local players.
+    pub fn new(players: usize) -> Inputs {
+        Inputs((0..players).map(|_| Input::default()).collect())
+    }
+
+    /// Update status of keys, called once per frame.
+    pub fn update(&mut self) {
+        for input in &mut self.0 {
+            input.update();
+        }
+    }
+}
+
+impl Default for Inputs {
+    fn default() -> Inputs {
+        Inputs::new(1)
+    }
+}
+
+impl Deref for Inputs {
+    type Target = [Input];
+
+    fn deref(&self) -> &[Input] {
+        &self.0
+    }
+}
+
+impl DerefMut for Inputs {
+    fn deref_mut(&mut self) -> &mut [Input] {
+        &mut self.0
+    }
+}
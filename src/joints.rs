@@ -0,0 +1,263 @@
+//! Joints between entities.
+//!
+//! A `Joint` ties an entity to another one, through an anchor point on each
+//! of them, so they move together. This is used for tow cables, docking, and
+//! the like. `SysJoints` solves the constraints by applying impulses to the
+//! `Blocky` objects.
+//!
+//! Anchors are given relative to the center of mass, so they move if the
+//! object loses blocks.
+
+use specs::{Component, Entities, Entity, Read, Join, HashMapStorage,
+            LazyUpdate, ReadStorage, System, WriteStorage};
+use vecmath::*;
+
+use crate::blocks::Blocky;
+use crate::math::sin_cos;
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{DeltaTime, Position, Velocity};
+use crate::utils::angle_wrap;
+
+/// Number of passes of the solver per frame.
+///
+/// An object can be part of several joints, more passes let them agree.
+const SOLVER_ITERATIONS: usize = 4;
+
+/// Fraction of the position error corrected each second by rigid joints.
+const CORRECTION_RATE: f32 = 10.0;
+
+/// The type of joint, and its parameters.
+#[derive(Debug, Clone)]
+pub enum JointKind {
+    /// Rigidly holds the anchors together, and keeps the relative rotation
+    /// of the objects (rotation of the other object minus this one's).
+    Weld { angle: f32 },
+    /// Pulls the anchors towards a given distance, like a cable or a
+    /// trailer hitch.
+    Spring {
+        length: f32,
+        stiffness: f32,
+        damping: f32,
+    },
+}
+
+/// A joint, attached to one of the two entities it ties.
+///
+/// An entity can only hold one joint, but it can be the `other` end of any
+/// number of them.
+#[derive(Debug, Clone)]
+pub struct Joint {
+    /// The entity at the other end.
+    pub other: Entity,
+    /// Anchor point, in this entity's coordinate system.
+    pub anchor: [f32; 2],
+    /// Anchor point, in the other entity's coordinate system.
+    pub other_anchor: [f32; 2],
+    pub kind: JointKind,
+}
+
+impl Component for Joint {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Whether two entities are tied by a joint, whichever holds it.
+pub fn jointed(
+    joints: &ReadStorage<Joint>,
+    ent1: Entity,
+    ent2: Entity,
+) -> bool {
+    joints.get(ent1).map(|j| j.other) == Some(ent2)
+        || joints.get(ent2).map(|j| j.other) == Some(ent1)
+}
+
+/// Cross product of planar vectors.
+fn cross(a: [f32; 2], b: [f32; 2]) -> f32 {
+    a[0] * b[1] - a[1] * b[0]
+}
+
+/// Velocity of a point of an object, from its offset to the center.
+fn point_velocity(vel: &Velocity, r: [f32; 2]) -> [f32; 2] {
+    [vel.vel[0] - vel.rot * r[1], vel.vel[1] + vel.rot * r[0]]
+}
+
+/// Applies an impulse at some offset from the center of an object.
+fn apply_impulse(
+    vel: &mut Velocity,
+    blocky: &Blocky,
+    r: [f32; 2],
+    impulse: [f32; 2],
+) {
+    vel.vel = vec2_add(vel.vel, vec2_scale(impulse, 1.0 / blocky.mass));
+    vel.rot += cross(r, impulse) / blocky.inertia;
+}
+
+/// Joint solver system.
+pub struct SysJoints;
+
+impl<'a> System<'a> for SysJoints {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        Read<'a, LazyUpdate>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        WriteStorage<'a, Velocity>,
+        ReadStorage<'a, Blocky>,
+        WriteStorage<'a, Joint>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            lazy,
+            entities,
+            pos,
+            mut vel,
+            blocky,
+            mut joints,
+        ): Self::SystemData,
+    ) {
+        let dt = dt.0;
+        if dt <= 0.0 {
+            return;
+        }
+
+        // Drop joints to objects that are gone
+        let broken = (&*entities, &joints)
+            .join()
+            .filter(|&(_, joint)| {
+                !entities.is_alive(joint.other)
+                    || blocky.get(joint.other).is_none()
+            })
+            .map(|(ent, _)| ent)
+            .collect::<Vec<_>>();
+        for ent in broken {
+            joints.remove(ent);
+        }
+
+        let list = (&*entities, &joints, &pos, &blocky)
+            .join()
+            .map(|(ent, joint, _, _)| (ent, joint.clone()))
+            .collect::<Vec<_>>();
+        if list.is_empty() {
+            return;
+        }
+
+        // Springs are forces, apply them once
+        for &(ent, ref joint) in &list {
+            if let JointKind::Spring {
+                length,
+                stiffness,
+                damping,
+            } = joint.kind
+            {
+                solve(ent, joint, &pos, &mut vel, &blocky, |_, dist, vn| {
+                    -(stiffness * (dist - length) + damping * vn) * dt
+                });
+            }
+        }
+
+        // Welds are constraints, iterate so they converge
+        for _ in 0..SOLVER_ITERATIONS {
+            for &(ent, ref joint) in &list {
+                if let JointKind::Weld { angle } = joint.kind {
+                    solve(ent, joint, &pos, &mut vel, &blocky, |k, dist, vn| {
+                        -(vn + dist * CORRECTION_RATE) / k
+                    });
+                    solve_angle(
+                        ent,
+                        joint.other,
+                        angle,
+                        &pos,
+                        &mut vel,
+                        &blocky,
+                    );
+                }
+            }
+        }
+
+        #[cfg(feature = "network")]
+        for &(ent, ref joint) in &list {
+            lazy.insert(ent, net::Dirty);
+            lazy.insert(joint.other, net::Dirty);
+        }
+        #[cfg(not(feature = "network"))]
+        let _ = lazy;
+    }
+}
+
+/// Solves the linear part of a joint.
+///
+/// The callback gets the effective mass factor, the current distance between
+/// the anchors and their relative velocity along the joint's axis, and
+/// returns the impulse to apply along that axis.
+fn solve<'a, F>(
+    ent: Entity,
+    joint: &Joint,
+    pos: &ReadStorage<'a, Position>,
+    vel: &mut WriteStorage<'a, Velocity>,
+    blocky: &ReadStorage<'a, Blocky>,
+    impulse: F,
+) where
+    F: Fn(f32, f32, f32) -> f32,
+{
+    let (pos1, pos2) = match (pos.get(ent), pos.get(joint.other)) {
+        (Some(p1), Some(p2)) => (p1, p2),
+        _ => return,
+    };
+    let blk1 = blocky.get(ent).unwrap();
+    let blk2 = blocky.get(joint.other).unwrap();
+
+    // Anchors in world orientation, relative to each center
+    let rotate = |p: &Position, a: [f32; 2]| {
+        let (s, c) = sin_cos(p.rot);
+        [a[0] * c - a[1] * s, a[0] * s + a[1] * c]
+    };
+    let r1 = rotate(pos1, joint.anchor);
+    let r2 = rotate(pos2, joint.other_anchor);
+    let diff = vec2_sub(vec2_add(pos2.pos, r2), vec2_add(pos1.pos, r1));
+    let dist = vec2_len(diff);
+    if dist < 0.000_1 {
+        return;
+    }
+    let n = vec2_scale(diff, 1.0 / dist);
+
+    let (v1, v2) = match (vel.get(ent), vel.get(joint.other)) {
+        (Some(v1), Some(v2)) => {
+            (point_velocity(v1, r1), point_velocity(v2, r2))
+        }
+        _ => return,
+    };
+    let vn = vec2_dot(vec2_sub(v2, v1), n);
+    let k = 1.0 / blk1.mass
+        + 1.0 / blk2.mass
+        + cross(r1, n) * cross(r1, n) / blk1.inertia
+        + cross(r2, n) * cross(r2, n) / blk2.inertia;
+
+    let p = vec2_scale(n, impulse(k, dist, vn));
+    apply_impulse(vel.get_mut(joint.other).unwrap(), blk2, r2, p);
+    apply_impulse(vel.get_mut(ent).unwrap(), blk1, r1, vec2_neg(p));
+}
+
+/// Solves the angular part of a weld joint.
+fn solve_angle<'a>(
+    ent: Entity,
+    other: Entity,
+    angle: f32,
+    pos: &ReadStorage<'a, Position>,
+    vel: &mut WriteStorage<'a, Velocity>,
+    blocky: &ReadStorage<'a, Blocky>,
+) {
+    let error = angle_wrap(
+        pos.get(other).unwrap().rot - pos.get(ent).unwrap().rot - angle,
+    );
+    let (i1, i2) = (
+        blocky.get(ent).unwrap().inertia,
+        blocky.get(other).unwrap().inertia,
+    );
+    let rel = vel.get(other).unwrap().rot - vel.get(ent).unwrap().rot;
+    let impulse = -(rel + error * CORRECTION_RATE) / (1.0 / i1 + 1.0 / i2);
+    vel.get_mut(other).unwrap().rot += impulse / i2;
+    vel.get_mut(ent).unwrap().rot -= impulse / i1;
+}
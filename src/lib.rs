@@ -11,32 +11,150 @@
 //! `Position`, `Velocity`, `Hits`... Integrates positions, finds collisions.
 //! * `asteroid.rs`: system spawning asteroids, deleting them when they fall
 //! off.
+//! * `events.rs`: the `GameEvent` channel, to react to things happening.
+//! * `boarding.rs`: taking over derelict ships with boarding clamps.
+//! * `control.rs`: handing entities over between players and the computer.
+//! * `respawn.rs`: respawning players after their cockpit is destroyed.
+//! * `autopilot.rs`: flying ships to a point, or in formation.
+//! * `faction.rs`: factions, telling friends from foes.
+//! * `ai/`: computer-controlled pilots, flying by behavior trees.
+//! * `director.rs`: the director, sending pirate raids after the players.
+//! * `traffic.rs`: neutral traders crossing the map.
+//! * `drones.rs`: companion drones, launched from drone bays.
+//! * `boss.rs`: the dreadnought, a boss the pirates send late in the game.
+//! * `joints.rs`: joints tying entities together, and their solver.
+//! * `salvage.rs`: salvage beams, deconstructing wrecks into cargo.
+//! * `tractor.rs`: tractor beams, grabbing loose pieces.
+//! * `economy.rs`: ore from mining and salvage, to pay for blocks.
+//! * `medium.rs`: zones like nebulae, where ships get slowed down.
+//! * `sensors.rs`: how far ships see, and how nebulae hide them.
+//! * `sector.rs`: the grid of sectors space is split into.
+//! * `station.rs`: space stations, where ships dock for repairs and fuel.
+//! * `planet.rs`: planets, with their gravity and surface.
+//! * `hud.rs`: state of the local ship for the HUD, and pilot feedback.
+//! * `stats.rs`: player statistics (shots, damage, kills) for the scoreboard.
+//! * `players.rs`: names of the players, for scoreboards and name tags.
+//! * `achievements.rs`: milestones players reach, for frontends to show.
+//! * `rules.rs`: match rules, ending and summarizing matches.
+//! * `modes.rs`: the `GameMode` trait and the modes the game is played in.
+//! * `survival.rs`: the survival game mode, with waves of enemies.
+//! * `teams.rs`: teams for team deathmatch, with their scores.
+//! * `capture.rs`: control points that teams capture to score.
+//! * `profiles.rs`: player progression, kept by servers across sessions.
+//! * `chat.rs`: chat between players, relayed by the server.
+//! * `save.rs`: saving and loading standalone games to files.
+//! * `webhook.rs`: posts game events to a URL (`webhook` feature).
+//! * `master.rs`: server listings on a master server (`master` feature).
+//! * `metrics.rs`: server metrics, for Prometheus (`metrics` feature).
+//! * `math.rs`: trigonometry for the simulation, optionally deterministic.
+//!
+//! # Determinism
+//!
+//! With the `deterministic` feature, two machines running the same inputs
+//! compute the exact same world. This relies on `math.rs` for trigonometry,
+//! on the `GameRng` resource being the only source of randomness for the
+//! authoritative simulation, and on systems being run sequentially (specs is
+//! built without its `parallel` feature) so that entities are created, and
+//! then joined, in a stable order. Particles, which only graphical machines
+//! have, draw from their own `EffectRng` instead. `GameBuilder::rng_seed()`
+//! picks the seed, to reproduce a game.
 
+pub mod achievements;
+pub mod ai;
 pub mod asteroid;
+pub mod autopilot;
 pub mod blocks;
+pub mod boarding;
+pub mod boss;
+pub mod capture;
+#[cfg(feature = "network")]
+pub mod chat;
+pub mod control;
+pub mod director;
+pub mod drones;
+pub mod economy;
+pub mod events;
+pub mod faction;
 pub mod guns;
+pub mod hud;
 pub mod input;
+pub mod joints;
+#[cfg(feature = "master")]
+pub mod master;
+pub mod math;
+pub mod medium;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod modes;
 #[cfg(feature = "network")]
 pub mod net;
 pub mod particles;
 pub mod physics;
+pub mod planet;
+pub mod players;
+#[cfg(feature = "network")]
+pub mod profiles;
+pub mod respawn;
+pub mod rules;
+pub mod salvage;
+pub mod save;
+pub mod sector;
+pub mod sensors;
 mod sat;
 pub mod ship;
+pub mod station;
+pub mod stats;
+pub mod survival;
+pub mod teams;
 mod tree;
+pub mod tractor;
+pub mod traffic;
 pub mod utils;
+#[cfg(feature = "webhook")]
+pub mod webhook;
 
+use achievements::{Achievements, SysAchievements};
+use ai::{AiPilot, SysAi, Wing};
 use asteroid::{Asteroid, SysAsteroid};
+use autopilot::{Autopilot, SysAutopilot};
 use blocks::Blocky;
-use guns::{Projectile, SysProjectile};
-use input::Input;
-use log::info;
-use particles::{Effect, Particle, SysParticles};
-use physics::{DeltaTime, DetectCollision, Hits, LocalControl, Position,
-              SysCollision, SysSimu, Velocity};
-use ship::{Ship, SysShip};
+use boarding::{Boarding, SysBoarding};
+use capture::CaptureZone;
+use drones::{Drone, SysDrones};
+use economy::{OrePickup, SysEconomy, Wallet};
+use events::GameEvents;
+use faction::Faction;
+use guns::{Beam, Projectile, SysBeam, SysProjectile};
+use hud::{FeedbackEvents, HudState, LocalPlayer, SysHud};
+use input::Inputs;
+use joints::{Joint, SysJoints};
+use log::{info, warn};
+use medium::MediumZone;
+use modes::{GameMode, Mode};
+use particles::{Effect, EffectRng, Particle, SysParticles};
+use physics::{CircleCollider, CollisionEvents, DeltaTime, DetectCollision,
+              delete_entity, Hits, Lifetime, LocalControl, PhysicsConfig,
+              Position, SysCollision, SysLifetime, SysSimu, Velocity};
+use planet::{Planet, SysGravity};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use respawn::{PlayerState, SysRespawn};
+use rules::{end_match, LastMatch, MatchState, Rules, SysRules};
+use salvage::{Cargo, Salvaging, SysSalvage};
+use sector::{SectorId, SectorManager, SysSector};
+use ship::{PlayerClasses, Ship, ShipClass, ShipIntegrity, SysShip};
 use specs::{Dispatcher, DispatcherBuilder, Entity, Join, World, WorldExt};
+use station::{InSafeZone, Station, SysStation};
+use stats::{Scoreboard, SysStats};
+use teams::Team;
 use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tractor::{SysTractor, Tractor};
+use traffic::{SysTraffic, Trader};
 
 /// This describes the role of the local machine in the game.
 ///
@@ -47,6 +165,8 @@ pub enum Role {
     Standalone,
     Server,
     Client,
+    /// A server with local players, see `GameBuilder::host()`.
+    Host,
 }
 
 impl Role {
@@ -59,6 +179,7 @@ impl Role {
             Role::Standalone => true,
             Role::Server => true,
             Role::Client => false,
+            Role::Host => true,
         }
     }
 
@@ -71,6 +192,7 @@ impl Role {
             Role::Standalone => true,
             Role::Server => false,
             Role::Client => true,
+            Role::Host => true,
         }
     }
 
@@ -82,6 +204,7 @@ impl Role {
             Role::Standalone => false,
             Role::Server => true,
             Role::Client => true,
+            Role::Host => true,
         }
     }
 }
@@ -132,107 +255,698 @@ impl Deref for Clock {
     }
 }
 
+/// Seed used for `GameRng` in deterministic mode.
+#[cfg(feature = "deterministic")]
+const DETERMINISTIC_SEED: u64 = 1;
+
+/// Random number generator for the simulation, available as a resource.
+///
+/// Systems that affect the game state should draw their random numbers from
+/// this rather than `rand::thread_rng()`. It can be seeded through
+/// `GameBuilder::rng_seed()`, to reproduce a game. Otherwise, with the
+/// `deterministic` feature, it is seeded with a constant so that every run is
+/// the same; without it, it is seeded from the operating system.
+pub struct GameRng(StdRng);
+
+impl GameRng {
+    pub fn new(seed: u64) -> GameRng {
+        GameRng(StdRng::seed_from_u64(seed))
+    }
+}
+
+impl Default for GameRng {
+    #[cfg(feature = "deterministic")]
+    fn default() -> GameRng {
+        GameRng::new(DETERMINISTIC_SEED)
+    }
+
+    #[cfg(not(feature = "deterministic"))]
+    fn default() -> GameRng {
+        GameRng(StdRng::from_entropy())
+    }
+}
+
+impl RngCore for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.0.try_fill_bytes(dest)
+    }
+}
+
+/// A callback run by `Game::update()`, see `Game::add_pre_step()`.
+pub type StepHook = Box<dyn FnMut(&mut World)>;
+
 /// The game structure, containing globals not specific to frontend.
 pub struct Game {
     pub world: World,
     pub dispatcher: Dispatcher<'static, 'static>,
+    pre_step: Vec<StepHook>,
+    post_step: Vec<StepHook>,
 }
 
-impl Game {
-    fn new_common<'a, 'b>(role: Role) -> (World, DispatcherBuilder<'a, 'b>) {
+/// Builder for `Game`, allowing to tune it before it's created.
+///
+/// `Game::new_standalone()` and friends use the default settings.
+#[derive(Default)]
+pub struct GameBuilder {
+    physics: PhysicsConfig,
+    rules: Rules,
+    mode: Option<Mode>,
+    ship_class: Option<ShipClass>,
+    local_players: Option<usize>,
+    world_seed: Option<u64>,
+    rng_seed: Option<u64>,
+    sector_dir: Option<PathBuf>,
+    #[cfg(feature = "network")]
+    profiles: Option<Box<dyn profiles::ProfileStore>>,
+    #[cfg(feature = "network")]
+    profile_key: Option<u64>,
+    #[cfg(feature = "network")]
+    session_token: Option<u64>,
+    #[cfg(feature = "network")]
+    player_name: Option<String>,
+    #[cfg(feature = "network")]
+    client_bandwidth: Option<u32>,
+    #[cfg(feature = "network")]
+    max_clients: Option<usize>,
+    #[cfg(feature = "network")]
+    ban_list: Option<PathBuf>,
+    #[cfg(feature = "network")]
+    capture: Option<PathBuf>,
+    #[cfg(feature = "webhook")]
+    webhook: Option<String>,
+    #[cfg(feature = "master")]
+    master_server: Option<(String, String, String)>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<String>,
+}
+
+impl GameBuilder {
+    pub fn new() -> GameBuilder {
+        Default::default()
+    }
+
+    /// Sets the physics constants.
+    pub fn physics(mut self, physics: PhysicsConfig) -> GameBuilder {
+        self.physics = physics;
+        self
+    }
+
+    /// Sets the match rules.
+    pub fn rules(mut self, rules: Rules) -> GameBuilder {
+        self.rules = rules;
+        self
+    }
+
+    /// Sets the game mode, `modes::Skirmish` by default.
+    pub fn mode<M: GameMode + 'static>(mut self, mode: M) -> GameBuilder {
+        self.mode = Some(Mode(Arc::new(mode)));
+        self
+    }
+
+    /// Sets the class of the local player's ship, when running standalone,
+    /// as a client or as a host.
+    pub fn ship_class(mut self, class: ShipClass) -> GameBuilder {
+        self.ship_class = Some(class);
+        self
+    }
+
+    /// Sets the number of players sharing the screen, when running
+    /// standalone or as a host. Each gets a ship, controlled by its own
+    /// `Input`.
+    pub fn local_players(mut self, players: usize) -> GameBuilder {
+        self.local_players = Some(players);
+        self
+    }
+
+    /// Sets the seed the sectors are generated from, see `sector.rs`. By
+    /// default, it is drawn from the `GameRng`.
+    pub fn world_seed(mut self, seed: u64) -> GameBuilder {
+        self.world_seed = Some(seed);
+        self
+    }
+
+    /// Sets the seed of the `GameRng`, so that the same inputs give the same
+    /// game.
+    pub fn rng_seed(mut self, seed: u64) -> GameBuilder {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// Streams out the sectors no player is close to, to files in the
+    /// given directory, see `sector.rs`.
+    pub fn sector_dir<P: Into<PathBuf>>(mut self, dir: P) -> GameBuilder {
+        self.sector_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets where the server keeps player profiles, see `profiles.rs`.
+    ///
+    /// Without a store, profiles only last as long as the server.
+    #[cfg(feature = "network")]
+    pub fn profiles<S>(mut self, store: S) -> GameBuilder
+    where
+        S: profiles::ProfileStore + 'static,
+    {
+        self.profiles = Some(Box::new(store));
+        self
+    }
+
+    /// Sets the key of the local player's profile, when running as a
+    /// client. This should be kept secret, and the same across sessions.
+    #[cfg(feature = "network")]
+    pub fn profile_key(mut self, key: u64) -> GameBuilder {
+        self.profile_key = Some(key);
+        self
+    }
+
+    /// Limits the bytes per second sent to each client, when running as a
+    /// server. Less important updates are put off to stay under it.
+    #[cfg(feature = "network")]
+    pub fn client_bandwidth(mut self, bytes: u32) -> GameBuilder {
+        self.client_bandwidth = Some(bytes);
+        self
+    }
+
+    /// Limits the number of clients, when running as a server. Clients
+    /// trying to join past it are told the server is full.
+    #[cfg(feature = "network")]
+    pub fn max_clients(mut self, max: usize) -> GameBuilder {
+        self.max_clients = Some(max);
+        self
+    }
+
+    /// Sets the file the server keeps its bans in, see `net::BanList`.
+    ///
+    /// Without it, bans only last as long as the server.
+    #[cfg(feature = "network")]
+    pub fn ban_list<P: Into<PathBuf>>(mut self, path: P) -> GameBuilder {
+        self.ban_list = Some(path.into());
+        self
+    }
+
+    /// Records the traffic of the server or client to a file, that
+    /// `net::capture` can read back and play back against another game.
+    #[cfg(feature = "network")]
+    pub fn capture<P: Into<PathBuf>>(mut self, path: P) -> GameBuilder {
+        self.capture = Some(path.into());
+        self
+    }
+
+    /// Sets the name of the local player, when running as a client or as a
+    /// host.
+    #[cfg(feature = "network")]
+    pub fn player_name(mut self, name: String) -> GameBuilder {
+        self.player_name = Some(name);
+        self
+    }
+
+    /// Resumes a session when running as a client, with the
+    /// `net::SessionToken` of a connection that dropped.
+    #[cfg(feature = "network")]
+    pub fn session_token(mut self, token: u64) -> GameBuilder {
+        self.session_token = Some(token);
+        self
+    }
+
+    /// Posts game events to this URL, when running as a server.
+    #[cfg(feature = "webhook")]
+    pub fn webhook(mut self, url: String) -> GameBuilder {
+        self.webhook = Some(url);
+        self
+    }
+
+    /// Registers with a master server at this URL, when running as a
+    /// server, see `master.rs`. `address` is where clients can reach the
+    /// server, as `host:port`.
+    #[cfg(feature = "master")]
+    pub fn master_server(
+        mut self,
+        url: String,
+        name: String,
+        address: String,
+    ) -> GameBuilder {
+        self.master_server = Some((url, name, address));
+        self
+    }
+
+    /// Serves metrics over HTTP on this address, when running as a server,
+    /// see `metrics.rs`.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, address: String) -> GameBuilder {
+        self.metrics = Some(address);
+        self
+    }
+
+    fn build_common<'a, 'b>(
+        self,
+        role: Role,
+        local_players: usize,
+    ) -> (World, DispatcherBuilder<'a, 'b>) {
+        let mode = self.mode.unwrap_or_default();
         let mut world = World::new();
         world.register::<Position>();
         world.register::<Velocity>();
+        world.register::<Lifetime>();
         world.register::<Blocky>();
         world.register::<DetectCollision>();
+        world.register::<CircleCollider>();
         world.register::<Hits>();
         world.register::<LocalControl>();
         world.register::<Ship>();
+        world.register::<Autopilot>();
+        world.register::<AiPilot>();
+        world.register::<Wing>();
+        world.register::<Trader>();
+        world.register::<Drone>();
+        world.register::<ShipIntegrity>();
+        world.register::<Faction>();
+        world.register::<Team>();
         world.register::<Projectile>();
+        world.register::<Beam>();
         world.register::<Asteroid>();
         world.register::<Particle>();
         world.register::<Effect>();
+        world.register::<Boarding>();
+        world.register::<Joint>();
+        world.register::<Tractor>();
+        world.register::<Cargo>();
+        world.register::<Salvaging>();
+        world.register::<MediumZone>();
+        world.register::<CaptureZone>();
+        world.register::<OrePickup>();
+        world.register::<SectorId>();
+        world.register::<Station>();
+        world.register::<InSafeZone>();
+        world.register::<Planet>();
         #[cfg(feature = "network")]
         {
             world.register::<net::Replicated>();
             world.register::<net::Dirty>();
             world.register::<net::Delete>();
             world.register::<net::ClientControlled>();
+            world.register::<net::Interpolated>();
+            world.insert(<net::NetworkStats as Default>::default());
+            world.insert(net::ConnectionState::Connecting);
+            world.insert(net::SessionToken::default());
+            world.insert(<net::ServerClock as Default>::default());
+            world.insert(<chat::ChatLog as Default>::default());
+            world.insert(<net::Moderation as Default>::default());
+            world.insert(<profiles::Profiles as Default>::default());
+            world.insert(<profiles::LocalProfile as Default>::default());
         }
 
         world.insert(DeltaTime(0.02));
+        world.insert(self.physics);
+        world.insert(MatchState::Warmup(self.rules.warmup));
+        world.insert(self.rules);
+        world.insert(mode.clone());
         world.insert(<Clock as Default>::default());
-        world.insert(<Input as Default>::default());
+        let mut rng = match self.rng_seed {
+            Some(seed) => GameRng::new(seed),
+            None => <GameRng as Default>::default(),
+        };
+        let seed = self.world_seed.unwrap_or_else(|| rng.next_u64());
+        world.insert(EffectRng::new(rng.next_u64()));
+        world.insert(rng);
+        world.insert(<GameEvents as Default>::default());
+        world.insert(<CollisionEvents as Default>::default());
+        world.insert(<Scoreboard as Default>::default());
+        world.insert(<players::Players as Default>::default());
+        world.insert(<Achievements as Default>::default());
+        world.insert(<Wallet as Default>::default());
+        let mut sectors = SectorManager::new(seed);
+        sectors.dir = self.sector_dir;
+        world.insert(sectors);
+        world.insert(<LastMatch as Default>::default());
+        world.insert(<HudState as Default>::default());
+        world.insert(<LocalPlayer as Default>::default());
+        world.insert(<PlayerClasses as Default>::default());
+        world.insert(PlayerState::Waiting);
+        world.insert(<FeedbackEvents as Default>::default());
+        world.insert(Inputs::new(local_players));
         world.insert(role);
 
+        if role.authoritative() {
+            world.write_resource::<SectorManager>().generate(
+                SectorId::default(),
+                &world.entities(),
+                &world.system_data(),
+            );
+            mode.0.setup(&mut world);
+        }
+
         let dispatcher = if role.authoritative() {
-            DispatcherBuilder::new()
+            let dispatcher = DispatcherBuilder::new()
                 .with(SysSimu, "simu", &[])
+                .with(SysLifetime, "lifetime", &[])
                 .with(SysProjectile, "projectile", &[])
                 .with(SysAsteroid, "asteroid", &[])
-                .with(SysShip, "ship", &[])
+                .with(SysGravity, "gravity", &["simu"]);
+            mode.0
+                .systems(&world, dispatcher)
+                .with(SysTraffic::default(), "traffic", &[])
+                .with(SysDrones, "drones", &[])
+                .with(SysAi, "ai", &["drones"])
+                .with(SysAutopilot, "autopilot", &["ai"])
+                .with(SysStation::default(), "station", &["autopilot"])
+                .with(SysShip, "ship", &["station"])
+                .with(SysHud, "hud", &["ship"])
+                .with(SysBoarding, "boarding", &[])
+                .with(SysRespawn::default(), "respawn", &["boarding"])
+                .with(SysTractor, "tractor", &["ship"])
+                .with(SysJoints, "joints", &["tractor"])
                 .with(SysParticles, "particles", &[])
                 .with(
                     SysCollision,
                     "collision",
-                    &["projectile", "asteroid", "ship"],
+                    &["projectile", "asteroid", "ship", "boarding"],
+                )
+                .with(SysSalvage, "salvage", &["collision"])
+                .with(SysEconomy::default(), "economy", &["salvage"])
+                .with(SysBeam, "beam", &["collision"])
+                .with(SysSector, "sector", &["collision"])
+                .with(SysStats::new(&world), "stats", &["salvage"])
+                .with(
+                    SysAchievements::new(&world),
+                    "achievements",
+                    &["stats"],
                 )
+                .with(SysRules::new(&world), "rules", &["stats"])
         } else {
             DispatcherBuilder::new()
                 .with(SysSimu, "simu", &[])
+                .with(SysSector, "sector", &["simu"])
                 .with(SysShip, "ship", &[])
+                .with(SysHud, "hud", &["ship"])
+                .with(SysRespawn::default(), "respawn", &["ship"])
                 .with(SysParticles, "particles", &[])
         };
 
         (world, dispatcher)
     }
 
-    pub fn new_standalone() -> Game {
-        let (world, dispatcher) = Self::new_common(Role::Standalone);
+    /// Gives a ship to each of the local players.
+    fn create_local_players(
+        world: &mut World,
+        class: ShipClass,
+        players: usize,
+    ) {
+        for index in 0..players {
+            world
+                .write_resource::<PlayerClasses>()
+                .0
+                .insert(index as u64, class);
+            let ship = Ship::create(
+                &world.entities(),
+                &world.system_data(),
+                class,
+            );
+            world
+                .write_component::<LocalControl>()
+                .insert(ship, LocalControl(index)).unwrap();
+            let mode = world.read_resource::<Mode>().clone();
+            mode.0.player_joined(world, index as u64, ship);
+        }
+        // Create the ships now, or SysRespawn would take them for wrecks
+        world.maintain();
+    }
 
-        let ship = Ship::create(
-            &world.entities(),
-            &world.system_data(),
-        );
-        world
-            .write_component::<LocalControl>()
-            .insert(ship, LocalControl).unwrap();
+    /// Creates a standalone game, with locally-controlled ships.
+    pub fn standalone(self) -> Game {
+        let class = self.ship_class.unwrap_or(ShipClass::Fighter);
+        let players = self.local_players.unwrap_or(1);
+        let (mut world, dispatcher) =
+            self.build_common(Role::Standalone, players);
+        GameBuilder::create_local_players(&mut world, class, players);
 
         Game {
             world: world,
             dispatcher: dispatcher.build(),
+            pre_step: Vec::new(),
+            post_step: Vec::new(),
         }
     }
 
     #[cfg(feature = "network")]
-    pub fn new_server<S: net::Server>(server: S) -> Game {
-        let (world, mut dispatcher) = Self::new_common(Role::Server);
+    /// Creates a game server, that clients can connect to.
+    pub fn server<S: net::Server>(self, server: S) -> Game {
+        self.build_server(server, Role::Server, 0)
+    }
 
-        dispatcher = dispatcher.with(
-            net::SysNetServer::new(server),
-            "netserver",
-            &[],
-        );
+    #[cfg(feature = "network")]
+    /// Creates a game server with locally-controlled ships, so players can
+    /// host a game and play it without a separate server.
+    pub fn host<S: net::Server>(self, server: S) -> Game {
+        let players = self.local_players.unwrap_or(1);
+        self.build_server(server, Role::Host, players)
+    }
 
-        Game {
+    #[cfg(feature = "network")]
+    fn build_server<S: net::Server>(
+        mut self,
+        server: S,
+        role: Role,
+        local_players: usize,
+    ) -> Game {
+        if let Some(file) = self.capture_file() {
+            let server = net::capture::CaptureServer::new(server, file);
+            return self.build_server_on(server, role, local_players);
+        }
+        self.build_server_on(server, role, local_players)
+    }
+
+    #[cfg(feature = "network")]
+    fn build_server_on<S: net::Server>(
+        mut self,
+        server: S,
+        role: Role,
+        local_players: usize,
+    ) -> Game {
+        let class = self.ship_class.unwrap_or(ShipClass::Fighter);
+        let name = self.player_name.take();
+        #[cfg(feature = "webhook")]
+        let webhook = self.webhook.clone();
+        #[cfg(feature = "master")]
+        let master_server = self.master_server.take();
+        #[cfg(feature = "metrics")]
+        let metrics = self.metrics.take();
+        let store = self.profiles.take();
+        let bandwidth = self.client_bandwidth;
+        let max_clients = self.max_clients;
+        let ban_list = self.ban_list.take();
+        let (mut world, mut dispatcher) =
+            self.build_common(role, local_players);
+
+        GameBuilder::create_local_players(&mut world, class, local_players);
+        let name = name.as_ref().and_then(|n| players::sanitize_name(n));
+        if let Some(name) = name {
+            world
+                .write_resource::<players::Players>()
+                .players
+                .insert(0, players::PlayerInfo { name });
+        }
+
+        if let Some(store) = store {
+            world.insert(profiles::Profiles::new(store));
+        }
+        if let Some(path) = ban_list {
+            match net::BanList::load(&path) {
+                Ok(bans) => world.insert(net::Moderation::new(bans)),
+                Err(e) => {
+                    warn!("Can't load ban list {}: {}", path.display(), e)
+                }
+            }
+        }
+        dispatcher = dispatcher
+            .with(profiles::SysProfiles::new(&world), "profiles", &[])
+            .with(
+                net::SysNetServer::new(server, bandwidth, max_clients)
+                    .local_players(local_players),
+                "netserver",
+                &["profiles"],
+            );
+        #[cfg(feature = "webhook")]
+        {
+            if let Some(url) = webhook {
+                dispatcher = dispatcher.with(
+                    webhook::SysWebhook::new(url, &world),
+                    "webhook",
+                    &["netserver"],
+                );
+            }
+        }
+        #[cfg(feature = "master")]
+        {
+            if let Some((url, name, address)) = master_server {
+                dispatcher = dispatcher.with(
+                    master::SysMasterServer::new(
+                        url,
+                        name,
+                        address,
+                        max_clients,
+                    ),
+                    "master",
+                    &["netserver"],
+                );
+            }
+        }
+        #[cfg(feature = "metrics")]
+        let timed = match metrics {
+            Some(address) => match metrics::SysMetrics::new(&address) {
+                Ok(sys) => {
+                    dispatcher =
+                        dispatcher.with(sys, "metrics", &["netserver"]);
+                    true
+                }
+                Err(e) => {
+                    warn!("Can't serve metrics on {}: {}", address, e);
+                    false
+                }
+            },
+            None => false,
+        };
+
+        let game = Game {
             world: world,
             dispatcher: dispatcher.build(),
+            pre_step: Vec::new(),
+            post_step: Vec::new(),
+        };
+        #[cfg(feature = "metrics")]
+        let game = {
+            let mut game = game;
+            if timed {
+                metrics::time_steps(&mut game);
+            }
+            game
+        };
+        game
+    }
+
+    #[cfg(feature = "network")]
+    /// Creates a game client, connected to a server.
+    pub fn client<C: net::Client>(mut self, client: C) -> Game {
+        if let Some(file) = self.capture_file() {
+            let client = net::capture::CaptureClient::new(client, file);
+            return self.build_client(client);
         }
+        self.build_client(client)
     }
 
+    /// Creates the file to record the traffic to, if asked to.
     #[cfg(feature = "network")]
-    pub fn new_client<C: net::Client>(client: C) -> Game {
-        let (world, mut dispatcher) = Self::new_common(Role::Client);
+    fn capture_file(&mut self) -> Option<fs::File> {
+        let path = self.capture.take()?;
+        match fs::File::create(&path) {
+            Ok(file) => {
+                info!("Capturing network traffic to {}", path.display());
+                Some(file)
+            }
+            Err(e) => {
+                warn!("Can't capture to {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    #[cfg(feature = "network")]
+    fn build_client<C: net::Client>(self, client: C) -> Game {
+        let class = self.ship_class.unwrap_or(ShipClass::Fighter);
+        let key = self.profile_key.unwrap_or(0);
+        let token = self.session_token.unwrap_or(0);
+        let name = self.player_name.clone().unwrap_or_default();
+        let (world, mut dispatcher) = self.build_common(Role::Client, 1);
 
-        dispatcher = dispatcher.with(
-            net::SysNetClient::new(client),
-            "netclient",
-            &[],
-        );
+        dispatcher = dispatcher
+            .with(
+                net::SysNetClient::new(client, class, key, token, &name),
+                "netclient",
+                &[],
+            )
+            .with(
+                net::SysInterpolation::default(),
+                "interpolation",
+                &["netclient"],
+            );
 
         Game {
             world: world,
             dispatcher: dispatcher.build(),
+            pre_step: Vec::new(),
+            post_step: Vec::new(),
+        }
+    }
+
+}
+
+impl Game {
+    pub fn new_standalone() -> Game {
+        GameBuilder::new().standalone()
+    }
+
+    #[cfg(feature = "network")]
+    pub fn new_server<S: net::Server>(server: S) -> Game {
+        GameBuilder::new().server(server)
+    }
+
+    #[cfg(feature = "network")]
+    pub fn new_client<C: net::Client>(client: C) -> Game {
+        GameBuilder::new().client(client)
+    }
+
+    #[cfg(feature = "network")]
+    pub fn new_host<S: net::Server>(server: S) -> Game {
+        GameBuilder::new().host(server)
+    }
+
+    /// Registers a callback to run at each step, before the systems.
+    ///
+    /// This is an extension point for frontends, for example to sample the
+    /// state of the world at precise points of the fixed step. The callbacks
+    /// are not part of the simulation: they should not change the game
+    /// state, which servers and other clients won't know about.
+    pub fn add_pre_step<F>(&mut self, hook: F)
+    where
+        F: FnMut(&mut World) + 'static,
+    {
+        self.pre_step.push(Box::new(hook));
+    }
+
+    /// Registers a callback to run at each step, after the systems.
+    ///
+    /// See `add_pre_step()`.
+    pub fn add_post_step<F>(&mut self, hook: F)
+    where
+        F: FnMut(&mut World) + 'static,
+    {
+        self.post_step.push(Box::new(hook));
+    }
+
+    #[cfg(feature = "network")]
+    /// Leaves the server, on clients.
+    ///
+    /// This happens on the next update, after which `net::ConnectionState`
+    /// is `Disconnected`. Nothing happens if the connection is already over.
+    pub fn disconnect(&mut self) {
+        let mut state = self.world.write_resource::<net::ConnectionState>();
+        match *state {
+            net::ConnectionState::Connecting
+            | net::ConnectionState::Connected => {
+                *state = net::ConnectionState::Leaving
+            }
+            _ => {}
         }
     }
 
@@ -244,11 +958,125 @@ impl Game {
             let mut r_clock = self.world.write_resource::<Clock>();
             r_clock.advance_frame(dt);
         }
+        for hook in &mut self.pre_step {
+            hook(&mut self.world);
+        }
         self.dispatcher.dispatch(&self.world);
         self.world.maintain();
+        self.check_match();
+        self.stream_sectors();
+        for hook in &mut self.post_step {
+            hook(&mut self.world);
+        }
 
-        let mut input = self.world.write_resource::<Input>();
-        input.update();
+        let mut inputs = self.world.write_resource::<Inputs>();
+        inputs.update();
+    }
+
+    /// Ends the match if the game mode says it is won.
+    fn check_match(&mut self) {
+        if !self.world.read_resource::<Role>().authoritative()
+            || !self.world.read_resource::<MatchState>().playing()
+        {
+            return;
+        }
+        let mode = self.world.read_resource::<Mode>().clone();
+        if mode.0.match_over(&self.world) {
+            end_match(
+                &mut self.world.write_resource::<Scoreboard>(),
+                &mut self.world.write_resource::<LastMatch>(),
+                &mut self.world.write_resource::<GameEvents>(),
+            );
+        }
+    }
+
+    /// Streams out the sectors players left, and loads back those they come
+    /// close to.
+    fn stream_sectors(&mut self) {
+        if !self.world.read_resource::<Role>().authoritative() {
+            return;
+        }
+        let (store, load) = {
+            let sectors = self.world.read_resource::<SectorManager>();
+            (sectors.to_store(), sectors.to_load())
+        };
+        for id in store {
+            if let Err(e) = self.store_sector(id) {
+                warn!("Error storing sector {},{}: {}", id.x, id.y, e);
+            }
+        }
+        for id in load {
+            if let Err(e) = self.load_sector(id) {
+                warn!("Error loading sector {},{}: {}", id.x, id.y, e);
+            }
+        }
+    }
+
+    /// Writes the entities of a sector to its file, and deletes them.
+    fn store_sector(&mut self, id: SectorId) -> io::Result<()> {
+        let path = self.world.read_resource::<SectorManager>().path(id);
+        let path = path.unwrap();
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        let in_sector = save::save_sector(&self.world, id, &mut out)?;
+        out.flush()?;
+
+        let role = *self.world.read_resource::<Role>();
+        for ent in in_sector {
+            delete_entity(
+                role,
+                &self.world.entities(),
+                &self.world.system_data(),
+                ent,
+            );
+        }
+        let mut sectors = self.world.write_resource::<SectorManager>();
+        sectors.sectors.get_mut(&id).unwrap().stored = true;
+        Ok(())
+    }
+
+    /// Loads back the entities of a sector from its file.
+    ///
+    /// The sector counts as loaded even if the file can't be read, so its
+    /// contents are lost then.
+    fn load_sector(&mut self, id: SectorId) -> io::Result<()> {
+        let path = {
+            let mut sectors = self.world.write_resource::<SectorManager>();
+            sectors.sectors.get_mut(&id).unwrap().stored = false;
+            sectors.path(id).unwrap()
+        };
+        let text = fs::read_to_string(&path)?;
+        save::load_sector(&mut self.world, &text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::remove_file(path)
+    }
+
+    /// Saves a standalone game to a file, see `save.rs`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.check_standalone()?;
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        save::save(&self.world, &mut out)?;
+        out.flush()
+    }
+
+    /// Replaces a standalone game with one saved to a file.
+    ///
+    /// The game is left as it was if the file can't be loaded.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.check_standalone()?;
+        let text = fs::read_to_string(path)?;
+        save::load(&mut self.world, &text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn check_standalone(&self) -> io::Result<()> {
+        if *self.world.read_resource::<Role>() == Role::Standalone {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only standalone games can be saved",
+            ))
+        }
     }
 
     /// Print out entity counts as `INFO`.
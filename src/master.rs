@@ -0,0 +1,134 @@
+//! Listing servers on a master server, for in-game server browsers.
+//!
+//! With `GameBuilder::master_server()`, the server registers itself every
+//! `REGISTER_INTERVAL`, posting its name, address, player count and mode as
+//! JSON to `<url>/servers`; the master server is expected to forget the
+//! servers that stop doing so. Clients get the list with `fetch_servers()`,
+//! a GET on the same URL. Registrations are sent from a background thread so
+//! they never stall the simulation, like the requests of `webhook.rs`.
+
+use log::warn;
+use serde::Deserialize;
+use specs::{Read, ReadExpect, System};
+use std::fmt::Display;
+use std::io;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::modes::Mode;
+use crate::net::PROTOCOL_VERSION;
+use crate::players::Players;
+
+/// Interval between registrations.
+const REGISTER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A server, as listed by the master server.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListedServer {
+    pub name: String,
+    /// Address to connect to, as `host:port`.
+    pub address: String,
+    pub players: u32,
+    pub max_players: Option<u32>,
+    pub mode: String,
+    pub version: u16,
+}
+
+fn servers_url(url: &str) -> String {
+    format!("{}/servers", url.trim_end_matches('/'))
+}
+
+fn other_error<E: Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Fetches the servers listed by a master server, leaving out those that
+/// don't speak our version of the protocol.
+///
+/// This blocks until the master server answers, frontends should call it
+/// from a thread.
+pub fn fetch_servers(url: &str) -> io::Result<Vec<ListedServer>> {
+    let body = ureq::get(&servers_url(url))
+        .call()
+        .map_err(other_error)?
+        .into_string()?;
+    let servers: Vec<ListedServer> =
+        serde_json::from_str(&body).map_err(other_error)?;
+    Ok(servers
+        .into_iter()
+        .filter(|s| s.version == PROTOCOL_VERSION)
+        .collect())
+}
+
+/// Background thread posting the registrations.
+fn register_thread(url: String, listings: Receiver<serde_json::Value>) {
+    for listing in listings {
+        if let Err(e) = ureq::post(&url)
+            .set("Content-Type", "application/json")
+            .send_string(&listing.to_string())
+        {
+            warn!("Error registering with master server: {}", e);
+        }
+    }
+}
+
+/// Master server system, registers the server periodically.
+pub struct SysMasterServer {
+    sender: SyncSender<serde_json::Value>,
+    name: String,
+    address: String,
+    max_players: Option<usize>,
+    last: Option<Instant>,
+}
+
+impl SysMasterServer {
+    /// Create the system, starting the thread that will post to `url`.
+    ///
+    /// `address` is where clients can reach the server, as `host:port`.
+    pub fn new(
+        url: String,
+        name: String,
+        address: String,
+        max_players: Option<usize>,
+    ) -> SysMasterServer {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let url = servers_url(&url);
+        thread::spawn(move || register_thread(url, receiver));
+        SysMasterServer {
+            sender,
+            name,
+            address,
+            max_players,
+            last: None,
+        }
+    }
+}
+
+impl<'a> System<'a> for SysMasterServer {
+    type SystemData = (Read<'a, Players>, ReadExpect<'a, Mode>);
+
+    fn run(&mut self, (players, mode): Self::SystemData) {
+        let now = Instant::now();
+        match self.last {
+            Some(last) if now.duration_since(last) < REGISTER_INTERVAL => {
+                return
+            }
+            _ => {}
+        }
+        self.last = Some(now);
+
+        let listing = serde_json::json!({
+            "name": self.name,
+            "address": self.address,
+            "players": players.players.len(),
+            "max_players": self.max_players,
+            "mode": mode.0.name(),
+            "version": PROTOCOL_VERSION,
+        });
+        // If the last one is still being posted, skip this one
+        if self.sender.try_send(listing).is_err() {
+            warn!("Master server is slow, skipping registration");
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Math functions used by the simulation.
+//!
+//! The results of `f32::sin_cos()` and `f32::atan2()` depend on the
+//! platform's math library, which means two machines can disagree on the
+//! outcome of a simulation step. With the `deterministic` feature, software
+//! implementations built only from basic IEEE operations are used instead, so
+//! the simulation is bit-identical everywhere.
+//!
+//! Code that affects the game state should use these functions rather than
+//! the methods on `f32`. Purely cosmetic code (particles, rendering) doesn't
+//! need to.
+
+/// Computes the sine and cosine of an angle, in radians.
+#[cfg(feature = "deterministic")]
+pub fn sin_cos(a: f32) -> (f32, f32) {
+    soft::sin_cos(a)
+}
+
+/// Computes the sine and cosine of an angle, in radians.
+#[cfg(not(feature = "deterministic"))]
+pub fn sin_cos(a: f32) -> (f32, f32) {
+    a.sin_cos()
+}
+
+/// Computes the four-quadrant arctangent of `y` and `x`.
+#[cfg(feature = "deterministic")]
+pub fn atan2(y: f32, x: f32) -> f32 {
+    soft::atan2(y, x)
+}
+
+/// Computes the four-quadrant arctangent of `y` and `x`.
+#[cfg(not(feature = "deterministic"))]
+pub fn atan2(y: f32, x: f32) -> f32 {
+    y.atan2(x)
+}
+
+/// Software implementations, using only operations that are exactly
+/// specified by IEEE 754 (add, sub, mul, div, round).
+#[cfg(any(feature = "deterministic", test))]
+mod soft {
+    use std::f64::consts::{FRAC_2_PI, FRAC_PI_2, FRAC_PI_4, PI};
+
+    const TAN_PI_8: f64 = 0.414_213_562_373_095_03;
+
+    pub fn sin_cos(a: f32) -> (f32, f32) {
+        // Reduce to [-pi/4, pi/4] and remember the quadrant
+        let x = a as f64;
+        let q = (x * FRAC_2_PI).round();
+        let r = x - q * FRAC_PI_2;
+        let r2 = r * r;
+
+        // Taylor series, accurate well beyond f32 precision on that range
+        let s = r
+            * (1.0
+                + r2 * (-1.0 / 6.0
+                    + r2 * (1.0 / 120.0
+                        + r2 * (-1.0 / 5040.0
+                            + r2 * (1.0 / 362_880.0
+                                + r2 * (-1.0 / 39_916_800.0))))));
+        let c = 1.0
+            + r2 * (-1.0 / 2.0
+                + r2 * (1.0 / 24.0
+                    + r2 * (-1.0 / 720.0
+                        + r2 * (1.0 / 40320.0
+                            + r2 * (-1.0 / 3_628_800.0
+                                + r2 * (1.0 / 479_001_600.0))))));
+
+        let (s, c) = match (q as i64).rem_euclid(4) {
+            0 => (s, c),
+            1 => (c, -s),
+            2 => (-s, -c),
+            _ => (-c, s),
+        };
+        (s as f32, c as f32)
+    }
+
+    /// Arctangent of a value in [0, 1].
+    fn atan_unit(t: f64) -> f64 {
+        // Reduce to [-tan(pi/8), tan(pi/8)] so the series converges quickly
+        let (base, u) = if t > TAN_PI_8 {
+            (FRAC_PI_4, (t - 1.0) / (t + 1.0))
+        } else {
+            (0.0, t)
+        };
+        let u2 = u * u;
+        let mut sum = 0.0;
+        for n in (0..13).rev() {
+            let coef = 1.0 / (2 * n + 1) as f64;
+            let coef = if n % 2 == 0 { coef } else { -coef };
+            sum = sum * u2 + coef;
+        }
+        base + u * sum
+    }
+
+    pub fn atan2(y: f32, x: f32) -> f32 {
+        let (y, x) = (y as f64, x as f64);
+        if x == 0.0 && y == 0.0 {
+            return 0.0;
+        }
+        let (ax, ay) = (x.abs(), y.abs());
+        let mut a = if ay <= ax {
+            atan_unit(ay / ax)
+        } else {
+            FRAC_PI_2 - atan_unit(ax / ay)
+        };
+        if x < 0.0 {
+            a = PI - a;
+        }
+        if y < 0.0 {
+            a = -a;
+        }
+        a as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::soft;
+
+    #[test]
+    fn test_sin_cos() {
+        let mut a = -20.0f32;
+        while a < 20.0 {
+            let (s, c) = soft::sin_cos(a);
+            assert!((s - a.sin()).abs() < 1e-6, "sin({})", a);
+            assert!((c - a.cos()).abs() < 1e-6, "cos({})", a);
+            a += 0.013;
+        }
+    }
+
+    #[test]
+    fn test_atan2() {
+        let mut a = -3.1f32;
+        while a < 3.1 {
+            for &r in &[0.01f32, 1.0, 250.0] {
+                let (y, x) = (r * a.sin(), r * a.cos());
+                let res = soft::atan2(y, x);
+                assert!((res - y.atan2(x)).abs() < 1e-6, "atan2({}, {})", y, x);
+            }
+            a += 0.007;
+        }
+        assert_eq!(soft::atan2(0.0, 0.0), 0.0);
+        assert!((soft::atan2(0.0, -1.0) - std::f32::consts::PI).abs() < 1e-6);
+    }
+}
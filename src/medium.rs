@@ -0,0 +1,59 @@
+//! Medium zones, areas such as nebulae where ships get slowed down.
+//!
+//! A `MediumZone` is an entity with a `Position` and a radius. Ships inside
+//! it get extra drag, added to the friction from `PhysicsConfig`. Nebulae
+//! are placed with the sectors, see `sector.rs`.
+
+use specs::{Component, Entities, Entity, LazyUpdate, Read, VecStorage};
+use vecmath::*;
+
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::Position;
+
+/// A zone of the world with a different medium.
+#[derive(Debug, Clone)]
+pub struct MediumZone {
+    pub radius: f32,
+    /// Drag added to `PhysicsConfig::friction` inside the zone. Only affects
+    /// linear motion.
+    pub drag: f32,
+}
+
+impl MediumZone {
+    pub fn create(
+        entities: &Entities,
+        lazy: &Read<LazyUpdate>,
+        pos: [f32; 2],
+        radius: f32,
+        drag: f32,
+    ) -> Entity {
+        let entity = entities.create();
+        lazy.insert(entity, Position { pos, rot: 0.0 });
+        lazy.insert(entity, MediumZone { radius, drag });
+        #[cfg(feature = "network")]
+        {
+            lazy.insert(entity, net::Replicated::new());
+            lazy.insert(entity, net::Dirty);
+        }
+        entity
+    }
+}
+
+impl Component for MediumZone {
+    type Storage = VecStorage<Self>;
+}
+
+/// Extra drag at a point, from the densest zone containing it.
+///
+/// `zones` lists the zones with the position of their center.
+pub fn drag_at(pos: [f32; 2], zones: &[([f32; 2], MediumZone)]) -> f32 {
+    zones
+        .iter()
+        .filter(|(center, zone)| {
+            let sq_dist = vec2_square_len(vec2_sub(pos, *center));
+            sq_dist <= zone.radius * zone.radius
+        })
+        .map(|(_, zone)| zone.drag)
+        .fold(0.0, f32::max)
+}
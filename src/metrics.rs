@@ -0,0 +1,337 @@
+//! Metrics of a server, served over HTTP for Prometheus.
+//!
+//! With `GameBuilder::metrics()`, the server listens on an address and
+//! answers `GET /metrics` with the Prometheus text format: how long steps
+//! take, how many entities and players there are, and the ping and traffic
+//! of each client. `SysMetrics` renders the text every `RENDER_INTERVAL`,
+//! and a background thread serves the last one, so scrapes never stall the
+//! simulation.
+
+use log::{info, warn};
+use specs::{Entities, Join, Read, ReadStorage, System, WorldExt};
+use std::fmt::Write as _;
+use std::io::{self, Read as _, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::asteroid::Asteroid;
+use crate::guns::Projectile;
+use crate::net::NetworkStats;
+use crate::players::Players;
+use crate::ship::Ship;
+use crate::station::Station;
+use crate::Game;
+
+/// Interval between two renderings of the metrics.
+const RENDER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time a scraper has to send its request and read the answer.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest request read, the rest is ignored.
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// Time the steps take, as a resource.
+///
+/// This is measured by hooks around `Game::update()`, see `time_steps()`.
+#[derive(Default)]
+pub struct StepTime {
+    started: Option<Instant>,
+    /// Duration of the last step.
+    pub last: Duration,
+    /// Total duration of the steps, and their number.
+    pub total: Duration,
+    pub count: u64,
+}
+
+impl StepTime {
+    /// Called before a step.
+    pub fn start(&mut self) {
+        self.started = Some(Instant::now());
+    }
+
+    /// Called after a step.
+    pub fn end(&mut self) {
+        if let Some(started) = self.started.take() {
+            self.last = started.elapsed();
+            self.total += self.last;
+            self.count += 1;
+        }
+    }
+}
+
+/// Measures the steps of a game into its `StepTime` resource.
+pub fn time_steps(game: &mut Game) {
+    game.world.insert(StepTime::default());
+    game.add_pre_step(|world| world.write_resource::<StepTime>().start());
+    game.add_post_step(|world| world.write_resource::<StepTime>().end());
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Writes the help and type lines of a metric.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP vigilant_steel_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE vigilant_steel_{} {}", name, kind).unwrap();
+}
+
+/// Answers a scraper.
+fn answer(mut stream: TcpStream, text: &Mutex<String>) -> io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buffer = [0; 512];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n")
+        && request.len() < MAX_REQUEST_LEN
+    {
+        let len = stream.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..len]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", text.lock().unwrap().clone())
+        }
+        _ => ("404 Not Found", "Not found\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Background thread answering the scrapers, one at a time.
+fn serve_thread(listener: TcpListener, text: Arc<Mutex<String>>) {
+    for stream in listener.incoming() {
+        let res = stream.and_then(|s| answer(s, &text));
+        if let Err(e) = res {
+            warn!("Error serving metrics: {}", e);
+        }
+    }
+}
+
+/// Metrics system, renders the metrics for the thread to serve.
+pub struct SysMetrics {
+    text: Arc<Mutex<String>>,
+    last: Option<Instant>,
+}
+
+impl SysMetrics {
+    /// Create the system, starting the thread that will listen on
+    /// `address`.
+    pub fn new(address: &str) -> io::Result<SysMetrics> {
+        let listener = TcpListener::bind(address)?;
+        info!("Serving metrics on {}", listener.local_addr()?);
+        let text = Arc::new(Mutex::new(String::new()));
+        {
+            let text = text.clone();
+            thread::spawn(move || serve_thread(listener, text));
+        }
+        Ok(SysMetrics { text, last: None })
+    }
+}
+
+impl<'a> System<'a> for SysMetrics {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, Asteroid>,
+        ReadStorage<'a, Projectile>,
+        ReadStorage<'a, Station>,
+        Read<'a, Players>,
+        Read<'a, NetworkStats>,
+        Read<'a, StepTime>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            ship,
+            asteroid,
+            projectile,
+            station,
+            players,
+            stats,
+            step_time,
+        ): Self::SystemData,
+    ) {
+        let now = Instant::now();
+        match self.last {
+            Some(last) if now.duration_since(last) < RENDER_INTERVAL => {
+                return
+            }
+            _ => {}
+        }
+        self.last = Some(now);
+
+        let mut out = String::new();
+        header(&mut out, "step_seconds", "summary", "Time steps take.");
+        writeln!(
+            out,
+            "vigilant_steel_step_seconds_sum {}\n\
+             vigilant_steel_step_seconds_count {}",
+            step_time.total.as_secs_f64(),
+            step_time.count
+        )
+        .unwrap();
+        header(
+            &mut out,
+            "last_step_seconds",
+            "gauge",
+            "Time the last step took.",
+        );
+        writeln!(
+            out,
+            "vigilant_steel_last_step_seconds {}",
+            step_time.last.as_secs_f64()
+        )
+        .unwrap();
+
+        header(&mut out, "entities", "gauge", "Entities in the world.");
+        writeln!(out, "vigilant_steel_entities {}", (&entities).join().count())
+            .unwrap();
+        header(&mut out, "kind_entities", "gauge", "Entities, by kind.");
+        let counts = [
+            ("ship", (&ship).join().count()),
+            ("asteroid", (&asteroid).join().count()),
+            ("projectile", (&projectile).join().count()),
+            ("station", (&station).join().count()),
+        ];
+        for &(kind, count) in &counts {
+            writeln!(
+                out,
+                "vigilant_steel_kind_entities{{kind=\"{}\"}} {}",
+                kind, count
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "replicated_entities",
+            "gauge",
+            "Entities sent to clients.",
+        );
+        writeln!(out, "vigilant_steel_replicated_entities {}", stats.entities)
+            .unwrap();
+        header(&mut out, "players", "gauge", "Players in the game.");
+        writeln!(out, "vigilant_steel_players {}", players.players.len())
+            .unwrap();
+        header(&mut out, "clients", "gauge", "Connected clients.");
+        writeln!(out, "vigilant_steel_clients {}", stats.clients.len())
+            .unwrap();
+
+        let counters = [
+            ("received_bytes_total", "Bytes received.", stats.bytes_received),
+            ("sent_bytes_total", "Bytes sent.", stats.bytes_sent),
+            (
+                "received_messages_total",
+                "Messages received.",
+                stats.messages_received,
+            ),
+            (
+                "invalid_messages_total",
+                "Messages dropped as invalid.",
+                stats.invalid_messages,
+            ),
+            (
+                "rate_limited_total",
+                "Packets and messages dropped by the rate limits.",
+                stats.rate_limited,
+            ),
+        ];
+        for &(name, help, value) in &counters {
+            header(&mut out, name, "counter", help);
+            writeln!(out, "vigilant_steel_{} {}", name, value).unwrap();
+        }
+
+        // Clients are labelled with their ID and their player's name
+        let labels = stats
+            .clients
+            .iter()
+            .map(|c| {
+                let name = players
+                    .players
+                    .get(&c.client_id)
+                    .map_or("", |p| &p.name);
+                format!("client=\"{}\",name=\"{}\"", c.client_id, escape(name))
+            })
+            .collect::<Vec<_>>();
+        header(
+            &mut out,
+            "client_rtt_seconds",
+            "gauge",
+            "Round-trip time to each client.",
+        );
+        for (client, labels) in stats.clients.iter().zip(&labels) {
+            writeln!(
+                out,
+                "vigilant_steel_client_rtt_seconds{{{}}} {}",
+                labels, client.rtt
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "client_packet_loss",
+            "gauge",
+            "Fraction of the last packets from each client that got lost.",
+        );
+        for (client, labels) in stats.clients.iter().zip(&labels) {
+            writeln!(
+                out,
+                "vigilant_steel_client_packet_loss{{{}}} {}",
+                labels, client.packet_loss
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "client_received_bytes_total",
+            "counter",
+            "Bytes received from each client.",
+        );
+        for (client, labels) in stats.clients.iter().zip(&labels) {
+            writeln!(
+                out,
+                "vigilant_steel_client_received_bytes_total{{{}}} {}",
+                labels, client.bytes_received
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "client_sent_bytes_total",
+            "counter",
+            "Bytes sent to each client.",
+        );
+        for (client, labels) in stats.clients.iter().zip(&labels) {
+            writeln!(
+                out,
+                "vigilant_steel_client_sent_bytes_total{{{}}} {}",
+                labels, client.bytes_sent
+            )
+            .unwrap();
+        }
+
+        *self.text.lock().unwrap() = out;
+    }
+}
@@ -0,0 +1,233 @@
+//! Game modes, what the players are up against and how matches are won.
+//!
+//! A `GameMode` is picked with `GameBuilder::mode()`, `Skirmish` by default.
+//! It only runs on authoritative machines: it sets up the world, adds the
+//! systems running its rules to the dispatcher, says when a match is won, and
+//! gets new players settled. Clients see all of it through replication.
+
+use specs::{DispatcherBuilder, Entity, Join, ReadStorage, World, WorldExt};
+use std::sync::Arc;
+
+use crate::boss::SysBoss;
+use crate::capture::{self, SysCapture};
+use crate::director::SysDirector;
+use crate::faction::Faction;
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{pilot, LocalControl, RemoteControl};
+use crate::ship::Ship;
+use crate::survival::{SysSurvival, WaveState};
+use crate::teams::{SysTeams, Team, Teams};
+
+/// A way to play the game.
+pub trait GameMode: Send + Sync {
+    /// Name of the mode, for server listings.
+    fn name(&self) -> &str;
+
+    /// Sets up the world when the game is created, for example inserting the
+    /// mode's resources.
+    fn setup(&self, _world: &mut World) {}
+
+    /// Adds the systems running the mode's rules, every tick.
+    fn systems<'a, 'b>(
+        &self,
+        _world: &World,
+        dispatcher: DispatcherBuilder<'a, 'b>,
+    ) -> DispatcherBuilder<'a, 'b> {
+        dispatcher
+    }
+
+    /// Whether the current match is won, checked after every tick.
+    ///
+    /// `Game` then ends the match, see `rules::end_match()`.
+    fn match_over(&self, _world: &World) -> bool {
+        false
+    }
+
+    /// Called when a player joins the game and gets their first ship.
+    fn player_joined(&self, _world: &World, _player: u64, _ship: Entity) {}
+}
+
+/// The game mode, available as a resource.
+#[derive(Clone)]
+pub struct Mode(pub Arc<dyn GameMode>);
+
+impl Default for Mode {
+    fn default() -> Mode {
+        Mode(Arc::new(Skirmish::default()))
+    }
+}
+
+/// Whether any player still has a ship.
+fn players_left(world: &World) -> bool {
+    let entities = world.entities();
+    let ship = world.read_storage::<Ship>();
+    let (local, remote) =
+        world.system_data::<(ReadStorage<LocalControl>, RemoteControl)>();
+    (&*entities, &ship)
+        .join()
+        .any(|(e, _)| pilot(e, &local, &remote).is_some())
+}
+
+/// Puts a new player's ship in the team with the fewest players.
+///
+/// This doesn't go through `teams::join()`: servers call `player_joined()`
+/// from `LazyUpdate::exec()`, which can't queue more lazy updates.
+fn join_team(world: &World, player: u64, ship: Entity) {
+    let team = world.write_resource::<Teams>().assign(player);
+    world.write_storage::<Team>().insert(ship, team).unwrap();
+    world
+        .write_storage::<Faction>()
+        .insert(ship, team.faction())
+        .unwrap();
+    #[cfg(feature = "network")]
+    world.write_storage::<net::Dirty>().insert(ship, net::Dirty).unwrap();
+}
+
+/// Asteroids, traders, and pirate raids, see `director.rs` and `boss.rs`.
+#[derive(Debug, Clone)]
+pub struct Skirmish {
+    /// Whether pirate raids and dreadnoughts are sent after the players.
+    pub pirates: bool,
+}
+
+impl Default for Skirmish {
+    fn default() -> Skirmish {
+        Skirmish { pirates: true }
+    }
+}
+
+impl GameMode for Skirmish {
+    fn name(&self) -> &str {
+        "skirmish"
+    }
+
+    fn systems<'a, 'b>(
+        &self,
+        _world: &World,
+        dispatcher: DispatcherBuilder<'a, 'b>,
+    ) -> DispatcherBuilder<'a, 'b> {
+        if self.pirates {
+            dispatcher
+                .with(SysDirector::default(), "director", &[])
+                .with(SysBoss::default(), "boss", &[])
+        } else {
+            dispatcher
+        }
+    }
+}
+
+/// Waves of enemies with build phases in between, see `survival.rs`.
+///
+/// The match is over when every player has lost their ship at once.
+#[derive(Debug, Clone, Default)]
+pub struct Survival;
+
+impl GameMode for Survival {
+    fn name(&self) -> &str {
+        "survival"
+    }
+
+    fn setup(&self, world: &mut World) {
+        world.insert(<WaveState as Default>::default());
+    }
+
+    fn systems<'a, 'b>(
+        &self,
+        world: &World,
+        dispatcher: DispatcherBuilder<'a, 'b>,
+    ) -> DispatcherBuilder<'a, 'b> {
+        dispatcher.with(SysSurvival::new(world), "survival", &[])
+    }
+
+    fn match_over(&self, world: &World) -> bool {
+        world.read_resource::<WaveState>().wave > 0 && !players_left(world)
+    }
+}
+
+/// Players split in teams fighting each other, see `teams.rs`.
+#[derive(Debug, Clone)]
+pub struct TeamDeathmatch {
+    /// Kills a team needs to win the match, `None` to play until the time
+    /// is up.
+    pub score_limit: Option<u32>,
+}
+
+impl Default for TeamDeathmatch {
+    fn default() -> TeamDeathmatch {
+        TeamDeathmatch {
+            score_limit: Some(25),
+        }
+    }
+}
+
+impl GameMode for TeamDeathmatch {
+    fn name(&self) -> &str {
+        "team deathmatch"
+    }
+
+    fn setup(&self, world: &mut World) {
+        world.insert(<Teams as Default>::default());
+    }
+
+    fn systems<'a, 'b>(
+        &self,
+        world: &World,
+        dispatcher: DispatcherBuilder<'a, 'b>,
+    ) -> DispatcherBuilder<'a, 'b> {
+        dispatcher.with(SysTeams::new(world, true), "teams", &[])
+    }
+
+    fn match_over(&self, world: &World) -> bool {
+        world.read_resource::<Teams>().reached(self.score_limit)
+    }
+
+    fn player_joined(&self, world: &World, player: u64, ship: Entity) {
+        join_team(world, player, ship);
+    }
+}
+
+/// Teams fighting over control points, see `capture.rs`.
+#[derive(Debug, Clone)]
+pub struct ControlPoints {
+    /// Points a team needs to win the match, `None` to play until the time
+    /// is up.
+    pub score_limit: Option<u32>,
+}
+
+impl Default for ControlPoints {
+    fn default() -> ControlPoints {
+        ControlPoints {
+            score_limit: Some(400),
+        }
+    }
+}
+
+impl GameMode for ControlPoints {
+    fn name(&self) -> &str {
+        "control points"
+    }
+
+    fn setup(&self, world: &mut World) {
+        world.insert(<Teams as Default>::default());
+        capture::spawn_zones(&world.entities(), &world.system_data());
+    }
+
+    fn systems<'a, 'b>(
+        &self,
+        world: &World,
+        dispatcher: DispatcherBuilder<'a, 'b>,
+    ) -> DispatcherBuilder<'a, 'b> {
+        dispatcher
+            .with(SysTeams::new(world, false), "teams", &[])
+            .with(SysCapture, "capture", &["teams"])
+    }
+
+    fn match_over(&self, world: &World) -> bool {
+        world.read_resource::<Teams>().reached(self.score_limit)
+    }
+
+    fn player_joined(&self, world: &World, player: u64, ship: Entity) {
+        join_team(world, player, ship);
+    }
+}
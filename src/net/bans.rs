@@ -0,0 +1,155 @@
+//! Kicking and banning players, on servers.
+//!
+//! The `Moderation` resource queues the kicks asked for by the server's
+//! operator; `SysNetServer` sends each of those clients a `Kicked` message
+//! with the reason, then drops them. Banning a client also adds its host and
+//! session token to the `BanList`, which turns them down from then on, and
+//! can be kept in a file across restarts (see `GameBuilder::ban_list()`).
+
+use log::warn;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::utils::clean_text;
+
+/// Maximum length of the reason for a kick, in bytes.
+pub const MAX_REASON_LEN: usize = 200;
+
+/// A kick asked for, to be done on the next update.
+pub struct Kick {
+    pub client_id: u64,
+    pub reason: String,
+    /// Whether to also ban the client.
+    pub ban: bool,
+}
+
+/// Hosts and session tokens turned down by the server, with the reasons.
+///
+/// Kept in a text file if it has a path, with a line per ban.
+#[derive(Default)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    hosts: HashMap<String, String>,
+    tokens: HashMap<u64, String>,
+}
+
+impl BanList {
+    /// Loads the ban list from a file, which is created on the first ban if
+    /// it doesn't exist.
+    pub fn load<P: Into<PathBuf>>(path: P) -> io::Result<BanList> {
+        let mut bans = BanList {
+            path: Some(path.into()),
+            ..Default::default()
+        };
+        let text = match fs::read_to_string(bans.path.as_ref().unwrap()) {
+            Ok(t) => t,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(bans)
+            }
+            Err(e) => return Err(e),
+        };
+        for line in text.lines() {
+            let mut fields = line.splitn(3, ' ');
+            let (kind, value, reason) =
+                match (fields.next(), fields.next(), fields.next()) {
+                    (Some(k), Some(v), r) => (k, v, r.unwrap_or("")),
+                    _ => continue,
+                };
+            match kind {
+                "host" => {
+                    bans.hosts.insert(value.to_owned(), reason.to_owned());
+                }
+                "token" => match u64::from_str_radix(value, 16) {
+                    Ok(token) => {
+                        bans.tokens.insert(token, reason.to_owned());
+                    }
+                    Err(_) => warn!("Invalid token {:?} in ban list", value),
+                },
+                _ => warn!("Unknown ban {:?}", kind),
+            }
+        }
+        Ok(bans)
+    }
+
+    /// Why a client is banned, if it is.
+    pub fn reason(&self, host: &str, token: u64) -> Option<&str> {
+        match self.hosts.get(host) {
+            Some(reason) => Some(reason),
+            None if token != 0 => self.tokens.get(&token).map(|r| &r[..]),
+            None => None,
+        }
+    }
+
+    /// Bans a host, and a session token if not 0.
+    pub fn ban(&mut self, host: String, token: u64, reason: &str) {
+        self.hosts.insert(host, reason.to_owned());
+        if token != 0 {
+            self.tokens.insert(token, reason.to_owned());
+        }
+        self.save();
+    }
+
+    /// Lifts the ban on a host.
+    pub fn unban(&mut self, host: &str) {
+        if self.hosts.remove(host).is_some() {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return,
+        };
+        let mut text = String::new();
+        for (host, reason) in &self.hosts {
+            text.push_str(&format!("host {} {}\n", host, reason));
+        }
+        for (token, reason) in &self.tokens {
+            text.push_str(&format!("token {:016x} {}\n", token, reason));
+        }
+        // Write then rename, so a crash doesn't lose the bans
+        let tmp = path.with_extension("tmp");
+        let saved = fs::write(&tmp, text).and_then(|_| fs::rename(&tmp, path));
+        if let Err(e) = saved {
+            warn!("Can't save ban list: {}", e);
+        }
+    }
+}
+
+/// Kicks and bans, available as a resource on servers.
+#[derive(Default)]
+pub struct Moderation {
+    pub bans: BanList,
+    pub(super) kicks: Vec<Kick>,
+}
+
+impl Moderation {
+    pub fn new(bans: BanList) -> Moderation {
+        Moderation {
+            bans,
+            kicks: Vec::new(),
+        }
+    }
+
+    /// Disconnects a client, telling it why.
+    pub fn kick(&mut self, client_id: u64, reason: &str) {
+        self.queue(client_id, reason, false);
+    }
+
+    /// Disconnects a client, and bans its host and session.
+    pub fn ban(&mut self, client_id: u64, reason: &str) {
+        self.queue(client_id, reason, true);
+    }
+
+    fn queue(&mut self, client_id: u64, reason: &str, ban: bool) {
+        let reason = clean_text(reason, MAX_REASON_LEN).unwrap_or_default();
+        self.kicks.push(Kick {
+            client_id,
+            reason,
+            ban,
+        });
+    }
+}
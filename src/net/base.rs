@@ -3,15 +3,11 @@ use specs::{Component, HashMapStorage, NullStorage, VecStorage};
 /// Replicated entities have an id to match them on multiple machines.
 pub struct Replicated {
     pub id: u64,
-    pub last_update: u32,
 }
 
 impl Replicated {
     pub fn new() -> Replicated {
-        Replicated {
-            id: 0,
-            last_update: 0,
-        }
+        Replicated { id: 0 }
     }
 }
 
@@ -45,3 +41,33 @@ pub struct ClientControlled {
 impl Component for ClientControlled {
     type Storage = HashMapStorage<Self>;
 }
+
+/// State of the connection to the server, on clients, available as a
+/// resource.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Waiting for the server to accept us.
+    Connecting,
+    Connected,
+    /// The frontend asked to leave, see `Game::disconnect()`.
+    Leaving,
+    /// The server dropped us, stopped answering, or we left. Replicated
+    /// entities are gone.
+    Disconnected,
+    /// The server doesn't speak our version of the protocol, but this one.
+    /// Nothing happens anymore, like when `Disconnected`.
+    IncompatibleVersion(u16),
+    /// The server already has as many players as it takes, this many.
+    /// Nothing happens anymore either.
+    ServerFull(u16),
+    /// The server dropped or turned us down, for this reason.
+    Kicked(String),
+}
+
+/// Token of the client's session on the server, available as a resource.
+///
+/// If the connection drops, a new client given this token with
+/// `GameBuilder::session_token()` gets the same player and ship back, as long
+/// as the server didn't drop the session yet. 0 if there is none.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct SessionToken(pub u64);
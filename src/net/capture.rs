@@ -0,0 +1,383 @@
+//! Recording the traffic of a server or client, and playing it back.
+//!
+//! `CaptureServer` and `CaptureClient` wrap a transport and write every
+//! packet they send or receive to a file, with the time since the capture
+//! started (see `GameBuilder::capture()`). They should wrap the encrypted
+//! transport, if any, for the file to hold the messages and not ciphertext.
+//!
+//! `Capture::load()` reads such a file back, for tools to go over the
+//! packets and the messages in them. `ReplayServer` and `ReplayClient` are
+//! transports that feed the packets received during the capture to a fresh
+//! `Game`, at the same pace, and drop what it sends; with the same seeds,
+//! this goes through the same states, to reproduce desyncs.
+//!
+//! The file starts with `CAPTURE_MAGIC`, the protocol version, and whether
+//! it is from a server. Each packet follows, with its direction, its time in
+//! microseconds, the address of the other side (empty on clients), and its
+//! bytes.
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use log::warn;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::conn::{split_messages, HEADER_LEN, MAX_CLIENT_BATCH_LEN,
+                  MAX_SERVER_BATCH_LEN};
+use super::{Client, Message, Server, ORDER, PROTOCOL_VERSION};
+
+/// Start of every capture file.
+const CAPTURE_MAGIC: &[u8] = b"SPACCAP";
+
+/// Whether a packet was received or sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// A packet in a capture.
+pub struct Record {
+    /// Time since the capture started.
+    pub time: Duration,
+    pub direction: Direction,
+    /// Address of the other side, on servers.
+    pub peer: String,
+    pub packet: Vec<u8>,
+}
+
+/// Writes packets to a capture file.
+struct Writer {
+    file: Option<BufWriter<File>>,
+    start: Instant,
+}
+
+impl Writer {
+    fn new(file: File, server: bool) -> Writer {
+        let mut file = BufWriter::new(file);
+        let file = match write_header(&mut file, server) {
+            Ok(()) => Some(file),
+            Err(e) => {
+                warn!("Can't write capture: {}", e);
+                None
+            }
+        };
+        Writer {
+            file,
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, direction: Direction, peer: &str, packet: &[u8]) {
+        let time = self.start.elapsed();
+        let file = match self.file {
+            Some(ref mut f) => f,
+            None => return,
+        };
+        let res = write_record(file, direction, time, peer, packet);
+        if let Err(e) = res {
+            warn!("Can't write capture, stopping: {}", e);
+            self.file = None;
+        }
+    }
+}
+
+fn write_header<W: Write>(mut writer: W, server: bool) -> io::Result<()> {
+    writer.write_all(CAPTURE_MAGIC)?;
+    writer.write_u16::<ORDER>(PROTOCOL_VERSION)?;
+    writer.write_u8(server as u8)
+}
+
+fn write_record<W: Write>(
+    mut writer: W,
+    direction: Direction,
+    time: Duration,
+    peer: &str,
+    packet: &[u8],
+) -> io::Result<()> {
+    writer.write_u8(direction as u8)?;
+    writer.write_u64::<ORDER>(time.as_micros() as u64)?;
+    writer.write_u16::<ORDER>(peer.len() as u16)?;
+    writer.write_all(peer.as_bytes())?;
+    writer.write_u16::<ORDER>(packet.len() as u16)?;
+    writer.write_all(packet)
+}
+
+fn read_record<R: Read>(
+    mut reader: R,
+    direction: Direction,
+) -> io::Result<Record> {
+    let time = Duration::from_micros(reader.read_u64::<ORDER>()?);
+    let mut peer = vec![0; reader.read_u16::<ORDER>()? as usize];
+    reader.read_exact(&mut peer)?;
+    let mut packet = vec![0; reader.read_u16::<ORDER>()? as usize];
+    reader.read_exact(&mut packet)?;
+    Ok(Record {
+        time,
+        direction,
+        peer: String::from_utf8_lossy(&peer).into_owned(),
+        packet,
+    })
+}
+
+/// A server transport recording its packets.
+pub struct CaptureServer<S: Server> {
+    server: S,
+    writer: RefCell<Writer>,
+}
+
+impl<S: Server> CaptureServer<S> {
+    /// Wraps a transport, capturing to a file.
+    pub fn new(server: S, file: File) -> CaptureServer<S> {
+        CaptureServer {
+            server,
+            writer: RefCell::new(Writer::new(file, true)),
+        }
+    }
+}
+
+impl<S: Server> Server for CaptureServer<S> {
+    type Address = S::Address;
+
+    fn send(&self, msg: &[u8], addr: &S::Address) -> io::Result<usize> {
+        let peer = addr.to_string();
+        self.writer
+            .borrow_mut()
+            .record(Direction::Outbound, &peer, msg);
+        self.server.send(msg, addr)
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, S::Address)> {
+        let (len, addr) = self.server.recv(buffer)?;
+        let peer = addr.to_string();
+        self.writer
+            .borrow_mut()
+            .record(Direction::Inbound, &peer, &buffer[..len]);
+        Ok((len, addr))
+    }
+
+    fn host(&self, address: &S::Address) -> String {
+        self.server.host(address)
+    }
+}
+
+/// A client transport recording its packets.
+pub struct CaptureClient<C: Client> {
+    client: C,
+    writer: RefCell<Writer>,
+}
+
+impl<C: Client> CaptureClient<C> {
+    /// Wraps a transport, capturing to a file.
+    pub fn new(client: C, file: File) -> CaptureClient<C> {
+        CaptureClient {
+            client,
+            writer: RefCell::new(Writer::new(file, false)),
+        }
+    }
+}
+
+impl<C: Client> Client for CaptureClient<C> {
+    fn send(&self, msg: &[u8]) -> io::Result<usize> {
+        self.writer.borrow_mut().record(Direction::Outbound, "", msg);
+        self.client.send(msg)
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let len = self.client.recv(buffer)?;
+        self.writer
+            .borrow_mut()
+            .record(Direction::Inbound, "", &buffer[..len]);
+        Ok(len)
+    }
+}
+
+/// A capture read back from a file.
+pub struct Capture {
+    /// Whether it was recorded by a server.
+    pub server: bool,
+    pub records: Vec<Record>,
+}
+
+impl Capture {
+    /// Reads a capture file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Capture> {
+        let invalid =
+            |what: &str| io::Error::new(io::ErrorKind::InvalidData, what);
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; 7];
+        file.read_exact(&mut magic)?;
+        if &magic[..] != CAPTURE_MAGIC {
+            return Err(invalid("not a capture file"));
+        }
+        if file.read_u16::<ORDER>()? != PROTOCOL_VERSION {
+            return Err(invalid("capture is from another protocol version"));
+        }
+        let server = file.read_u8()? != 0;
+        let mut records = Vec::new();
+        loop {
+            let direction = match file.read_u8() {
+                Ok(0) => Direction::Inbound,
+                Ok(1) => Direction::Outbound,
+                Ok(_) => return Err(invalid("invalid packet direction")),
+                // A capture cut short ends on the last whole packet
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    break
+                }
+                Err(e) => return Err(e),
+            };
+            let record = read_record(&mut file, direction);
+            match record {
+                Ok(r) => records.push(r),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    break
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Capture { server, records })
+    }
+
+    /// Describes the messages in a packet, `None` if it can't be split.
+    pub fn messages(&self, record: &Record) -> Option<Vec<String>> {
+        // Packets from clients start with their ID
+        let inbound = record.direction == Direction::Inbound;
+        let from_client = self.server == inbound;
+        let start = if from_client { 8 } else { 0 } + HEADER_LEN;
+        if record.packet.len() < start {
+            return None;
+        }
+        let max_len = if from_client {
+            MAX_CLIENT_BATCH_LEN
+        } else {
+            MAX_SERVER_BATCH_LEN
+        };
+        let messages = split_messages(&record.packet[start..], max_len)?;
+        Some(
+            messages
+                .iter()
+                .map(|msg| match Message::parse(msg) {
+                    Some(m) => format!("{:?}", m),
+                    None => format!("invalid message {:?}", msg),
+                })
+                .collect(),
+        )
+    }
+
+    /// The packets received, with their time and sender.
+    fn inbound(self) -> VecDeque<(Duration, String, Vec<u8>)> {
+        self.records
+            .into_iter()
+            .filter(|r| r.direction == Direction::Inbound)
+            .map(|r| (r.time, r.peer, r.packet))
+            .collect()
+    }
+}
+
+/// Packets to feed at the pace they were captured at.
+struct Playback {
+    packets: VecDeque<(Duration, String, Vec<u8>)>,
+    /// When the playback started, on the first receive.
+    start: Option<Instant>,
+}
+
+impl Playback {
+    fn new(capture: Capture) -> Playback {
+        Playback {
+            packets: capture.inbound(),
+            start: None,
+        }
+    }
+
+    /// The next packet due, if any.
+    fn next(&mut self) -> Option<(String, Vec<u8>)> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        match self.packets.front() {
+            Some(&(time, _, _)) if time <= start.elapsed() => {
+                self.packets.pop_front().map(|(_, peer, p)| (peer, p))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A server transport playing back the packets of a capture.
+///
+/// Clients are known by the address they had during the capture.
+pub struct ReplayServer {
+    playback: RefCell<Playback>,
+}
+
+impl ReplayServer {
+    /// Plays back a server capture.
+    pub fn new(capture: Capture) -> io::Result<ReplayServer> {
+        if !capture.server {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a server capture",
+            ));
+        }
+        Ok(ReplayServer {
+            playback: RefCell::new(Playback::new(capture)),
+        })
+    }
+}
+
+impl Server for ReplayServer {
+    type Address = String;
+
+    fn send(&self, msg: &[u8], _addr: &String) -> io::Result<usize> {
+        Ok(msg.len())
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, String)> {
+        match self.playback.borrow_mut().next() {
+            Some((peer, packet)) => {
+                let len = packet.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&packet[..len]);
+                Ok((len, peer))
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+/// A client transport playing back the packets of a capture.
+pub struct ReplayClient {
+    playback: RefCell<Playback>,
+}
+
+impl ReplayClient {
+    /// Plays back a client capture.
+    pub fn new(capture: Capture) -> io::Result<ReplayClient> {
+        if capture.server {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a client capture",
+            ));
+        }
+        Ok(ReplayClient {
+            playback: RefCell::new(Playback::new(capture)),
+        })
+    }
+}
+
+impl Client for ReplayClient {
+    fn send(&self, msg: &[u8]) -> io::Result<usize> {
+        Ok(msg.len())
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.playback.borrow_mut().next() {
+            Some((_, packet)) => {
+                let len = packet.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&packet[..len]);
+                Ok(len)
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Estimate of the server's clock, on clients.
+//!
+//! Each `Pong` from the server carries the time it was sent. Assuming the
+//! reply took half the round trip, that gives a sample of the offset between
+//! the server's clock and ours. `ServerClock` keeps the last samples, trusts
+//! those with the shortest round trips (the least held up in queues), and
+//! fits a line through them, to get the offset and how fast the clocks drift
+//! apart. Entity updates also carry the server's tick, the number of its
+//! frame, which it keeps the latest of.
+//!
+//! Times are in seconds, from `time_decode()`, so they wrap around like the
+//! timestamps do.
+
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{time_decode, time_encode};
+
+/// Number of samples kept.
+const MAX_SAMPLES: usize = 16;
+
+/// Time between the first and last samples under which the drift isn't
+/// estimated, in seconds.
+const MIN_DRIFT_SPAN: f64 = 10.0;
+
+/// Largest drift believed, in seconds per second.
+const MAX_DRIFT: f64 = 0.001;
+
+/// Period after which timestamps wrap around, in seconds.
+const WRAP: f64 = (1u64 << 22) as f64;
+
+/// Wraps a difference of times to the shortest way around.
+fn wrap_diff(d: f64) -> f64 {
+    let d = d % WRAP;
+    if d >= WRAP / 2.0 {
+        d - WRAP
+    } else if d < -WRAP / 2.0 {
+        d + WRAP
+    } else {
+        d
+    }
+}
+
+/// The local time, in the same unit as the timestamps.
+pub fn local_time() -> f64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    time_decode(time_encode(now)).as_secs_f64()
+}
+
+/// A measure of the offset between the clocks.
+struct Sample {
+    /// Local time of the measure.
+    local: f64,
+    /// Server time minus local time.
+    offset: f64,
+    /// Round-trip time, in seconds.
+    rtt: f32,
+}
+
+/// Client resource estimating the server's clock and tick.
+#[derive(Default)]
+pub struct ServerClock {
+    samples: VecDeque<Sample>,
+    /// Offset at `base`, and drift since, once there are samples.
+    fit: Option<(f64, f64, f64)>,
+    /// Latest tick of the server heard of.
+    tick: Option<u32>,
+}
+
+impl ServerClock {
+    /// Adds a sample: the server sent `remote` as its time in a reply
+    /// received at `local`, `rtt` seconds after the request.
+    pub fn sample(&mut self, local: f64, remote: f64, rtt: f32) {
+        let offset = wrap_diff(remote + rtt as f64 * 0.5 - local);
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample { local, offset, rtt });
+        self.refit();
+    }
+
+    /// Fits the offset and drift through the samples with the shortest
+    /// round trips.
+    fn refit(&mut self) {
+        let mut rtts = self.samples.iter().map(|s| s.rtt).collect::<Vec<_>>();
+        rtts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let cutoff = rtts[rtts.len() / 2];
+        let best = self
+            .samples
+            .iter()
+            .filter(|s| s.rtt <= cutoff)
+            .collect::<Vec<_>>();
+
+        let base = best[0].local;
+        let n = best.len() as f64;
+        let xs = best.iter().map(|s| wrap_diff(s.local - base));
+        let mean_x = xs.clone().sum::<f64>() / n;
+        let mean_y = best.iter().map(|s| s.offset).sum::<f64>() / n;
+        let span = xs.clone().fold(0.0, |a: f64, x| a.max(x));
+        let drift = if span < MIN_DRIFT_SPAN {
+            0.0
+        } else {
+            let (mut cov, mut var) = (0.0, 0.0);
+            for (x, s) in xs.zip(&best) {
+                cov += (x - mean_x) * (s.offset - mean_y);
+                var += (x - mean_x) * (x - mean_x);
+            }
+            let drift = cov / var;
+            if drift.abs() > MAX_DRIFT {
+                MAX_DRIFT.copysign(drift)
+            } else {
+                drift
+            }
+        };
+        let offset = mean_y - drift * mean_x;
+        self.fit = Some((base, offset, drift));
+    }
+
+    /// Server time minus local time, if known.
+    pub fn offset(&self) -> Option<f64> {
+        self.offset_at(local_time())
+    }
+
+    fn offset_at(&self, local: f64) -> Option<f64> {
+        self.fit.map(|(base, offset, drift)| {
+            offset + drift * wrap_diff(local - base)
+        })
+    }
+
+    /// How fast the server's clock runs ahead of ours, in seconds per
+    /// second.
+    pub fn drift(&self) -> f64 {
+        self.fit.map(|(_, _, drift)| drift).unwrap_or(0.0)
+    }
+
+    /// The server's time at a local time, if known.
+    pub fn server_time(&self, local: f64) -> Option<f64> {
+        self.offset_at(local)
+            .map(|offset| (local + offset).rem_euclid(WRAP))
+    }
+
+    /// The server's time now, if known.
+    pub fn now(&self) -> Option<f64> {
+        self.server_time(local_time())
+    }
+
+    /// Records the tick an update was stamped with.
+    pub fn update_tick(&mut self, tick: u32) {
+        match self.tick {
+            Some(last) if tick.wrapping_sub(last) >= 1 << 31 => {}
+            _ => self.tick = Some(tick),
+        }
+    }
+
+    /// The latest tick of the server heard of, if any.
+    pub fn tick(&self) -> Option<u32> {
+        self.tick
+    }
+}
@@ -0,0 +1,432 @@
+//! Sequence numbers and acks, in the header of every packet.
+//!
+//! Each side numbers the packets it sends on a connection, and tells the
+//! other which of its packets it got: the latest one, and a bitfield for the
+//! 32 before it. Receivers use the numbers to drop messages older than ones
+//! they already handled, so that entities don't get rolled back by updates
+//! arriving out of order, and count a packet as lost if it didn't come
+//! before falling out of the bitfield.
+//!
+//! The messages sent during a frame are batched in as few packets as
+//! possible, each preceded by its length. Messages longer than
+//! `COMPRESS_THRESHOLD` are deflated if that makes them smaller, which is
+//! flagged by the top bit of their length. Receivers cap the size the
+//! messages of a packet decompress to, so a small packet can't cost a lot.
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use std::borrow::Cow;
+use std::io::{self, Cursor};
+
+use super::stats::NetworkStats;
+use super::ORDER;
+
+/// Size of the header, before the messages.
+pub const HEADER_LEN: usize = 12;
+
+/// Maximum size of a packet.
+pub const MAX_PACKET_LEN: usize = 1200;
+
+/// Room for messages in a packet, after the client ID and the header.
+const MAX_BATCH_LEN: usize = MAX_PACKET_LEN - 8 - HEADER_LEN;
+
+/// Messages longer than this are compressed.
+const COMPRESS_THRESHOLD: usize = 256;
+
+/// Flag in the length of a message, set if it is compressed.
+const COMPRESSED: u16 = 0x8000;
+
+/// Maximum size of the messages in a packet from the server, once
+/// decompressed.
+pub const MAX_SERVER_BATCH_LEN: usize = 64 * 1024;
+
+/// Maximum size of the messages in a packet from a client, once
+/// decompressed. Clients only send short messages, this bounds the work a
+/// packet can cost the server.
+pub const MAX_CLIENT_BATCH_LEN: usize = 4 * MAX_PACKET_LEN;
+
+/// Whether sequence number `a` comes after `b`, allowing for wrapping.
+pub fn seq_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// State of one side of a connection.
+#[derive(Default)]
+pub struct Connection {
+    /// Sequence number of the last packet sent.
+    sent: u32,
+    /// Sequence number of the latest packet received.
+    received: Option<u32>,
+    /// Packets received among the 32 before `received`, the least
+    /// significant bit being the one right before.
+    ack_bits: u32,
+    /// Messages waiting for the next packet, with their lengths.
+    queued: Vec<u8>,
+}
+
+/// Splits the messages in a packet, after the header, decompressing them.
+///
+/// Returns `None` if the lengths don't add up, a message doesn't
+/// decompress, or the messages add up to more than `max_len` bytes.
+pub fn split_messages(
+    mut batch: &[u8],
+    max_len: usize,
+) -> Option<Vec<Cow<'_, [u8]>>> {
+    let mut messages = Vec::new();
+    let mut left = max_len;
+    while !batch.is_empty() {
+        let len = batch.read_u16::<ORDER>().ok()?;
+        let compressed = len & COMPRESSED != 0;
+        let len = (len & !COMPRESSED) as usize;
+        if len > batch.len() {
+            return None;
+        }
+        if compressed {
+            // Only inflate what is left of the limit
+            let msg =
+                decompress_to_vec_with_limit(&batch[..len], left).ok()?;
+            left -= msg.len();
+            messages.push(Cow::Owned(msg));
+        } else {
+            left = left.checked_sub(len)?;
+            messages.push(Cow::Borrowed(&batch[..len]));
+        }
+        batch = &batch[len..];
+    }
+    Some(messages)
+}
+
+/// Compresses a message if it is long, and if that makes it smaller.
+///
+/// Returns the bytes to queue, and whether they are compressed.
+pub fn compress(msg: &[u8]) -> (Cow<'_, [u8]>, bool) {
+    if msg.len() > COMPRESS_THRESHOLD {
+        let compressed = compress_to_vec(msg, 6);
+        if compressed.len() < msg.len() {
+            return (Cow::Owned(compressed), true);
+        }
+    }
+    (Cow::Borrowed(msg), false)
+}
+
+impl Connection {
+    /// Sequence number of the latest packet received.
+    pub fn received(&self) -> Option<u32> {
+        self.received
+    }
+
+    /// Fraction of the 32 packets before the latest one that didn't arrive.
+    pub fn loss(&self) -> f32 {
+        match self.received {
+            Some(_) => self.ack_bits.count_zeros() as f32 / 32.0,
+            None => 0.0,
+        }
+    }
+
+    /// Queues a message for the next packet, as given by `compress()`.
+    ///
+    /// Returns `false` if it doesn't fit, the packet should be sent first,
+    /// and an error if it wouldn't fit even in an empty packet.
+    pub fn queue(&mut self, msg: &[u8], compressed: bool) -> io::Result<bool> {
+        let flag = if compressed { COMPRESSED } else { 0 };
+        if 2 + msg.len() > MAX_BATCH_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Message too long ({} bytes)", msg.len()),
+            ));
+        }
+        if self.queued.len() + 2 + msg.len() > MAX_BATCH_LEN {
+            return Ok(false);
+        }
+        // The top bit of the length is the compression flag
+        assert!(msg.len() < COMPRESSED as usize);
+        self.queued
+            .write_u16::<ORDER>(msg.len() as u16 | flag)
+            .unwrap();
+        self.queued.extend_from_slice(msg);
+        Ok(true)
+    }
+
+    /// Whether messages are waiting to be sent.
+    pub fn has_queued(&self) -> bool {
+        !self.queued.is_empty()
+    }
+
+    /// Writes the next packet: the header, then the queued messages.
+    pub fn write_packet(&mut self, packet: &mut Vec<u8>) {
+        self.sent = self.sent.wrapping_add(1);
+        packet.write_u32::<ORDER>(self.sent).unwrap();
+        packet.write_u32::<ORDER>(self.received.unwrap_or(0)).unwrap();
+        packet.write_u32::<ORDER>(self.ack_bits).unwrap();
+        packet.extend_from_slice(&self.queued);
+        self.queued.clear();
+    }
+
+    /// Reads the header of a packet, returning its sequence number.
+    ///
+    /// Returns `None` if that packet was already received, or is too old to
+    /// tell.
+    pub fn read_header(
+        &mut self,
+        header: &[u8],
+        stats: &mut NetworkStats,
+    ) -> Option<u32> {
+        let mut rdr = Cursor::new(header);
+        let seq = rdr.read_u32::<ORDER>().unwrap();
+        // Nothing gets resent, so the other side's acks aren't used yet
+        let _ack = rdr.read_u32::<ORDER>().unwrap();
+        let _ack_bits = rdr.read_u32::<ORDER>().unwrap();
+
+        let latest = match self.received {
+            Some(l) => l,
+            None => {
+                // Don't wait for packets from before the first one
+                self.received = Some(seq);
+                self.ack_bits = !0;
+                return Some(seq);
+            }
+        };
+        if seq_newer(seq, latest) {
+            // Shift the bitfield, the packets falling out of it are lost
+            let shift = seq.wrapping_sub(latest);
+            let lost = if shift < 32 {
+                (self.ack_bits >> (32 - shift)).count_zeros() - (32 - shift)
+            } else {
+                self.ack_bits.count_zeros() + (shift - 32).saturating_sub(1)
+            };
+            stats.packets_lost += lost as u64;
+            self.ack_bits = self.ack_bits.checked_shl(shift).unwrap_or(0)
+                | 1u32.checked_shl(shift - 1).unwrap_or(0);
+            self.received = Some(seq);
+        } else {
+            let back = latest.wrapping_sub(seq);
+            if back == 0 {
+                return None;
+            } else if back <= 32 {
+                let bit = 1 << (back - 1);
+                if self.ack_bits & bit != 0 {
+                    return None;
+                }
+                self.ack_bits |= bit;
+            } else {
+                // Out of the bitfield, it might be a replay
+                return None;
+            }
+        }
+        Some(seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ReadBytesExt, WriteBytesExt};
+
+    use std::borrow::Cow;
+
+    use super::{compress, seq_newer, split_messages, Connection, HEADER_LEN,
+                MAX_BATCH_LEN, MAX_SERVER_BATCH_LEN};
+    use crate::net::stats::NetworkStats;
+    use crate::net::ORDER;
+
+    /// Header of a packet with that sequence number.
+    fn header(seq: u32) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.write_u32::<ORDER>(seq).unwrap();
+        header.write_u32::<ORDER>(0).unwrap();
+        header.write_u32::<ORDER>(0).unwrap();
+        header
+    }
+
+    /// The ack and ack bits a connection sends back.
+    fn acks(conn: &mut Connection) -> (u32, u32) {
+        let mut packet = Vec::new();
+        conn.write_packet(&mut packet);
+        let mut rdr = &packet[4..];
+        (
+            rdr.read_u32::<ORDER>().unwrap(),
+            rdr.read_u32::<ORDER>().unwrap(),
+        )
+    }
+
+    /// Reads the headers of packets with those sequence numbers.
+    fn receive(
+        conn: &mut Connection,
+        stats: &mut NetworkStats,
+        seqs: &[u32],
+    ) -> Vec<Option<u32>> {
+        seqs.iter()
+            .map(|&seq| conn.read_header(&header(seq), stats))
+            .collect()
+    }
+
+    #[test]
+    fn test_queue() {
+        let mut conn = Connection::default();
+        // Noise, that doesn't compress
+        let mut state = 1u32;
+        let noise = (0..MAX_BATCH_LEN)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        let msg = &noise[..500];
+        assert_eq!(compress(msg), (Cow::Borrowed(msg), false));
+        assert!(conn.queue(msg, false).unwrap());
+        assert!(conn.queue(msg, false).unwrap());
+        // No more room
+        assert!(!conn.queue(msg, false).unwrap());
+        // Never enough room
+        assert!(conn.queue(&noise, false).is_err());
+
+        let mut packet = Vec::new();
+        conn.write_packet(&mut packet);
+        let messages =
+            split_messages(&packet[HEADER_LEN..], MAX_SERVER_BATCH_LEN)
+                .unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m[..] == msg[..]));
+        assert!(!conn.has_queued());
+
+        // A message that compresses
+        let msg = [1; 2000];
+        let (bytes, compressed) = compress(&msg);
+        assert!(compressed);
+        assert!(conn.queue(&bytes, compressed).unwrap());
+        let mut packet = Vec::new();
+        conn.write_packet(&mut packet);
+        let messages =
+            split_messages(&packet[HEADER_LEN..], MAX_SERVER_BATCH_LEN)
+                .unwrap();
+        assert_eq!(messages, [&msg[..]]);
+    }
+
+    #[test]
+    fn test_batch_limit() {
+        let mut conn = Connection::default();
+        let msg = [1; 2000];
+        let (bytes, compressed) = compress(&msg);
+        assert!(conn.queue(&bytes, compressed).unwrap());
+        assert!(conn.queue(&bytes, compressed).unwrap());
+        assert!(conn.queue(b"hello", false).unwrap());
+        let mut packet = Vec::new();
+        conn.write_packet(&mut packet);
+        let batch = &packet[HEADER_LEN..];
+
+        // The limit is on all the messages, decompressed
+        assert_eq!(split_messages(batch, 4005).unwrap().len(), 3);
+        assert_eq!(split_messages(batch, 4004), None);
+        assert_eq!(split_messages(batch, 3999), None);
+        assert_eq!(split_messages(batch, 1000), None);
+    }
+
+    #[test]
+    fn test_seq_newer() {
+        assert!(seq_newer(2, 1));
+        assert!(!seq_newer(1, 2));
+        assert!(!seq_newer(1, 1));
+        assert!(seq_newer(0, u32::MAX));
+        assert!(seq_newer(3, u32::MAX - 3));
+        assert!(!seq_newer(u32::MAX, 0));
+    }
+
+    #[test]
+    fn test_acks() {
+        let mut conn = Connection::default();
+        let mut stats = NetworkStats::default();
+        assert_eq!(
+            receive(&mut conn, &mut stats, &[1, 2, 3, 5]),
+            [Some(1), Some(2), Some(3), Some(5)],
+        );
+        // 4 is missing, 3, 2 and 1 are there, and there was nothing to wait
+        // for before 1
+        assert_eq!(acks(&mut conn), (5, 0xFFFF_FFFE));
+        assert_eq!(conn.loss(), 1.0 / 32.0);
+
+        // 4 comes in late
+        assert_eq!(receive(&mut conn, &mut stats, &[4]), [Some(4)]);
+        assert_eq!(acks(&mut conn), (5, 0xFFFF_FFFF));
+        assert_eq!(conn.loss(), 0.0);
+        assert_eq!(stats.packets_lost, 0);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut conn = Connection::default();
+        let mut stats = NetworkStats::default();
+        assert_eq!(
+            receive(&mut conn, &mut stats, &[10, 10, 12, 11, 11, 12, 10]),
+            [Some(10), None, Some(12), Some(11), None, None, None],
+        );
+        assert_eq!(acks(&mut conn), (12, 0xFFFF_FFFF));
+        assert_eq!(stats.packets_lost, 0);
+    }
+
+    #[test]
+    fn test_loss() {
+        let mut conn = Connection::default();
+        let mut stats = NetworkStats::default();
+        receive(&mut conn, &mut stats, &[1, 2, 3, 4, 5]);
+
+        // 6 is missing, it is only lost once it falls out of the bitfield
+        let seqs = (7..39).collect::<Vec<_>>();
+        receive(&mut conn, &mut stats, &seqs);
+        assert_eq!(acks(&mut conn).1, !(1 << 31));
+        assert_eq!(stats.packets_lost, 0);
+        receive(&mut conn, &mut stats, &[39]);
+        assert_eq!(acks(&mut conn).1, !0);
+        assert_eq!(stats.packets_lost, 1);
+
+        // Too late, it stays lost
+        assert_eq!(receive(&mut conn, &mut stats, &[6]), [None]);
+        assert_eq!(stats.packets_lost, 1);
+
+        // A long gap: 32 packets are in the bitfield, the rest are lost
+        receive(&mut conn, &mut stats, &[139]);
+        assert_eq!(acks(&mut conn), (139, 0));
+        assert_eq!(stats.packets_lost, 1 + 99 - 32);
+        assert_eq!(conn.loss(), 1.0);
+    }
+
+    #[test]
+    fn test_too_old() {
+        let mut conn = Connection::default();
+        let mut stats = NetworkStats::default();
+        // 8 is late, the last one still in the bitfield
+        let seqs = (1..=40).filter(|&s| s != 8).collect::<Vec<_>>();
+        receive(&mut conn, &mut stats, &seqs);
+        assert_eq!(acks(&mut conn), (40, !(1 << 31)));
+
+        // Packets from before the bitfield are dropped, they might be replays
+        assert_eq!(
+            receive(&mut conn, &mut stats, &[1, 7, 8, 7, 8]),
+            [None, None, Some(8), None, None],
+        );
+        assert_eq!(acks(&mut conn), (40, !0));
+        assert_eq!(stats.packets_lost, 0);
+    }
+
+    #[test]
+    fn test_wraparound() {
+        let mut conn = Connection::default();
+        let mut stats = NetworkStats::default();
+        let max = u32::MAX;
+        assert_eq!(
+            receive(&mut conn, &mut stats, &[max - 1, max, 0, 1, 3]),
+            [Some(max - 1), Some(max), Some(0), Some(1), Some(3)],
+        );
+        assert_eq!(acks(&mut conn), (3, 0xFFFF_FFFE));
+        assert_eq!(receive(&mut conn, &mut stats, &[max, 2]), [None, Some(2)]);
+        assert_eq!(acks(&mut conn), (3, 0xFFFF_FFFF));
+
+        // Losses are counted across the wrap too
+        let mut conn = Connection::default();
+        let mut stats = NetworkStats::default();
+        receive(&mut conn, &mut stats, &[max - 2, max - 1]);
+        receive(&mut conn, &mut stats, &[40]);
+        assert_eq!(stats.packets_lost, 41 - 32);
+    }
+}
@@ -0,0 +1,1024 @@
+//! Encrypted transport, wrapping another one (`encryption` feature).
+//!
+//! `EncryptedServer` and `EncryptedClient` wrap a `Server` and a `Client`,
+//! so that packets, and the session tokens and profile keys in them, aren't
+//! sent in plaintext. Before anything else, the client sends a handshake
+//! with an ephemeral X25519 key. The server answers with its own, signed
+//! with its long-term Ed25519 `ServerIdentity`, which the client checks
+//! against the key it was given. Both sides derive a ChaCha20-Poly1305 key
+//! for each direction from the shared secret, and every packet after that is
+//! sealed, with a counter as the nonce. Packets that don't open, or that were
+//! already received, are dropped.
+//!
+//! The handshake request is padded to the size of the reply, so that servers
+//! can't be used to amplify traffic. Before doing the costly part of the
+//! handshake, the server has the client send it again with a cookie, a MAC
+//! of its address, which proves the address isn't spoofed. Answered
+//! handshakes only become sessions once a packet opens with their keys, and
+//! the server answers a limited number per second, and per address.
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use log::{debug, info, warn};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey};
+use ring::hkdf;
+use ring::hmac;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::conn::MAX_PACKET_LEN;
+use super::{Client, Server, ORDER};
+
+/// Packet kinds, in the first byte.
+const HANDSHAKE: u8 = 1;
+const HANDSHAKE_REPLY: u8 = 2;
+const DATA: u8 = 3;
+const COOKIE: u8 = 4;
+
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+const COOKIE_LEN: usize = 32;
+
+/// Size of a handshake, request or reply.
+const HANDSHAKE_LEN: usize = 1 + KEY_LEN + SIGNATURE_LEN;
+
+/// What a sealed packet adds: kind, nonce and tag.
+const OVERHEAD: usize = 1 + 8 + 16;
+
+/// Context of the server's signature, before the two ephemeral keys.
+const SIGNATURE_CONTEXT: &[u8] = b"vigilant-steel handshake";
+
+/// Interval at which clients send the handshake again, until answered.
+/// Servers don't answer a new handshake from an address more often.
+const HANDSHAKE_RETRY: Duration = Duration::from_secs(1);
+
+/// Handshakes per second the server answers, with bursts of up to
+/// `HANDSHAKE_BURST`. Each costs a key agreement and a signature.
+const HANDSHAKE_RATE: f32 = 20.0;
+const HANDSHAKE_BURST: f32 = 20.0;
+
+/// Time the server waits for a packet with the keys of a handshake it
+/// answered.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of handshakes answered and waiting for a packet. The rate
+/// limit keeps it from filling up, this is only a bound on memory.
+const MAX_HALF_OPEN: usize = 256;
+
+/// Interval at which the key of the cookies changes.
+const COOKIE_LIFETIME: Duration = Duration::from_secs(30);
+
+/// Packets clients keep until the handshake is done, more get dropped.
+const MAX_PENDING: usize = 16;
+
+/// Time after which the server forgets a quiet session. Clients are
+/// dropped long before.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum number of sessions, which bounds memory use.
+const MAX_SESSIONS: usize = 4096;
+
+/// The long-term key a server proves its identity with.
+pub struct ServerIdentity {
+    key_pair: Ed25519KeyPair,
+}
+
+impl ServerIdentity {
+    /// Loads the key from a file, creating it if it doesn't exist.
+    pub fn load_or_generate<P: AsRef<Path>>(
+        path: P,
+    ) -> io::Result<ServerIdentity> {
+        let invalid = || {
+            io::Error::new(io::ErrorKind::InvalidData, "invalid identity key")
+        };
+        let pkcs8 = match fs::read(path.as_ref()) {
+            Ok(d) => d,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let rng = SystemRandom::new();
+                let doc = Ed25519KeyPair::generate_pkcs8(&rng)
+                    .map_err(|_| invalid())?;
+                fs::write(path.as_ref(), doc.as_ref())?;
+                doc.as_ref().to_vec()
+            }
+            Err(e) => return Err(e),
+        };
+        let key_pair =
+            Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| invalid())?;
+        Ok(ServerIdentity { key_pair })
+    }
+
+    /// The public key, which clients need to check the server.
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(self.key_pair.public_key().as_ref());
+        key
+    }
+
+    /// The public key in hexadecimal, see `parse_public_key()`.
+    pub fn public_key_hex(&self) -> String {
+        self.public_key()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Reads a server's public key from hexadecimal.
+pub fn parse_public_key(hex: &str) -> Option<[u8; KEY_LEN]> {
+    if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Nonces received recently, to drop replayed packets.
+#[derive(Default)]
+struct ReplayWindow {
+    /// Highest nonce received, 0 for none.
+    highest: u64,
+    /// Nonces received among the 64 before `highest`.
+    bits: u64,
+}
+
+impl ReplayWindow {
+    fn is_new(&self, nonce: u64) -> bool {
+        if nonce > self.highest {
+            true
+        } else {
+            let back = self.highest - nonce;
+            back > 0 && back <= 64 && self.bits & (1 << (back - 1)) == 0
+        }
+    }
+
+    fn mark(&mut self, nonce: u64) {
+        if nonce > self.highest {
+            let shift = nonce - self.highest;
+            self.bits = if shift < 64 {
+                (self.bits << shift) | (1 << (shift - 1))
+            } else if shift == 64 {
+                1 << 63
+            } else {
+                0
+            };
+            self.highest = nonce;
+        } else {
+            self.bits |= 1 << (self.highest - nonce - 1);
+        }
+    }
+}
+
+/// Keys of a session, one for each direction.
+struct Keys {
+    seal: LessSafeKey,
+    open: LessSafeKey,
+    next_nonce: u64,
+    replay: ReplayWindow,
+}
+
+impl Keys {
+    /// Derives the keys from the handshake.
+    fn derive(
+        my_key: EphemeralPrivateKey,
+        peer_key: &[u8],
+        transcript: &[u8],
+        server: bool,
+    ) -> Option<Keys> {
+        let peer_key = UnparsedPublicKey::new(&agreement::X25519, peer_key);
+        let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, transcript);
+        let prk = agreement::agree_ephemeral(my_key, &peer_key, |shared| {
+            salt.extract(shared)
+        })
+        .ok()?;
+        let key = |info: &[u8]| -> Option<LessSafeKey> {
+            let info = [info];
+            let okm = prk.expand(&info, &aead::CHACHA20_POLY1305).ok()?;
+            Some(LessSafeKey::new(UnboundKey::from(okm)))
+        };
+        let to_server = key(b"client to server")?;
+        let to_client = key(b"server to client")?;
+        let (seal, open) = if server {
+            (to_client, to_server)
+        } else {
+            (to_server, to_client)
+        };
+        Some(Keys {
+            seal,
+            open,
+            next_nonce: 1,
+            replay: ReplayWindow::default(),
+        })
+    }
+
+    fn nonce(n: u64) -> Nonce {
+        let mut nonce = [0; 12];
+        (&mut nonce[4..]).write_u64::<ORDER>(n).unwrap();
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Seals a packet.
+    fn seal(&mut self, msg: &[u8]) -> Vec<u8> {
+        let n = self.next_nonce;
+        self.next_nonce += 1;
+        let mut packet = Vec::with_capacity(msg.len() + OVERHEAD);
+        packet.push(DATA);
+        packet.write_u64::<ORDER>(n).unwrap();
+        let mut body = msg.to_vec();
+        self.seal
+            .seal_in_place_append_tag(Keys::nonce(n), Aad::empty(), &mut body)
+            .unwrap();
+        packet.extend_from_slice(&body);
+        packet
+    }
+
+    /// Opens a sealed packet, after its kind, `None` if it is invalid or
+    /// was already received.
+    fn open<'b>(&mut self, packet: &'b mut [u8]) -> Option<&'b [u8]> {
+        if packet.len() < 8 {
+            return None;
+        }
+        let n = (&packet[..8]).read_u64::<ORDER>().unwrap();
+        if !self.replay.is_new(n) {
+            return None;
+        }
+        let body = self
+            .open
+            .open_in_place(Keys::nonce(n), Aad::empty(), &mut packet[8..])
+            .ok()?;
+        self.replay.mark(n);
+        Some(body)
+    }
+}
+
+/// Copies an opened packet to the buffer given to `recv()`.
+fn copy_body(body: &[u8], buffer: &mut [u8]) -> io::Result<usize> {
+    if body.len() > buffer.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Packet too long for the buffer ({} bytes)", body.len()),
+        ));
+    }
+    buffer[..body.len()].copy_from_slice(body);
+    Ok(body.len())
+}
+
+/// A client's session, on the server.
+struct Session {
+    keys: Keys,
+    last_seen: Instant,
+    /// When the handshake the keys come from was answered.
+    handshake: Instant,
+}
+
+/// A handshake the server answered, until a packet opens with its keys.
+struct HalfOpen {
+    client_key: Vec<u8>,
+    /// The reply, sent again if the client retries with the same key.
+    reply: Vec<u8>,
+    keys: Keys,
+    started: Instant,
+}
+
+/// State of the server's side of the handshakes.
+struct Handshakes<A> {
+    half_open: HashMap<A, HalfOpen>,
+    /// Keys of the cookies, the previous one still accepted.
+    secret: hmac::Key,
+    old_secret: hmac::Key,
+    rotated: Instant,
+    /// Handshakes that can be answered right now.
+    tokens: f32,
+    last_refill: Instant,
+}
+
+impl<A: Display + Eq + Hash> Handshakes<A> {
+    fn new(rng: &SystemRandom) -> Handshakes<A> {
+        let now = Instant::now();
+        let secret = || {
+            hmac::Key::generate(hmac::HMAC_SHA256, rng)
+                .expect("Couldn't generate cookie secret")
+        };
+        Handshakes {
+            half_open: HashMap::new(),
+            secret: secret(),
+            old_secret: secret(),
+            rotated: now,
+            tokens: HANDSHAKE_BURST,
+            last_refill: now,
+        }
+    }
+
+    /// What the cookie of an address and key is computed over.
+    fn cookie_input(addr: &A, client_key: &[u8]) -> Vec<u8> {
+        let mut input = client_key.to_vec();
+        input.extend_from_slice(addr.to_string().as_bytes());
+        input
+    }
+
+    /// Makes the cookie a client has to send back with its handshake.
+    fn cookie(
+        &mut self,
+        addr: &A,
+        client_key: &[u8],
+        rng: &SystemRandom,
+    ) -> Vec<u8> {
+        let now = Instant::now();
+        if now.duration_since(self.rotated) >= COOKIE_LIFETIME {
+            if let Ok(secret) = hmac::Key::generate(hmac::HMAC_SHA256, rng) {
+                self.old_secret = std::mem::replace(&mut self.secret, secret);
+                self.rotated = now;
+            }
+        }
+        let input = Handshakes::cookie_input(addr, client_key);
+        hmac::sign(&self.secret, &input).as_ref().to_vec()
+    }
+
+    /// Whether a client sent back a cookie we made for it.
+    fn valid_cookie(
+        &self,
+        addr: &A,
+        client_key: &[u8],
+        cookie: &[u8],
+    ) -> bool {
+        let input = Handshakes::cookie_input(addr, client_key);
+        hmac::verify(&self.secret, &input, cookie).is_ok()
+            || hmac::verify(&self.old_secret, &input, cookie).is_ok()
+    }
+
+    /// Takes a handshake from the budget, false if there is none left.
+    fn charge(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f32();
+        self.last_refill = now;
+        self.tokens =
+            (self.tokens + elapsed * HANDSHAKE_RATE).min(HANDSHAKE_BURST);
+        if self.tokens < 1.0 {
+            false
+        } else {
+            self.tokens -= 1.0;
+            true
+        }
+    }
+}
+
+/// Forgets the sessions that have been quiet for `SESSION_TIMEOUT`.
+fn prune<A: Eq + Hash>(sessions: &mut HashMap<A, Session>, now: Instant) {
+    sessions.retain(|_, s| now.duration_since(s.last_seen) < SESSION_TIMEOUT);
+}
+
+/// Server transport sealing the packets of another.
+pub struct EncryptedServer<S: Server> {
+    inner: S,
+    identity: ServerIdentity,
+    rng: SystemRandom,
+    sessions: RefCell<HashMap<S::Address, Session>>,
+    handshakes: RefCell<Handshakes<S::Address>>,
+    last_prune: RefCell<Instant>,
+}
+
+impl<S: Server> EncryptedServer<S> {
+    pub fn new(inner: S, identity: ServerIdentity) -> EncryptedServer<S> {
+        let rng = SystemRandom::new();
+        let handshakes = Handshakes::new(&rng);
+        EncryptedServer {
+            inner,
+            identity,
+            rng,
+            sessions: RefCell::new(HashMap::new()),
+            handshakes: RefCell::new(handshakes),
+            last_prune: RefCell::new(Instant::now()),
+        }
+    }
+
+    fn send_raw(&self, packet: &[u8], addr: &S::Address) {
+        if let Err(e) = self.inner.send(packet, addr) {
+            warn!("Network error: {}", e);
+        }
+    }
+
+    /// Answers a handshake, with a cookie if it doesn't have a valid one,
+    /// else with keys for the address.
+    fn handshake(&self, packet: &[u8], addr: &S::Address) {
+        let client_key = &packet[1..1 + KEY_LEN];
+        let cookie = &packet[1 + KEY_LEN..1 + KEY_LEN + COOKIE_LEN];
+        let mut handshakes = self.handshakes.borrow_mut();
+        let now = Instant::now();
+
+        // Check the address is real before doing anything costly
+        if !handshakes.valid_cookie(addr, client_key, cookie) {
+            let mut reply = vec![COOKIE];
+            reply.extend_from_slice(
+                &handshakes.cookie(addr, client_key, &self.rng),
+            );
+            self.send_raw(&reply, addr);
+            return;
+        }
+
+        // The client didn't get the reply, send it again
+        if let Some(half_open) = handshakes.half_open.get(addr) {
+            if half_open.client_key == client_key {
+                self.send_raw(&half_open.reply, addr);
+                return;
+            }
+            if now.duration_since(half_open.started) < HANDSHAKE_RETRY {
+                debug!("Handshaking too often, dropping {}", addr);
+                return;
+            }
+        }
+        match self.sessions.borrow().get(addr) {
+            Some(s) if now.duration_since(s.handshake) < HANDSHAKE_RETRY => {
+                debug!("Handshaking too often, dropping {}", addr);
+                return;
+            }
+            _ => {}
+        }
+        if !handshakes.charge(now) {
+            debug!("Too many handshakes, dropping {}", addr);
+            return;
+        }
+        if handshakes.half_open.len() >= MAX_HALF_OPEN
+            && !handshakes.half_open.contains_key(addr)
+        {
+            handshakes.half_open.retain(|_, h| {
+                now.duration_since(h.started) < HANDSHAKE_TIMEOUT
+            });
+            if handshakes.half_open.len() >= MAX_HALF_OPEN {
+                warn!("Too many handshakes, dropping {}", addr);
+                return;
+            }
+        }
+
+        let my_key = match EphemeralPrivateKey::generate(
+            &agreement::X25519,
+            &self.rng,
+        ) {
+            Ok(k) => k,
+            Err(_) => return,
+        };
+        let my_public = match my_key.compute_public_key() {
+            Ok(k) => k,
+            Err(_) => return,
+        };
+        let mut transcript = client_key.to_vec();
+        transcript.extend_from_slice(my_public.as_ref());
+        let keys = match Keys::derive(my_key, client_key, &transcript, true) {
+            Some(k) => k,
+            None => {
+                debug!("Invalid handshake from {}", addr);
+                return;
+            }
+        };
+
+        let mut signed = SIGNATURE_CONTEXT.to_vec();
+        signed.extend_from_slice(&transcript);
+        let mut reply = vec![HANDSHAKE_REPLY];
+        reply.extend_from_slice(my_public.as_ref());
+        reply.extend_from_slice(self.identity.key_pair.sign(&signed).as_ref());
+        self.send_raw(&reply, addr);
+
+        // Not a session until a packet opens with the keys, so a spoofed
+        // handshake can't break an established one
+        handshakes.half_open.insert(
+            addr.clone(),
+            HalfOpen {
+                client_key: client_key.to_vec(),
+                reply,
+                keys,
+                started: now,
+            },
+        );
+    }
+
+    /// Opens a sealed packet from an address, into `buffer`.
+    ///
+    /// Returns `None` if it doesn't open.
+    fn open(
+        &self,
+        packet: &mut [u8],
+        addr: &S::Address,
+        buffer: &mut [u8],
+    ) -> io::Result<Option<usize>> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.borrow_mut();
+        let mut handshakes = self.handshakes.borrow_mut();
+        if let Some(half_open) = handshakes.half_open.get_mut(addr) {
+            // Opening can garble the packet if it fails, try on a copy
+            let mut copy = packet[1..].to_vec();
+            if let Some(body) = half_open.keys.open(&mut copy) {
+                if sessions.len() >= MAX_SESSIONS
+                    && !sessions.contains_key(addr)
+                {
+                    prune(&mut sessions, now);
+                    if sessions.len() >= MAX_SESSIONS {
+                        warn!("Too many sessions, dropping {}", addr);
+                        return Ok(None);
+                    }
+                }
+                let len = copy_body(body, buffer)?;
+                // The handshake is done, this is the session now
+                let half_open = handshakes.half_open.remove(addr).unwrap();
+                sessions.insert(
+                    addr.clone(),
+                    Session {
+                        keys: half_open.keys,
+                        last_seen: now,
+                        handshake: half_open.started,
+                    },
+                );
+                return Ok(Some(len));
+            }
+        }
+        let session = match sessions.get_mut(addr) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let body = match session.keys.open(&mut packet[1..]) {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        session.last_seen = now;
+        copy_body(body, buffer).map(Some)
+    }
+}
+
+impl<S: Server> Server for EncryptedServer<S> {
+    type Address = S::Address;
+
+    fn send(&self, msg: &[u8], addr: &S::Address) -> io::Result<usize> {
+        let packet = match self.sessions.borrow_mut().get_mut(addr) {
+            Some(session) => session.keys.seal(msg),
+            // No handshake, nothing can be sent
+            None => return Ok(0),
+        };
+        self.inner.send(&packet, addr)
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, S::Address)> {
+        let now = Instant::now();
+        if now.duration_since(*self.last_prune.borrow()) >= SESSION_TIMEOUT {
+            *self.last_prune.borrow_mut() = now;
+            prune(&mut self.sessions.borrow_mut(), now);
+            self.handshakes.borrow_mut().half_open.retain(|_, h| {
+                now.duration_since(h.started) < HANDSHAKE_TIMEOUT
+            });
+        }
+
+        let mut packet = [0; MAX_PACKET_LEN + OVERHEAD];
+        loop {
+            let (len, addr) = self.inner.recv(&mut packet)?;
+            let packet = &mut packet[..len];
+            match packet.first() {
+                Some(&HANDSHAKE) if len == HANDSHAKE_LEN => {
+                    self.handshake(packet, &addr)
+                }
+                Some(&DATA) => {
+                    if let Some(len) = self.open(packet, &addr, buffer)? {
+                        return Ok((len, addr));
+                    }
+                    debug!("Dropping packet from {}", addr);
+                }
+                _ => debug!("Invalid packet from {}", addr),
+            }
+        }
+    }
+
+    fn host(&self, address: &S::Address) -> String {
+        self.inner.host(address)
+    }
+}
+
+/// State of the client's side of the handshake.
+struct ClientState {
+    /// Our ephemeral key and its public half, until the server answers.
+    ///
+    /// It is kept across retries, so they can be answered with the same
+    /// cookie and reply.
+    handshake: Option<(EphemeralPrivateKey, Vec<u8>)>,
+    /// The cookie the server gave us, to send back.
+    cookie: Option<Vec<u8>>,
+    last_handshake: Option<Instant>,
+    keys: Option<Keys>,
+    /// Packets to send once the handshake is done.
+    pending: Vec<Vec<u8>>,
+}
+
+/// Client transport sealing the packets of another.
+pub struct EncryptedClient<C: Client> {
+    inner: C,
+    server_key: [u8; KEY_LEN],
+    rng: SystemRandom,
+    state: RefCell<ClientState>,
+}
+
+impl<C: Client> EncryptedClient<C> {
+    /// Wraps a client, which will only talk to the server with this
+    /// public key (see `ServerIdentity`).
+    pub fn new(inner: C, server_key: [u8; KEY_LEN]) -> EncryptedClient<C> {
+        let client = EncryptedClient {
+            inner,
+            server_key,
+            rng: SystemRandom::new(),
+            state: RefCell::new(ClientState {
+                handshake: None,
+                cookie: None,
+                last_handshake: None,
+                keys: None,
+                pending: Vec::new(),
+            }),
+        };
+        client.handshake(&mut client.state.borrow_mut());
+        client
+    }
+
+    /// Sends the handshake, if it is time to.
+    fn handshake(&self, state: &mut ClientState) {
+        let now = Instant::now();
+        match state.last_handshake {
+            Some(last) if now.duration_since(last) < HANDSHAKE_RETRY => {
+                return
+            }
+            _ => {}
+        }
+        state.last_handshake = Some(now);
+
+        if state.handshake.is_none() {
+            let key = match EphemeralPrivateKey::generate(
+                &agreement::X25519,
+                &self.rng,
+            ) {
+                Ok(k) => k,
+                Err(_) => return,
+            };
+            let public = match key.compute_public_key() {
+                Ok(k) => k.as_ref().to_vec(),
+                Err(_) => return,
+            };
+            state.handshake = Some((key, public));
+        }
+        let mut packet = vec![HANDSHAKE];
+        if let Some((_, ref public)) = state.handshake {
+            packet.extend_from_slice(public);
+        }
+        match state.cookie {
+            Some(ref cookie) => packet.extend_from_slice(cookie),
+            None => packet.resize(1 + KEY_LEN + COOKIE_LEN, 0),
+        }
+        packet.resize(HANDSHAKE_LEN, 0);
+        if let Err(e) = self.inner.send(&packet) {
+            warn!("Network error: {}", e);
+        }
+    }
+
+    /// Checks the server's reply, and sets up the keys.
+    fn finish_handshake(&self, state: &mut ClientState, packet: &[u8]) {
+        let server_public = &packet[1..1 + KEY_LEN];
+        let mut transcript = match state.handshake {
+            Some((_, ref public)) => public.clone(),
+            None => return,
+        };
+        transcript.extend_from_slice(server_public);
+        let mut signed = SIGNATURE_CONTEXT.to_vec();
+        signed.extend_from_slice(&transcript);
+        let identity = signature::UnparsedPublicKey::new(
+            &signature::ED25519,
+            &self.server_key[..],
+        );
+        if identity.verify(&signed, &packet[1 + KEY_LEN..]).is_err() {
+            warn!("Server failed to prove its identity");
+            return;
+        }
+        let (key, _) = state.handshake.take().unwrap();
+        state.keys = Keys::derive(key, server_public, &transcript, false);
+        if let Some(ref mut keys) = state.keys {
+            info!("Encrypted session established");
+            for msg in state.pending.drain(..) {
+                let packet = keys.seal(&msg);
+                if let Err(e) = self.inner.send(&packet) {
+                    warn!("Network error: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl<C: Client> Client for EncryptedClient<C> {
+    fn send(&self, msg: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.borrow_mut();
+        match state.keys {
+            Some(ref mut keys) => self.inner.send(&keys.seal(msg)),
+            None => {
+                if state.pending.len() < MAX_PENDING {
+                    state.pending.push(msg.to_vec());
+                }
+                Ok(msg.len())
+            }
+        }
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.borrow_mut();
+        if state.keys.is_none() {
+            self.handshake(&mut state);
+        }
+
+        let mut packet = [0; MAX_PACKET_LEN + OVERHEAD];
+        loop {
+            let len = self.inner.recv(&mut packet)?;
+            let packet = &mut packet[..len];
+            match (packet.first(), state.keys.as_mut()) {
+                (Some(&COOKIE), None) if len == 1 + COOKIE_LEN => {
+                    // Send the handshake again right away, with the cookie
+                    state.cookie = Some(packet[1..].to_vec());
+                    state.last_handshake = None;
+                    self.handshake(&mut state);
+                }
+                (Some(&HANDSHAKE_REPLY), None) if len == HANDSHAKE_LEN => {
+                    self.finish_handshake(&mut state, packet)
+                }
+                (Some(&DATA), Some(keys)) => {
+                    if let Some(body) = keys.open(&mut packet[1..]) {
+                        return copy_body(body, buffer);
+                    }
+                    debug!("Dropping packet from server");
+                }
+                _ => debug!("Invalid packet from server"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+    use ring::signature::Ed25519KeyPair;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use super::{EncryptedClient, EncryptedServer, ReplayWindow,
+                ServerIdentity, COOKIE, DATA, HANDSHAKE, HANDSHAKE_BURST,
+                HANDSHAKE_LEN, HANDSHAKE_REPLY};
+    use crate::net::{Client, Server};
+
+    type Queue = Arc<Mutex<VecDeque<(Vec<u8>, u32)>>>;
+
+    /// Packets going both ways between a server and its clients, which are
+    /// known by a number.
+    #[derive(Clone, Default)]
+    struct Link {
+        to_server: Queue,
+        to_client: Queue,
+    }
+
+    impl Link {
+        /// Kinds of the packets waiting, in one direction.
+        fn kinds(queue: &Queue) -> Vec<u8> {
+            queue.lock().unwrap().iter().map(|p| p.0[0]).collect()
+        }
+    }
+
+    struct TestServer(Link);
+
+    impl Server for TestServer {
+        type Address = u32;
+
+        fn send(&self, msg: &[u8], addr: &u32) -> io::Result<usize> {
+            self.0.to_client.lock().unwrap().push_back((msg.to_vec(), *addr));
+            Ok(msg.len())
+        }
+
+        fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, u32)> {
+            match self.0.to_server.lock().unwrap().pop_front() {
+                Some((packet, addr)) => {
+                    buffer[..packet.len()].copy_from_slice(&packet);
+                    Ok((packet.len(), addr))
+                }
+                None => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    struct TestClient(Link, u32);
+
+    impl Client for TestClient {
+        fn send(&self, msg: &[u8]) -> io::Result<usize> {
+            self.0.to_server.lock().unwrap().push_back((msg.to_vec(), self.1));
+            Ok(msg.len())
+        }
+
+        fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+            let mut queue = self.0.to_client.lock().unwrap();
+            match queue.iter().position(|p| p.1 == self.1) {
+                Some(i) => {
+                    let (packet, _) = queue.remove(i).unwrap();
+                    buffer[..packet.len()].copy_from_slice(&packet);
+                    Ok(packet.len())
+                }
+                None => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    fn identity() -> ServerIdentity {
+        let rng = SystemRandom::new();
+        let doc = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        ServerIdentity {
+            key_pair: Ed25519KeyPair::from_pkcs8(doc.as_ref()).unwrap(),
+        }
+    }
+
+    fn server_recv<S: Server>(server: &S) -> Option<(Vec<u8>, S::Address)> {
+        let mut buffer = [0; 1200];
+        match server.recv(&mut buffer) {
+            Ok((len, addr)) => Some((buffer[..len].to_vec(), addr)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn client_recv<C: Client>(client: &C) -> Option<Vec<u8>> {
+        let mut buffer = [0; 1200];
+        match client.recv(&mut buffer) {
+            Ok(len) => Some(buffer[..len].to_vec()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn test_replay_in_order() {
+        let mut window = ReplayWindow::default();
+        for n in 1..200 {
+            assert!(window.is_new(n));
+            window.mark(n);
+            assert!(!window.is_new(n));
+        }
+        assert_eq!(window.highest, 199);
+        assert_eq!(window.bits, !0);
+    }
+
+    #[test]
+    fn test_replay_duplicates() {
+        let mut window = ReplayWindow::default();
+        for &n in &[1, 3, 2, 10] {
+            assert!(window.is_new(n));
+            window.mark(n);
+        }
+        for &n in &[1, 2, 3, 10] {
+            assert!(!window.is_new(n));
+        }
+        // Out of order, but not seen yet
+        for n in 4..10 {
+            assert!(window.is_new(n));
+        }
+    }
+
+    #[test]
+    fn test_replay_too_old() {
+        let mut window = ReplayWindow::default();
+        window.mark(1);
+        window.mark(100);
+        // Out of the window, it can't be told apart from a replay
+        assert!(!window.is_new(1));
+        assert!(!window.is_new(35));
+        assert!(window.is_new(36));
+        assert!(window.is_new(99));
+        // 0 is never a nonce
+        assert!(!window.is_new(0));
+
+        // A jump of exactly the window's size
+        let mut window = ReplayWindow::default();
+        window.mark(1);
+        window.mark(65);
+        assert!(!window.is_new(1));
+        assert!(window.is_new(2));
+    }
+
+    #[test]
+    fn test_handshake() {
+        let link = Link::default();
+        let identity = identity();
+        let key = identity.public_key();
+        let server = EncryptedServer::new(TestServer(link.clone()), identity);
+        let client = EncryptedClient::new(TestClient(link.clone(), 1), key);
+
+        // Without a cookie, the server only answers with one
+        let first = link.to_server.lock().unwrap()[0].0.clone();
+        assert_eq!(first[0], HANDSHAKE);
+        assert_eq!(server_recv(&server), None);
+        assert_eq!(Link::kinds(&link.to_client), [COOKIE]);
+        assert!(server.handshakes.borrow().half_open.is_empty());
+
+        // The client sends it back, with the same key
+        client.send(b"hello").unwrap();
+        assert_eq!(client_recv(&client), None);
+        let second = link.to_server.lock().unwrap()[0].0.clone();
+        assert_eq!(second.len(), HANDSHAKE_LEN);
+        assert_eq!(first[..33], second[..33]);
+        assert_eq!(server_recv(&server), None);
+        assert_eq!(Link::kinds(&link.to_client), [HANDSHAKE_REPLY]);
+        // Not a session yet
+        assert_eq!(server.handshakes.borrow().half_open.len(), 1);
+        assert!(server.sessions.borrow().is_empty());
+
+        // The client sends what it queued, which makes it a session
+        assert_eq!(client_recv(&client), None);
+        assert_eq!(Link::kinds(&link.to_server), [DATA]);
+        let sealed = link.to_server.lock().unwrap()[0].clone();
+        assert_eq!(server_recv(&server), Some((b"hello".to_vec(), 1)));
+        assert!(server.handshakes.borrow().half_open.is_empty());
+        assert_eq!(server.sessions.borrow().len(), 1);
+
+        server.send(b"welcome", &1).unwrap();
+        assert_eq!(client_recv(&client), Some(b"welcome".to_vec()));
+        client.send(b"thanks").unwrap();
+        assert_eq!(server_recv(&server), Some((b"thanks".to_vec(), 1)));
+
+        // Replays are dropped
+        link.to_server.lock().unwrap().push_back(sealed);
+        assert_eq!(server_recv(&server), None);
+
+        // Packets have to fit the buffer
+        server.send(&[0; 100], &1).unwrap();
+        assert!(client.recv(&mut [0; 10]).is_err());
+    }
+
+    #[test]
+    fn test_wrong_identity() {
+        let link = Link::default();
+        let server_identity = identity();
+        let server =
+            EncryptedServer::new(TestServer(link.clone()), server_identity);
+        // Not the key of that server
+        let key = identity().public_key();
+        let client = EncryptedClient::new(TestClient(link.clone(), 1), key);
+        client.send(b"hello").unwrap();
+        for _ in 0..3 {
+            assert_eq!(server_recv(&server), None);
+            assert_eq!(client_recv(&client), None);
+        }
+        // The reply didn't check out, nothing was sent
+        assert!(link.to_server.lock().unwrap().is_empty());
+        assert!(client.state.borrow().keys.is_none());
+    }
+
+    #[test]
+    fn test_spoofed_handshake() {
+        let link = Link::default();
+        let identity = identity();
+        let key = identity.public_key();
+        let server = EncryptedServer::new(TestServer(link.clone()), identity);
+        let _client = EncryptedClient::new(TestClient(link.clone(), 1), key);
+        let handshake = link.to_server.lock().unwrap()[0].0.clone();
+
+        // Handshakes from addresses that never get the cookie cost nothing
+        for addr in 2..100 {
+            link.to_server
+                .lock()
+                .unwrap()
+                .push_back((handshake.clone(), addr));
+        }
+        assert_eq!(server_recv(&server), None);
+        assert_eq!(link.to_client.lock().unwrap().len(), 1 + 98);
+        assert!(Link::kinds(&link.to_client).iter().all(|&k| k == COOKIE));
+        assert!(server.handshakes.borrow().half_open.is_empty());
+
+        // Nor does the real client's cookie, sent from another address
+        let (cookie, addr) = link.to_client.lock().unwrap()[0].clone();
+        assert_eq!(addr, 1);
+        link.to_client.lock().unwrap().clear();
+        let mut spoofed = handshake;
+        spoofed[33..65].copy_from_slice(&cookie[1..]);
+        link.to_server.lock().unwrap().push_back((spoofed.clone(), 2));
+        assert_eq!(server_recv(&server), None);
+        assert_eq!(Link::kinds(&link.to_client), [COOKIE]);
+        assert!(server.handshakes.borrow().half_open.is_empty());
+
+        // The real address gets keys, once
+        link.to_client.lock().unwrap().clear();
+        link.to_server.lock().unwrap().push_back((spoofed.clone(), 1));
+        assert_eq!(server_recv(&server), None);
+        assert_eq!(Link::kinds(&link.to_client), [HANDSHAKE_REPLY]);
+        let tokens = server.handshakes.borrow().tokens as u32;
+        assert_eq!(tokens, HANDSHAKE_BURST as u32 - 1);
+        // Retries get the same reply, without more work
+        link.to_server.lock().unwrap().push_back((spoofed, 1));
+        assert_eq!(server_recv(&server), None);
+        let replies = link.to_client.lock().unwrap().clone();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0], replies[1]);
+        let tokens = server.handshakes.borrow().tokens as u32;
+        assert_eq!(tokens, HANDSHAKE_BURST as u32 - 1);
+        assert!(server.sessions.borrow().is_empty());
+    }
+}
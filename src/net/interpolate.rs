@@ -0,0 +1,158 @@
+//! Interpolation of remote entities between server updates.
+//!
+//! Updates from the server come in at the server's rate, with some jitter,
+//! so moving entities would jump from one to the next. Instead, clients keep
+//! the last few updates of remote ships and asteroids, stamped with the time
+//! the server sent them, and show these entities `INTERPOLATION_DELAY` in the
+//! past, between the two updates around that time. Past the last update, an
+//! entity moves on with the velocity it had. The locally-controlled ship is
+//! predicted instead (see `predict.rs`).
+
+use specs::{Component, Join, Read, ReadStorage, System, VecStorage,
+            WriteStorage};
+use std::collections::VecDeque;
+use vecmath::*;
+
+use crate::physics::{DeltaTime, LocalControl, Position, Velocity};
+use crate::utils::angle_wrap;
+
+use super::ServerClock;
+
+/// How far in the past remote entities are shown, in seconds.
+const INTERPOLATION_DELAY: f64 = 0.1;
+
+/// Number of updates kept for each entity.
+const MAX_SNAPSHOTS: usize = 8;
+
+/// Distance between the client's clock and the server's past which the
+/// client starts over from the server's time, in seconds.
+const MAX_CLOCK_DRIFT: f64 = 10.0;
+
+/// An update of an entity, at a time on the server.
+struct Snapshot {
+    time: f64,
+    pos: Position,
+    vel: Velocity,
+}
+
+/// Client component keeping the last updates of a remote entity.
+#[derive(Default)]
+pub struct Interpolated {
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl Component for Interpolated {
+    type Storage = VecStorage<Self>;
+}
+
+impl Interpolated {
+    /// Records an update, sent by the server at `time`.
+    ///
+    /// Updates arriving after a more recent one are dropped.
+    pub fn push(&mut self, time: f64, pos: &Position, vel: &Velocity) {
+        if let Some(last) = self.snapshots.back() {
+            if time <= last.time {
+                return;
+            }
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(Snapshot {
+            time,
+            pos: pos.clone(),
+            vel: vel.clone(),
+        });
+    }
+
+    /// Time of the last update.
+    fn latest(&self) -> Option<f64> {
+        self.snapshots.back().map(|s| s.time)
+    }
+
+    /// Position of the entity at a time on the server.
+    fn at(&self, time: f64) -> Option<Position> {
+        let mut prev: Option<&Snapshot> = None;
+        for next in &self.snapshots {
+            if next.time > time {
+                let prev = match prev {
+                    Some(p) => p,
+                    None => return Some(next.pos.clone()),
+                };
+                let t = ((time - prev.time) / (next.time - prev.time)) as f32;
+                let diff = vec2_sub(next.pos.pos, prev.pos.pos);
+                let rot_diff = angle_wrap(next.pos.rot - prev.pos.rot);
+                return Some(Position {
+                    pos: vec2_add(prev.pos.pos, vec2_scale(diff, t)),
+                    rot: prev.pos.rot + rot_diff * t,
+                });
+            }
+            prev = Some(next);
+        }
+
+        // Past the last update, extrapolate from it
+        prev.map(|last| {
+            let dt = (time - last.time) as f32;
+            Position {
+                pos: vec2_add(last.pos.pos, vec2_scale(last.vel.vel, dt)),
+                rot: last.pos.rot + last.vel.rot * dt,
+            }
+        })
+    }
+}
+
+/// Client system moving remote entities between their updates.
+///
+/// This goes by the `ServerClock`. Until that is synchronized, it keeps its
+/// own estimate of the server's clock, running at the local rate and
+/// catching up when updates arrive ahead of it.
+#[derive(Default)]
+pub struct SysInterpolation {
+    clock: Option<f64>,
+}
+
+impl<'a> System<'a> for SysInterpolation {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        Read<'a, ServerClock>,
+        ReadStorage<'a, Interpolated>,
+        ReadStorage<'a, LocalControl>,
+        WriteStorage<'a, Position>,
+    );
+
+    fn run(
+        &mut self,
+        (dt, server, interpolated, local, mut position): Self::SystemData,
+    ) {
+        let latest = (&interpolated)
+            .join()
+            .filter_map(Interpolated::latest)
+            .fold(None, |acc: Option<f64>, t| match acc {
+                Some(a) if a >= t => Some(a),
+                _ => Some(t),
+            });
+        let latest = match latest {
+            Some(t) => t,
+            None => return,
+        };
+        let clock = match (server.now(), self.clock) {
+            (Some(now), _) => now,
+            (None, Some(c))
+                if c + (dt.0 as f64) - latest < MAX_CLOCK_DRIFT =>
+            {
+                (c + dt.0 as f64).max(latest)
+            }
+            _ => latest,
+        };
+        self.clock = Some(clock);
+
+        let time = clock - INTERPOLATION_DELAY;
+        for (interp, pos, _) in
+            (&interpolated, &mut position, !&local).join()
+        {
+            if let Some(p) = interp.at(time) {
+                *pos = p;
+            }
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Buffering of the controls received from clients, on servers.
+//!
+//! Clients send their controls every frame, stamped with their tick (see
+//! `predict.rs`), but packets don't arrive at a steady pace: some are held
+//! up, then come in bunches. Applying controls as they arrive would skip
+//! some and hold others for several frames, which makes ships jerk and the
+//! clients' prediction miss. Instead, `InputBuffer` maps each tick of the
+//! client to a frame of the server, and hands the controls out on that
+//! frame. The delay grows when controls come in late, and shrinks back when
+//! they have all been early for a while.
+
+use std::collections::VecDeque;
+
+/// Frames the first controls are held for.
+const INITIAL_DELAY: u32 = 2;
+
+/// Maximum number of controls kept, older ones are dropped.
+const MAX_INPUTS: usize = 32;
+
+/// Number of controls after which the delay is cut by how early they all
+/// were.
+const SHRINK_WINDOW: u32 = 120;
+
+/// Whether tick `a` comes before tick `b`, as they wrap around.
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Controls received from a client for an entity, waiting for their frame.
+#[derive(Default)]
+pub struct InputBuffer {
+    /// Frame minus tick: the frame controls are due on, once known.
+    offset: Option<u32>,
+    /// Controls with their tick, in order.
+    inputs: VecDeque<(u32, Vec<u8>)>,
+    /// Tick of the last controls handed out.
+    last: Option<u32>,
+    /// Fewest frames controls came early by in the current window, and the
+    /// number of controls in it.
+    slack: u32,
+    count: u32,
+}
+
+impl InputBuffer {
+    /// Adds controls received on `frame`, false if they are too old.
+    pub fn push(&mut self, frame: u32, tick: u32, data: Vec<u8>) -> bool {
+        if let Some(last) = self.last {
+            if !before(last, tick) {
+                return false;
+            }
+        }
+        let mut offset = match self.offset {
+            Some(o) => o,
+            None => frame.wrapping_sub(tick).wrapping_add(INITIAL_DELAY),
+        };
+
+        // Hold the next controls for longer if these are late
+        let early = tick.wrapping_add(offset).wrapping_sub(frame) as i32;
+        if early < 0 {
+            offset = offset.wrapping_add(-early as u32);
+            self.slack = 0;
+        } else if self.count == 0 {
+            self.slack = early as u32;
+        } else {
+            self.slack = self.slack.min(early as u32);
+        }
+        // Hold them for less if they were all early for a while
+        self.count += 1;
+        if self.count >= SHRINK_WINDOW {
+            offset = offset.wrapping_sub(self.slack);
+            self.count = 0;
+        }
+        self.offset = Some(offset);
+
+        let pos = self
+            .inputs
+            .iter()
+            .position(|&(t, _)| !before(t, tick))
+            .unwrap_or(self.inputs.len());
+        match self.inputs.get(pos) {
+            Some(&(t, _)) if t == tick => return false,
+            _ => self.inputs.insert(pos, (tick, data)),
+        }
+        if self.inputs.len() > MAX_INPUTS {
+            self.inputs.pop_front();
+        }
+        true
+    }
+
+    /// The controls due by `frame`, with their tick, if any.
+    ///
+    /// If several are due, only the last one is returned.
+    pub fn pop(&mut self, frame: u32) -> Option<(u32, Vec<u8>)> {
+        let offset = self.offset?;
+        let mut due = None;
+        while let Some(&(tick, _)) = self.inputs.front() {
+            if before(frame, tick.wrapping_add(offset)) {
+                break;
+            }
+            due = self.inputs.pop_front();
+        }
+        if let Some((tick, _)) = due {
+            self.last = Some(tick);
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InputBuffer, MAX_INPUTS, SHRINK_WINDOW};
+
+    /// Pops controls on each of these frames, returning their ticks.
+    fn pop(buffer: &mut InputBuffer, frames: &[u32]) -> Vec<Option<u32>> {
+        frames
+            .iter()
+            .map(|&frame| buffer.pop(frame).map(|(tick, _)| tick))
+            .collect()
+    }
+
+    #[test]
+    fn test_steady() {
+        let mut buffer = InputBuffer::default();
+        assert_eq!(buffer.pop(100), None);
+        for tick in 0..5 {
+            assert!(buffer.push(100 + tick, tick, vec![tick as u8]));
+        }
+        // Held for the initial delay
+        assert_eq!(pop(&mut buffer, &[100, 101]), [None, None]);
+        assert_eq!(buffer.pop(102), Some((0, vec![0])));
+        assert_eq!(pop(&mut buffer, &[103, 104]), [Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn test_late() {
+        let mut buffer = InputBuffer::default();
+        buffer.push(100, 0, vec![]);
+        assert_eq!(pop(&mut buffer, &[101, 102]), [None, Some(0)]);
+
+        // Due on frame 103, but 3 frames late: the next ones are held longer
+        buffer.push(106, 1, vec![]);
+        buffer.push(106, 2, vec![]);
+        assert_eq!(
+            pop(&mut buffer, &[105, 106, 107]),
+            [None, Some(1), Some(2)],
+        );
+
+        // If several are due, only the last one is used
+        buffer.push(108, 3, vec![]);
+        buffer.push(108, 4, vec![]);
+        assert_eq!(pop(&mut buffer, &[109, 110]), [Some(4), None]);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut buffer = InputBuffer::default();
+        assert!(buffer.push(100, 5, vec![1]));
+        assert!(!buffer.push(100, 5, vec![2]));
+        assert!(buffer.push(101, 6, vec![]));
+        assert!(!buffer.push(101, 6, vec![]));
+        assert_eq!(buffer.pop(102), Some((5, vec![1])));
+
+        // Controls that were already handed out, or are older
+        assert!(!buffer.push(102, 5, vec![]));
+        assert!(!buffer.push(102, 4, vec![]));
+        assert_eq!(pop(&mut buffer, &[103, 104]), [Some(6), None]);
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let mut buffer = InputBuffer::default();
+        assert!(buffer.push(100, 2, vec![]));
+        assert!(buffer.push(100, 0, vec![]));
+        assert!(buffer.push(100, 1, vec![]));
+        assert_eq!(
+            pop(&mut buffer, &[100, 101, 102]),
+            [Some(0), Some(1), Some(2)],
+        );
+
+        // Too late, a later one was used
+        assert!(buffer.push(103, 4, vec![]));
+        assert_eq!(pop(&mut buffer, &[104]), [Some(4)]);
+        assert!(!buffer.push(104, 3, vec![]));
+    }
+
+    #[test]
+    fn test_wraparound() {
+        let mut buffer = InputBuffer::default();
+        let max = u32::MAX;
+        assert!(buffer.push(5, 0, vec![]));
+        assert!(buffer.push(5, max, vec![]));
+        assert!(buffer.push(5, max - 1, vec![]));
+        assert_eq!(
+            pop(&mut buffer, &[5, 6, 7, 8]),
+            [Some(max - 1), Some(max), Some(0), None],
+        );
+        assert!(!buffer.push(9, max, vec![]));
+    }
+
+    #[test]
+    fn test_shrink() {
+        let mut buffer = InputBuffer::default();
+        for tick in 0..SHRINK_WINDOW {
+            buffer.push(1000 + tick, tick, vec![]);
+            let popped = buffer.pop(1000 + tick).map(|(t, _)| t);
+            if tick < SHRINK_WINDOW - 1 {
+                // Held for the initial delay
+                assert_eq!(popped, tick.checked_sub(2));
+            } else {
+                // They were all early, no need to hold them anymore
+                assert_eq!(popped, Some(tick));
+            }
+        }
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut buffer = InputBuffer::default();
+        for tick in 0..40 {
+            assert!(buffer.push(0, tick, vec![]));
+        }
+        assert_eq!(buffer.inputs.len(), MAX_INPUTS);
+        assert_eq!(buffer.inputs.front().unwrap().0, 40 - MAX_INPUTS as u32);
+    }
+}
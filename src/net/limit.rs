@@ -0,0 +1,211 @@
+//! Limits on the messages the server takes from each address.
+//!
+//! A client flooding the server would hog its receive loop. Each address gets
+//! a budget of `MESSAGE_RATE` messages per second, with bursts of up to
+//! `MESSAGE_BURST`; each packet and each message in it cost one. What comes
+//! over budget is dropped, and an address that keeps going over is an
+//! offender, which the server disconnects. Everything it sends is dropped
+//! until the end of the `OFFENCE_WINDOW`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Messages per second each address can send. Clients send a packet with
+/// their controls each frame, so this is room for a few hundred frames per
+/// second.
+const MESSAGE_RATE: f32 = 600.0;
+
+/// Messages an address can send at once, after being quiet.
+const MESSAGE_BURST: f32 = 1200.0;
+
+/// Messages dropped during an `OFFENCE_WINDOW` past which an address is an
+/// offender.
+const MAX_DROPPED: u32 = 2000;
+
+/// Interval over which dropped messages are counted.
+const OFFENCE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Maximum number of addresses with their own budget; the others share one.
+///
+/// This bounds memory use if the messages come from spoofed addresses.
+const MAX_ADDRESSES: usize = 4096;
+
+/// Budget of one address.
+struct Budget {
+    tokens: f32,
+    last: Instant,
+    /// Messages dropped since the start of the window.
+    dropped: u32,
+    window: Instant,
+}
+
+impl Budget {
+    fn new(now: Instant) -> Budget {
+        Budget {
+            tokens: MESSAGE_BURST,
+            last: now,
+            dropped: 0,
+            window: now,
+        }
+    }
+
+    fn charge(&mut self, cost: u32, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last).as_secs_f32();
+        self.last = now;
+        self.tokens =
+            (self.tokens + elapsed * MESSAGE_RATE).min(MESSAGE_BURST);
+        if now.duration_since(self.window) >= OFFENCE_WINDOW {
+            self.window = now;
+            self.dropped = 0;
+        }
+
+        if self.is_offender() || self.tokens < cost as f32 {
+            self.dropped = self.dropped.saturating_add(cost);
+            false
+        } else {
+            self.tokens -= cost as f32;
+            true
+        }
+    }
+
+    fn is_offender(&self) -> bool {
+        self.dropped > MAX_DROPPED
+    }
+}
+
+/// Message budgets of the addresses the server hears from.
+pub struct RateLimiter<A> {
+    budgets: HashMap<A, Budget>,
+    /// Budget shared by the addresses past `MAX_ADDRESSES`.
+    others: Budget,
+}
+
+impl<A: Clone + Eq + Hash> RateLimiter<A> {
+    pub fn new() -> RateLimiter<A> {
+        RateLimiter {
+            budgets: HashMap::new(),
+            others: Budget::new(Instant::now()),
+        }
+    }
+
+    /// Charges messages to an address, returning whether they are within
+    /// its budget.
+    pub fn charge(&mut self, addr: &A, cost: u32) -> bool {
+        let now = Instant::now();
+        if let Some(budget) = self.budgets.get_mut(addr) {
+            return budget.charge(cost, now);
+        }
+        if self.budgets.len() >= MAX_ADDRESSES {
+            return self.others.charge(cost, now);
+        }
+        let mut budget = Budget::new(now);
+        let allowed = budget.charge(cost, now);
+        self.budgets.insert(addr.clone(), budget);
+        allowed
+    }
+
+    /// Whether an address went over its budget too often, and should be
+    /// disconnected.
+    pub fn is_offender(&self, addr: &A) -> bool {
+        match self.budgets.get(addr) {
+            Some(budget) => budget.is_offender(),
+            None => false,
+        }
+    }
+
+    /// Forgets the addresses that have been quiet for a whole window.
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        self.budgets
+            .retain(|_, b| now.duration_since(b.last) < OFFENCE_WINDOW);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Budget, RateLimiter, MAX_ADDRESSES, MAX_DROPPED,
+                MESSAGE_BURST, MESSAGE_RATE, OFFENCE_WINDOW};
+
+    #[test]
+    fn test_burst() {
+        let now = Instant::now();
+        let mut budget = Budget::new(now);
+        for _ in 0..MESSAGE_BURST as u32 {
+            assert!(budget.charge(1, now));
+        }
+        assert!(!budget.charge(1, now));
+        assert_eq!(budget.dropped, 1);
+        assert!(!budget.is_offender());
+
+        // A batch is allowed whole or not at all
+        let mut budget = Budget::new(now);
+        assert!(budget.charge(MESSAGE_BURST as u32 - 10, now));
+        assert!(!budget.charge(11, now));
+        assert!(budget.charge(10, now));
+    }
+
+    #[test]
+    fn test_refill() {
+        let start = Instant::now();
+        let mut budget = Budget::new(start);
+        assert!(budget.charge(MESSAGE_BURST as u32, start));
+        assert!(!budget.charge(1, start));
+
+        // A tenth of a second gives back a tenth of the rate
+        let later = start + Duration::from_millis(100);
+        let refilled = (MESSAGE_RATE / 10.0) as u32;
+        assert!(budget.charge(refilled - 1, later));
+        assert!(!budget.charge(2, later));
+
+        // But never more than the burst
+        let much_later = later + Duration::from_secs(3600);
+        assert!(budget.charge(MESSAGE_BURST as u32, much_later));
+        assert!(!budget.charge(1, much_later));
+    }
+
+    #[test]
+    fn test_offender() {
+        let start = Instant::now();
+        let mut budget = Budget::new(start);
+        assert!(budget.charge(MESSAGE_BURST as u32, start));
+        assert!(!budget.charge(MAX_DROPPED, start));
+        assert!(!budget.is_offender());
+        assert!(!budget.charge(1, start));
+        assert!(budget.is_offender());
+
+        // Offenders get nothing through, even with tokens
+        let later = start + Duration::from_secs(1);
+        assert!(!budget.charge(1, later));
+
+        // Until the window is over
+        let after = start + OFFENCE_WINDOW;
+        assert!(budget.charge(1, after));
+        assert!(!budget.is_offender());
+    }
+
+    #[test]
+    fn test_isolation() {
+        let mut limiter = RateLimiter::new();
+        assert!(limiter.charge(&1, MESSAGE_BURST as u32));
+        assert!(!limiter.charge(&1, MAX_DROPPED + 1));
+        assert!(limiter.is_offender(&1));
+
+        // Others still have their own budget
+        assert!(limiter.charge(&2, MESSAGE_BURST as u32));
+        assert!(!limiter.is_offender(&2));
+        assert!(!limiter.is_offender(&3));
+
+        // Past MAX_ADDRESSES, the rest share one
+        for addr in 3..=MAX_ADDRESSES as u32 {
+            assert!(limiter.charge(&addr, 1));
+        }
+        let extra = MAX_ADDRESSES as u32 + 1;
+        assert!(limiter.charge(&extra, MESSAGE_BURST as u32));
+        assert!(!limiter.charge(&(extra + 1), 100));
+        // Addresses with their own budget are not affected
+        assert!(limiter.charge(&3, 1));
+    }
+}
@@ -1,27 +1,156 @@
 //! Network code.
 
+mod bans;
 mod base;
+pub mod capture;
+mod clock;
+mod conn;
+#[cfg(feature = "encryption")]
+pub mod crypto;
+mod interpolate;
+mod jitter;
+mod limit;
+mod predict;
+mod quantize;
+mod registry;
+mod stats;
+pub mod tcp;
 pub mod udp;
+mod validate;
 
 use byteorder::{self, ReadBytesExt, WriteBytesExt};
-use log::{info, warn};
-use specs::{Entities, Read, Join, LazyUpdate, ReadStorage, System,
-            WriteStorage};
+use log::{debug, info, warn};
+use rand::random;
+use specs::shrev::ReaderId;
+use specs::{Entities, Read, ReadExpect, Join, LazyUpdate, ReadStorage, System,
+            Write, WriteExpect, WriteStorage};
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
-use std::io::{self, Cursor, Write};
+use std::hash::Hash;
+use std::io::{self, Cursor};
+use std::mem::{self, discriminant};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use vecmath::*;
 
+use crate::achievements::{Achievement, Achievements};
+use crate::ai::Personality;
 use crate::asteroid::Asteroid;
-use crate::guns::{Projectile, ProjectileType};
+use crate::blocks::Blocky;
+use crate::capture::CaptureZone;
+use crate::chat::{sanitize, ChatLimit, ChatLog, MAX_CHAT_LEN};
+use crate::control::{transfer_control, Controller};
+use crate::economy::{OrePickup, Wallet};
+use crate::events::{GameEvent, GameEvents};
+use crate::guns::{Beam, Projectile};
+use crate::hud::LocalPlayer;
+use crate::medium::MediumZone;
+use crate::modes::Mode;
 use crate::particles::Effect;
-use crate::physics::{LocalControl, Position, Velocity};
-use crate::ship::Ship;
+use crate::physics::{CircleCollider, DeltaTime, LocalControl, Position,
+                     Velocity};
+use crate::planet::Planet;
+use crate::players::{sanitize_name, PlayerInfo, Players, MAX_NAME_LEN};
+use crate::profiles::{LocalProfile, Profile, Profiles, Unlocks};
+use crate::rules::{LastMatch, MatchState, Rules};
+use crate::sector::{Backdrop, DistantPlanet, SectorId, SectorManager,
+                    MAX_DISTANT_PLANETS};
+use crate::sensors::can_see;
+use crate::ship::{PlayerClasses, Ship, ShipClass, ShipIntegrity,
+                  WEAPON_GROUPS};
+use crate::station::Station;
+use crate::stats::{MatchSummary, PlayerStats, Scoreboard};
+use crate::teams::Team;
 
-pub use self::base::{Replicated, Delete, Dirty, ClientControlled};
+pub use self::bans::{BanList, Moderation};
+use self::bans::MAX_REASON_LEN;
+pub use self::base::{Replicated, Delete, Dirty, ClientControlled,
+                     ConnectionState, SessionToken};
+pub use self::clock::ServerClock;
+pub use self::interpolate::{Interpolated, SysInterpolation};
+pub use self::stats::{ClientStats, NetworkStats};
+use self::clock::local_time;
+use self::jitter::InputBuffer;
+use self::conn::{compress, seq_newer, split_messages, Connection,
+                  HEADER_LEN, MAX_CLIENT_BATCH_LEN, MAX_PACKET_LEN,
+                  MAX_SERVER_BATCH_LEN};
+use self::limit::RateLimiter;
+use self::predict::Prediction;
+use self::quantize::Quantization;
+use self::registry::{ClientView, Creation, Registry, ServerView};
+use self::stats::{InvalidLog, RateMeter};
+use self::validate::{flies, valid_target, ToggleLimit};
 
 type ORDER = byteorder::BigEndian;
 
+/// Start of every message.
+///
+/// The last two bytes used to be the version of the protocol. They are
+/// frozen now that `PROTOCOL_VERSION` is sent in the hellos: changing them
+/// would keep older versions from reading `IncompatibleVersion`.
+const MAGIC: &[u8] = b"SPAC\x00\x01";
+
+/// Version of the protocol, exchanged in `ClientHello` and `ServerHello`.
+///
+/// It changes with the format of any message, except for the start of the
+/// hellos up to the version and `IncompatibleVersion`, which old and new
+/// versions need to read.
+pub const PROTOCOL_VERSION: u16 = 10;
+
+/// Size of a player's statistics in messages.
+const STATS_LEN: usize = 28;
+
+/// Size of a player's entry in a `MatchSummary` or `Scoreboard` message.
+const SUMMARY_PLAYER_LEN: usize = 8 + STATS_LEN;
+
+/// Maximum number of players in a `MatchSummary` or `Scoreboard` message, so
+/// that it fits in the receive buffer.
+const SUMMARY_MAX_PLAYERS: usize = 28;
+
+/// Size of a distant planet in a `SectorBackdrop` message.
+const DISTANT_PLANET_LEN: usize = 24;
+
+/// Number of entities a client remembers the last update of, past which it
+/// forgets those not heard of in `UPDATE_SEQS_MAX_AGE` packets.
+const UPDATE_SEQS_MAX: usize = 4096;
+
+/// Age of the entries dropped past `UPDATE_SEQS_MAX`, in packets.
+const UPDATE_SEQS_MAX_AGE: u32 = 1 << 20;
+
+/// Priority past which an entity is sent to a client again, see
+/// `update_priority()`. A still entity next to the client's ship gets there
+/// in 200 frames.
+const REFRESH_PRIORITY: f32 = 200.0;
+
+/// Distance from the client's ship at which updates come half as often.
+const PRIORITY_DISTANCE: f32 = 100.0;
+
+/// Speed at which updates come twice as often.
+const PRIORITY_SPEED: f32 = 10.0;
+
+/// Interval between two pings to each client.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time without a pong after which a client is dropped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time without hearing from the server after which a client gives up.
+///
+/// This is shorter than `CLIENT_TIMEOUT`, so that the frontend can reconnect
+/// before the server drops the session (see `GameBuilder::session_token()`).
+const SERVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval between two summaries of the server's network health in the
+/// log.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Frames between two `Scoreboard` messages to the clients.
+const SCOREBOARD_INTERVAL: u32 = 50;
+
+/// Frames between two `MatchState` messages to the clients, if the phase of
+/// the match doesn't change.
+const MATCH_STATE_INTERVAL: u32 = 10;
+
 fn time_encode(d: Duration) -> u32 {
     (d.as_secs() as u32).wrapping_shl(10) | d.subsec_nanos().wrapping_shr(22)
 }
@@ -32,6 +161,24 @@ fn time_decode(b: u32) -> Duration {
     Duration::new(secs, nanos)
 }
 
+/// The current time, from `time_encode()`.
+fn timestamp() -> u32 {
+    time_encode(SystemTime::now().duration_since(UNIX_EPOCH).unwrap())
+}
+
+/// Time since a timestamp from `time_encode()`, in seconds.
+///
+/// Timestamps only keep 22 bits of seconds, so this wraps around.
+fn time_since(b: u32) -> f32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let now = time_encode(now);
+    let mut d = time_decode(now).as_secs_f64() - time_decode(b).as_secs_f64();
+    if d < 0.0 {
+        d += (1u64 << 22) as f64;
+    }
+    d as f32
+}
+
 fn write_float<W: io::Write>(mut writer: W, v: f32) {
     let v = v as f32;
     assert_eq!(
@@ -49,60 +196,300 @@ fn read_float<R: io::Read>(mut reader: R) -> f32 {
     v as f32
 }
 
+/// Writes a ship's team, as 0 for none or the team number plus 1.
+fn write_team<W: io::Write>(mut writer: W, team: Option<&Team>) {
+    let team = match team {
+        Some(t) => t.0 + 1,
+        None => 0,
+    };
+    writer.write_u8(team).unwrap();
+}
+
+fn read_team<R: io::Read>(mut reader: R) -> Option<Team> {
+    match reader.read_u8().unwrap() {
+        0 => None,
+        t => Some(Team(t - 1)),
+    }
+}
+
+/// Reads the state of a control point, after its position.
+fn read_zone<R: io::Read>(mut reader: R, zone: &mut CaptureZone) {
+    zone.radius = read_float(&mut reader);
+    zone.owner = read_team(&mut reader);
+    let capturing = read_team(&mut reader);
+    let progress = read_float(&mut reader);
+    zone.capturing = capturing.map(|t| (t, progress));
+    zone.contested = reader.read_u8().unwrap() != 0;
+}
+
+/// Reads a color, as 3 floats.
+fn read_color<R: io::Read>(mut reader: R) -> [f32; 3] {
+    [
+        read_float(&mut reader),
+        read_float(&mut reader),
+        read_float(&mut reader),
+    ]
+}
+
+fn write_color<W: io::Write>(mut writer: W, color: [f32; 3]) {
+    for &c in &color {
+        write_float(&mut writer, c);
+    }
+}
+
 /// The message exchanged by server and clients.
+#[derive(Debug)]
 enum Message {
-    /// Message sent by a client to introduce itself.
+    /// Message sent by a client to introduce itself, with its protocol
+    /// version, the class of ship it wants, its profile key (0 for none),
+    /// the token of the session it resumes (0 for a new one), and the name
+    /// of the player.
     ///
-    /// The server will reply with ServerHello.
-    ClientHello,
-    /// Message sent by the server to accept a client, and assign it a client
-    /// ID.
-    ServerHello(u64),
+    /// The server will reply with ServerHello, then Profile, or with
+    /// IncompatibleVersion, ServerFull or Kicked.
+    ClientHello(u16, ShipClass, u64, u64, String),
+    /// Message sent by the server to accept a client, with its protocol
+    /// version, and assign it a client ID and a session token. It also gives
+    /// the scales of the fixed-point numbers in entity updates (see
+    /// `quantize.rs`).
+    ServerHello(u16, u64, u64, Quantization),
+    /// Message sent by the server to turn down a client whose version of the
+    /// protocol it doesn't speak, with its own.
+    IncompatibleVersion(u16),
+    /// Message sent by the server to turn down a client because it already
+    /// has as many players as it takes, with that number.
+    ServerFull(u16),
+    /// Message sent by the server to a client it drops or turns down, with
+    /// the reason (see `bans.rs`).
+    Kicked(String),
     /// Ping request, other side should send bytes back as Pong.
     Ping(u32),
-    /// Pong reply, with the bytes from the Ping request.
-    Pong(u32),
+    /// Pong reply, with the bytes from the Ping request and the time it was
+    /// sent (see `clock.rs`).
+    Pong(u32, u32),
     /// Message sent by the server to give the client an entity to
     /// control.
     StartEntityControl(u64),
+    /// Message sent by the server to take an entity away from the client.
+    StopEntityControl(u64),
     /// Entity update, from either side.
     ///
-    /// The server sends full entity updates that the client applies. The
-    /// client sends update to the controls, preceded by its secret.
-    EntityUpdate(u64, Vec<u8>),
+    /// The server sends full entity updates that the client applies,
+    /// starting with the tag of the entity's kind (see `registry.rs`). The
+    /// client sends update to the controls, preceded by its secret. Both
+    /// stamp it with the time it was sent (see `time_encode()`), which
+    /// clients use for interpolation (see `interpolate.rs`), and with their
+    /// tick: the server's frame, or the tick of the client's controls (see
+    /// `predict.rs`).
+    EntityUpdate(u64, u32, u32, Vec<u8>),
     /// Entity deleted, from server.
     EntityDelete(u64),
+    /// Results of the match that just ended, from server.
+    MatchSummary(MatchSummary),
+    /// Statistics of the match in progress, best player first, sent
+    /// regularly by the server.
+    Scoreboard(Vec<(u64, PlayerStats)>),
+    /// Phase of the match and its timer, sent regularly by the server.
+    MatchState(MatchState),
+    /// The player's profile, sent by the server on join and after each
+    /// match.
+    Profile(Profile),
+    /// An achievement the player earned, from server.
+    Achievement(Achievement),
+    /// The player's ore, sent by the server when it changes.
+    Wallet(u32),
+    /// How a sector looks, sent by the server once to each client getting
+    /// near it.
+    SectorBackdrop(SectorId, Backdrop),
+    /// The connection is over, from either side.
+    Disconnect,
+    /// Name of a player, from server, sent to everyone when the player
+    /// joins, and to each client joining for the players already there. An
+    /// empty name means the player left.
+    PlayerInfo(u64, String),
+    /// A chat message, from either side (see `chat.rs`).
+    ///
+    /// Clients send 0 as the player, the server relays it to everyone with
+    /// the ID of the player who said it.
+    Chat(u64, String),
+}
+
+/// Reads the player statistics of a `MatchSummary` or `Scoreboard` message.
+fn read_players(msg: &[u8], name: &str) -> Option<Vec<(u64, PlayerStats)>> {
+    if msg.len() < 10 {
+        debug!("Invalid {} length", name);
+        return None;
+    }
+    let mut rdr = Cursor::new(&msg[8..]);
+    let count = rdr.read_u16::<ORDER>().unwrap() as usize;
+    if msg.len() != 10 + count * SUMMARY_PLAYER_LEN {
+        debug!("Invalid {} length", name);
+        return None;
+    }
+    let mut players = Vec::with_capacity(count);
+    for _ in 0..count {
+        let player = rdr.read_u64::<ORDER>().unwrap();
+        players.push((player, read_stats(&mut rdr)));
+    }
+    Some(players)
+}
+
+/// Reads a player's statistics, `STATS_LEN` bytes.
+fn read_stats<R: io::Read>(mut reader: R) -> PlayerStats {
+    PlayerStats {
+        shots_fired: reader.read_u32::<ORDER>().unwrap(),
+        shots_hit: reader.read_u32::<ORDER>().unwrap(),
+        damage_dealt: read_float(&mut reader),
+        damage_taken: read_float(&mut reader),
+        kills: reader.read_u32::<ORDER>().unwrap(),
+        deaths: reader.read_u32::<ORDER>().unwrap(),
+        largest_ship: reader.read_u32::<ORDER>().unwrap(),
+    }
+}
+
+/// Writes a player's statistics.
+fn write_stats(msg: &mut Vec<u8>, stats: &PlayerStats) {
+    msg.write_u32::<ORDER>(stats.shots_fired).unwrap();
+    msg.write_u32::<ORDER>(stats.shots_hit).unwrap();
+    write_float(&mut *msg, stats.damage_dealt);
+    write_float(&mut *msg, stats.damage_taken);
+    msg.write_u32::<ORDER>(stats.kills).unwrap();
+    msg.write_u32::<ORDER>(stats.deaths).unwrap();
+    msg.write_u32::<ORDER>(stats.largest_ship).unwrap();
+}
+
+/// Writes player statistics, up to `SUMMARY_MAX_PLAYERS` of them.
+fn write_players(msg: &mut Vec<u8>, players: &[(u64, PlayerStats)]) {
+    let players = &players[..players.len().min(SUMMARY_MAX_PLAYERS)];
+    msg.write_u16::<ORDER>(players.len() as u16).unwrap();
+    for &(player, ref stats) in players {
+        msg.write_u64::<ORDER>(player).unwrap();
+        write_stats(msg, stats);
+    }
+    assert_eq!(msg.len(), 10 + players.len() * SUMMARY_PLAYER_LEN);
 }
 
 impl Message {
     /// Parse a message from some bytes.
     fn parse(msg: &[u8]) -> Option<Message> {
-        if msg.len() < 8 || &msg[..6] != b"SPAC\x00\x01" {
+        if msg.len() < 8 || &msg[..6] != MAGIC {
             return None;
         }
         let mut rdr = Cursor::new(&msg[8..]);
         match &msg[6..8] {
             b"hc" => {
-                if msg.len() != 8 {
-                    info!("Invalid ClientHello length");
+                // Other versions can't be read past the version
+                if msg.len() < 8 + 2 {
+                    debug!("Invalid ClientHello length");
+                    return None;
+                }
+                let version = rdr.read_u16::<ORDER>().unwrap();
+                if version != PROTOCOL_VERSION {
+                    return Some(Message::ClientHello(
+                        version,
+                        ShipClass::Fighter,
+                        0,
+                        0,
+                        String::new(),
+                    ));
+                }
+                if msg.len() < 8 + 2 + 1 + 16
+                    || msg.len() > 8 + 2 + 1 + 16 + MAX_NAME_LEN
+                {
+                    debug!("Invalid ClientHello length");
                     None
                 } else {
-                    Some(Message::ClientHello)
+                    let class = match msg[10] {
+                        0 => ShipClass::Fighter,
+                        1 => ShipClass::Scout,
+                        2 => ShipClass::Freighter,
+                        3 => ShipClass::Gunship,
+                        _ => {
+                            debug!("Invalid ship class in ClientHello");
+                            return None;
+                        }
+                    };
+                    rdr.set_position(3);
+                    let key = rdr.read_u64::<ORDER>().unwrap();
+                    let token = rdr.read_u64::<ORDER>().unwrap();
+                    let name = match String::from_utf8(msg[27..].to_vec()) {
+                        Ok(n) => n,
+                        Err(_) => {
+                            debug!("Invalid name in ClientHello");
+                            return None;
+                        }
+                    };
+                    Some(Message::ClientHello(
+                        version, class, key, token, name,
+                    ))
                 }
             }
             b"hs" => {
-                if msg.len() != 8 + 8 {
-                    info!("Invalid ServerHello length");
+                if msg.len() < 8 + 2 {
+                    debug!("Invalid ServerHello length");
+                    return None;
+                }
+                let version = rdr.read_u16::<ORDER>().unwrap();
+                if version != PROTOCOL_VERSION {
+                    let quantization = Quantization::default();
+                    return Some(Message::ServerHello(
+                        version,
+                        0,
+                        0,
+                        quantization,
+                    ));
+                }
+                if msg.len() != 8 + 22 {
+                    debug!("Invalid ServerHello length");
                     None
                 } else {
-                    Some(Message::ServerHello(
-                        rdr.read_u64::<ORDER>().unwrap(),
-                    ))
+                    let id = rdr.read_u64::<ORDER>().unwrap();
+                    let token = rdr.read_u64::<ORDER>().unwrap();
+                    let quantization = Quantization {
+                        pos_scale: rdr.read_u16::<ORDER>().unwrap(),
+                        vel_scale: rdr.read_u16::<ORDER>().unwrap(),
+                    };
+                    if !quantization.is_valid() {
+                        debug!("Invalid scales in ServerHello");
+                        return None;
+                    }
+                    let hello =
+                        Message::ServerHello(version, id, token, quantization);
+                    Some(hello)
+                }
+            }
+            b"iv" => {
+                if msg.len() != 8 + 2 {
+                    debug!("Invalid IncompatibleVersion length");
+                    return None;
+                }
+                let version = rdr.read_u16::<ORDER>().unwrap();
+                Some(Message::IncompatibleVersion(version))
+            }
+            b"sf" => {
+                if msg.len() != 8 + 2 {
+                    debug!("Invalid ServerFull length");
+                    return None;
+                }
+                let max = rdr.read_u16::<ORDER>().unwrap();
+                Some(Message::ServerFull(max))
+            }
+            b"kk" => {
+                if msg.len() > 8 + MAX_REASON_LEN {
+                    debug!("Invalid Kicked length");
+                    return None;
+                }
+                match String::from_utf8(msg[8..].to_vec()) {
+                    Ok(reason) => Some(Message::Kicked(reason)),
+                    Err(_) => {
+                        debug!("Invalid reason in Kicked");
+                        None
+                    }
                 }
             }
             b"pi" => {
                 if msg.len() != 12 {
-                    info!("Invalid Ping length");
+                    debug!("Invalid Ping length");
                     None
                 } else {
                     let buf = rdr.read_u32::<ORDER>().unwrap();
@@ -110,17 +497,18 @@ impl Message {
                 }
             }
             b"po" => {
-                if msg.len() != 12 {
-                    info!("Invalid Pong length");
+                if msg.len() != 16 {
+                    debug!("Invalid Pong length");
                     None
                 } else {
                     let buf = rdr.read_u32::<ORDER>().unwrap();
-                    Some(Message::Pong(buf))
+                    let time = rdr.read_u32::<ORDER>().unwrap();
+                    Some(Message::Pong(buf, time))
                 }
             }
             b"ec" => {
                 if msg.len() != 8 + 8 {
-                    info!("Invalid StartEntityControl length");
+                    debug!("Invalid StartEntityControl length");
                     None
                 } else {
                     Some(Message::StartEntityControl(
@@ -128,20 +516,32 @@ impl Message {
                     ))
                 }
             }
+            b"es" => {
+                if msg.len() != 8 + 8 {
+                    debug!("Invalid StopEntityControl length");
+                    None
+                } else {
+                    Some(Message::StopEntityControl(
+                        rdr.read_u64::<ORDER>().unwrap(),
+                    ))
+                }
+            }
             b"eu" => {
-                if msg.len() < 16 {
-                    info!("Invalid EntityUpdate length");
+                if msg.len() < 24 {
+                    debug!("Invalid EntityUpdate length");
                     None
                 } else {
                     Some(Message::EntityUpdate(
                         rdr.read_u64::<ORDER>().unwrap(),
-                        msg[16..].into(),
+                        rdr.read_u32::<ORDER>().unwrap(),
+                        rdr.read_u32::<ORDER>().unwrap(),
+                        msg[24..].into(),
                     ))
                 }
             }
             b"er" => {
                 if msg.len() != 16 {
-                    info!("Invalid EntityDelete length");
+                    debug!("Invalid EntityDelete length");
                     None
                 } else {
                     Some(Message::EntityDelete(
@@ -149,41 +549,276 @@ impl Message {
                     ))
                 }
             }
+            b"ms" => read_players(msg, "MatchSummary").map(|players| {
+                Message::MatchSummary(MatchSummary { players })
+            }),
+            b"sb" => read_players(msg, "Scoreboard").map(Message::Scoreboard),
+            b"mt" => {
+                if msg.len() != 8 + 5 {
+                    debug!("Invalid MatchState length");
+                    return None;
+                }
+                let phase = rdr.read_u8().unwrap();
+                let time = read_float(&mut rdr);
+                match phase {
+                    0 => Some(MatchState::Warmup(time)),
+                    1 => Some(MatchState::Playing(time)),
+                    2 => Some(MatchState::Finished(time)),
+                    _ => {
+                        debug!("Invalid phase in MatchState");
+                        None
+                    }
+                }.map(Message::MatchState)
+            }
+            b"ac" => {
+                if msg.len() != 8 + 5 {
+                    debug!("Invalid Achievement length");
+                    return None;
+                }
+                let kind = rdr.read_u8().unwrap();
+                let value = rdr.read_u32::<ORDER>().unwrap();
+                match kind {
+                    0 => Some(Achievement::FirstKill),
+                    1 => Some(Achievement::Survivor(value)),
+                    2 => Some(Achievement::Demolition(value)),
+                    _ => {
+                        debug!("Invalid kind in Achievement");
+                        None
+                    }
+                }.map(Message::Achievement)
+            }
+            b"pf" => {
+                if msg.len() != 8 + STATS_LEN + 12 {
+                    debug!("Invalid Profile length");
+                    return None;
+                }
+                Some(Message::Profile(Profile {
+                    stats: read_stats(&mut rdr),
+                    matches: rdr.read_u32::<ORDER>().unwrap(),
+                    wins: rdr.read_u32::<ORDER>().unwrap(),
+                    unlocks: Unlocks(rdr.read_u32::<ORDER>().unwrap()),
+                }))
+            }
+            b"wl" => {
+                if msg.len() != 8 + 4 {
+                    debug!("Invalid Wallet length");
+                    return None;
+                }
+                Some(Message::Wallet(rdr.read_u32::<ORDER>().unwrap()))
+            }
+            b"dc" => {
+                if msg.len() != 8 {
+                    debug!("Invalid Disconnect length");
+                    return None;
+                }
+                Some(Message::Disconnect)
+            }
+            b"pn" => {
+                if msg.len() < 8 + 8 || msg.len() > 8 + 8 + MAX_NAME_LEN {
+                    debug!("Invalid PlayerInfo length");
+                    return None;
+                }
+                let player = rdr.read_u64::<ORDER>().unwrap();
+                match String::from_utf8(msg[16..].to_vec()) {
+                    Ok(name) => Some(Message::PlayerInfo(player, name)),
+                    Err(_) => {
+                        debug!("Invalid name in PlayerInfo");
+                        None
+                    }
+                }
+            }
+            b"ch" => {
+                if msg.len() < 8 + 8 || msg.len() > 8 + 8 + MAX_CHAT_LEN {
+                    debug!("Invalid Chat length");
+                    return None;
+                }
+                let player = rdr.read_u64::<ORDER>().unwrap();
+                match String::from_utf8(msg[16..].to_vec()) {
+                    Ok(text) => Some(Message::Chat(player, text)),
+                    Err(_) => {
+                        debug!("Invalid text in Chat");
+                        None
+                    }
+                }
+            }
+            b"sd" => {
+                if msg.len() < 8 + 33 {
+                    debug!("Invalid SectorBackdrop length");
+                    return None;
+                }
+                let id = SectorId {
+                    x: rdr.read_i32::<ORDER>().unwrap(),
+                    y: rdr.read_i32::<ORDER>().unwrap(),
+                };
+                let sun_pos = [read_float(&mut rdr), read_float(&mut rdr)];
+                let sun_size = read_float(&mut rdr);
+                let sun_color = read_color(&mut rdr);
+                let count = rdr.read_u8().unwrap() as usize;
+                if count > MAX_DISTANT_PLANETS
+                    || msg.len() != 8 + 33 + count * DISTANT_PLANET_LEN
+                {
+                    debug!("Invalid SectorBackdrop length");
+                    return None;
+                }
+                let planets = (0..count)
+                    .map(|_| DistantPlanet {
+                        pos: [read_float(&mut rdr), read_float(&mut rdr)],
+                        size: read_float(&mut rdr),
+                        color: read_color(&mut rdr),
+                    })
+                    .collect();
+                Some(Message::SectorBackdrop(
+                    id,
+                    Backdrop {
+                        sun_pos,
+                        sun_size,
+                        sun_color,
+                        planets,
+                    },
+                ))
+            }
             _ => None,
         }
     }
 
     /// Write a message into a vector of bytes.
     fn to_bytes(&self, msg: &mut Vec<u8>) {
-        msg.extend_from_slice(b"SPAC\x00\x01");
+        msg.extend_from_slice(MAGIC);
         match *self {
-            Message::ClientHello => msg.extend_from_slice(b"hc"),
-            Message::ServerHello(id) => {
+            Message::ClientHello(version, class, key, token, ref name) => {
+                msg.extend_from_slice(b"hc");
+                msg.write_u16::<ORDER>(version).unwrap();
+                msg.push(match class {
+                    ShipClass::Fighter => 0,
+                    ShipClass::Scout => 1,
+                    ShipClass::Freighter => 2,
+                    ShipClass::Gunship => 3,
+                });
+                msg.write_u64::<ORDER>(key).unwrap();
+                msg.write_u64::<ORDER>(token).unwrap();
+                msg.extend_from_slice(name.as_bytes());
+            }
+            Message::ServerHello(version, id, token, quantization) => {
                 msg.extend_from_slice(b"hs");
+                msg.write_u16::<ORDER>(version).unwrap();
                 msg.write_u64::<ORDER>(id).unwrap();
-                assert_eq!(msg.len(), 8 + 8);
+                msg.write_u64::<ORDER>(token).unwrap();
+                msg.write_u16::<ORDER>(quantization.pos_scale).unwrap();
+                msg.write_u16::<ORDER>(quantization.vel_scale).unwrap();
+                assert_eq!(msg.len(), 8 + 22);
+            }
+            Message::IncompatibleVersion(version) => {
+                msg.extend_from_slice(b"iv");
+                msg.write_u16::<ORDER>(version).unwrap();
+            }
+            Message::ServerFull(max) => {
+                msg.extend_from_slice(b"sf");
+                msg.write_u16::<ORDER>(max).unwrap();
+            }
+            Message::Kicked(ref reason) => {
+                msg.extend_from_slice(b"kk");
+                msg.extend_from_slice(reason.as_bytes());
             }
             Message::Ping(buf) => {
                 msg.extend_from_slice(b"pi");
                 msg.write_u32::<ORDER>(buf).unwrap();
             }
-            Message::Pong(buf) => {
+            Message::Pong(buf, time) => {
                 msg.extend_from_slice(b"po");
                 msg.write_u32::<ORDER>(buf).unwrap();
+                msg.write_u32::<ORDER>(time).unwrap();
             }
             Message::StartEntityControl(id) => {
                 msg.extend_from_slice(b"ec");
                 msg.write_u64::<ORDER>(id).unwrap();
             }
-            Message::EntityUpdate(id, ref bytes) => {
+            Message::StopEntityControl(id) => {
+                msg.extend_from_slice(b"es");
+                msg.write_u64::<ORDER>(id).unwrap();
+            }
+            Message::EntityUpdate(id, time, tick, ref bytes) => {
                 msg.extend_from_slice(b"eu");
                 msg.write_u64::<ORDER>(id).unwrap();
+                msg.write_u32::<ORDER>(time).unwrap();
+                msg.write_u32::<ORDER>(tick).unwrap();
                 msg.extend_from_slice(bytes);
             }
             Message::EntityDelete(id) => {
                 msg.extend_from_slice(b"er");
                 msg.write_u64::<ORDER>(id).unwrap();
             }
+            Message::MatchSummary(ref summary) => {
+                msg.extend_from_slice(b"ms");
+                write_players(msg, &summary.players);
+            }
+            Message::Scoreboard(ref players) => {
+                msg.extend_from_slice(b"sb");
+                write_players(msg, players);
+            }
+            Message::MatchState(state) => {
+                msg.extend_from_slice(b"mt");
+                let (phase, time) = match state {
+                    MatchState::Warmup(t) => (0, t),
+                    MatchState::Playing(t) => (1, t),
+                    MatchState::Finished(t) => (2, t),
+                };
+                msg.push(phase);
+                write_float(&mut *msg, time);
+            }
+            Message::Achievement(achievement) => {
+                msg.extend_from_slice(b"ac");
+                let (kind, value) = match achievement {
+                    Achievement::FirstKill => (0, 0),
+                    Achievement::Survivor(waves) => (1, waves),
+                    Achievement::Demolition(blocks) => (2, blocks),
+                };
+                msg.push(kind);
+                msg.write_u32::<ORDER>(value).unwrap();
+            }
+            Message::Profile(ref profile) => {
+                msg.extend_from_slice(b"pf");
+                write_stats(msg, &profile.stats);
+                msg.write_u32::<ORDER>(profile.matches).unwrap();
+                msg.write_u32::<ORDER>(profile.wins).unwrap();
+                msg.write_u32::<ORDER>(profile.unlocks.0).unwrap();
+            }
+            Message::Wallet(ore) => {
+                msg.extend_from_slice(b"wl");
+                msg.write_u32::<ORDER>(ore).unwrap();
+            }
+            Message::Disconnect => msg.extend_from_slice(b"dc"),
+            Message::PlayerInfo(player, ref name) => {
+                msg.extend_from_slice(b"pn");
+                msg.write_u64::<ORDER>(player).unwrap();
+                msg.extend_from_slice(name.as_bytes());
+            }
+            Message::Chat(player, ref text) => {
+                msg.extend_from_slice(b"ch");
+                msg.write_u64::<ORDER>(player).unwrap();
+                msg.extend_from_slice(text.as_bytes());
+            }
+            Message::SectorBackdrop(id, ref backdrop) => {
+                msg.extend_from_slice(b"sd");
+                msg.write_i32::<ORDER>(id.x).unwrap();
+                msg.write_i32::<ORDER>(id.y).unwrap();
+                write_float(&mut *msg, backdrop.sun_pos[0]);
+                write_float(&mut *msg, backdrop.sun_pos[1]);
+                write_float(&mut *msg, backdrop.sun_size);
+                write_color(&mut *msg, backdrop.sun_color);
+                let planets = &backdrop.planets
+                    [..backdrop.planets.len().min(MAX_DISTANT_PLANETS)];
+                msg.push(planets.len() as u8);
+                for planet in planets {
+                    write_float(&mut *msg, planet.pos[0]);
+                    write_float(&mut *msg, planet.pos[1]);
+                    write_float(&mut *msg, planet.size);
+                    write_color(&mut *msg, planet.color);
+                }
+                assert_eq!(
+                    msg.len(),
+                    8 + 33 + planets.len() * DISTANT_PLANET_LEN
+                );
+            }
         }
     }
 
@@ -204,10 +839,15 @@ fn chk<T>(res: Result<T, io::Error>) {
 }
 
 pub trait Server: Send + 'static {
-    type Address: Clone + Display + Eq + Send;
+    type Address: Clone + Display + Eq + Hash + Send;
 
     fn send(&self, msg: &[u8], addr: &Self::Address) -> io::Result<usize>;
     fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, Self::Address)>;
+
+    /// The host an address is on, which bans apply to.
+    fn host(&self, address: &Self::Address) -> String {
+        address.to_string()
+    }
 }
 
 pub trait Client: Send + 'static {
@@ -218,40 +858,326 @@ pub trait Client: Send + 'static {
 pub struct ConnectedClient<A: Eq> {
     address: A,
     client_id: u64,
+    /// Secret the client can reconnect with, to resume its session.
+    token: u64,
+    /// Name of the player.
+    name: String,
     ping: f32,
+    last_ping: SystemTime,
     last_pong: SystemTime,
+    connection: Connection,
+    /// Sequence number of the packet the last controls came in.
+    last_controls: u32,
+    /// Bytes the client can still be sent, if the bandwidth is limited.
+    allowance: f32,
+    last_refill: SystemTime,
+    /// Bytes sent since they were last added to `NetworkStats`.
+    bytes_sent: usize,
+    /// Bytes received from and sent to the client since it connected.
+    total_received: u64,
+    total_sent: u64,
+}
+
+impl<A: Eq> ConnectedClient<A> {
+    /// Queues a message for the client, sending the packet if it is full.
+    fn send<S: Server<Address = A>>(
+        &mut self,
+        server: &S,
+        msg: &[u8],
+    ) -> io::Result<()> {
+        let (bytes, compressed) = compress(msg);
+        if !self.connection.queue(&bytes, compressed)? {
+            self.flush(server)?;
+            // The packet is empty now, so this can't fail
+            let queued = self.connection.queue(&bytes, compressed)?;
+            assert!(queued);
+        }
+        self.allowance -= (2 + bytes.len()) as f32;
+        Ok(())
+    }
+
+    /// Sends the queued messages.
+    fn flush<S: Server<Address = A>>(&mut self, server: &S) -> io::Result<()> {
+        if self.connection.has_queued() {
+            let mut packet = Vec::with_capacity(MAX_PACKET_LEN);
+            self.connection.write_packet(&mut packet);
+            self.allowance -= HEADER_LEN as f32;
+            self.bytes_sent += packet.len();
+            server.send(&packet, &self.address)?;
+        }
+        Ok(())
+    }
+
+    /// Gives back the bytes the client can be sent since the last refill,
+    /// up to a second's worth.
+    fn refill(&mut self, bandwidth: u32, now: SystemTime) {
+        let elapsed = now.duration_since(self.last_refill).unwrap_or_default();
+        self.last_refill = now;
+        self.allowance = (self.allowance
+            + elapsed.as_secs_f32() * bandwidth as f32)
+            .min(bandwidth as f32);
+    }
+}
+
+/// How much a client needs an update of an entity.
+///
+/// This grows with the frames since the client was last sent the entity,
+/// faster for entities close to its ship and those moving fast, which it
+/// extrapolates worse. Entities that changed get a head start.
+fn update_priority(age: u32, distance: f32, speed: f32, dirty: bool) -> f32 {
+    let nearness = PRIORITY_DISTANCE / (PRIORITY_DISTANCE + distance);
+    let motion = 1.0 + speed / PRIORITY_SPEED;
+    let priority = age as f32 * nearness * motion;
+    if dirty {
+        priority + REFRESH_PRIORITY
+    } else {
+        priority
+    }
+}
+
+/// An entity update waiting to be sent to a client.
+struct QueuedUpdate {
+    client_id: u64,
+    entity_id: u64,
+    /// Whether it can't be put off, see `SysNetServer`.
+    urgent: bool,
+    priority: f32,
+    /// Index of the message in the frame's updates.
+    index: usize,
 }
 
 /// Network server system.
 ///
 /// Gets controls from clients and sends game updates.
+///
+/// Each client is sent the entities that changed, and the others from time
+/// to time, more often when they are close to its ship or moving fast (see
+/// `update_priority()`). The most important updates go first. If the
+/// bandwidth to each client is limited, the updates that don't fit are put
+/// off to later frames, except those of the client's own ship, of entities
+/// it just started seeing, and when it just joined.
 pub struct SysNetServer<S: Server> {
     server: S,
     frame: u32,
     next_client: u64,
     clients: HashMap<u64, ConnectedClient<S::Address>>,
+    /// Which entities each client has been told it controls, as pairs of
+    /// client ID and entity ID.
+    controls: HashSet<(u64, u64)>,
+    /// Entities hidden from each client, as pairs of client ID and entity
+    /// ID: those away from the sectors around its ship, and the ships it
+    /// can't see (see `sensors.rs`). The client was told to delete them, and
+    /// gets them again once they are relevant.
+    hidden: HashSet<(u64, u64)>,
+    /// Clients that joined this frame, which get all the entities they can
+    /// see rather than only those that changed.
+    joining: HashSet<u64>,
+    /// Bytes per second each client can be sent, if limited.
+    bandwidth: Option<u32>,
+    /// Number of clients past which new ones are turned down, if limited.
+    max_clients: Option<usize>,
+    /// Entity updates put off because a client was over its bandwidth, as
+    /// pairs of client ID and entity ID. They are sent on a later frame.
+    deferred: HashSet<(u64, u64)>,
+    /// Frame each entity was last sent to each client on, by pairs of client
+    /// ID and entity ID.
+    sent_frames: HashMap<(u64, u64), u32>,
+    /// Reader for `GameEvents`, registered on the first run.
+    events: Option<ReaderId<GameEvent>>,
+    /// Frame the scoreboard was last sent on.
+    last_scoreboard: u32,
+    /// Match state last sent, and the frame it was sent on.
+    last_match_state: (MatchState, u32),
+    /// Ore last sent to each client.
+    last_wallets: HashMap<u64, u32>,
+    /// Sectors whose backdrop was sent to each client.
+    sent_backdrops: HashSet<(u64, SectorId)>,
+    /// Controls received from each client for each ship, waiting for their
+    /// frame, by pairs of client ID and entity ID (see `jitter.rs`).
+    inputs: HashMap<(u64, u64), InputBuffer>,
+    /// How fast each client toggles the controls of each ship, by pairs of
+    /// client ID and entity ID (see `validate.rs`).
+    toggles: HashMap<(u64, u64), ToggleLimit>,
+    /// Tick of the last controls applied to each ship, by entity ID.
+    received_ticks: HashMap<u64, u32>,
+    /// Tick of the last controls simulated for each ship, sent back to
+    /// clients for their prediction (see `predict.rs`).
+    acked_ticks: HashMap<u64, u32>,
+    /// Scales of the fixed-point numbers in entity updates.
+    quantization: Quantization,
+    /// How the replicated entities are written.
+    registry: Registry,
+    invalid: InvalidLog<S::Address>,
+    limiter: RateLimiter<S::Address>,
+    chat_limit: ChatLimit,
+    meter: RateMeter,
+    /// When the network health was last logged.
+    last_health: SystemTime,
 }
 
 impl<S: Server> SysNetServer<S> {
     /// Create a server, listening on the given port.
-    pub fn new(server: S) -> SysNetServer<S> {
+    ///
+    /// If `bandwidth` is set, each client is sent about that many bytes per
+    /// second at most. If `max_clients` is set, clients trying to join once
+    /// there are that many are told the server is full.
+    pub fn new(
+        server: S,
+        bandwidth: Option<u32>,
+        max_clients: Option<usize>,
+    ) -> SysNetServer<S> {
         SysNetServer {
             server,
             frame: 0,
             next_client: 1,
             clients: HashMap::new(),
+            controls: HashSet::new(),
+            hidden: HashSet::new(),
+            joining: HashSet::new(),
+            bandwidth,
+            max_clients,
+            deferred: HashSet::new(),
+            sent_frames: HashMap::new(),
+            events: None,
+            last_scoreboard: 0,
+            last_match_state: (MatchState::default(), 0),
+            last_wallets: HashMap::new(),
+            sent_backdrops: HashSet::new(),
+            inputs: HashMap::new(),
+            toggles: HashMap::new(),
+            received_ticks: HashMap::new(),
+            acked_ticks: HashMap::new(),
+            quantization: Quantization::default(),
+            registry: Registry::default(),
+            invalid: InvalidLog::new(),
+            limiter: RateLimiter::new(),
+            chat_limit: ChatLimit::default(),
+            meter: RateMeter::new(),
+            last_health: SystemTime::now(),
+        }
+    }
+
+    /// Keeps the first player IDs for the local players of a host.
+    pub fn local_players(mut self, players: usize) -> SysNetServer<S> {
+        self.next_client = (players as u64).max(1);
+        self
+    }
+
+    /// Queues a message for a client.
+    fn send(&mut self, msg: &Message, client_id: u64) -> io::Result<()> {
+        match self.clients.get_mut(&client_id) {
+            Some(client) => client.send(&self.server, &msg.bytes()),
+            None => Ok(()),
+        }
+    }
+
+    /// Turns down a client, telling it why.
+    ///
+    /// It isn't connected, so this goes in a packet of its own.
+    fn reject(
+        &self,
+        address: &S::Address,
+        message: &Message,
+        stats: &mut NetworkStats,
+    ) {
+        let mut connection = Connection::default();
+        let msg = message.bytes();
+        let (bytes, compressed) = compress(&msg);
+        if let Err(e) = connection.queue(&bytes, compressed) {
+            warn!("Network error: {}", e);
+            return;
+        }
+        let mut packet = Vec::with_capacity(MAX_PACKET_LEN);
+        connection.write_packet(&mut packet);
+        stats.bytes_sent += packet.len() as u64;
+        chk(self.server.send(&packet, address));
+    }
+
+    /// Reattaches a session to the new connection of its client.
+    ///
+    /// The client starts over, so it is sent everything again: the
+    /// entities, control of its ship, its profile, ore and backdrops.
+    fn resume(
+        &mut self,
+        client_id: u64,
+        address: S::Address,
+        header: &[u8],
+        stats: &mut NetworkStats,
+        profile: Option<&Profile>,
+        players: &Players,
+    ) {
+        let now = SystemTime::now();
+        let client = self.clients.get_mut(&client_id).unwrap();
+        client.address = address;
+        client.connection = Connection::default();
+        client.connection.read_header(header, stats);
+        client.last_ping = now;
+        client.last_pong = now;
+        client.last_controls = 0;
+        client.allowance = 0.0;
+        client.last_refill = now;
+        let token = client.token;
+
+        // Its ticks start over too
+        for &(c, id) in &self.controls {
+            if c == client_id {
+                self.received_ticks.remove(&id);
+                self.acked_ticks.remove(&id);
+            }
         }
+        self.controls.retain(|&(c, _)| c != client_id);
+        self.inputs.retain(|&(c, _), _| c != client_id);
+        self.toggles.retain(|&(c, _), _| c != client_id);
+        self.joining.insert(client_id);
+        self.deferred.retain(|&(c, _)| c != client_id);
+        self.sent_frames.retain(|&(c, _), _| c != client_id);
+        self.last_wallets.remove(&client_id);
+        self.sent_backdrops.retain(|&(c, _)| c != client_id);
+
+        let message = Message::ServerHello(
+            PROTOCOL_VERSION,
+            client_id,
+            token,
+            self.quantization,
+        );
+        chk(self.send(&message, client_id));
+        if let Some(profile) = profile {
+            chk(self.send(&Message::Profile(profile.clone()), client_id));
+        }
+        self.send_players(players, client_id);
+        let d = time_encode(now.duration_since(UNIX_EPOCH).unwrap());
+        chk(self.send(&Message::Ping(d), client_id));
     }
 
-    /// Sends a message.
-    fn send(&self, msg: &Message, addr: &S::Address) -> io::Result<usize> {
-        self.server.send(&msg.bytes(), addr)
+    /// Sends a client the names of all the players.
+    fn send_players(&mut self, players: &Players, client_id: u64) {
+        for (&player, info) in &players.players {
+            let message = Message::PlayerInfo(player, info.name.clone());
+            chk(self.send(&message, client_id));
+        }
+    }
+
+    /// Sends everyone the name of a player, empty if they left.
+    fn broadcast_player(&mut self, player: u64, name: &str) {
+        let message = Message::PlayerInfo(player, name.to_owned()).bytes();
+        for client in self.clients.values_mut() {
+            chk(client.send(&self.server, &message));
+        }
     }
 }
 
 impl<'a, S: Server> System<'a> for SysNetServer<S> {
     type SystemData = (
         Read<'a, LazyUpdate>,
+        Write<'a, GameEvents>,
+        Write<'a, NetworkStats>,
+        Write<'a, PlayerClasses>,
+        Read<'a, Scoreboard>,
+        Read<'a, MatchState>,
+        Write<'a, Profiles>,
+        Read<'a, Wallet>,
+        Read<'a, SectorManager>,
+        ReadExpect<'a, Mode>,
         Entities<'a>,
         ReadStorage<'a, ClientControlled>,
         WriteStorage<'a, Replicated>,
@@ -260,15 +1186,40 @@ impl<'a, S: Server> System<'a> for SysNetServer<S> {
         ReadStorage<'a, Position>,
         ReadStorage<'a, Velocity>,
         WriteStorage<'a, Ship>,
+        ReadStorage<'a, ShipIntegrity>,
         ReadStorage<'a, Asteroid>,
         ReadStorage<'a, Projectile>,
+        ReadStorage<'a, MediumZone>,
+        ReadStorage<'a, Beam>,
         ReadStorage<'a, Effect>,
+        ReadStorage<'a, Team>,
+        (
+            ReadStorage<'a, CaptureZone>,
+            ReadStorage<'a, OrePickup>,
+            ReadStorage<'a, Station>,
+            ReadStorage<'a, Planet>,
+            ReadStorage<'a, CircleCollider>,
+            ReadStorage<'a, Blocky>,
+            Write<'a, ChatLog>,
+            Write<'a, Players>,
+            Write<'a, Moderation>,
+            Read<'a, Rules>,
+        ),
     );
 
     fn run(
         &mut self,
         (
             lazy,
+            mut events,
+            mut stats,
+            mut classes,
+            scoreboard,
+            match_state,
+            mut profiles,
+            wallet,
+            sectors,
+            mode,
             entities,
             ctrl,
             mut replicated,
@@ -277,16 +1228,43 @@ impl<'a, S: Server> System<'a> for SysNetServer<S> {
             position,
             velocity,
             mut ship,
+            integrity,
             asteroid,
             projectile,
+            medium,
+            beam,
             effects,
+            team,
+            (
+                capture,
+                ore,
+                station,
+                planet,
+                circle,
+                blocky,
+                mut chat,
+                mut players,
+                mut moderation,
+                rules,
+            ),
         ): Self::SystemData,
     ) {
         self.frame = self.frame.wrapping_add(1);
 
+        // The controls received last frame have been simulated since
+        self.acked_ticks.extend(self.received_ticks.drain());
+
+        if let Some(bandwidth) = self.bandwidth {
+            let now = SystemTime::now();
+            for client in self.clients.values_mut() {
+                client.refill(bandwidth, now);
+            }
+        }
+
         // Receive messages
         let mut messages = Vec::new();
-        let mut buffer = [0; 1024];
+        let mut dropped = Vec::new();
+        let mut buffer = [0; MAX_PACKET_LEN];
         loop {
             let (len, src) = match self.server.recv(&mut buffer) {
                 Ok(r) => r,
@@ -297,100 +1275,435 @@ impl<'a, S: Server> System<'a> for SysNetServer<S> {
                     break;
                 }
             };
-            if len < 8 + 8 {
-                info!("Invalid message from {}", src);
+            stats.bytes_received += len as u64;
+            if !self.limiter.charge(&src, 1) {
+                stats.rate_limited += 1;
+                continue;
+            }
+            if len < 8 + HEADER_LEN {
+                stats.messages_received += 1;
+                self.invalid.record(&src, &mut stats);
                 continue;
             }
             let client_id = (&buffer[0..]).read_u64::<ORDER>().unwrap();
-
-            if let Some(msg) = Message::parse(&buffer[8..len]) {
-                match msg {
-                    Message::ClientHello => {
-                        warn!("Got ClientHello from {}", src);
-
-                        // Create a client
-                        let client_id = self.next_client;
-                        self.next_client += 1;
-                        let now = SystemTime::now();
-                        self.clients.insert(
-                            client_id,
-                            ConnectedClient {
-                                address: src.clone(),
-                                client_id: client_id,
-                                ping: 0.0,
-                                last_pong: now,
-                            },
-                        );
-
-                        // Send ServerHello
-                        chk(self.send(&Message::ServerHello(client_id), &src));
-
-                        // Create a ship for the new player
-                        let newship = Ship::create(&entities, &lazy);
-                        lazy.insert(
-                            newship,
-                            ClientControlled {
-                                client_id: client_id,
-                            },
-                        );
-                        let ship_id = (newship.gen().id() as u64) << 32
-                            | newship.id() as u64;
-                        chk(self.send(
-                            &Message::StartEntityControl(ship_id),
-                            &src,
-                        ));
-
-                        warn!(
-                            "Created Ship {} for new client {}",
-                            ship_id, client_id
-                        );
-
-                        // Send initial Ping message
-                        let d = now.duration_since(UNIX_EPOCH).unwrap();
-                        let d = time_encode(d);
-                        chk(self.send(&Message::Ping(d), &src));
-                    }
-                    Message::Ping(buf) => {
-                        chk(self.send(&Message::Pong(buf), &src))
-                    }
-                    Message::Pong(_) | Message::EntityUpdate(_, _) => {
-                        messages.push((client_id, msg))
+            let header = &buffer[8..8 + HEADER_LEN];
+            let seq = match self.clients.get_mut(&client_id) {
+                // Only take a client's packets from its own address, the ID
+                // isn't a secret. A client that moved resumes its session
+                // with a new hello
+                Some(client) if client.address != src => {
+                    stats.messages_received += 1;
+                    self.invalid.record(&src, &mut stats);
+                    continue;
+                }
+                Some(client) => {
+                    client.total_received += len as u64;
+                    match client.connection.read_header(header, &mut stats) {
+                        Some(seq) => seq,
+                        None => {
+                            stats.stale_messages += 1;
+                            continue;
+                        }
                     }
-                    Message::ServerHello(_)
-                    | Message::StartEntityControl(_)
-                    | Message::EntityDelete(_) => {
-                        info!("Invalid message from {}", src)
+                }
+                None => 0,
+            };
+
+            let batch = match split_messages(
+                &buffer[8 + HEADER_LEN..len],
+                MAX_CLIENT_BATCH_LEN,
+            ) {
+                Some(b) => b,
+                None => {
+                    stats.messages_received += 1;
+                    self.invalid.record(&src, &mut stats);
+                    continue;
+                }
+            };
+            for body in batch {
+                stats.messages_received += 1;
+                if !self.limiter.charge(&src, 1) {
+                    stats.rate_limited += 1;
+                    continue;
+                }
+                if let Some(msg) = Message::parse(&body) {
+                    match msg {
+                        Message::ClientHello(version, _, _, _, _)
+                            if version != PROTOCOL_VERSION =>
+                        {
+                            warn!(
+                                "Client {} speaks protocol version {}, \
+                                 turning it down",
+                                src, version
+                            );
+                            let message =
+                                Message::IncompatibleVersion(PROTOCOL_VERSION);
+                            self.reject(&src, &message, &mut stats);
+                        }
+                        Message::ClientHello(_, class, key, token, name) => {
+                            let host = self.server.host(&src);
+                            if let Some(reason) =
+                                moderation.bans.reason(&host, token)
+                            {
+                                warn!("Turning down banned client {}", src);
+                                let message =
+                                    Message::Kicked(reason.to_owned());
+                                self.reject(&src, &message, &mut stats);
+                                continue;
+                            }
+                            // Resume the session, if it's still there
+                            let resumed = self
+                                .clients
+                                .values()
+                                .find(|c| token != 0 && c.token == token)
+                                .map(|c| c.client_id);
+                            if let Some(client_id) = resumed {
+                                warn!(
+                                    "Client {} reconnected from {}",
+                                    client_id, src
+                                );
+                                let profile = profiles.get(client_id);
+                                self.resume(
+                                    client_id,
+                                    src.clone(),
+                                    header,
+                                    &mut stats,
+                                    profile,
+                                    &players,
+                                );
+                                continue;
+                            }
+                            if let Some(max) = self.max_clients {
+                                if self.clients.len() >= max {
+                                    warn!(
+                                        "Server full, turning down {}",
+                                        src
+                                    );
+                                    let max = max.min(u16::MAX as usize);
+                                    let message =
+                                        Message::ServerFull(max as u16);
+                                    self.reject(&src, &message, &mut stats);
+                                    continue;
+                                }
+                            }
+                            warn!("Got ClientHello from {}", src);
+
+                            // Create a client
+                            let client_id = self.next_client;
+                            self.next_client += 1;
+                            let token = random::<u64>().max(1);
+                            let name =
+                                sanitize_name(&name).unwrap_or_else(|| {
+                                    format!("Player {}", client_id)
+                                });
+                            let now = SystemTime::now();
+                            let mut connection = Connection::default();
+                            connection.read_header(header, &mut stats);
+                            self.clients.insert(
+                                client_id,
+                                ConnectedClient {
+                                    address: src.clone(),
+                                    client_id: client_id,
+                                    token,
+                                    name: name.clone(),
+                                    ping: 0.0,
+                                    last_ping: now,
+                                    last_pong: now,
+                                    connection,
+                                    last_controls: 0,
+                                    allowance: 0.0,
+                                    last_refill: now,
+                                    bytes_sent: 0,
+                                    total_received: 0,
+                                    total_sent: 0,
+                                },
+                            );
+
+                            // Send ServerHello, then the player's profile
+                            let message = Message::ServerHello(
+                                PROTOCOL_VERSION,
+                                client_id,
+                                token,
+                                self.quantization,
+                            );
+                            chk(self.send(&message, client_id));
+                            let profile = profiles.join(client_id, key);
+                            let message = Message::Profile(profile.clone());
+                            chk(self.send(&message, client_id));
+                            events.single_write(GameEvent::PlayerJoined {
+                                player: client_id,
+                            });
+                            self.joining.insert(client_id);
+
+                            // Tell everyone the name of the new player, and
+                            // tell them everyone else's
+                            self.send_players(&players, client_id);
+                            self.broadcast_player(client_id, &name);
+                            players
+                                .players
+                                .insert(client_id, PlayerInfo { name });
+
+                            // Create a ship for the new player, of a class
+                            // they unlocked
+                            let class = if profile.can_fly(class) {
+                                class
+                            } else {
+                                warn!(
+                                    "Client {} can't fly {:?}",
+                                    client_id, class
+                                );
+                                ShipClass::Fighter
+                            };
+                            classes.0.insert(client_id, class);
+                            let newship =
+                                Ship::create(&entities, &lazy, class);
+                            lazy.insert(
+                                newship,
+                                ClientControlled {
+                                    client_id: client_id,
+                                },
+                            );
+                            let mode = mode.clone();
+                            lazy.exec(move |world| {
+                                mode.0.player_joined(world, client_id, newship)
+                            });
+                            let ship_id = (newship.gen().id() as u64) << 32
+                                | newship.id() as u64;
+
+                            warn!(
+                                "Created Ship {} for new client {}",
+                                ship_id, client_id
+                            );
+
+                            // Send initial Ping message
+                            let d = now.duration_since(UNIX_EPOCH).unwrap();
+                            let d = time_encode(d);
+                            chk(self.send(&Message::Ping(d), client_id));
+                        }
+                        Message::Ping(buf) => {
+                            let message = Message::Pong(buf, timestamp());
+                            chk(self.send(&message, client_id))
+                        }
+                        Message::Pong(_, _) => {
+                            messages.push((client_id, msg))
+                        }
+                        Message::Disconnect => {
+                            if self.clients.contains_key(&client_id) {
+                                warn!("Client {} disconnected", client_id);
+                                dropped.push(client_id);
+                            }
+                        }
+                        Message::Chat(_, text) => {
+                            let name = match self.clients.get(&client_id) {
+                                Some(client) => client.name.clone(),
+                                None => continue,
+                            };
+                            if !self.chat_limit.allow(client_id) {
+                                debug!("Client {} chats too fast", client_id);
+                                continue;
+                            }
+                            let text = match sanitize(&text) {
+                                Some(t) => t,
+                                None => continue,
+                            };
+                            info!("<{}> {}", name, text);
+                            let message =
+                                Message::Chat(client_id, text.clone()).bytes();
+                            for client in self.clients.values_mut() {
+                                chk(client.send(&self.server, &message));
+                            }
+                            chat.push(client_id, text);
+                        }
+                        Message::EntityUpdate(id, _, tick, data) => {
+                            // Drop controls older than the last ones
+                            let client =
+                                match self.clients.get_mut(&client_id) {
+                                    Some(c) => c,
+                                    None => continue,
+                                };
+                            if seq_newer(client.last_controls, seq) {
+                                stats.stale_messages += 1;
+                                continue;
+                            }
+                            client.last_controls = seq;
+                            // Only for the ships it flies
+                            if !flies(&self.controls, client_id, id) {
+                                debug!(
+                                    "Client {} sent controls for entity {} \
+                                     it doesn't control",
+                                    client_id, id
+                                );
+                                self.invalid.record(&src, &mut stats);
+                                continue;
+                            }
+                            if data.len() != 10 {
+                                self.invalid.record(&src, &mut stats);
+                                continue;
+                            }
+                            // Hold them until their tick
+                            let pushed = self
+                                .inputs
+                                .entry((client_id, id))
+                                .or_default()
+                                .push(self.frame, tick, data);
+                            if !pushed {
+                                stats.stale_messages += 1;
+                            }
+                        }
+                        Message::ServerHello(_, _, _, _)
+                        | Message::IncompatibleVersion(_)
+                        | Message::ServerFull(_)
+                        | Message::Kicked(_)
+                        | Message::PlayerInfo(_, _)
+                        | Message::StartEntityControl(_)
+                        | Message::StopEntityControl(_)
+                        | Message::EntityDelete(_)
+                        | Message::MatchSummary(_)
+                        | Message::Scoreboard(_)
+                        | Message::MatchState(_)
+                        | Message::Profile(_)
+                        | Message::Achievement(_)
+                        | Message::Wallet(_)
+                        | Message::SectorBackdrop(_, _) => {
+                            self.invalid.record(&src, &mut stats)
+                        }
                     }
+                } else {
+                    self.invalid.record(&src, &mut stats);
                 }
-            } else {
-                info!("Invalid message from {}", src);
-                continue;
             }
         }
+        self.invalid.report();
+
+        // Disconnect the clients flooding us
+        for client in self.clients.values_mut() {
+            if self.limiter.is_offender(&client.address) {
+                warn!("Client {} is flooding us", client.client_id);
+                chk(client.send(&self.server, &Message::Disconnect.bytes()));
+                chk(client.flush(&self.server));
+                dropped.push(client.client_id);
+            }
+        }
+        self.limiter.prune();
+
+        // Kick the clients the operator asked us to
+        let kicks = mem::take(&mut moderation.kicks);
+        for kick in kicks {
+            let client = match self.clients.get_mut(&kick.client_id) {
+                Some(c) => c,
+                None => continue,
+            };
+            warn!("Kicking client {}: {}", kick.client_id, kick.reason);
+            let message = Message::Kicked(kick.reason.clone()).bytes();
+            chk(client.send(&self.server, &message));
+            chk(client.flush(&self.server));
+            if kick.ban {
+                let host = self.server.host(&client.address);
+                moderation.bans.ban(host, client.token, &kick.reason);
+            }
+            dropped.push(kick.client_id);
+        }
+
+        // Relay what the local player says, on hosts
+        for text in mem::take(&mut chat.outgoing) {
+            let text = match sanitize(&text) {
+                Some(t) => t,
+                None => continue,
+            };
+            info!("<{}> {}", players.name(0), text);
+            let message = Message::Chat(0, text.clone()).bytes();
+            for client in self.clients.values_mut() {
+                chk(client.send(&self.server, &message));
+            }
+            chat.push(0, text);
+        }
 
-        // Handle Pong from clients
+        // Handle Pong from clients, and ping them
+        let now = SystemTime::now();
         for client in self.clients.values_mut() {
             for &(ref client_id, ref msg) in &messages {
                 if client_id != &client.client_id {
                     continue;
                 }
 
-                if let Message::Pong(d) = *msg {
-                    let d = time_decode(d);
-                    let now = SystemTime::now();
-                    let now_d = now.duration_since(UNIX_EPOCH).unwrap();
-                    if let Some(d) = now_d.checked_sub(d) {
-                        client.last_pong = now;
-                        client.ping = d.as_secs() as f32
-                            + d.subsec_nanos() as f32 / 0.000_000_001;
-                    }
+                if let Message::Pong(d, _) = *msg {
+                    client.last_pong = SystemTime::now();
+                    client.ping = time_since(d);
                 }
             }
 
-            // TODO: Drop old clients
+            if now.duration_since(client.last_ping).unwrap_or_default()
+                >= PING_INTERVAL
+            {
+                client.last_ping = now;
+                let d = time_encode(now.duration_since(UNIX_EPOCH).unwrap());
+                let message = Message::Ping(d).bytes();
+                chk(client.send(&self.server, &message));
+            }
+            if now.duration_since(client.last_pong).unwrap_or_default()
+                > CLIENT_TIMEOUT
+            {
+                warn!(
+                    "Client {} ({}) timed out",
+                    client.client_id, client.name
+                );
+                chk(client.send(&self.server, &Message::Disconnect.bytes()));
+                chk(client.flush(&self.server));
+                dropped.push(client.client_id);
+            }
         }
 
-        // Go over entities, send updates
+        // Drop the clients that left or stopped answering, and their ships
+        // (unless the computer takes those over)
+        dropped.sort();
+        dropped.dedup();
+        for client_id in dropped {
+            if let Some(client) = self.clients.remove(&client_id) {
+                stats.bytes_sent += client.bytes_sent as u64;
+            }
+            players.players.remove(&client_id);
+            self.broadcast_player(client_id, "");
+            self.controls.retain(|&(c, _)| c != client_id);
+            self.inputs.retain(|&(c, _), _| c != client_id);
+            self.toggles.retain(|&(c, _), _| c != client_id);
+            self.joining.remove(&client_id);
+            self.hidden.retain(|&(c, _)| c != client_id);
+            self.deferred.retain(|&(c, _)| c != client_id);
+            self.sent_frames.retain(|&(c, _), _| c != client_id);
+            self.chat_limit.forget(client_id);
+            self.last_wallets.remove(&client_id);
+            self.sent_backdrops.retain(|&(c, _)| c != client_id);
+            for (ent, _) in (&*entities, &ctrl)
+                .join()
+                .filter(|&(_, c)| c.client_id == client_id)
+            {
+                if rules.ai_takeover && ship.get(ent).is_some() {
+                    let ai = Controller::Ai(Personality::Interceptor);
+                    transfer_control(&lazy, ent, Some(ai));
+                } else {
+                    lazy.remove::<ClientControlled>(ent);
+                    lazy.insert(ent, Delete);
+                }
+            }
+            events.single_write(GameEvent::PlayerLeft { player: client_id });
+        }
+
+        // Where each client sees from, clients without a ship see everything
+        let viewers = (&position, &ctrl)
+            .join()
+            .map(|(pos, ctrl)| (ctrl.client_id, pos.pos))
+            .collect::<HashMap<_, _>>();
+        let zones = (&position, &medium)
+            .join()
+            .map(|(pos, zone)| (pos.pos, zone.clone()))
+            .collect::<Vec<_>>();
+
+        // Go over entities, queue updates
+        let now = time_encode(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+        );
+        let mut updates = Vec::new();
+        let mut queued = Vec::new();
         for (ent, mut repli) in (&*entities, &mut replicated).join() {
             // Assign replicated object ID
             if repli.id == 0 {
@@ -401,76 +1714,256 @@ impl<'a, S: Server> System<'a> for SysNetServer<S> {
             if delete.get(ent).is_some() {
                 let message = Message::EntityDelete(repli.id).bytes();
                 for client in self.clients.values_mut() {
-                    chk(self.server.send(&message, &client.address));
+                    chk(client.send(&self.server, &message));
                 }
+                let id = repli.id;
+                self.hidden.retain(|&(_, e)| e != id);
+                self.deferred.retain(|&(_, e)| e != id);
+                self.sent_frames.retain(|&(_, e), _| e != id);
+                self.acked_ticks.remove(&id);
                 entities.delete(ent).unwrap();
                 continue;
             }
 
-            // Send an update if dirty, or if it hasn't been updated in a while
-            if dirty.get(ent).is_none()
-                && self.frame.wrapping_sub(repli.last_update) < 200
+            // Only send clients the entities in the sector their ship is in
+            // and the ones next to it, and the ships they can see; send them
+            // again when they become relevant
+            let mut revealed = false;
+            let owner = ctrl.get(ent).map(|c| c.client_id);
+            if let Some(pos) = position.get(ent) {
+                let pos = pos.pos;
+                let sector = SectorId::at(pos);
+                let is_ship = ship.get(ent).is_some();
+                for client in self.clients.values_mut() {
+                    let visible = owner == Some(client.client_id)
+                        || match viewers.get(&client.client_id) {
+                            Some(&v) => {
+                                SectorId::at(v).is_near(sector)
+                                    && (!is_ship || can_see(v, pos, &zones))
+                            }
+                            None => true,
+                        };
+                    let key = (client.client_id, repli.id);
+                    if visible {
+                        revealed |= self.hidden.remove(&key);
+                    } else if self.hidden.insert(key) {
+                        self.sent_frames.remove(&key);
+                        let message = Message::EntityDelete(repli.id).bytes();
+                        chk(client.send(&self.server, &message));
+                    }
+                }
+            }
+
+            // Pick the clients that need an update: those that just joined
+            // or started seeing it, those it was put off for, and those it
+            // got important enough for (see `update_priority()`)
+            let id = repli.id;
+            let is_dirty = dirty.get(ent).is_some();
+            let pos = position.get(ent).map(|p| p.pos);
+            let speed = velocity.get(ent).map_or(0.0, |v| vec2_len(v.vel));
+            let mut recipients = Vec::new();
+            for &client_id in self.clients.keys() {
+                let key = (client_id, id);
+                if self.hidden.contains(&key) {
+                    continue;
+                }
+                let urgent = self.joining.contains(&client_id)
+                    || revealed
+                    || owner == Some(client_id);
+                let age = match self.sent_frames.get(&key) {
+                    Some(&frame) => self.frame.wrapping_sub(frame),
+                    None => u32::MAX,
+                };
+                let distance = match (pos, viewers.get(&client_id)) {
+                    (Some(p), Some(&v)) => vec2_len(vec2_sub(p, v)),
+                    _ => 0.0,
+                };
+                let priority = update_priority(age, distance, speed, is_dirty);
+                if urgent
+                    || priority >= REFRESH_PRIORITY
+                    || self.deferred.contains(&key)
+                {
+                    recipients.push(QueuedUpdate {
+                        client_id,
+                        entity_id: id,
+                        urgent,
+                        priority,
+                        index: updates.len(),
+                    });
+                }
+            }
+            if recipients.is_empty() {
+                continue;
+            }
+
+            // Build entity update
+            let view = ServerView {
+                position: &position,
+                velocity: &velocity,
+                ship: &ship,
+                integrity: &integrity,
+                asteroid: &asteroid,
+                projectile: &projectile,
+                medium: &medium,
+                beam: &beam,
+                team: &team,
+                capture: &capture,
+                ore: &ore,
+                station: &station,
+                planet: &planet,
+                circle: &circle,
+                blocky: &blocky,
+                quantization: self.quantization,
+                acked_ticks: &self.acked_ticks,
+            };
+            let mut data = Vec::new();
+            if !self.registry.write(&view, ent, id, &mut data) {
+                warn!("Replicated entity {} is of no known kind", id);
+                continue;
+            }
+            updates.push(
+                Message::EntityUpdate(repli.id, now, self.frame, data).bytes(),
+            );
+            queued.extend(recipients);
+        }
+        self.joining.clear();
+
+        // Send the updates, most important first, putting off the others
+        // past the bandwidth
+        queued.sort_by(|a, b| {
+            b.urgent.cmp(&a.urgent).then_with(|| {
+                b.priority.partial_cmp(&a.priority).unwrap_or(Ordering::Equal)
+            })
+        });
+        for update in queued {
+            let client = match self.clients.get_mut(&update.client_id) {
+                Some(c) => c,
+                None => continue,
+            };
+            let key = (update.client_id, update.entity_id);
+            if self.bandwidth.is_some()
+                && !update.urgent
+                && client.allowance <= 0.0
             {
+                if self.deferred.insert(key) {
+                    stats.deferred_updates += 1;
+                }
                 continue;
             }
+            self.deferred.remove(&key);
+            self.sent_frames.insert(key, self.frame);
+            chk(client.send(&self.server, &updates[update.index]));
+        }
 
-            // Send entity update
-            let mut data;
-            if let Some(ship) = ship.get(ent) {
-                let pos = position.get(ent).unwrap();
-                let vel = velocity.get(ent).unwrap();
-                data = Vec::with_capacity(56);
-                write_float(&mut data, pos.pos[0]);
-                write_float(&mut data, pos.pos[1]);
-                write_float(&mut data, pos.rot);
-                write_float(&mut data, vel.vel[0]);
-                write_float(&mut data, vel.vel[1]);
-                write_float(&mut data, vel.rot);
-                write_float(&mut data, ship.want_thrust[0]);
-                write_float(&mut data, ship.want_thrust[1]);
-                write_float(&mut data, ship.want_thrust_rot);
-                write_float(&mut data, ship.want_target[0]);
-                write_float(&mut data, ship.want_target[1]);
-                write_float(&mut data, ship.thrust[0]);
-                write_float(&mut data, ship.thrust[1]);
-                write_float(&mut data, ship.thrust_rot);
-                assert_eq!(data.len(), 56);
-            } else if asteroid.get(ent).is_some() {
-                let pos = position.get(ent).unwrap();
-                let vel = velocity.get(ent).unwrap();
-                data = Vec::with_capacity(24);
-                write_float(&mut data, pos.pos[0]);
-                write_float(&mut data, pos.pos[1]);
-                write_float(&mut data, pos.rot);
-                write_float(&mut data, vel.vel[0]);
-                write_float(&mut data, vel.vel[1]);
-                write_float(&mut data, vel.rot);
-                assert_eq!(data.len(), 24);
-            } else if let Some(proj) = projectile.get(ent) {
-                let pos = position.get(ent).unwrap();
-                let vel = velocity.get(ent).unwrap();
-                data = Vec::with_capacity(25);
-                write_float(&mut data, pos.pos[0]);
-                write_float(&mut data, pos.pos[1]);
-                write_float(&mut data, pos.rot);
-                write_float(&mut data, vel.vel[0]);
-                write_float(&mut data, vel.vel[1]);
-                write_float(&mut data, vel.rot);
-                let kind = match proj.kind {
-                    ProjectileType::Plasma => 1,
-                    ProjectileType::Rail => 2,
-                };
-                assert_eq!(data.write(&[0u8]).unwrap(), kind);
-                assert_eq!(data.len(), 25);
-            } else {
-                panic!("Need to send update for unknown entity!");
+        // Tell clients about the entities they gained or lost control of
+        let controls = (&*entities, &replicated, &ctrl)
+            .join()
+            .filter(|&(_, repli, _)| repli.id != 0)
+            .map(|(_, repli, ctrl)| (ctrl.client_id, repli.id))
+            .collect::<HashSet<_>>();
+        let started = controls
+            .difference(&self.controls)
+            .map(|&(c, id)| (c, Message::StartEntityControl(id)));
+        let stopped = self
+            .controls
+            .difference(&controls)
+            .map(|&(c, id)| (c, Message::StopEntityControl(id)));
+        let changes = started.chain(stopped).collect::<Vec<_>>();
+        for (client_id, message) in changes {
+            chk(self.send(&message, client_id));
+        }
+        // The ticks of entities handed over were the old pilot's
+        for &(_, id) in self.controls.symmetric_difference(&controls) {
+            self.acked_ticks.remove(&id);
+        }
+        self.inputs.retain(|key, _| controls.contains(key));
+        self.toggles.retain(|key, _| controls.contains(key));
+        self.controls = controls;
+
+        // Send match results
+        let reader = {
+            let events = &mut events;
+            self.events.get_or_insert_with(|| events.register_reader())
+        };
+        for event in events.read(reader) {
+            if let GameEvent::Achievement {
+                player,
+                achievement,
+            } = *event
+            {
+                if let Some(client) = self.clients.get_mut(&player) {
+                    let message = Message::Achievement(achievement).bytes();
+                    chk(client.send(&self.server, &message));
+                }
+            }
+            if let GameEvent::MatchEnd { ref summary } = *event {
+                let message = Message::MatchSummary(summary.clone()).bytes();
+                for client in self.clients.values_mut() {
+                    chk(client.send(&self.server, &message));
+                }
+
+                // Send the profiles, updated by `SysProfiles`
+                for (&client_id, client) in &mut self.clients {
+                    if let Some(profile) = profiles.get(client_id) {
+                        let message =
+                            Message::Profile(profile.clone()).bytes();
+                        chk(client.send(&self.server, &message));
+                    }
+                }
             }
-            let update = Message::EntityUpdate(repli.id, data).bytes();
+        }
+
+        // Send the scoreboard
+        if self.frame.wrapping_sub(self.last_scoreboard) >= SCOREBOARD_INTERVAL
+        {
+            self.last_scoreboard = self.frame;
+            let players = scoreboard.summary().players;
+            let message = Message::Scoreboard(players).bytes();
             for client in self.clients.values_mut() {
-                chk(self.server.send(&update, &client.address));
+                chk(client.send(&self.server, &message));
             }
+        }
 
-            repli.last_update = self.frame;
+        // Send the match state, right away when the phase changes
+        let (last_state, last_frame) = self.last_match_state;
+        if discriminant(&*match_state) != discriminant(&last_state)
+            || self.frame.wrapping_sub(last_frame) >= MATCH_STATE_INTERVAL
+        {
+            self.last_match_state = (*match_state, self.frame);
+            let message = Message::MatchState(*match_state).bytes();
+            for client in self.clients.values_mut() {
+                chk(client.send(&self.server, &message));
+            }
+        }
+
+        // Send the players their ore, when it changes
+        for (&client_id, client) in &mut self.clients {
+            let balance = wallet.balance(client_id);
+            if self.last_wallets.get(&client_id) != Some(&balance) {
+                self.last_wallets.insert(client_id, balance);
+                let message = Message::Wallet(balance).bytes();
+                chk(client.send(&self.server, &message));
+            }
+        }
+
+        // Send the backdrops of the sectors around the players' ships
+        for (&client_id, client) in &mut self.clients {
+            let view = match viewers.get(&client_id) {
+                Some(&v) => SectorId::at(v),
+                None => continue,
+            };
+            for id in Some(view).into_iter().chain(view.neighbors()) {
+                if self.sent_backdrops.contains(&(client_id, id)) {
+                    continue;
+                }
+                let backdrop =
+                    sectors.get(id).and_then(|s| s.backdrop.clone());
+                if let Some(backdrop) = backdrop {
+                    self.sent_backdrops.insert((client_id, id));
+                    let message = Message::SectorBackdrop(id, backdrop);
+                    chk(client.send(&self.server, &message.bytes()));
+                }
+            }
         }
 
         // Send particle effects
@@ -480,44 +1973,115 @@ impl<'a, S: Server> System<'a> for SysNetServer<S> {
 
         dirty.clear();
 
-        // Handle messages
+        // Apply the controls due this frame
+        let frame = self.frame;
+        let now = SystemTime::now();
         for (ent, ship, repli, ctrl) in
-            (&*entities, &mut ship, &mut replicated, &ctrl).join()
+            (&*entities, &mut ship, &replicated, &ctrl).join()
         {
-            for &(ref client_id, ref msg) in &messages {
-                if let Message::EntityUpdate(id, ref data) = *msg {
-                    if repli.id == id && client_id == &ctrl.client_id {
-                        repli.last_update = self.frame;
+            let key = (ctrl.client_id, repli.id);
+            let (tick, data) = match self
+                .inputs
+                .get_mut(&key)
+                .and_then(|inputs| inputs.pop(frame))
+            {
+                Some(i) => i,
+                None => continue,
+            };
 
-                        // Update entity from message data
-                        if data.len() != 9 {
-                            info!("Invalid ship control update");
-                            continue;
-                        }
-                        let flags = data[0];
-                        ship.want_fire = flags & 0x01 == 0x01;
-                        ship.want_thrust[0] = match flags & 0x06 {
-                            0x02 => 1.0,
-                            0x04 => -1.0,
-                            _ => 0.0,
-                        };
-                        ship.want_thrust[1] = if flags & 0x08 == 0x08 {
-                            1.0
-                        } else {
-                            0.0
-                        };
-                        ship.want_thrust_rot = match flags & 0x30 {
-                            0x10 => 1.0,
-                            0x20 => -1.0,
-                            _ => 0.0,
-                        };
-                        let mut data = Cursor::new(&data[1..]);
-                        ship.want_target[0] = read_float(&mut data);
-                        ship.want_target[1] = read_float(&mut data);
-                        dirty.insert(ent, Dirty).unwrap();
-                    }
+            // Drop the controls no player could have made
+            let mut rdr = Cursor::new(&data[1..9]);
+            let target = [read_float(&mut rdr), read_float(&mut rdr)];
+            let toggled = (data[0] as u16) << 8 | data[9] as u16;
+            if !valid_target(target)
+                || !self.toggles.entry(key).or_default().allow(toggled, now)
+            {
+                if let Some(client) = self.clients.get(&ctrl.client_id) {
+                    self.invalid.record(&client.address, &mut stats);
                 }
+                continue;
+            }
+
+            let flags = data[0];
+            ship.want_fire = [
+                flags & 0x01 == 0x01,
+                flags & 0x40 == 0x40,
+                flags & 0x80 == 0x80,
+            ];
+            if !match_state.can_fire() {
+                ship.want_fire = [false; WEAPON_GROUPS];
             }
+            ship.want_thrust[0] = match flags & 0x06 {
+                0x02 => 1.0,
+                0x04 => -1.0,
+                _ => 0.0,
+            };
+            ship.want_thrust[1] = if flags & 0x08 == 0x08 {
+                1.0
+            } else {
+                0.0
+            };
+            ship.want_thrust_rot = match flags & 0x30 {
+                0x10 => 1.0,
+                0x20 => -1.0,
+                _ => 0.0,
+            };
+            ship.want_target = target;
+            let flags = data[9];
+            ship.want_tractor = flags & 0x01 == 0x01;
+            ship.dampeners = flags & 0x02 == 0x02;
+            ship.want_boost = flags & 0x04 == 0x04;
+            ship.want_match = flags & 0x08 == 0x08;
+            self.received_ticks.insert(repli.id, tick);
+            dirty.insert(ent, Dirty).unwrap();
+        }
+
+        // Send this frame's messages
+        for client in self.clients.values_mut() {
+            chk(client.flush(&self.server));
+            stats.bytes_sent += client.bytes_sent as u64;
+            client.total_sent += client.bytes_sent as u64;
+            client.bytes_sent = 0;
+        }
+
+        // Measure the network health
+        let count = self.clients.len().max(1) as f32;
+        stats.rtt = self.clients.values().map(|c| c.ping).sum::<f32>() / count;
+        stats.packet_loss = self
+            .clients
+            .values()
+            .map(|c| c.connection.loss())
+            .sum::<f32>()
+            / count;
+        stats.entities = (&replicated).join().count();
+        stats.clients = self
+            .clients
+            .values()
+            .map(|c| ClientStats {
+                client_id: c.client_id,
+                rtt: c.ping,
+                packet_loss: c.connection.loss(),
+                bytes_received: c.total_received,
+                bytes_sent: c.total_sent,
+            })
+            .collect();
+        stats.clients.sort_by_key(|c| c.client_id);
+        self.meter.update(&mut stats);
+        let now = SystemTime::now();
+        if now.duration_since(self.last_health).unwrap_or_default()
+            >= HEALTH_INTERVAL
+        {
+            self.last_health = now;
+            info!(
+                "{} clients, {} entities, RTT {:.0} ms, {:.1}% loss, \
+                 {:.0} B/s in, {:.0} B/s out",
+                self.clients.len(),
+                stats.entities,
+                stats.rtt * 1000.0,
+                stats.packet_loss * 100.0,
+                stats.bytes_in_rate,
+                stats.bytes_out_rate
+            );
         }
     }
 }
@@ -528,67 +2092,205 @@ impl<'a, S: Server> System<'a> for SysNetServer<S> {
 pub struct SysNetClient<C: Client> {
     client: C,
     client_id: u64,
+    last_ping: SystemTime,
     last_pong: SystemTime,
+    /// When the last packet came from the server.
+    last_received: SystemTime,
     ping: f32,
     controlled_entities: HashSet<u64>,
+    prediction: Prediction,
+    connection: Connection,
+    /// Sequence number of the packet each entity was last updated or
+    /// deleted by, to drop the updates that come after a more recent one.
+    update_seqs: HashMap<u64, u32>,
+    /// Scales of the fixed-point numbers in entity updates, from
+    /// `ServerHello`.
+    quantization: Option<Quantization>,
+    /// How the replicated entities are read.
+    registry: Registry,
+    invalid: InvalidLog<&'static str>,
+    meter: RateMeter,
+    /// Bytes sent since they were last added to `NetworkStats`.
+    bytes_sent: usize,
 }
 
 impl<C: Client> SysNetClient<C> {
     /// Create a client, connected to the specified server.
-    pub fn new(client: C) -> SysNetClient<C> {
-        let client = SysNetClient {
+    ///
+    /// `key` identifies the player's profile on the server, see
+    /// `profiles.rs`. `token` resumes a session, if not 0.
+    pub fn new(
+        client: C,
+        class: ShipClass,
+        key: u64,
+        token: u64,
+        name: &str,
+    ) -> SysNetClient<C> {
+        let mut client = SysNetClient {
             client,
             client_id: 0,
+            last_ping: SystemTime::now(),
             last_pong: SystemTime::now(),
+            last_received: SystemTime::now(),
             ping: 0.0,
             controlled_entities: HashSet::new(),
+            prediction: Prediction::default(),
+            connection: Connection::default(),
+            update_seqs: HashMap::new(),
+            quantization: None,
+            registry: Registry::default(),
+            invalid: InvalidLog::new(),
+            meter: RateMeter::new(),
+            bytes_sent: 0,
         };
-        client.send(&Message::ClientHello).unwrap();
+        let name = sanitize_name(name).unwrap_or_default();
+        let hello =
+            Message::ClientHello(PROTOCOL_VERSION, class, key, token, name);
+        client.send(&hello).unwrap();
+        client.flush().unwrap();
         client
     }
 
-    /// Sends a message
-    fn send(&self, msg: &Message) -> io::Result<usize> {
-        let mut bytes = Vec::new();
-        bytes
-            .write_u64::<ORDER>(self.client_id)
-            .unwrap();
-        msg.to_bytes(&mut bytes);
-        self.client
-            .send(&bytes)
+    /// Queues a message, sending the packet if it is full.
+    fn send(&mut self, msg: &Message) -> io::Result<()> {
+        let msg = msg.bytes();
+        let (bytes, compressed) = compress(&msg);
+        if !self.connection.queue(&bytes, compressed)? {
+            self.flush()?;
+            // The packet is empty now, so this can't fail
+            let queued = self.connection.queue(&bytes, compressed)?;
+            assert!(queued);
+        }
+        Ok(())
+    }
+
+    /// Sends the queued messages.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.connection.has_queued() {
+            let mut packet = Vec::with_capacity(MAX_PACKET_LEN);
+            packet.write_u64::<ORDER>(self.client_id).unwrap();
+            self.connection.write_packet(&mut packet);
+            self.bytes_sent += packet.len();
+            self.client.send(&packet)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets everything the server told us, once disconnected.
+    fn tear_down(
+        &mut self,
+        entities: &Entities,
+        replicated: &ReadStorage<Replicated>,
+        clock: &mut ServerClock,
+    ) {
+        for (ent, _) in (&**entities, replicated).join() {
+            entities.delete(ent).unwrap();
+        }
+        *clock = ServerClock::default();
+        self.controlled_entities.clear();
+        self.update_seqs.clear();
+        self.prediction = Prediction::default();
+        self.quantization = None;
     }
 }
 
 impl<'a, C: Client> System<'a> for SysNetClient<C> {
     type SystemData = (
+        Read<'a, DeltaTime>,
         Entities<'a>,
         Read<'a, LazyUpdate>,
+        Write<'a, GameEvents>,
+        Write<'a, LastMatch>,
+        Write<'a, NetworkStats>,
+        Write<'a, Scoreboard>,
+        Write<'a, MatchState>,
+        Write<'a, LocalProfile>,
+        Write<'a, Achievements>,
+        Write<'a, Wallet>,
+        Write<'a, SectorManager>,
+        Write<'a, LocalPlayer>,
         ReadStorage<'a, Replicated>,
         WriteStorage<'a, Dirty>,
         WriteStorage<'a, Position>,
         WriteStorage<'a, Velocity>,
         WriteStorage<'a, Ship>,
-        ReadStorage<'a, Asteroid>,
-        ReadStorage<'a, Projectile>,
+        WriteStorage<'a, ShipIntegrity>,
+        WriteStorage<'a, MediumZone>,
+        WriteStorage<'a, Beam>,
+        WriteStorage<'a, LocalControl>,
+        WriteStorage<'a, Team>,
+        (
+            WriteStorage<'a, CaptureZone>,
+            WriteStorage<'a, OrePickup>,
+            WriteStorage<'a, Station>,
+            WriteStorage<'a, CircleCollider>,
+            WriteStorage<'a, Interpolated>,
+            WriteExpect<'a, ConnectionState>,
+            Write<'a, SessionToken>,
+            Write<'a, ChatLog>,
+            Write<'a, Players>,
+            Write<'a, ServerClock>,
+        ),
     );
 
     fn run(
         &mut self,
         (
+            dt,
             entities,
             lazy,
+            mut events,
+            mut last_match,
+            mut stats,
+            mut scoreboard,
+            mut match_state,
+            mut local_profile,
+            mut achievements,
+            mut wallet,
+            mut sectors,
+            mut local_player,
             replicated,
             mut dirty,
             mut position,
             mut velocity,
             mut ship,
-            asteroid,
-            projectile,
+            mut integrity,
+            mut medium,
+            mut beam,
+            mut local,
+            mut team,
+            (
+                mut capture,
+                mut ore,
+                mut station,
+                mut circle,
+                mut interpolated,
+                mut conn_state,
+                mut session,
+                mut chat,
+                mut players,
+                mut clock,
+            ),
         ): Self::SystemData,
     ) {
+        match *conn_state {
+            ConnectionState::Disconnected
+            | ConnectionState::IncompatibleVersion(_)
+            | ConnectionState::ServerFull(_)
+            | ConnectionState::Kicked(_) => return,
+            _ => {}
+        }
+        let leaving = *conn_state == ConnectionState::Leaving;
+        if leaving {
+            chk(self.send(&Message::Disconnect));
+            chk(self.flush());
+        }
+
         // Receive messages
         let mut messages = Vec::new();
-        let mut buffer = [0; 1024];
+        let mut dropped = false;
+        let mut rejected = None;
+        let mut buffer = [0; MAX_PACKET_LEN];
         loop {
             let len = match self.client.recv(&mut buffer) {
                 Ok(r) => r,
@@ -599,230 +2301,339 @@ impl<'a, C: Client> System<'a> for SysNetClient<C> {
                     break;
                 }
             };
+            stats.bytes_received += len as u64;
+            if len < HEADER_LEN {
+                stats.messages_received += 1;
+                self.invalid.record(&"server", &mut stats);
+                continue;
+            }
+            let header = &buffer[..HEADER_LEN];
+            let seq = match self.connection.read_header(header, &mut stats) {
+                Some(seq) => seq,
+                None => {
+                    stats.stale_messages += 1;
+                    continue;
+                }
+            };
+            self.last_received = SystemTime::now();
 
-            if let Some(msg) = Message::parse(&buffer[..len]) {
-                match msg {
-                    Message::ServerHello(client_id) => {
-                        warn!("Got ServerHello, our ID is {}", client_id);
-                        self.client_id = client_id;
-                    }
-                    Message::Ping(buf) => chk(self.send(&Message::Pong(buf))),
-                    Message::Pong(d) => {
-                        let d = time_decode(d);
-                        let now = SystemTime::now();
-                        let now_d = now.duration_since(UNIX_EPOCH).unwrap();
-                        if let Some(d) = now_d.checked_sub(d) {
-                            self.last_pong = now;
-                            self.ping = d.as_secs() as f32
-                                + d.subsec_nanos() as f32 / 0.000_000_001;
+            let batch = match split_messages(
+                &buffer[HEADER_LEN..len],
+                MAX_SERVER_BATCH_LEN,
+            ) {
+                Some(b) => b,
+                None => {
+                    stats.messages_received += 1;
+                    self.invalid.record(&"server", &mut stats);
+                    continue;
+                }
+            };
+            for body in batch {
+                stats.messages_received += 1;
+                if let Some(msg) = Message::parse(&body) {
+                    match msg {
+                        Message::ServerHello(version, _, _, _)
+                        | Message::IncompatibleVersion(version)
+                            if version != PROTOCOL_VERSION =>
+                        {
+                            warn!(
+                                "Server speaks protocol version {}, we speak \
+                                 {}",
+                                version, PROTOCOL_VERSION
+                            );
+                            rejected =
+                                Some(ConnectionState::IncompatibleVersion(
+                                    version,
+                                ));
+                        }
+                        Message::ServerFull(max) => {
+                            warn!("Server is full ({} players)", max);
+                            rejected = Some(ConnectionState::ServerFull(max));
+                        }
+                        Message::Kicked(reason) => {
+                            warn!("Kicked by the server: {}", reason);
+                            rejected = Some(ConnectionState::Kicked(reason));
+                        }
+                        Message::ServerHello(_, client_id, token, quant) => {
+                            warn!("Got ServerHello, our ID is {}", client_id);
+                            self.client_id = client_id;
+                            session.0 = token;
+                            self.quantization = Some(quant);
+                            if *conn_state == ConnectionState::Connecting {
+                                *conn_state = ConnectionState::Connected;
+                            }
+                            local_player.0 = client_id;
+                        }
+                        Message::Ping(buf) => {
+                            chk(self.send(&Message::Pong(buf, timestamp())))
+                        }
+                        Message::Pong(d, time) => {
+                            self.last_pong = SystemTime::now();
+                            self.ping = time_since(d);
+                            let time = time_decode(time).as_secs_f64();
+                            clock.sample(local_time(), time, self.ping);
+                        }
+                        Message::StartEntityControl(id) => {
+                            self.controlled_entities.insert(id);
+                        }
+                        Message::StopEntityControl(id) => {
+                            self.controlled_entities.remove(&id);
+                        }
+                        Message::MatchSummary(summary) => {
+                            warn!("Match over");
+                            last_match.0 = Some(summary.clone());
+                            events
+                                .single_write(GameEvent::MatchEnd { summary });
+                        }
+                        Message::Scoreboard(players) => {
+                            scoreboard.players = players.into_iter().collect();
+                        }
+                        Message::MatchState(state) => *match_state = state,
+                        Message::Profile(profile) => {
+                            local_profile.0 = Some(profile)
+                        }
+                        Message::Achievement(achievement) => {
+                            achievements
+                                .players
+                                .entry(self.client_id)
+                                .or_default()
+                                .earned
+                                .push(achievement);
+                            events.single_write(GameEvent::Achievement {
+                                player: self.client_id,
+                                achievement,
+                            });
+                        }
+                        Message::Wallet(balance) => {
+                            wallet.balances.insert(self.client_id, balance);
+                        }
+                        Message::SectorBackdrop(id, backdrop) => {
+                            let sector =
+                                sectors.sectors.entry(id).or_default();
+                            sector.backdrop = Some(backdrop);
+                        }
+                        Message::EntityUpdate(id, _, _, _)
+                        | Message::EntityDelete(id) => {
+                            // Can't read updates before ServerHello
+                            if self.quantization.is_none() {
+                                continue;
+                            }
+                            // Drop what comes after a more recent message
+                            // about the same entity
+                            if let Some(&last) = self.update_seqs.get(&id) {
+                                if seq_newer(last, seq) {
+                                    stats.stale_messages += 1;
+                                    continue;
+                                }
+                            }
+                            self.update_seqs.insert(id, seq);
+                            messages.push(msg)
+                        }
+                        Message::Disconnect => {
+                            warn!("Server closed the connection");
+                            dropped = true;
+                        }
+                        Message::PlayerInfo(player, name) => {
+                            if name.is_empty() {
+                                players.players.remove(&player);
+                            } else {
+                                players
+                                    .players
+                                    .insert(player, PlayerInfo { name });
+                            }
+                        }
+                        Message::Chat(player, text) => chat.push(player, text),
+                        Message::ClientHello(_, _, _, _, _)
+                        | Message::IncompatibleVersion(_) => {
+                            self.invalid.record(&"server", &mut stats)
                         }
                     }
-                    Message::StartEntityControl(id) => {
-                        self.controlled_entities.insert(id);
-                    }
-                    Message::EntityUpdate(_, _) | Message::EntityDelete(_) => {
-                        messages.push((msg, false))
-                    }
-                    Message::ClientHello => warn!("Invalid message"),
+                } else {
+                    self.invalid.record(&"server", &mut stats);
                 }
-            } else {
-                warn!("Invalid message");
             }
         }
+        self.invalid.report();
 
-        // Update entities from messages
-        for (ent, repli, mut pos, mut vel) in (
-            &*entities,
-            &replicated,
-            &mut position,
-            &mut velocity,
-        ).join()
-        {
-            for &mut (ref msg, ref mut handled) in &mut messages {
-                if let Message::EntityUpdate(id, ref data) = *msg {
-                    if id != repli.id {
-                        continue;
-                    }
+        let silent = SystemTime::now()
+            .duration_since(self.last_received)
+            .unwrap_or_default();
+        if silent > SERVER_TIMEOUT {
+            warn!("Server timed out");
+            dropped = true;
+        }
+        if leaving || dropped {
+            // The session is over if we left, it might be resumed otherwise
+            if leaving {
+                session.0 = 0;
+            }
+            self.tear_down(&entities, &replicated, &mut clock);
+            *conn_state = ConnectionState::Disconnected;
+            return;
+        }
+        if let Some(state) = rejected {
+            session.0 = 0;
+            self.tear_down(&entities, &replicated, &mut clock);
+            *conn_state = state;
+            return;
+        }
 
-                    *handled = true;
-
-                    // Update entity from message
-                    if let Some(ship) = ship.get_mut(ent) {
-                        assert_eq!(data.len(), 56);
-                        let mut data = Cursor::new(data);
-                        pos.pos[0] = read_float(&mut data);
-                        pos.pos[1] = read_float(&mut data);
-                        pos.rot = read_float(&mut data);
-                        vel.vel[0] = read_float(&mut data);
-                        vel.vel[1] = read_float(&mut data);
-                        vel.rot = read_float(&mut data);
-                        ship.want_thrust[0] = read_float(&mut data);
-                        ship.want_thrust[1] = read_float(&mut data);
-                        ship.want_thrust_rot = read_float(&mut data);
-                        ship.want_target[0] = read_float(&mut data);
-                        ship.want_target[1] = read_float(&mut data);
-                        ship.thrust[0] = read_float(&mut data);
-                        ship.thrust[1] = read_float(&mut data);
-                        ship.thrust_rot = read_float(&mut data);
-                        assert_eq!(data.position(), 56);
-                    } else if asteroid.get(ent).is_some() {
-                        assert_eq!(data.len(), 24);
-                        let mut data = Cursor::new(data);
-                        pos.pos[0] = read_float(&mut data);
-                        pos.pos[1] = read_float(&mut data);
-                        pos.rot = read_float(&mut data);
-                        vel.vel[0] = read_float(&mut data);
-                        vel.vel[1] = read_float(&mut data);
-                        vel.rot = read_float(&mut data);
-                        assert_eq!(data.position(), 24);
-                    } else if projectile.get(ent).is_some() {
-                        assert_eq!(data.len(), 25);
-                        let mut data = Cursor::new(data);
-                        pos.pos[0] = read_float(&mut data);
-                        pos.pos[1] = read_float(&mut data);
-                        pos.rot = read_float(&mut data);
-                        vel.vel[0] = read_float(&mut data);
-                        vel.vel[1] = read_float(&mut data);
-                        vel.rot = read_float(&mut data);
-                        assert_eq!(data.position(), 24);
-                    } else {
-                        panic!("Got update for unknown entity!");
-                    }
-                } else if let Message::EntityDelete(id) = *msg {
-                    if id != repli.id {
-                        continue;
-                    }
+        // Forget the entities not heard of in a long time
+        if self.update_seqs.len() > UPDATE_SEQS_MAX {
+            if let Some(latest) = self.connection.received() {
+                self.update_seqs.retain(|_, &mut seq| {
+                    latest.wrapping_sub(seq) < UPDATE_SEQS_MAX_AGE
+                });
+            }
+        }
 
-                    // Delete entity
-                    entities.delete(ent).unwrap();
-                }
+        let quantization = self.quantization.unwrap_or_default();
+
+        // Keep track of the server's tick
+        for msg in &messages {
+            if let Message::EntityUpdate(_, _, tick, _) = *msg {
+                clock.update_tick(tick);
             }
         }
 
-        // Create new entities
-        for &(ref msg, handled) in &messages {
-            if handled {
-                continue;
+        // Update which existing entities we control
+        for (ent, repli) in (&*entities, &replicated).join() {
+            if self.controlled_entities.contains(&repli.id) {
+                if local.get(ent).is_none() {
+                    warn!("Taking control of ship {}", repli.id);
+                    local.insert(ent, LocalControl(0)).unwrap();
+                }
+            } else if local.remove(ent).is_some() {
+                warn!("Lost control of ship {}", repli.id);
             }
-            if let Message::EntityUpdate(id, ref data) = *msg {
-                if data.len() == 56 {
-                    let mut data = Cursor::new(data);
-                    let pos = Position {
-                        pos: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    let vel = Velocity {
-                        vel: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    let ship = Ship {
-                        want_fire: false,
-                        want_thrust: [
-                            read_float(&mut data),
-                            read_float(&mut data),
-                        ],
-                        want_thrust_rot: read_float(&mut data),
-                        want_target: [
-                            read_float(&mut data),
-                            read_float(&mut data),
-                        ],
-                        thrust: [read_float(&mut data), read_float(&mut data)],
-                        thrust_rot: read_float(&mut data),
+        }
+
+        // Update entities from messages, creating the new ones
+        let mut known = (&*entities, &replicated)
+            .join()
+            .map(|(ent, repli)| (repli.id, ent))
+            .collect::<HashMap<_, _>>();
+        let mut created = HashSet::new();
+        let mut view = ClientView {
+            position: &mut position,
+            velocity: &mut velocity,
+            ship: &mut ship,
+            integrity: &mut integrity,
+            medium: &mut medium,
+            beam: &mut beam,
+            team: &mut team,
+            capture: &mut capture,
+            ore: &mut ore,
+            station: &mut station,
+            circle: &mut circle,
+            interpolated: &mut interpolated,
+            local: &local,
+            prediction: &mut self.prediction,
+            quantization,
+            time: 0.0,
+        };
+        for msg in &messages {
+            match *msg {
+                Message::EntityUpdate(id, time, _, ref data) => {
+                    let (kind, mut data) = match self.registry.read(data) {
+                        Some(r) => r,
+                        None => {
+                            self.invalid.record(&"server", &mut stats);
+                            continue;
+                        }
                     };
-                    assert_eq!(data.position(), 56);
-
-                    let entity = entities.create();
-                    lazy.insert(entity, pos);
-                    lazy.insert(entity, vel);
-                    lazy.insert(entity, ship);
-                    lazy.insert(
-                        entity,
-                        Replicated {
-                            id: id,
-                            last_update: 0,
-                        },
-                    );
-
-                    // Maybe we control this?
-                    if self.controlled_entities.contains(&id) {
-                        warn!("Created locally-controlled ship {}", id);
-                        lazy.insert(entity, LocalControl);
+                    let time = time_decode(time).as_secs_f64();
+                    match known.get(&id) {
+                        // The components of entities created this frame
+                        // aren't there yet, the next update will do
+                        Some(_) if created.contains(&id) => {}
+                        Some(&ent) => {
+                            view.time = time;
+                            (kind.apply)(&mut data, &mut view, ent);
+                        }
+                        None => {
+                            let ent = entities.create();
+                            let creation = Creation {
+                                lazy: &lazy,
+                                quantization,
+                                time,
+                            };
+                            if !(kind.create)(&mut data, &creation, ent) {
+                                entities.delete(ent).unwrap();
+                                self.invalid.record(&"server", &mut stats);
+                                continue;
+                            }
+                            lazy.insert(ent, Replicated { id });
+
+                            // Maybe we control this?
+                            if self.controlled_entities.contains(&id) {
+                                warn!(
+                                    "Created locally-controlled {} {}",
+                                    kind.name, id,
+                                );
+                                lazy.insert(ent, LocalControl(0));
+                            }
+                            known.insert(id, ent);
+                            created.insert(id);
+                        }
                     }
-                } else if data.len() == 24 {
-                    let mut data = Cursor::new(data);
-                    let pos = Position {
-                        pos: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    let vel = Velocity {
-                        vel: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    assert_eq!(data.position(), 24);
-
-                    let entity = entities.create();
-                    lazy.insert(entity, pos);
-                    lazy.insert(entity, vel);
-                    lazy.insert(entity, Asteroid);
-                    lazy.insert(
-                        entity,
-                        Replicated {
-                            id: id,
-                            last_update: 0,
-                        },
-                    );
-                } else if data.len() == 25 {
-                    let mut data = Cursor::new(data);
-                    let pos = Position {
-                        pos: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    let vel = Velocity {
-                        vel: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    let kind = match data.read_u8().unwrap() {
-                        1 => ProjectileType::Plasma,
-                        2 => ProjectileType::Rail,
-                        _ => panic!("Got unknown projectile type"),
-                    };
-                    assert_eq!(data.position(), 25);
-
-                    let entity = entities.create();
-                    lazy.insert(entity, pos);
-                    lazy.insert(entity, vel);
-                    lazy.insert(
-                        entity,
-                        Projectile {
-                            kind,
-                            shooter: entity,
-                        },
-                    );
-                    lazy.insert(
-                        entity,
-                        Replicated {
-                            id: id,
-                            last_update: 0,
-                        },
-                    );
-                } else {
-                    panic!(
-                        "Need to create unknown entity! data {:?} (len {})",
-                        &data[0..50],
-                        data.len(),
-                    );
                 }
+                Message::EntityDelete(id) => {
+                    if let Some(ent) = known.remove(&id) {
+                        entities.delete(ent).unwrap();
+                    }
+                }
+                _ => {}
             }
         }
 
         // TODO: Materialize particle effects
 
-        // Go over Dirty, send messages
-        for (ship, repli, _) in (&ship, &replicated, &dirty).join() {
+        // Send what the player said, once the server knows us
+        if *conn_state == ConnectionState::Connected {
+            for text in chat.outgoing.drain(..) {
+                if let Some(text) = sanitize(&text) {
+                    chk(self.send(&Message::Chat(0, text)));
+                }
+            }
+        }
+
+        // Ping the server, to measure the round-trip time
+        let now = SystemTime::now();
+        if now.duration_since(self.last_ping).unwrap_or_default()
+            >= PING_INTERVAL
+        {
+            self.last_ping = now;
+            let d = time_encode(now.duration_since(UNIX_EPOCH).unwrap());
+            chk(self.send(&Message::Ping(d)));
+        }
+
+        // Go over Dirty, send messages, and move our ship right away
+        let now = time_encode(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+        );
+        for (ship, repli, _, pos, vel, _) in (
+            &ship,
+            &replicated,
+            &dirty,
+            &position,
+            &mut velocity,
+            &local,
+        ).join()
+        {
+            let tick = self.prediction.record(ship, dt.0);
+            self.prediction.apply(ship, pos, vel, dt.0);
+
             let mut flags = 0;
-            if ship.want_fire {
+            if ship.want_fire[0] {
                 flags |= 0x01;
             }
+            if ship.want_fire[1] {
+                flags |= 0x40;
+            }
+            if ship.want_fire[2] {
+                flags |= 0x80;
+            }
             if ship.want_thrust[0] > 0.5 {
                 flags |= 0x02;
             } else if ship.want_thrust[0] < -0.5 {
@@ -836,13 +2647,37 @@ impl<'a, C: Client> System<'a> for SysNetClient<C> {
             } else if ship.want_thrust_rot < -0.5 {
                 flags |= 0x20;
             }
-            let mut data = Vec::with_capacity(9);
+            let mut data = Vec::with_capacity(10);
             data.write_u8(flags).unwrap();
             write_float(&mut data, ship.want_target[0]);
             write_float(&mut data, ship.want_target[1]);
-            assert_eq!(data.len(), 9);
-            chk(self.send(&Message::EntityUpdate(repli.id, data)))
+            let mut flags = 0;
+            if ship.want_tractor {
+                flags |= 0x01;
+            }
+            if ship.dampeners {
+                flags |= 0x02;
+            }
+            if ship.want_boost {
+                flags |= 0x04;
+            }
+            if ship.want_match {
+                flags |= 0x08;
+            }
+            data.write_u8(flags).unwrap();
+            assert_eq!(data.len(), 10);
+            let message = Message::EntityUpdate(repli.id, now, tick, data);
+            chk(self.send(&message))
         }
+        chk(self.flush());
+
+        // Measure the network health
+        stats.bytes_sent += self.bytes_sent as u64;
+        self.bytes_sent = 0;
+        stats.rtt = self.ping;
+        stats.packet_loss = self.connection.loss();
+        stats.entities = (&replicated).join().count();
+        self.meter.update(&mut stats);
 
         dirty.clear();
     }
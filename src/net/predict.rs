@@ -0,0 +1,118 @@
+//! Client-side prediction of the locally-controlled ship.
+//!
+//! Clients don't know the blocks of ships, so they can't run `SysShip`'s
+//! thrust solver; they estimate the thrust with `ship::predict_thrust()`
+//! instead. Each frame, the client records the controls it sends along with
+//! a tick number, and moves its ship right away as the server would. The
+//! server echoes, in each update of the ship, the tick of the last controls
+//! it simulated; the client then resets the ship to the update, drops the
+//! controls the server already used, and replays the others on top of it.
+
+use std::collections::VecDeque;
+use vecmath::*;
+
+use crate::math::sin_cos;
+use crate::physics::{integrate, Position, Velocity};
+use crate::ship::{predict_thrust, Ship};
+
+/// Maximum number of controls kept for replay, older ones are dropped.
+const MAX_INPUTS: usize = 256;
+
+/// Controls sent to the server, not yet acknowledged.
+struct Input {
+    tick: u32,
+    want_thrust: [f32; 2],
+    want_thrust_rot: f32,
+    dampeners: bool,
+    dt: f32,
+}
+
+/// State of the prediction for the locally-controlled ship.
+#[derive(Default)]
+pub struct Prediction {
+    /// Tick of the last controls sent.
+    tick: u32,
+    inputs: VecDeque<Input>,
+    /// Mass and inertia of the ship, from the last update.
+    mass: f32,
+    inertia: f32,
+}
+
+impl Prediction {
+    /// Records the current controls of the ship, returning their tick.
+    pub fn record(&mut self, ship: &Ship, dt: f32) -> u32 {
+        self.tick = self.tick.wrapping_add(1);
+        if self.inputs.len() >= MAX_INPUTS {
+            self.inputs.pop_front();
+        }
+        self.inputs.push_back(Input {
+            tick: self.tick,
+            want_thrust: ship.want_thrust,
+            want_thrust_rot: ship.want_thrust_rot,
+            dampeners: ship.dampeners,
+            dt,
+        });
+        self.tick
+    }
+
+    /// Accelerates the ship from its current controls.
+    pub fn apply(
+        &self,
+        ship: &Ship,
+        pos: &Position,
+        vel: &mut Velocity,
+        dt: f32,
+    ) {
+        if self.mass <= 0.0 || self.inertia <= 0.0 {
+            return;
+        }
+        let (s, c) = sin_cos(pos.rot);
+        let (thrust, rot) = predict_thrust(ship, pos, vel);
+        vel.rot += rot * dt / self.inertia;
+        vel.vel = vec2_add(
+            vel.vel,
+            vec2_scale(
+                [
+                    c * thrust[0] - s * thrust[1],
+                    s * thrust[0] + c * thrust[1],
+                ],
+                dt / self.mass,
+            ),
+        );
+    }
+
+    /// Reconciles with an update from the server, whose state is already in
+    /// `pos` and `vel`, acknowledging controls up to `ack`.
+    pub fn reconcile(
+        &mut self,
+        ack: u32,
+        mass: f32,
+        inertia: f32,
+        ship: &mut Ship,
+        pos: &mut Position,
+        vel: &mut Velocity,
+    ) {
+        self.mass = mass;
+        self.inertia = inertia;
+        // Ticks wrap around, compare the difference
+        while let Some(input) = self.inputs.front() {
+            if (ack.wrapping_sub(input.tick) as i32) < 0 {
+                break;
+            }
+            self.inputs.pop_front();
+        }
+
+        // Replay the controls the server hasn't seen yet
+        let current = (ship.want_thrust, ship.want_thrust_rot, ship.dampeners);
+        for input in &self.inputs {
+            ship.want_thrust = input.want_thrust;
+            ship.want_thrust_rot = input.want_thrust_rot;
+            ship.dampeners = input.dampeners;
+            self.apply(ship, pos, vel, input.dt);
+            integrate(pos, vel, input.dt);
+        }
+        ship.want_thrust = current.0;
+        ship.want_thrust_rot = current.1;
+        ship.dampeners = current.2;
+    }
+}
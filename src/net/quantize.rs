@@ -0,0 +1,221 @@
+//! Fixed-point encoding of positions and velocities in entity updates.
+//!
+//! Updates of the entities that change every frame (ships, asteroids,
+//! projectiles) don't send their motion as floats. Coordinates are 24-bit
+//! fixed-point numbers, velocities 16-bit ones, and angles a 16-bit fraction
+//! of a turn, which takes 14 bytes instead of 24. The server picks the
+//! scales and tells clients in `ServerHello`; the default reaches 32768 units
+//! from the origin with a precision of 1/256, and velocities of 512 units/s.
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use std::f32::consts::PI;
+use std::io;
+
+use super::ORDER;
+use crate::physics::{Position, Velocity};
+use crate::utils::{angle_wrap, clamp};
+
+/// Steps per radian/s for rotation speeds, up to 32 radians/s.
+const ROT_VEL_SCALE: f32 = 1024.0;
+
+const I24_MAX: f32 = ((1 << 23) - 1) as f32;
+
+/// Scales of the fixed-point numbers, in steps per unit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantization {
+    pub pos_scale: u16,
+    pub vel_scale: u16,
+}
+
+impl Default for Quantization {
+    fn default() -> Quantization {
+        Quantization {
+            pos_scale: 256,
+            vel_scale: 64,
+        }
+    }
+}
+
+impl Quantization {
+    /// Whether scales received from the other side can be used.
+    pub fn is_valid(&self) -> bool {
+        self.pos_scale > 0 && self.vel_scale > 0
+    }
+
+    /// Writes a position and velocity.
+    pub fn write_motion<W: io::Write>(
+        &self,
+        mut writer: W,
+        pos: &Position,
+        vel: &Velocity,
+    ) {
+        let scale = self.pos_scale as f32;
+        for &c in &pos.pos {
+            let c = clamp((c * scale).round(), -I24_MAX, I24_MAX);
+            writer.write_i24::<ORDER>(c as i32).unwrap();
+        }
+        write_i16(&mut writer, angle_wrap(pos.rot) * (32768.0 / PI));
+        let scale = self.vel_scale as f32;
+        for &v in &vel.vel {
+            write_i16(&mut writer, v * scale);
+        }
+        write_i16(&mut writer, vel.rot * ROT_VEL_SCALE);
+    }
+
+    /// Reads a position and velocity.
+    pub fn read_motion<R: io::Read>(
+        &self,
+        mut reader: R,
+    ) -> (Position, Velocity) {
+        let scale = self.pos_scale as f32;
+        let x = reader.read_i24::<ORDER>().unwrap() as f32 / scale;
+        let y = reader.read_i24::<ORDER>().unwrap() as f32 / scale;
+        let rot = reader.read_i16::<ORDER>().unwrap() as f32 * (PI / 32768.0);
+        let scale = self.vel_scale as f32;
+        let vx = reader.read_i16::<ORDER>().unwrap() as f32 / scale;
+        let vy = reader.read_i16::<ORDER>().unwrap() as f32 / scale;
+        let vrot = reader.read_i16::<ORDER>().unwrap() as f32 / ROT_VEL_SCALE;
+        (
+            Position { pos: [x, y], rot },
+            Velocity {
+                vel: [vx, vy],
+                rot: vrot,
+            },
+        )
+    }
+}
+
+/// Writes a number as a 16-bit integer, saturating.
+fn write_i16<W: io::Write>(mut writer: W, v: f32) {
+    let v = clamp(v.round(), -32768.0, 32767.0);
+    writer.write_i16::<ORDER>(v as i16).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::{Quantization, I24_MAX, ROT_VEL_SCALE};
+    use crate::physics::{Position, Velocity};
+
+    /// Writes then reads back a position and velocity.
+    fn round_trip(
+        quant: &Quantization,
+        pos: [f32; 3],
+        vel: [f32; 3],
+    ) -> ([f32; 3], [f32; 3]) {
+        let mut buf = Vec::new();
+        quant.write_motion(
+            &mut buf,
+            &Position {
+                pos: [pos[0], pos[1]],
+                rot: pos[2],
+            },
+            &Velocity {
+                vel: [vel[0], vel[1]],
+                rot: vel[2],
+            },
+        );
+        assert_eq!(buf.len(), 14);
+        let (pos, vel) = quant.read_motion(&buf[..]);
+        (
+            [pos.pos[0], pos.pos[1], pos.rot],
+            [vel.vel[0], vel.vel[1], vel.rot],
+        )
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3], error: [f32; 3]) {
+        for i in 0..3 {
+            assert!(
+                (a[i] - b[i]).abs() <= error[i],
+                "{:?} != {:?} (+/- {:?})",
+                a,
+                b,
+                error,
+            );
+        }
+    }
+
+    #[test]
+    fn test_precision() {
+        let quant = Quantization::default();
+        // Half a step of each scale
+        let pos_error = [1.0 / 512.0, 1.0 / 512.0, PI / 32768.0];
+        let vel_error = [1.0 / 128.0, 1.0 / 128.0, 0.5 / ROT_VEL_SCALE];
+        for &(pos, vel) in &[
+            ([0.0, 0.0, 0.0], [0.0, 0.0, 0.0]),
+            ([12.345, -678.9, 1.234], [3.21, -45.6, -2.5]),
+            // At the limits of the range
+            ([32767.9, -32767.9, 3.14], [511.9, -511.9, 31.9]),
+            ([-32767.9, 32767.9, -3.14], [-511.9, 511.9, -31.9]),
+        ] {
+            let (p, v) = round_trip(&quant, pos, vel);
+            assert_close(p, pos, pos_error);
+            assert_close(v, vel, vel_error);
+        }
+
+        // Other scales
+        let quant = Quantization {
+            pos_scale: 16,
+            vel_scale: 1024,
+        };
+        let pos = [524287.0, -0.7, 0.5];
+        let vel = [31.99, -0.001, 0.0];
+        let (p, v) = round_trip(&quant, pos, vel);
+        assert_close(p, pos, [1.0 / 32.0, 1.0 / 32.0, PI / 32768.0]);
+        assert_close(v, vel, [1.0 / 2048.0, 1.0 / 2048.0, 0.0]);
+    }
+
+    #[test]
+    fn test_angles() {
+        // Angles are sent as a fraction of a turn
+        let quant = Quantization::default();
+        let error = [1.0 / 512.0, 1.0 / 512.0, PI / 32768.0];
+        for &(rot, expected) in &[
+            (1.5 * PI, -0.5 * PI),
+            (-1.5 * PI, 0.5 * PI),
+            (4.5 * PI, 0.5 * PI),
+            (-4.5 * PI, -0.5 * PI),
+        ] {
+            let (p, _) = round_trip(&quant, [0.0, 0.0, rot], [0.0; 3]);
+            assert_close(p, [0.0, 0.0, expected], error);
+        }
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        // Values out of range saturate
+        let quant = Quantization::default();
+        let (p, v) =
+            round_trip(&quant, [1.0e6, -1.0e9, 0.0], [1.0e4, -1.0e4, 1.0e3]);
+        let max = I24_MAX / 256.0;
+        assert_eq!(p, [max, -max, 0.0]);
+        assert_eq!(v, [32767.0 / 64.0, -512.0, 32767.0 / ROT_VEL_SCALE]);
+
+        let inf = std::f32::INFINITY;
+        let (p, v) = round_trip(&quant, [inf, -inf, 0.0], [-inf, inf, -inf]);
+        assert_eq!(p, [max, -max, 0.0]);
+        assert_eq!(v, [-512.0, 32767.0 / 64.0, -32.0]);
+
+        // NaN is sent as zero
+        let nan = std::f32::NAN;
+        let (p, v) = round_trip(&quant, [nan; 3], [nan; 3]);
+        assert_eq!(p, [0.0; 3]);
+        assert_eq!(v, [0.0; 3]);
+    }
+
+    #[test]
+    fn test_is_valid() {
+        assert!(Quantization::default().is_valid());
+        let quant = Quantization {
+            pos_scale: 0,
+            vel_scale: 64,
+        };
+        assert!(!quant.is_valid());
+        let quant = Quantization {
+            pos_scale: 256,
+            vel_scale: 0,
+        };
+        assert!(!quant.is_valid());
+    }
+}
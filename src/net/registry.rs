@@ -0,0 +1,1136 @@
+//! Kinds of replicated entities, and how their updates are encoded.
+//!
+//! Each kind registers once, in `Registry::default()`, how servers recognize
+//! its entities and write their state, and how clients create entities from
+//! that state or update them with it. Entity updates start with the kind's
+//! tag, its index in the registry, so clients don't guess the kind from the
+//! length of the data, and turn down the updates they can't read instead of
+//! crashing. Server and client need to register the same kinds in the same
+//! order; changing them changes the protocol.
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use specs::{Entity, LazyUpdate, ReadStorage, WriteStorage};
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use super::predict::Prediction;
+use super::quantize::Quantization;
+use super::{read_float, read_team, read_zone, write_float, write_team,
+            Interpolated, ORDER};
+use crate::asteroid::Asteroid;
+use crate::blocks::Blocky;
+use crate::capture::CaptureZone;
+use crate::economy::OrePickup;
+use crate::guns::{Beam, Projectile, ProjectileType};
+use crate::medium::MediumZone;
+use crate::physics::{CircleCollider, LocalControl, Position, Velocity};
+use crate::planet::Planet;
+use crate::ship::{Ship, ShipIntegrity, WEAPON_GROUPS};
+use crate::station::Station;
+use crate::teams::Team;
+
+/// The storages servers write updates from.
+pub struct ServerView<'a, 'b> {
+    pub position: &'b ReadStorage<'a, Position>,
+    pub velocity: &'b ReadStorage<'a, Velocity>,
+    pub ship: &'b WriteStorage<'a, Ship>,
+    pub integrity: &'b ReadStorage<'a, ShipIntegrity>,
+    pub asteroid: &'b ReadStorage<'a, Asteroid>,
+    pub projectile: &'b ReadStorage<'a, Projectile>,
+    pub medium: &'b ReadStorage<'a, MediumZone>,
+    pub beam: &'b ReadStorage<'a, Beam>,
+    pub team: &'b ReadStorage<'a, Team>,
+    pub capture: &'b ReadStorage<'a, CaptureZone>,
+    pub ore: &'b ReadStorage<'a, OrePickup>,
+    pub station: &'b ReadStorage<'a, Station>,
+    pub planet: &'b ReadStorage<'a, Planet>,
+    pub circle: &'b ReadStorage<'a, CircleCollider>,
+    pub blocky: &'b ReadStorage<'a, Blocky>,
+    pub quantization: Quantization,
+    /// Tick of the last controls simulated, per entity ID.
+    pub acked_ticks: &'b HashMap<u64, u32>,
+}
+
+/// The storages clients apply updates to.
+pub struct ClientView<'a, 'b> {
+    pub position: &'b mut WriteStorage<'a, Position>,
+    pub velocity: &'b mut WriteStorage<'a, Velocity>,
+    pub ship: &'b mut WriteStorage<'a, Ship>,
+    pub integrity: &'b mut WriteStorage<'a, ShipIntegrity>,
+    pub medium: &'b mut WriteStorage<'a, MediumZone>,
+    pub beam: &'b mut WriteStorage<'a, Beam>,
+    pub team: &'b mut WriteStorage<'a, Team>,
+    pub capture: &'b mut WriteStorage<'a, CaptureZone>,
+    pub ore: &'b mut WriteStorage<'a, OrePickup>,
+    pub station: &'b mut WriteStorage<'a, Station>,
+    pub circle: &'b mut WriteStorage<'a, CircleCollider>,
+    pub interpolated: &'b mut WriteStorage<'a, Interpolated>,
+    pub local: &'b WriteStorage<'a, LocalControl>,
+    pub prediction: &'b mut Prediction,
+    pub quantization: Quantization,
+    /// When the server sent the update, in seconds.
+    pub time: f64,
+}
+
+/// What clients create entities with.
+///
+/// The components go through `LazyUpdate`, so they are there on the next
+/// `World::maintain()`.
+pub struct Creation<'a> {
+    pub lazy: &'a LazyUpdate,
+    pub quantization: Quantization,
+    /// When the server sent the update, in seconds.
+    pub time: f64,
+}
+
+/// A kind of replicated entity.
+pub struct EntityKind {
+    pub name: &'static str,
+    /// Length of the state, after the tag, without the list ending it.
+    pub len: usize,
+    /// Length of each entry of the list ending the state, 0 if there is no
+    /// list. The list starts with its number of entries, as a byte.
+    pub entry_len: usize,
+    /// Whether an entity is of this kind, on servers.
+    pub matches: fn(&ServerView, Entity) -> bool,
+    /// Writes the state of an entity, given its replicated ID.
+    pub write: fn(&ServerView, Entity, u64, &mut Vec<u8>),
+    /// Adds the components of a new entity, on clients; false if the state
+    /// is invalid.
+    pub create: fn(&mut Cursor<&[u8]>, &Creation, Entity) -> bool,
+    /// Updates an existing entity, on clients.
+    pub apply: fn(&mut Cursor<&[u8]>, &mut ClientView, Entity),
+}
+
+/// The kinds of replicated entities, by tag.
+pub struct Registry {
+    kinds: Vec<EntityKind>,
+}
+
+impl Registry {
+    /// Adds a kind, whose tag is the next number.
+    pub fn register(&mut self, kind: EntityKind) {
+        assert!(self.kinds.len() < 256, "Too many entity kinds");
+        self.kinds.push(kind);
+    }
+
+    /// Writes the tag and state of an entity, false if it is of no kind.
+    pub fn write(
+        &self,
+        view: &ServerView,
+        ent: Entity,
+        id: u64,
+        data: &mut Vec<u8>,
+    ) -> bool {
+        let found = self
+            .kinds
+            .iter()
+            .enumerate()
+            .find(|&(_, kind)| (kind.matches)(view, ent));
+        let (tag, kind) = match found {
+            Some(k) => k,
+            None => return false,
+        };
+        let start = data.len();
+        data.write_u8(tag as u8).unwrap();
+        (kind.write)(view, ent, id, data);
+        assert_eq!(
+            kind.state_len(&data[start + 1..]),
+            Some(data.len() - start - 1),
+            "Bad {} update",
+            kind.name
+        );
+        true
+    }
+
+    /// Finds the kind of an update by its tag, returning the state after
+    /// it; `None` if the tag is unknown or the length is wrong.
+    pub fn read<'d>(
+        &self,
+        data: &'d [u8],
+    ) -> Option<(&EntityKind, Cursor<&'d [u8]>)> {
+        let kind = self.kinds.get(*data.first()? as usize)?;
+        if kind.state_len(&data[1..])? != data.len() - 1 {
+            return None;
+        }
+        Some((kind, Cursor::new(&data[1..])))
+    }
+}
+
+impl EntityKind {
+    /// The length a state should have, from the number of entries of its
+    /// list; `None` if it is too short to tell.
+    fn state_len(&self, state: &[u8]) -> Option<usize> {
+        if self.entry_len == 0 {
+            return Some(self.len);
+        }
+        let count = *state.get(self.len)? as usize;
+        Some(self.len + 1 + count * self.entry_len)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        let mut registry = Registry { kinds: Vec::new() };
+        registry.register(EntityKind {
+            name: "ship",
+            len: 108,
+            entry_len: 12,
+            matches: |view, ent| view.ship.get(ent).is_some(),
+            write: write_ship,
+            create: create_ship,
+            apply: apply_ship,
+        });
+        registry.register(EntityKind {
+            name: "asteroid",
+            len: 14,
+            entry_len: 0,
+            matches: |view, ent| view.asteroid.get(ent).is_some(),
+            write: write_motion,
+            create: create_asteroid,
+            apply: apply_motion,
+        });
+        registry.register(EntityKind {
+            name: "projectile",
+            len: 15,
+            entry_len: 0,
+            matches: |view, ent| view.projectile.get(ent).is_some(),
+            write: write_projectile,
+            create: create_projectile,
+            apply: apply_motion,
+        });
+        registry.register(EntityKind {
+            name: "medium",
+            len: 16,
+            entry_len: 0,
+            matches: |view, ent| view.medium.get(ent).is_some(),
+            write: write_medium,
+            create: create_medium,
+            apply: apply_medium,
+        });
+        registry.register(EntityKind {
+            name: "beam",
+            len: 17,
+            entry_len: 0,
+            matches: |view, ent| view.beam.get(ent).is_some(),
+            write: write_beam,
+            create: create_beam,
+            apply: apply_beam,
+        });
+        registry.register(EntityKind {
+            name: "capture",
+            len: 19,
+            entry_len: 0,
+            matches: |view, ent| view.capture.get(ent).is_some(),
+            write: write_capture,
+            create: create_capture,
+            apply: apply_capture,
+        });
+        registry.register(EntityKind {
+            name: "ore",
+            len: 20,
+            entry_len: 0,
+            matches: |view, ent| view.ore.get(ent).is_some(),
+            write: write_ore,
+            create: create_ore,
+            apply: apply_ore,
+        });
+        registry.register(EntityKind {
+            name: "station",
+            len: 13,
+            entry_len: 0,
+            matches: |view, ent| view.station.get(ent).is_some(),
+            write: write_station,
+            create: create_station,
+            apply: apply_station,
+        });
+        registry.register(EntityKind {
+            name: "planet",
+            len: 12,
+            entry_len: 0,
+            matches: |view, ent| view.planet.get(ent).is_some(),
+            write: write_planet,
+            create: create_planet,
+            apply: apply_planet,
+        });
+        registry
+    }
+}
+
+/// Reads a position, which doesn't rotate.
+fn read_position(data: &mut Cursor<&[u8]>) -> Position {
+    Position {
+        pos: [read_float(&mut *data), read_float(&mut *data)],
+        rot: 0.0,
+    }
+}
+
+fn write_ship(view: &ServerView, ent: Entity, id: u64, data: &mut Vec<u8>) {
+    let ship = view.ship.get(ent).unwrap();
+    let pos = view.position.get(ent).unwrap();
+    let vel = view.velocity.get(ent).unwrap();
+    let integrity = view.integrity.get(ent).unwrap();
+    view.quantization.write_motion(&mut *data, pos, vel);
+    write_float(&mut *data, ship.want_thrust[0]);
+    write_float(&mut *data, ship.want_thrust[1]);
+    write_float(&mut *data, ship.want_thrust_rot);
+    write_float(&mut *data, ship.want_target[0]);
+    write_float(&mut *data, ship.want_target[1]);
+    write_float(&mut *data, ship.thrust[0]);
+    write_float(&mut *data, ship.thrust[1]);
+    write_float(&mut *data, ship.thrust_rot);
+    write_float(&mut *data, ship.disabled);
+    write_float(&mut *data, ship.charge);
+    write_float(&mut *data, ship.fuel);
+    for &a in &ship.authority {
+        write_float(&mut *data, a);
+    }
+    write_float(&mut *data, integrity.health);
+    data.write_u32::<ORDER>(integrity.blocks).unwrap();
+    write_float(&mut *data, integrity.cockpit);
+    data.write_u8(ship.tractor as u8).unwrap();
+    write_team(&mut *data, view.team.get(ent));
+    for &n in &ship.nominal_thrust {
+        write_float(&mut *data, n);
+    }
+    let (mass, inertia) = match view.blocky.get(ent) {
+        Some(blk) => (blk.mass, blk.inertia),
+        None => (0.0, 0.0),
+    };
+    write_float(&mut *data, mass);
+    write_float(&mut *data, inertia);
+    let ack = view.acked_ticks.get(&id).cloned();
+    data.write_u32::<ORDER>(ack.unwrap_or(0)).unwrap();
+    let count = ship.disabled_blocks.len().min(255);
+    let disabled = &ship.disabled_blocks[..count];
+    data.write_u8(disabled.len() as u8).unwrap();
+    for &(loc, time) in disabled {
+        write_float(&mut *data, loc[0]);
+        write_float(&mut *data, loc[1]);
+        write_float(&mut *data, time);
+    }
+}
+
+/// Reads the blocks disabled by an EMP, ending the state of a ship.
+fn read_disabled_blocks(data: &mut Cursor<&[u8]>) -> Vec<([f32; 2], f32)> {
+    let count = data.read_u8().unwrap();
+    (0..count)
+        .map(|_| {
+            let loc = [read_float(&mut *data), read_float(&mut *data)];
+            (loc, read_float(&mut *data))
+        })
+        .collect()
+}
+
+fn create_ship(
+    data: &mut Cursor<&[u8]>,
+    creation: &Creation,
+    ent: Entity,
+) -> bool {
+    let (pos, vel) = creation.quantization.read_motion(&mut *data);
+    let mut ship = Ship {
+        want_fire: [false; WEAPON_GROUPS],
+        want_thrust: [read_float(&mut *data), read_float(&mut *data)],
+        want_thrust_rot: read_float(&mut *data),
+        want_target: [read_float(&mut *data), read_float(&mut *data)],
+        want_tractor: false,
+        dampeners: false,
+        want_boost: false,
+        want_match: false,
+        thrust: [read_float(&mut *data), read_float(&mut *data)],
+        thrust_rot: read_float(&mut *data),
+        disabled: read_float(&mut *data),
+        disabled_blocks: Vec::new(),
+        charge: read_float(&mut *data),
+        fuel: read_float(&mut *data),
+        boosting: false,
+        heat: 0.0,
+        overheated: false,
+        authority: [
+            read_float(&mut *data),
+            read_float(&mut *data),
+            read_float(&mut *data),
+        ],
+        tractor: false,
+        nominal_thrust: [0.0; 3],
+    };
+    let integrity = ShipIntegrity::from_values(
+        read_float(&mut *data),
+        data.read_u32::<ORDER>().unwrap(),
+        read_float(&mut *data),
+    );
+    ship.tractor = data.read_u8().unwrap() != 0;
+    let team = read_team(&mut *data);
+    for n in &mut ship.nominal_thrust {
+        *n = read_float(&mut *data);
+    }
+    // Mass, inertia and tick are for the prediction, which starts with the
+    // next update
+    data.set_position(data.position() + 12);
+    ship.disabled_blocks = read_disabled_blocks(&mut *data);
+
+    let mut interp = Interpolated::default();
+    interp.push(creation.time, &pos, &vel);
+    let lazy = creation.lazy;
+    lazy.insert(ent, pos);
+    lazy.insert(ent, vel);
+    lazy.insert(ent, interp);
+    lazy.insert(ent, ship);
+    lazy.insert(ent, integrity);
+    if let Some(t) = team {
+        lazy.insert(ent, t);
+    }
+    true
+}
+
+fn apply_ship(data: &mut Cursor<&[u8]>, view: &mut ClientView, ent: Entity) {
+    let (p, v) = view.quantization.read_motion(&mut *data);
+    let want_thrust = [read_float(&mut *data), read_float(&mut *data)];
+    let want_thrust_rot = read_float(&mut *data);
+    let want_target = [read_float(&mut *data), read_float(&mut *data)];
+    let (pos, vel, ship) = match (
+        view.position.get_mut(ent),
+        view.velocity.get_mut(ent),
+        view.ship.get_mut(ent),
+    ) {
+        (Some(pos), Some(vel), Some(ship)) => (pos, vel, ship),
+        _ => return,
+    };
+    *pos = p;
+    *vel = v;
+    // Keep our own controls, the server's are behind
+    let ours = view.local.get(ent).is_some();
+    if !ours {
+        ship.want_thrust = want_thrust;
+        ship.want_thrust_rot = want_thrust_rot;
+        ship.want_target = want_target;
+    }
+    ship.thrust[0] = read_float(&mut *data);
+    ship.thrust[1] = read_float(&mut *data);
+    ship.thrust_rot = read_float(&mut *data);
+    ship.disabled = read_float(&mut *data);
+    ship.charge = read_float(&mut *data);
+    ship.fuel = read_float(&mut *data);
+    for a in &mut ship.authority {
+        *a = read_float(&mut *data);
+    }
+    let health = read_float(&mut *data);
+    let blocks = data.read_u32::<ORDER>().unwrap();
+    let cockpit = read_float(&mut *data);
+    if let Some(integrity) = view.integrity.get_mut(ent) {
+        integrity.health = health;
+        integrity.blocks = blocks;
+        integrity.cockpit = cockpit;
+    }
+    ship.tractor = data.read_u8().unwrap() != 0;
+    match read_team(&mut *data) {
+        Some(t) => {
+            view.team.insert(ent, t).unwrap();
+        }
+        None => {
+            view.team.remove(ent);
+        }
+    }
+    for n in &mut ship.nominal_thrust {
+        *n = read_float(&mut *data);
+    }
+    let mass = read_float(&mut *data);
+    let inertia = read_float(&mut *data);
+    let ack = data.read_u32::<ORDER>().unwrap();
+    ship.disabled_blocks = read_disabled_blocks(&mut *data);
+    if let Some(interp) = view.interpolated.get_mut(ent) {
+        interp.push(view.time, pos, vel);
+    }
+
+    // Replay our controls over the server's state
+    if ours {
+        view.prediction.reconcile(ack, mass, inertia, ship, pos, vel);
+    }
+}
+
+/// Writes the motion of an entity, all there is to asteroids.
+fn write_motion(view: &ServerView, ent: Entity, _: u64, data: &mut Vec<u8>) {
+    let pos = view.position.get(ent).unwrap();
+    let vel = view.velocity.get(ent).unwrap();
+    view.quantization.write_motion(data, pos, vel);
+}
+
+/// Updates the motion of an entity, from the start of its state.
+fn apply_motion(data: &mut Cursor<&[u8]>, view: &mut ClientView, ent: Entity) {
+    let (p, v) = view.quantization.read_motion(data);
+    if let (Some(pos), Some(vel)) =
+        (view.position.get_mut(ent), view.velocity.get_mut(ent))
+    {
+        *pos = p;
+        *vel = v;
+        if let Some(interp) = view.interpolated.get_mut(ent) {
+            interp.push(view.time, pos, vel);
+        }
+    }
+}
+
+fn create_asteroid(
+    data: &mut Cursor<&[u8]>,
+    creation: &Creation,
+    ent: Entity,
+) -> bool {
+    let (pos, vel) = creation.quantization.read_motion(data);
+    let mut interp = Interpolated::default();
+    interp.push(creation.time, &pos, &vel);
+    let lazy = creation.lazy;
+    lazy.insert(ent, pos);
+    lazy.insert(ent, vel);
+    lazy.insert(ent, Asteroid);
+    lazy.insert(ent, interp);
+    true
+}
+
+fn write_projectile(
+    view: &ServerView,
+    ent: Entity,
+    id: u64,
+    data: &mut Vec<u8>,
+) {
+    write_motion(view, ent, id, data);
+    let kind = match view.projectile.get(ent).unwrap().kind {
+        ProjectileType::Plasma => 1,
+        ProjectileType::Rail => 2,
+        ProjectileType::Emp => 3,
+        ProjectileType::Flak => 4,
+        ProjectileType::Charge(_) => 5,
+    };
+    data.write_u8(kind).unwrap();
+}
+
+fn create_projectile(
+    data: &mut Cursor<&[u8]>,
+    creation: &Creation,
+    ent: Entity,
+) -> bool {
+    let (pos, vel) = creation.quantization.read_motion(&mut *data);
+    let kind = match data.read_u8().unwrap() {
+        1 => ProjectileType::Plasma,
+        2 => ProjectileType::Rail,
+        3 => ProjectileType::Emp,
+        4 => ProjectileType::Flak,
+        // The charge doesn't get replicated
+        5 => ProjectileType::Charge(1.0),
+        _ => return false,
+    };
+    let lazy = creation.lazy;
+    lazy.insert(ent, pos);
+    lazy.insert(ent, vel);
+    lazy.insert(
+        ent,
+        Projectile {
+            kind,
+            shooter: ent,
+            immunity: 0.0,
+            fuse: None,
+            energy: 0.0,
+        },
+    );
+    true
+}
+
+fn write_medium(view: &ServerView, ent: Entity, _: u64, data: &mut Vec<u8>) {
+    let pos = view.position.get(ent).unwrap();
+    let zone = view.medium.get(ent).unwrap();
+    write_float(&mut *data, pos.pos[0]);
+    write_float(&mut *data, pos.pos[1]);
+    write_float(&mut *data, zone.radius);
+    write_float(&mut *data, zone.drag);
+}
+
+fn create_medium(
+    data: &mut Cursor<&[u8]>,
+    creation: &Creation,
+    ent: Entity,
+) -> bool {
+    let pos = read_position(data);
+    let zone = MediumZone {
+        radius: read_float(&mut *data),
+        drag: read_float(&mut *data),
+    };
+    creation.lazy.insert(ent, pos);
+    creation.lazy.insert(ent, zone);
+    true
+}
+
+fn apply_medium(data: &mut Cursor<&[u8]>, view: &mut ClientView, ent: Entity) {
+    let p = read_position(data);
+    if let Some(pos) = view.position.get_mut(ent) {
+        pos.pos = p.pos;
+    }
+    if let Some(zone) = view.medium.get_mut(ent) {
+        zone.radius = read_float(&mut *data);
+        zone.drag = read_float(&mut *data);
+    }
+}
+
+fn write_beam(view: &ServerView, ent: Entity, _: u64, data: &mut Vec<u8>) {
+    let beam = view.beam.get(ent).unwrap();
+    write_float(&mut *data, beam.start[0]);
+    write_float(&mut *data, beam.start[1]);
+    write_float(&mut *data, beam.rot);
+    write_float(&mut *data, beam.length);
+    data.write_u8(beam.hitting as u8).unwrap();
+}
+
+fn create_beam(
+    data: &mut Cursor<&[u8]>,
+    creation: &Creation,
+    ent: Entity,
+) -> bool {
+    let start = [read_float(&mut *data), read_float(&mut *data)];
+    let rot = read_float(&mut *data);
+    let length = read_float(&mut *data);
+    let hitting = data.read_u8().unwrap() != 0;
+    creation.lazy.insert(
+        ent,
+        Beam {
+            shooter: ent,
+            block: [0.0, 0.0],
+            start,
+            rot,
+            length,
+            hitting,
+        },
+    );
+    true
+}
+
+fn apply_beam(data: &mut Cursor<&[u8]>, view: &mut ClientView, ent: Entity) {
+    if let Some(beam) = view.beam.get_mut(ent) {
+        beam.start[0] = read_float(&mut *data);
+        beam.start[1] = read_float(&mut *data);
+        beam.rot = read_float(&mut *data);
+        beam.length = read_float(&mut *data);
+        beam.hitting = data.read_u8().unwrap() != 0;
+    }
+}
+
+fn write_capture(view: &ServerView, ent: Entity, _: u64, data: &mut Vec<u8>) {
+    let pos = view.position.get(ent).unwrap();
+    let zone = view.capture.get(ent).unwrap();
+    write_float(&mut *data, pos.pos[0]);
+    write_float(&mut *data, pos.pos[1]);
+    write_float(&mut *data, zone.radius);
+    write_team(&mut *data, zone.owner.as_ref());
+    let (capturing, progress) = match zone.capturing {
+        Some((t, progress)) => (Some(t), progress),
+        None => (None, 0.0),
+    };
+    write_team(&mut *data, capturing.as_ref());
+    write_float(&mut *data, progress);
+    data.write_u8(zone.contested as u8).unwrap();
+}
+
+fn create_capture(
+    data: &mut Cursor<&[u8]>,
+    creation: &Creation,
+    ent: Entity,
+) -> bool {
+    let pos = read_position(data);
+    let mut zone = CaptureZone::new(0.0);
+    read_zone(data, &mut zone);
+    creation.lazy.insert(ent, pos);
+    creation.lazy.insert(ent, zone);
+    true
+}
+
+fn apply_capture(
+    data: &mut Cursor<&[u8]>,
+    view: &mut ClientView,
+    ent: Entity,
+) {
+    let p = read_position(data);
+    if let Some(pos) = view.position.get_mut(ent) {
+        pos.pos = p.pos;
+    }
+    if let Some(zone) = view.capture.get_mut(ent) {
+        read_zone(data, zone);
+    }
+}
+
+fn write_ore(view: &ServerView, ent: Entity, _: u64, data: &mut Vec<u8>) {
+    let pos = view.position.get(ent).unwrap();
+    let vel = view.velocity.get(ent).unwrap();
+    let ore = view.ore.get(ent).unwrap();
+    write_float(&mut *data, pos.pos[0]);
+    write_float(&mut *data, pos.pos[1]);
+    write_float(&mut *data, vel.vel[0]);
+    write_float(&mut *data, vel.vel[1]);
+    data.write_u32::<ORDER>(ore.amount).unwrap();
+}
+
+fn create_ore(
+    data: &mut Cursor<&[u8]>,
+    creation: &Creation,
+    ent: Entity,
+) -> bool {
+    let pos = read_position(data);
+    let vel = Velocity {
+        vel: [read_float(&mut *data), read_float(&mut *data)],
+        rot: 0.0,
+    };
+    let amount = data.read_u32::<ORDER>().unwrap();
+    creation.lazy.insert(ent, pos);
+    creation.lazy.insert(ent, vel);
+    creation.lazy.insert(ent, OrePickup { amount });
+    true
+}
+
+fn apply_ore(data: &mut Cursor<&[u8]>, view: &mut ClientView, ent: Entity) {
+    let p = read_position(data);
+    let v = [read_float(&mut *data), read_float(&mut *data)];
+    let amount = data.read_u32::<ORDER>().unwrap();
+    if let Some(pos) = view.position.get_mut(ent) {
+        pos.pos = p.pos;
+    }
+    if let Some(vel) = view.velocity.get_mut(ent) {
+        vel.vel = v;
+    }
+    if let Some(ore) = view.ore.get_mut(ent) {
+        ore.amount = amount;
+    }
+}
+
+fn write_station(view: &ServerView, ent: Entity, _: u64, data: &mut Vec<u8>) {
+    let pos = view.position.get(ent).unwrap();
+    let station = view.station.get(ent).unwrap();
+    write_float(&mut *data, pos.pos[0]);
+    write_float(&mut *data, pos.pos[1]);
+    write_float(&mut *data, station.safe_radius);
+    data.write_u8(station.ports.min(255) as u8).unwrap();
+}
+
+fn create_station(
+    data: &mut Cursor<&[u8]>,
+    creation: &Creation,
+    ent: Entity,
+) -> bool {
+    let pos = read_position(data);
+    let safe_radius = read_float(&mut *data);
+    let ports = data.read_u8().unwrap() as u32;
+    creation.lazy.insert(ent, pos);
+    creation.lazy.insert(ent, Station { safe_radius, ports });
+    true
+}
+
+fn apply_station(
+    data: &mut Cursor<&[u8]>,
+    view: &mut ClientView,
+    ent: Entity,
+) {
+    let p = read_position(data);
+    if let Some(pos) = view.position.get_mut(ent) {
+        pos.pos = p.pos;
+    }
+    if let Some(station) = view.station.get_mut(ent) {
+        station.safe_radius = read_float(&mut *data);
+        station.ports = data.read_u8().unwrap() as u32;
+    }
+}
+
+fn write_planet(view: &ServerView, ent: Entity, _: u64, data: &mut Vec<u8>) {
+    let pos = view.position.get(ent).unwrap();
+    let circle = view.circle.get(ent).unwrap();
+    write_float(&mut *data, pos.pos[0]);
+    write_float(&mut *data, pos.pos[1]);
+    write_float(&mut *data, circle.radius);
+}
+
+fn create_planet(
+    data: &mut Cursor<&[u8]>,
+    creation: &Creation,
+    ent: Entity,
+) -> bool {
+    let pos = read_position(data);
+    let radius = read_float(&mut *data);
+    creation.lazy.insert(ent, pos);
+    creation.lazy.insert(ent, Planet);
+    creation.lazy.insert(ent, CircleCollider { radius });
+    true
+}
+
+fn apply_planet(data: &mut Cursor<&[u8]>, view: &mut ClientView, ent: Entity) {
+    let p = read_position(data);
+    if let Some(pos) = view.position.get_mut(ent) {
+        pos.pos = p.pos;
+    }
+    if let Some(circle) = view.circle.get_mut(ent) {
+        circle.radius = read_float(&mut *data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{Builder, Entity, LazyUpdate, World, WorldExt};
+    use std::collections::HashMap;
+
+    use super::{ClientView, Creation, Registry, ServerView};
+    use crate::asteroid::Asteroid;
+    use crate::blocks::Blocky;
+    use crate::capture::CaptureZone;
+    use crate::economy::OrePickup;
+    use crate::guns::{Beam, Projectile, ProjectileType};
+    use crate::medium::MediumZone;
+    use crate::net::predict::Prediction;
+    use crate::net::quantize::Quantization;
+    use crate::net::Interpolated;
+    use crate::physics::{CircleCollider, LocalControl, Position, Velocity};
+    use crate::planet::Planet;
+    use crate::ship::{Ship, ShipIntegrity};
+    use crate::station::Station;
+    use crate::teams::Team;
+
+    fn world() -> World {
+        let mut world = World::new();
+        world.register::<Position>();
+        world.register::<Velocity>();
+        world.register::<Ship>();
+        world.register::<ShipIntegrity>();
+        world.register::<Asteroid>();
+        world.register::<Projectile>();
+        world.register::<MediumZone>();
+        world.register::<Beam>();
+        world.register::<Team>();
+        world.register::<CaptureZone>();
+        world.register::<OrePickup>();
+        world.register::<Station>();
+        world.register::<Planet>();
+        world.register::<CircleCollider>();
+        world.register::<Blocky>();
+        world.register::<Interpolated>();
+        world.register::<LocalControl>();
+        world
+    }
+
+    /// Writes the tag and state of an entity, as servers do.
+    fn write(world: &World, registry: &Registry, ent: Entity) -> Vec<u8> {
+        let acked_ticks = HashMap::new();
+        let view = ServerView {
+            position: &world.read_storage(),
+            velocity: &world.read_storage(),
+            ship: &world.write_storage(),
+            integrity: &world.read_storage(),
+            asteroid: &world.read_storage(),
+            projectile: &world.read_storage(),
+            medium: &world.read_storage(),
+            beam: &world.read_storage(),
+            team: &world.read_storage(),
+            capture: &world.read_storage(),
+            ore: &world.read_storage(),
+            station: &world.read_storage(),
+            planet: &world.read_storage(),
+            circle: &world.read_storage(),
+            blocky: &world.read_storage(),
+            quantization: Quantization::default(),
+            acked_ticks: &acked_ticks,
+        };
+        let mut data = Vec::new();
+        assert!(registry.write(&view, ent, 1, &mut data));
+        data
+    }
+
+    /// Creates an entity from an update, as clients do.
+    fn create(world: &mut World, registry: &Registry, data: &[u8]) -> Entity {
+        let ent = world.create_entity().build();
+        {
+            let (kind, mut state) = registry.read(data).unwrap();
+            let creation = Creation {
+                lazy: &world.read_resource::<LazyUpdate>(),
+                quantization: Quantization::default(),
+                time: 0.0,
+            };
+            assert!((kind.create)(&mut state, &creation, ent));
+            assert_eq!(
+                state.position() as usize,
+                data.len() - 1,
+                "{}",
+                kind.name,
+            );
+        }
+        world.maintain();
+        ent
+    }
+
+    /// Updates an entity, as clients do.
+    fn apply(world: &World, registry: &Registry, data: &[u8], ent: Entity) {
+        let (kind, mut state) = registry.read(data).unwrap();
+        let mut prediction = Prediction::default();
+        let mut view = ClientView {
+            position: &mut world.write_storage(),
+            velocity: &mut world.write_storage(),
+            ship: &mut world.write_storage(),
+            integrity: &mut world.write_storage(),
+            medium: &mut world.write_storage(),
+            beam: &mut world.write_storage(),
+            team: &mut world.write_storage(),
+            capture: &mut world.write_storage(),
+            ore: &mut world.write_storage(),
+            station: &mut world.write_storage(),
+            circle: &mut world.write_storage(),
+            interpolated: &mut world.write_storage(),
+            local: &world.write_storage(),
+            prediction: &mut prediction,
+            quantization: Quantization::default(),
+            time: 1.0,
+        };
+        (kind.apply)(&mut state, &mut view, ent);
+    }
+
+    fn motion(world: &World, ent: Entity, pos: [f32; 2], vel: [f32; 2]) {
+        world
+            .write_storage()
+            .insert(ent, Position { pos, rot: 0.5 })
+            .unwrap();
+        world
+            .write_storage()
+            .insert(ent, Velocity { vel, rot: -0.25 })
+            .unwrap();
+    }
+
+    /// Sets up an entity on the server, then changes it.
+    type Setup = fn(&mut World) -> Entity;
+    type Change = fn(&World, Entity);
+
+    fn kinds() -> Vec<(&'static str, Setup, Change)> {
+        vec![
+            (
+                "ship",
+                |world| {
+                    let mut ship = Ship::new();
+                    ship.want_thrust = [1.0, -0.5];
+                    ship.fuel = 0.75;
+                    ship.nominal_thrust = [30.0, 20.0, 10.0];
+                    ship.disabled_blocks = vec![([1.0, 2.0], 3.0)];
+                    let ent = world
+                        .create_entity()
+                        .with(ship)
+                        .with(ShipIntegrity::from_values(0.9, 12, 1.0))
+                        .with(Team(1))
+                        .build();
+                    motion(world, ent, [12.5, -40.25], [3.0, -1.5]);
+                    ent
+                },
+                |world, ent| {
+                    let mut ship = world.write_storage::<Ship>();
+                    let ship = ship.get_mut(ent).unwrap();
+                    ship.thrust = [0.5, 0.5];
+                    ship.tractor = true;
+                    ship.disabled_blocks.push(([-1.0, 0.0], 0.5));
+                    world.write_storage::<Team>().remove(ent);
+                    motion(world, ent, [13.0, -41.0], [3.5, -1.0]);
+                },
+            ),
+            (
+                "asteroid",
+                |world| {
+                    let ent = world.create_entity().with(Asteroid).build();
+                    motion(world, ent, [-100.0, 30.5], [1.0, 2.0]);
+                    ent
+                },
+                |world, ent| motion(world, ent, [-99.0, 32.5], [1.0, 2.0]),
+            ),
+            (
+                "projectile",
+                |world| {
+                    let ent = world.create_entity().build();
+                    let projectile = Projectile {
+                        kind: ProjectileType::Rail,
+                        shooter: ent,
+                        immunity: 0.0,
+                        fuse: None,
+                        energy: 1.0,
+                    };
+                    world.write_storage().insert(ent, projectile).unwrap();
+                    motion(world, ent, [5.0, 5.0], [-80.0, 0.0]);
+                    ent
+                },
+                |world, ent| motion(world, ent, [1.0, 5.0], [-80.0, 0.0]),
+            ),
+            (
+                "medium",
+                |world| {
+                    let zone = MediumZone {
+                        radius: 25.0,
+                        drag: 0.3,
+                    };
+                    let pos = Position {
+                        pos: [45.0, 30.0],
+                        rot: 0.0,
+                    };
+                    world.create_entity().with(zone).with(pos).build()
+                },
+                |world, ent| {
+                    let mut zone = world.write_storage::<MediumZone>();
+                    zone.get_mut(ent).unwrap().drag = 0.5;
+                },
+            ),
+            (
+                "beam",
+                |world| {
+                    let ent = world.create_entity().build();
+                    let beam = Beam {
+                        shooter: ent,
+                        block: [0.0, 0.0],
+                        start: [1.0, 2.0],
+                        rot: 0.3,
+                        length: 40.0,
+                        hitting: false,
+                    };
+                    world.write_storage().insert(ent, beam).unwrap();
+                    ent
+                },
+                |world, ent| {
+                    let mut beam = world.write_storage::<Beam>();
+                    let beam = beam.get_mut(ent).unwrap();
+                    beam.length = 12.0;
+                    beam.hitting = true;
+                },
+            ),
+            (
+                "capture",
+                |world| {
+                    let mut zone = CaptureZone::new(20.0);
+                    zone.owner = Some(Team(0));
+                    let pos = Position {
+                        pos: [0.0, 100.0],
+                        rot: 0.0,
+                    };
+                    world.create_entity().with(zone).with(pos).build()
+                },
+                |world, ent| {
+                    let mut zone = world.write_storage::<CaptureZone>();
+                    let zone = zone.get_mut(ent).unwrap();
+                    zone.capturing = Some((Team(1), 0.4));
+                    zone.contested = true;
+                },
+            ),
+            (
+                "ore",
+                |world| {
+                    let ent = world
+                        .create_entity()
+                        .with(OrePickup { amount: 7 })
+                        .build();
+                    motion(world, ent, [3.0, 4.0], [0.5, 0.0]);
+                    ent
+                },
+                |world, ent| {
+                    let mut ore = world.write_storage::<OrePickup>();
+                    ore.get_mut(ent).unwrap().amount = 3;
+                },
+            ),
+            (
+                "station",
+                |world| {
+                    let station = Station {
+                        safe_radius: 30.0,
+                        ports: 4,
+                    };
+                    let pos = Position {
+                        pos: [-60.0, 0.0],
+                        rot: 0.0,
+                    };
+                    world.create_entity().with(station).with(pos).build()
+                },
+                |world, ent| {
+                    let mut station = world.write_storage::<Station>();
+                    station.get_mut(ent).unwrap().ports = 3;
+                },
+            ),
+            (
+                "planet",
+                |world| {
+                    let pos = Position {
+                        pos: [200.0, 200.0],
+                        rot: 0.0,
+                    };
+                    world
+                        .create_entity()
+                        .with(Planet)
+                        .with(CircleCollider { radius: 50.0 })
+                        .with(pos)
+                        .build()
+                },
+                |world, ent| {
+                    let mut circle = world.write_storage::<CircleCollider>();
+                    circle.get_mut(ent).unwrap().radius = 55.0;
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let registry = Registry::default();
+        let kinds = kinds();
+        assert_eq!(kinds.len(), registry.kinds.len());
+        for (tag, &(name, setup, change)) in kinds.iter().enumerate() {
+            let mut server = world();
+            let mut client = world();
+            let ent = setup(&mut server);
+            let data = write(&server, &registry, ent);
+            assert_eq!(data[0] as usize, tag);
+            assert_eq!(registry.read(&data).unwrap().0.name, name);
+
+            // The entity the client creates is written back the same
+            let client_ent = create(&mut client, &registry, &data);
+            let written = write(&client, &registry, client_ent);
+            assert_eq!(written, data, "{}", name);
+
+            // And so is it after an update
+            change(&server, ent);
+            let update = write(&server, &registry, ent);
+            assert_ne!(update, data, "{}", name);
+            apply(&client, &registry, &update, client_ent);
+            let written = write(&client, &registry, client_ent);
+            assert_eq!(written, update, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let registry = Registry::default();
+        let mut world = world();
+        let (_, setup, _) = kinds()[0];
+        let ent = setup(&mut world);
+        let ship = write(&world, &registry, ent);
+
+        assert!(registry.read(&ship).is_some());
+        assert!(registry.read(&[]).is_none());
+        // Unknown kind
+        let mut unknown = ship.clone();
+        unknown[0] = registry.kinds.len() as u8;
+        assert!(registry.read(&unknown).is_none());
+        assert!(registry.read(&[255]).is_none());
+        // Too short, or too long
+        assert!(registry.read(&ship[..50]).is_none());
+        assert!(registry.read(&ship[..ship.len() - 1]).is_none());
+        let mut long = ship.clone();
+        long.push(0);
+        assert!(registry.read(&long).is_none());
+        // More disabled blocks than there is room for
+        let mut count = ship.clone();
+        count[1 + 108] += 1;
+        assert!(registry.read(&count).is_none());
+
+        // Garbage of the right length doesn't crash the client
+        for kind in &registry.kinds {
+            let data = vec![0xFF; 1 + kind.len];
+            if let Some((kind, mut state)) = registry.read(&data) {
+                let ent = world.create_entity().build();
+                let creation = Creation {
+                    lazy: &world.read_resource::<LazyUpdate>(),
+                    quantization: Quantization::default(),
+                    time: 0.0,
+                };
+                (kind.create)(&mut state, &creation, ent);
+            }
+        }
+        world.maintain();
+    }
+}
@@ -0,0 +1,162 @@
+use log::warn;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Interval between two summaries of invalid messages in the log.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Maximum number of sources counted separately during an interval.
+///
+/// This bounds memory use if the messages come from spoofed addresses.
+const MAX_SOURCES: usize = 64;
+
+/// Interval over which the byte rates are measured.
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Network counters and health, available as a resource.
+///
+/// On the server, `rtt` and `packet_loss` are averaged over the clients.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkStats {
+    /// Messages received, valid or not.
+    pub messages_received: u64,
+    /// Messages dropped because they were invalid.
+    pub invalid_messages: u64,
+    /// Packets from the other side that never arrived (see `conn.rs`).
+    pub packets_lost: u64,
+    /// Messages dropped because a more recent one was already handled.
+    pub stale_messages: u64,
+    /// Packets and messages dropped because their source went over its
+    /// budget (see `limit.rs`).
+    pub rate_limited: u64,
+    /// Entity updates put off because a client was over its bandwidth.
+    pub deferred_updates: u64,
+    /// Bytes received, headers included.
+    pub bytes_received: u64,
+    /// Bytes sent, headers included.
+    pub bytes_sent: u64,
+    /// Bytes received per second, over the last `RATE_INTERVAL`.
+    pub bytes_in_rate: f32,
+    /// Bytes sent per second, over the last `RATE_INTERVAL`.
+    pub bytes_out_rate: f32,
+    /// Round-trip time, in seconds, from the last pong.
+    pub rtt: f32,
+    /// Fraction of the last 32 packets from the other side that didn't
+    /// arrive.
+    pub packet_loss: f32,
+    /// Number of replicated entities.
+    pub entities: usize,
+    /// Figures for each connected client, on servers.
+    pub clients: Vec<ClientStats>,
+}
+
+/// Network figures of a client, on the server.
+#[derive(Debug, Clone)]
+pub struct ClientStats {
+    pub client_id: u64,
+    /// Round-trip time, in seconds, from the last pong.
+    pub rtt: f32,
+    /// Fraction of the last 32 packets from the client that didn't arrive.
+    pub packet_loss: f32,
+    /// Bytes received from the client, since it connected.
+    pub bytes_received: u64,
+    /// Bytes sent to the client, since it connected.
+    pub bytes_sent: u64,
+}
+
+/// Computes the byte rates of `NetworkStats` from its totals.
+pub struct RateMeter {
+    since: Instant,
+    received: u64,
+    sent: u64,
+}
+
+impl RateMeter {
+    pub fn new() -> RateMeter {
+        RateMeter {
+            since: Instant::now(),
+            received: 0,
+            sent: 0,
+        }
+    }
+
+    /// Updates the rates, if the interval is over.
+    pub fn update(&mut self, stats: &mut NetworkStats) {
+        let elapsed = self.since.elapsed();
+        if elapsed < RATE_INTERVAL {
+            return;
+        }
+        let secs = elapsed.as_secs_f32();
+        stats.bytes_in_rate =
+            (stats.bytes_received - self.received) as f32 / secs;
+        stats.bytes_out_rate = (stats.bytes_sent - self.sent) as f32 / secs;
+        self.since = Instant::now();
+        self.received = stats.bytes_received;
+        self.sent = stats.bytes_sent;
+    }
+}
+
+/// Counts invalid messages, to log them as a single periodic summary.
+///
+/// Logging every invalid message would let a flood of garbage packets
+/// overwhelm the server with its own logging.
+pub struct InvalidLog<A> {
+    counts: HashMap<A, u32>,
+    /// Messages from sources past `MAX_SOURCES`.
+    others: u32,
+    since: Instant,
+}
+
+impl<A: Clone + Display + Eq + Hash> InvalidLog<A> {
+    pub fn new() -> InvalidLog<A> {
+        InvalidLog {
+            counts: HashMap::new(),
+            others: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Records an invalid message from a source.
+    pub fn record(&mut self, src: &A, stats: &mut NetworkStats) {
+        stats.invalid_messages += 1;
+        if let Some(count) = self.counts.get_mut(src) {
+            *count += 1;
+        } else if self.counts.len() < MAX_SOURCES {
+            self.counts.insert(src.clone(), 1);
+        } else {
+            self.others += 1;
+        }
+    }
+
+    /// Logs the summary, if the interval is over.
+    pub fn report(&mut self) {
+        if self.since.elapsed() < REPORT_INTERVAL {
+            return;
+        }
+        self.since = Instant::now();
+        let (worst, worst_count) =
+            match self.counts.iter().max_by_key(|&(_, &count)| count) {
+                Some(w) => w,
+                None => return,
+            };
+        let total = self.counts.values().sum::<u32>() + self.others;
+        let sources = if self.others > 0 {
+            format!("more than {}", self.counts.len())
+        } else {
+            format!("{}", self.counts.len())
+        };
+        warn!(
+            "Dropped {} invalid messages from {} sources in the last {}s, \
+             {} from {}",
+            total,
+            sources,
+            REPORT_INTERVAL.as_secs(),
+            worst_count,
+            worst
+        );
+        self.counts.clear();
+        self.others = 0;
+    }
+}
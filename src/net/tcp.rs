@@ -0,0 +1,400 @@
+//! TCP transport, for where UDP is blocked.
+//!
+//! Each packet is sent with its length as a 16-bit prefix. Streams are
+//! non-blocking like the UDP sockets: what can't be written right away waits
+//! in a buffer, and packets past `MAX_PENDING` bytes are dropped, as they
+//! would be by UDP. A closed connection is forgotten, and its client times
+//! out.
+//!
+//! The server accepts connections, flushes and reads all the streams once
+//! per poll, then hands out the packets it read until it runs out and the
+//! poll ends.
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use log::{debug, info};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+
+use super::{Client, Server, ORDER};
+
+/// Bytes waiting to be written to a stream, past which packets are dropped.
+const MAX_PENDING: usize = 64 * 1024;
+
+/// Connections a server keeps by default, past which new ones are closed.
+const MAX_CONNECTIONS: usize = 256;
+
+/// A stream with its framing.
+struct Stream {
+    stream: TcpStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl Stream {
+    fn new(stream: TcpStream) -> io::Result<Stream> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Stream {
+            stream,
+            input: Vec::new(),
+            output: Vec::new(),
+        })
+    }
+
+    /// Reads the next packet, `None` if it didn't arrive yet.
+    ///
+    /// Returns an error if the connection is closed.
+    fn read_packet(&mut self, buffer: &mut [u8]) -> io::Result<Option<usize>> {
+        loop {
+            if self.input.len() >= 2 {
+                let len = (&self.input[..2]).read_u16::<ORDER>()? as usize;
+                if len > buffer.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "packet too long",
+                    ));
+                }
+                if self.input.len() >= 2 + len {
+                    buffer[..len].copy_from_slice(&self.input[2..2 + len]);
+                    self.input.drain(..2 + len);
+                    return Ok(Some(len));
+                }
+            }
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed",
+                    ))
+                }
+                Ok(n) => self.input.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Queues a packet, and writes what the stream takes.
+    fn write_packet(&mut self, msg: &[u8]) -> io::Result<usize> {
+        if self.output.len() + 2 + msg.len() > MAX_PENDING {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "too much data pending, dropping packet",
+            ));
+        }
+        self.output.write_u16::<ORDER>(msg.len() as u16)?;
+        self.output.extend_from_slice(msg);
+        self.flush()?;
+        Ok(msg.len())
+    }
+
+    /// Writes the pending data the stream takes.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct TcpServer {
+    listener: TcpListener,
+    max_connections: usize,
+    streams: RefCell<HashMap<SocketAddr, Stream>>,
+    /// Packets read during the current poll, not handed out yet.
+    received: RefCell<VecDeque<(Vec<u8>, SocketAddr)>>,
+    /// Whether the current poll went over the streams already.
+    polled: Cell<bool>,
+}
+
+impl TcpServer {
+    pub fn new(port: u16) -> TcpServer {
+        let unspec = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+        let listener = match TcpListener::bind(SocketAddr::new(unspec, port))
+        {
+            Ok(l) => l,
+            Err(e) => panic!("Couldn't listen on port {}: {}", port, e),
+        };
+        listener
+            .set_nonblocking(true)
+            .expect("Couldn't set socket nonblocking");
+        TcpServer {
+            listener,
+            max_connections: MAX_CONNECTIONS,
+            streams: RefCell::new(HashMap::new()),
+            received: RefCell::new(VecDeque::new()),
+            polled: Cell::new(false),
+        }
+    }
+
+    /// Sets the number of connections kept, past which new ones are closed.
+    pub fn max_connections(mut self, max: usize) -> TcpServer {
+        self.max_connections = max;
+        self
+    }
+
+    /// Accepts the new connections, then flushes and reads all the streams.
+    fn poll(&self, max_len: usize) -> io::Result<()> {
+        let mut streams = self.streams.borrow_mut();
+        loop {
+            match self.listener.accept() {
+                Ok((_, addr)) if streams.len() >= self.max_connections => {
+                    info!("Too many connections, closing {}", addr);
+                }
+                Ok((stream, addr)) => match Stream::new(stream) {
+                    Ok(stream) => {
+                        debug!("New connection from {}", addr);
+                        streams.insert(addr, stream);
+                    }
+                    Err(e) => info!("Error setting up {}: {}", addr, e),
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut received = self.received.borrow_mut();
+        let mut buffer = vec![0; max_len];
+        streams.retain(|addr, stream| {
+            let res = stream.flush().and_then(|()| {
+                while let Some(len) = stream.read_packet(&mut buffer)? {
+                    received.push_back((buffer[..len].to_vec(), *addr));
+                }
+                Ok(())
+            });
+            match res {
+                Ok(()) => true,
+                Err(e) => {
+                    info!("Lost connection to {}: {}", addr, e);
+                    false
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Server for TcpServer {
+    type Address = SocketAddr;
+
+    fn send(&self, msg: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        let mut streams = self.streams.borrow_mut();
+        let stream = match streams.get_mut(addr) {
+            Some(s) => s,
+            // Gone, like a UDP client would be
+            None => return Ok(0),
+        };
+        match stream.write_packet(msg) {
+            Err(ref e) if e.kind() != io::ErrorKind::WouldBlock => {
+                info!("Lost connection to {}: {}", addr, e);
+                streams.remove(addr);
+                Ok(0)
+            }
+            r => r,
+        }
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if !self.polled.get() {
+            self.polled.set(true);
+            self.poll(buffer.len())?;
+        }
+        match self.received.borrow_mut().pop_front() {
+            Some((packet, addr)) => {
+                buffer[..packet.len()].copy_from_slice(&packet);
+                Ok((packet.len(), addr))
+            }
+            None => {
+                // The next call starts a new poll
+                self.polled.set(false);
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+
+    fn host(&self, address: &SocketAddr) -> String {
+        address.ip().to_string()
+    }
+}
+
+pub struct TcpClient {
+    stream: RefCell<Option<Stream>>,
+}
+
+impl TcpClient {
+    pub fn new(address: SocketAddr) -> io::Result<TcpClient> {
+        let stream = Stream::new(TcpStream::connect(address)?)?;
+        Ok(TcpClient {
+            stream: RefCell::new(Some(stream)),
+        })
+    }
+}
+
+impl Client for TcpClient {
+    fn send(&self, msg: &[u8]) -> io::Result<usize> {
+        let mut stream = self.stream.borrow_mut();
+        let res = match *stream {
+            Some(ref mut s) => s.write_packet(msg),
+            None => return Ok(0),
+        };
+        match res {
+            Err(ref e) if e.kind() != io::ErrorKind::WouldBlock => {
+                info!("Lost connection to server: {}", e);
+                *stream = None;
+                Ok(0)
+            }
+            r => r,
+        }
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut stream = self.stream.borrow_mut();
+        let res = match *stream {
+            Some(ref mut s) => s.flush().and_then(|()| s.read_packet(buffer)),
+            None => Ok(None),
+        };
+        match res {
+            Ok(Some(len)) => Ok(len),
+            Ok(None) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(e) => {
+                // The server stops answering, and times out
+                info!("Lost connection to server: {}", e);
+                *stream = None;
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::WriteBytesExt;
+    use std::io::{self, Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::{TcpClient, TcpServer};
+    use crate::net::{Client, Server, ORDER};
+
+    /// A server on a free port, and its address on the loopback.
+    fn server() -> (TcpServer, SocketAddr) {
+        let server = TcpServer::new(0);
+        let port = server.listener.local_addr().unwrap().port();
+        (server, SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    /// Polls the server until it gets a packet, or gives up.
+    fn recv(server: &TcpServer) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buffer = [0; 1200];
+        for _ in 0..50 {
+            match server.recv(&mut buffer) {
+                Ok((len, addr)) => return Some((buffer[..len].to_vec(), addr)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        None
+    }
+
+    /// Whether the server closed the connection.
+    fn closed(stream: &mut TcpStream) -> bool {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut buffer = [0; 16];
+        match stream.read(&mut buffer) {
+            Ok(0) => true,
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (server, addr) = server();
+        let client = TcpClient::new(addr).unwrap();
+        client.send(b"hello").unwrap();
+        let (packet, from) = recv(&server).unwrap();
+        assert_eq!(packet, b"hello");
+
+        server.send(b"welcome", &from).unwrap();
+        let mut buffer = [0; 1200];
+        let mut len = None;
+        for _ in 0..50 {
+            if let Ok(l) = client.recv(&mut buffer) {
+                len = Some(l);
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(&buffer[..len.unwrap()], b"welcome");
+    }
+
+    #[test]
+    fn test_partial() {
+        let (server, addr) = server();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+
+        // The length, and half the packet
+        stream.write_u16::<ORDER>(6).unwrap();
+        stream.write_all(b"abc").unwrap();
+        assert_eq!(recv(&server), None);
+
+        // The rest, then two packets at once
+        let mut rest = b"def".to_vec();
+        rest.write_u16::<ORDER>(1).unwrap();
+        rest.push(b'g');
+        rest.write_u16::<ORDER>(0).unwrap();
+        stream.write_all(&rest).unwrap();
+        assert_eq!(recv(&server).unwrap().0, b"abcdef");
+        assert_eq!(recv(&server).unwrap().0, b"g");
+        assert_eq!(recv(&server).unwrap().0, b"");
+        assert_eq!(recv(&server), None);
+    }
+
+    #[test]
+    fn test_too_long() {
+        let (server, addr) = server();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_u16::<ORDER>(1201).unwrap();
+        stream.write_all(&[0; 1201]).unwrap();
+        assert_eq!(recv(&server), None);
+        assert!(closed(&mut stream));
+        assert!(server.streams.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let (server, addr) = server();
+        let server = server.max_connections(1);
+        let first = TcpClient::new(addr).unwrap();
+        first.send(b"first").unwrap();
+        assert_eq!(recv(&server).unwrap().0, b"first");
+
+        let mut second = TcpStream::connect(addr).unwrap();
+        second.write_u16::<ORDER>(6).unwrap();
+        second.write_all(b"second").unwrap();
+        assert_eq!(recv(&server), None);
+        assert!(closed(&mut second));
+
+        // The first one is still there
+        first.send(b"again").unwrap();
+        assert_eq!(recv(&server).unwrap().0, b"again");
+    }
+}
@@ -1,8 +1,27 @@
-use log::info;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::io::{self, Cursor};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
-use std::io;
+use std::time::{Duration, Instant};
 
-use super::{Client, Server};
+use super::{Client, Server, ORDER, PROTOCOL_VERSION};
+use crate::utils::clean_text;
+
+/// Port servers announce themselves on, for clients on the local network.
+pub const DISCOVERY_PORT: u16 = 34245;
+
+/// Start of every announcement.
+const ANNOUNCE_MAGIC: &[u8] = b"SPACLAN";
+
+/// Interval between announcements.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time after which a server that stopped announcing itself is forgotten.
+const SERVER_EXPIRY: Duration = Duration::from_secs(5);
+
+/// Maximum length of a server's name, in bytes.
+pub const MAX_SERVER_NAME_LEN: usize = 64;
 
 pub struct UdpServer {
     socket: UdpSocket,
@@ -32,6 +51,10 @@ impl Server for UdpServer {
     fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
         self.socket.recv_from(buffer)
     }
+
+    fn host(&self, address: &SocketAddr) -> String {
+        address.ip().to_string()
+    }
 }
 
 pub struct UdpClient {
@@ -72,3 +95,135 @@ impl Client for UdpClient {
         }
     }
 }
+
+/// Announces a server on the local network, for `LanDiscovery`.
+///
+/// Each announcement is a broadcast with the protocol version, the port
+/// of the game, the number of players, and the name of the server.
+pub struct LanAnnouncer {
+    socket: UdpSocket,
+    message: Vec<u8>,
+    last: Option<Instant>,
+}
+
+impl LanAnnouncer {
+    pub fn new(name: &str, port: u16) -> io::Result<LanAnnouncer> {
+        let unspec = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+        let socket = UdpSocket::bind(SocketAddr::new(unspec, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        let name = clean_text(name, MAX_SERVER_NAME_LEN).unwrap_or_default();
+        let mut message = ANNOUNCE_MAGIC.to_vec();
+        message.write_u16::<ORDER>(PROTOCOL_VERSION).unwrap();
+        message.write_u16::<ORDER>(port).unwrap();
+        message.write_u16::<ORDER>(0).unwrap();
+        message.extend_from_slice(name.as_bytes());
+        Ok(LanAnnouncer {
+            socket,
+            message,
+            last: None,
+        })
+    }
+
+    /// Announces the server, if it hasn't been for `ANNOUNCE_INTERVAL`.
+    pub fn announce(&mut self, players: usize) {
+        let now = Instant::now();
+        match self.last {
+            Some(last) if now.duration_since(last) < ANNOUNCE_INTERVAL => {
+                return
+            }
+            _ => {}
+        }
+        self.last = Some(now);
+        let players = players.min(u16::MAX as usize) as u16;
+        let pos = ANNOUNCE_MAGIC.len() + 4;
+        (&mut self.message[pos..pos + 2])
+            .write_u16::<ORDER>(players)
+            .unwrap();
+        let broadcast = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)),
+            DISCOVERY_PORT,
+        );
+        if let Err(e) = self.socket.send_to(&self.message, broadcast) {
+            debug!("Can't announce server: {}", e);
+        }
+    }
+}
+
+/// A server found on the local network.
+#[derive(Debug, Clone)]
+pub struct LanServer {
+    /// Address to connect to.
+    pub address: SocketAddr,
+    pub name: String,
+    pub players: u16,
+    last_seen: Instant,
+}
+
+/// Listens for servers announcing themselves on the local network.
+pub struct LanDiscovery {
+    socket: UdpSocket,
+    servers: HashMap<SocketAddr, LanServer>,
+}
+
+impl LanDiscovery {
+    pub fn new() -> io::Result<LanDiscovery> {
+        let unspec = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+        let socket = UdpSocket::bind(SocketAddr::new(unspec, DISCOVERY_PORT))?;
+        socket.set_nonblocking(true)?;
+        Ok(LanDiscovery {
+            socket,
+            servers: HashMap::new(),
+        })
+    }
+
+    /// Reads the announcements received, and returns the servers heard
+    /// from recently that speak our version of the protocol.
+    pub fn poll(&mut self) -> Vec<LanServer> {
+        let mut buffer = [0; 128];
+        loop {
+            let (len, src) = match self.socket.recv_from(&mut buffer) {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("Error reading announcements: {}", e);
+                    break;
+                }
+            };
+            if let Some(server) = parse_announce(&buffer[..len], src) {
+                self.servers.insert(server.address, server);
+            }
+        }
+
+        let now = Instant::now();
+        self.servers
+            .retain(|_, s| now.duration_since(s.last_seen) < SERVER_EXPIRY);
+        let mut servers = self.servers.values().cloned().collect::<Vec<_>>();
+        servers.sort_by(|a, b| a.name.cmp(&b.name));
+        servers
+    }
+}
+
+/// Reads an announcement, sent from `src`.
+fn parse_announce(msg: &[u8], src: SocketAddr) -> Option<LanServer> {
+    let header = ANNOUNCE_MAGIC.len() + 6;
+    if msg.len() < header || &msg[..ANNOUNCE_MAGIC.len()] != ANNOUNCE_MAGIC {
+        return None;
+    }
+    let mut rdr = Cursor::new(&msg[ANNOUNCE_MAGIC.len()..]);
+    let version = rdr.read_u16::<ORDER>().unwrap();
+    if version != PROTOCOL_VERSION {
+        debug!("Server {} speaks protocol version {}", src, version);
+        return None;
+    }
+    let port = rdr.read_u16::<ORDER>().unwrap();
+    let players = rdr.read_u16::<ORDER>().unwrap();
+    let name = String::from_utf8_lossy(&msg[header..]);
+    let name = clean_text(&name, MAX_SERVER_NAME_LEN).unwrap_or_default();
+    Some(LanServer {
+        address: SocketAddr::new(src.ip(), port),
+        name,
+        players,
+        last_seen: Instant::now(),
+    })
+}
@@ -0,0 +1,145 @@
+//! Checks of the controls clients send, on servers.
+//!
+//! Controls that parse can still be impossible: for a ship the client
+//! doesn't fly, a target past anything the player can see, or keys toggled
+//! faster than anyone can press them. Those are dropped and counted as
+//! invalid messages, and the ship keeps its previous controls.
+
+use std::collections::HashSet;
+use std::time::SystemTime;
+use vecmath::vec2_len;
+
+use crate::sensors::SENSOR_RANGE;
+
+/// Farthest from its ship a player can aim.
+const MAX_TARGET_RANGE: f32 = SENSOR_RANGE;
+
+/// Changes per second allowed for each flag, about 10 key presses.
+const MAX_TOGGLE_RATE: f32 = 20.0;
+
+/// Changes each flag can make in a burst.
+const MAX_TOGGLE_BURST: f32 = 6.0;
+
+/// Whether a client flies this entity, given the (client, entity) pairs.
+pub fn flies(controls: &HashSet<(u64, u64)>, client_id: u64, id: u64) -> bool {
+    controls.contains(&(client_id, id))
+}
+
+/// Whether a player could have aimed at this target, relative to the ship.
+pub fn valid_target(target: [f32; 2]) -> bool {
+    target[0].is_finite()
+        && target[1].is_finite()
+        && vec2_len(target) <= MAX_TARGET_RANGE
+}
+
+/// Limits how fast a client toggles the flags of a ship's controls.
+pub struct ToggleLimit {
+    /// Flags of the last controls allowed.
+    flags: Option<u16>,
+    /// Changes left for each flag.
+    allowance: [f32; 16],
+    last_refill: SystemTime,
+}
+
+impl Default for ToggleLimit {
+    fn default() -> ToggleLimit {
+        ToggleLimit {
+            flags: None,
+            allowance: [MAX_TOGGLE_BURST; 16],
+            last_refill: SystemTime::now(),
+        }
+    }
+}
+
+impl ToggleLimit {
+    /// Records the flags of new controls, false if some changed too fast.
+    pub fn allow(&mut self, flags: u16, now: SystemTime) -> bool {
+        let elapsed = now.duration_since(self.last_refill).unwrap_or_default();
+        self.last_refill = now;
+        for allowance in &mut self.allowance {
+            *allowance = (*allowance
+                + elapsed.as_secs_f32() * MAX_TOGGLE_RATE)
+                .min(MAX_TOGGLE_BURST);
+        }
+
+        let changed = self.flags.map_or(0, |last| last ^ flags);
+        let too_fast = self
+            .allowance
+            .iter()
+            .enumerate()
+            .any(|(bit, &a)| changed & (1 << bit) != 0 && a < 1.0);
+        if too_fast {
+            return false;
+        }
+        for (bit, allowance) in self.allowance.iter_mut().enumerate() {
+            if changed & (1 << bit) != 0 {
+                *allowance -= 1.0;
+            }
+        }
+        self.flags = Some(flags);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::{Duration, SystemTime};
+
+    use super::{flies, valid_target, ToggleLimit, MAX_TARGET_RANGE,
+                MAX_TOGGLE_BURST};
+
+    #[test]
+    fn test_flies() {
+        let controls = [(1, 10), (1, 11), (2, 20)]
+            .iter()
+            .cloned()
+            .collect::<HashSet<_>>();
+        assert!(flies(&controls, 1, 10));
+        assert!(flies(&controls, 1, 11));
+        assert!(flies(&controls, 2, 20));
+        // Someone else's ship, or nobody's
+        assert!(!flies(&controls, 1, 20));
+        assert!(!flies(&controls, 2, 10));
+        assert!(!flies(&controls, 3, 30));
+    }
+
+    #[test]
+    fn test_valid_target() {
+        assert!(valid_target([0.0, 0.0]));
+        assert!(valid_target([-10.0, 25.0]));
+        assert!(valid_target([MAX_TARGET_RANGE, 0.0]));
+        assert!(valid_target([0.0, -MAX_TARGET_RANGE]));
+        assert!(!valid_target([MAX_TARGET_RANGE, 1.0]));
+        assert!(!valid_target([-1e6, 0.0]));
+        assert!(!valid_target([f32::NAN, 0.0]));
+        assert!(!valid_target([0.0, f32::INFINITY]));
+        assert!(!valid_target([f32::NEG_INFINITY, 0.0]));
+    }
+
+    #[test]
+    fn test_toggle_rate() {
+        let start = SystemTime::now();
+        let mut limit = ToggleLimit::default();
+        // The first flags are free, and so is holding them
+        assert!(limit.allow(0xFFFF, start));
+        assert!(limit.allow(0xFFFF, start));
+
+        // A burst of presses
+        let mut flags = 0xFFFF;
+        for _ in 0..MAX_TOGGLE_BURST as u32 {
+            flags ^= 0x0001;
+            assert!(limit.allow(flags, start));
+        }
+        // One more is too fast, and isn't recorded
+        assert!(!limit.allow(flags ^ 0x0001, start));
+        assert!(limit.allow(flags, start));
+        // Other flags have their own allowance
+        assert!(limit.allow(flags ^ 0x0100, start));
+
+        // Time gives the allowance back
+        let later = start + Duration::from_millis(100);
+        assert!(limit.allow(flags, later));
+        assert!(limit.allow(flags ^ 0x0001, later));
+    }
+}
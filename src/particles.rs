@@ -7,9 +7,10 @@
 //! explosions are an `Effect`, that is turned into particles by `SysParticles`
 //! once we got to replicate it to the clients.
 
-use rand::{self, Rng};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
 use specs::{Component, Entities, Read, ReadExpect, Join, LazyUpdate,
-            ReadStorage, System, VecStorage, WriteStorage};
+            ReadStorage, System, VecStorage, Write, WriteStorage};
 use std::f32::consts::PI;
 
 use crate::Role;
@@ -22,10 +23,14 @@ pub enum ParticleType {
     Spark,
     /// Smoke out of a thruster.
     Exhaust,
+    /// Flames out of a boosted thruster.
+    BoostExhaust,
     /// Destroyed parts blow up.
     Explosion,
     /// Laser hits flash.
     LaserHit,
+    /// Electromagnetic pulse flash.
+    EmpHit,
 }
 
 /// This entity is a particle.
@@ -52,6 +57,7 @@ pub enum EffectInner {
     Explosion(f32),
     MetalHit,
     LaserHit,
+    EmpHit,
 }
 
 pub struct Effect {
@@ -63,6 +69,42 @@ impl Component for Effect {
     type Storage = VecStorage<Self>;
 }
 
+/// Random number generator for particles, available as a resource.
+///
+/// Particles only exist on graphical machines, so they don't draw from the
+/// `GameRng`, which would make the simulation depend on being displayed.
+pub struct EffectRng(StdRng);
+
+impl EffectRng {
+    pub fn new(seed: u64) -> EffectRng {
+        EffectRng(StdRng::seed_from_u64(seed))
+    }
+}
+
+impl Default for EffectRng {
+    fn default() -> EffectRng {
+        EffectRng::new(0)
+    }
+}
+
+impl RngCore for EffectRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.0.try_fill_bytes(dest)
+    }
+}
+
 /// System that spawns particles (from effects) and deletes old particles.
 pub struct SysParticles;
 
@@ -71,6 +113,7 @@ impl<'a> System<'a> for SysParticles {
         Read<'a, DeltaTime>,
         ReadExpect<'a, Role>,
         Read<'a, LazyUpdate>,
+        Write<'a, EffectRng>,
         Entities<'a>,
         ReadStorage<'a, Position>,
         WriteStorage<'a, Effect>,
@@ -83,6 +126,7 @@ impl<'a> System<'a> for SysParticles {
             dt,
             role,
             lazy,
+            mut rng,
             entities,
             position,
             mut effects,
@@ -98,7 +142,7 @@ impl<'a> System<'a> for SysParticles {
         let dt = dt.0;
 
         // Spawn particles from effects
-        let mut rng = rand::thread_rng();
+        let rng = &mut *rng;
         for (ent, effect, pos) in (&*entities, &mut effects, &position).join()
         {
             match effect.effect {
@@ -178,6 +222,17 @@ impl<'a> System<'a> for SysParticles {
                         },
                     );
                 }
+                EffectInner::EmpHit => {
+                    let ent = entities.create();
+                    lazy.insert(ent, pos.clone());
+                    lazy.insert(
+                        ent,
+                        Particle {
+                            lifetime: 0.4,
+                            which: ParticleType::EmpHit,
+                        },
+                    );
+                }
             }
 
             effect.lifetime -= dt;
@@ -3,17 +3,24 @@
 //! This contains `Position`, `Velocity`, `Hits`, ... `SysSimu` integrates
 //! positions, finds collisions.
 
+use log::warn;
+use specs::shrev::EventChannel;
 use specs::{Component, Entities, Entity, Read, ReadExpect, HashMapStorage,
-            Join, LazyUpdate, NullStorage, ReadStorage, System, VecStorage,
-            WriteStorage};
+            Join, LazyUpdate, ReadStorage, System, VecStorage,
+            Write, WriteStorage};
+use std::collections::HashSet;
 use std::f32::consts::PI;
 use std::ops::Deref;
 use vecmath::*;
 
 use crate::Role;
 use crate::blocks::Blocky;
+use crate::faction::{hostile, Faction};
+use crate::joints::{jointed, Joint};
+use crate::math::sin_cos;
 #[cfg(feature = "network")]
 use crate::net;
+use crate::rules::Rules;
 use crate::sat;
 use crate::tree;
 
@@ -114,6 +121,14 @@ impl Component for Velocity {
     type Storage = VecStorage<Self>;
 }
 
+/// Lifetime component, for temporary entities: time left before
+/// `SysLifetime` deletes them.
+pub struct Lifetime(pub f32);
+
+impl Component for Lifetime {
+    type Storage = VecStorage<Self>;
+}
+
 /// Special collision.
 ///
 /// No built-in collision response, just detect collision and mark that object.
@@ -129,22 +144,61 @@ impl Component for DetectCollision {
     type Storage = VecStorage<Self>;
 }
 
+/// Static circular obstacle, such as the surface of a planet.
+///
+/// `Blocky` objects bounce off its edge as off an object of infinite mass,
+/// in `SysCollision`, alongside the collisions between blocks.
+#[derive(Debug, Clone)]
+pub struct CircleCollider {
+    pub radius: f32,
+}
+
+impl Component for CircleCollider {
+    type Storage = VecStorage<Self>;
+}
+
 /// Attached to a Hit, indicates the effect on the receiving entity.
 #[derive(Clone)]
 pub enum HitEffect {
     /// Material collision, such as between blocky objects.
     Collision(f32, Entity),
-    /// Caught in an explosion.
-    Explosion(f32),
+    /// Caught in an explosion: size, and the entity that caused it (such as
+    /// the ship that fired) if any.
+    Explosion(f32, Option<Entity>),
+    /// Caught in an electromagnetic pulse: radius and duration for which the
+    /// blocks get disabled.
+    Emp(f32, f32),
+    /// Block deconstructed by a salvage beam, at the exact location of the
+    /// block.
+    Salvage,
+    /// Damage to the single block under the hit, from a beam or a piercing
+    /// round, and the entity that fired.
+    Pierce(f32, Entity),
 }
 
 /// A single collision, stored in the Hits component.
+#[derive(Clone)]
 pub struct Hit {
     /// Location of the hit, in this entity's coordinate system.
     pub rel_location: [f32; 2],
     pub effect: HitEffect,
 }
 
+/// A `Hit` on an entity, as sent on the `CollisionEvents` channel.
+#[derive(Clone)]
+pub struct CollisionEvent {
+    pub entity: Entity,
+    pub hit: Hit,
+}
+
+/// Channel of collision events, available as a resource.
+///
+/// Unlike the `Hits` storage, which `SysCollision` clears every frame, this
+/// lets any number of systems observe every hit, whenever they run. Hits
+/// get sent when `SysCollision` clears them, so they reach readers the frame
+/// after they happened. Only authoritative games fill this in.
+pub type CollisionEvents = EventChannel<CollisionEvent>;
+
 /// Collision information: this flags an entity as having collided.
 pub struct Hits {
     hits_vec: Vec<Hit>,
@@ -182,12 +236,113 @@ impl Deref for Hits {
     }
 }
 
-/// Marks that this entity is controlled by the local player.
-#[derive(Default)]
-pub struct LocalControl;
+/// Marks that this entity is controlled by a local player.
+///
+/// This is the index of the player's controls in `input::Inputs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalControl(pub usize);
 
 impl Component for LocalControl {
-    type Storage = NullStorage<Self>;
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Storage telling which entities are controlled by network clients.
+///
+/// This is `()` without the `network` feature, so systems can ask for it
+/// either way and pass it to `pilot()`.
+#[cfg(feature = "network")]
+pub type RemoteControl<'a> = ReadStorage<'a, net::ClientControlled>;
+#[cfg(not(feature = "network"))]
+pub type RemoteControl<'a> = ();
+
+/// The player controlling an entity, if any.
+///
+/// Local players are identified by their index, network clients by their
+/// client ID (starting at 1). Hosts keep the first IDs for their local
+/// players, so those don't overlap.
+pub fn pilot(
+    ent: Entity,
+    local: &ReadStorage<LocalControl>,
+    remote: &RemoteControl,
+) -> Option<u64> {
+    if let Some(&LocalControl(index)) = local.get(ent) {
+        return Some(index as u64);
+    }
+    #[cfg(feature = "network")]
+    {
+        remote.get(ent).map(|ctrl| ctrl.client_id)
+    }
+    #[cfg(not(feature = "network"))]
+    {
+        let _ = remote;
+        None
+    }
+}
+
+/// Tuning constants for the physics, available as a resource.
+///
+/// Set through `GameBuilder::physics()`. Clients simulate ships locally
+/// between updates, so they should use the same values as the server.
+#[derive(Debug, Clone)]
+pub struct PhysicsConfig {
+    /// Coefficient of restitution for collisions between blocky objects.
+    pub elasticity: f32,
+    /// Relative speed under which colliding objects don't bounce, so that
+    /// objects resting against each other settle.
+    pub resting_speed: f32,
+    /// Speed under which objects coming down on a `CircleCollider` land
+    /// on it, without bouncing or taking damage, instead of crashing.
+    pub landing_speed: f32,
+    /// Fraction of the overlap between colliding objects corrected at each
+    /// step, by moving them apart.
+    pub correction: f32,
+    /// Overlap allowed between objects before it gets corrected, so that
+    /// resting contacts stay in contact instead of jittering.
+    pub penetration_slop: f32,
+    /// Factor for the drag slowing down objects, applied to the square of
+    /// the velocity.
+    pub friction: f32,
+    /// Factor for the drag slowing down rotation.
+    pub rot_friction: f32,
+    /// Force of a single thruster.
+    pub thrust: f32,
+    /// Impulse pushing a ship back when it fires a projectile.
+    pub recoil: f32,
+    /// Longest time step for the physics. Longer frames get integrated in
+    /// several sub-steps, checking for collisions in between, so that fast
+    /// objects don't go through each other.
+    pub max_step: f32,
+    /// Maximum linear speed of any object.
+    pub max_speed: f32,
+    /// Maximum rotation speed of any object, in radians per second.
+    pub max_rot_speed: f32,
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> PhysicsConfig {
+        PhysicsConfig {
+            elasticity: 0.6,
+            resting_speed: 1.0,
+            landing_speed: 6.0,
+            correction: 0.6,
+            penetration_slop: 0.01,
+            friction: 0.04,
+            rot_friction: 2.0,
+            thrust: 60.0,
+            recoil: 10.0,
+            max_step: 0.02,
+            max_speed: 100.0,
+            max_rot_speed: 20.0,
+        }
+    }
+}
+
+impl PhysicsConfig {
+    /// Splits a frame into sub-steps: returns their number and duration.
+    pub fn substeps(&self, dt: f32) -> (usize, f32) {
+        let count = (dt / self.max_step).ceil().max(1.0) as usize;
+        (count, dt / count as f32)
+    }
 }
 
 /// Delta resource, stores the simulation step.
@@ -199,143 +354,326 @@ impl Default for DeltaTime {
     }
 }
 
+/// Moves an object according to its velocity.
+pub fn integrate(pos: &mut Position, vel: &Velocity, dt: f32) {
+    pos.pos = vec2_add(pos.pos, vec2_scale(vel.vel, dt));
+    pos.rot += vel.rot * dt;
+    pos.rot %= 2.0 * PI;
+}
+
+/// Checks that an object's state is valid, and limits its speed.
+///
+/// Returns false if it contained NaN or infinite values, in which case it
+/// gets stopped (and moved to the origin if the position is invalid).
+fn sanitize(
+    pos: &mut Position,
+    vel: &mut Velocity,
+    config: &PhysicsConfig,
+) -> bool {
+    let mut valid = true;
+    if !(pos.pos[0].is_finite()
+        && pos.pos[1].is_finite()
+        && pos.rot.is_finite())
+    {
+        pos.pos = [0.0, 0.0];
+        pos.rot = 0.0;
+        valid = false;
+    }
+    if !(vel.vel[0].is_finite()
+        && vel.vel[1].is_finite()
+        && vel.rot.is_finite())
+    {
+        valid = false;
+    }
+    if !valid {
+        vel.vel = [0.0, 0.0];
+        vel.rot = 0.0;
+        return false;
+    }
+
+    let sq_speed = vec2_square_len(vel.vel);
+    if sq_speed > config.max_speed * config.max_speed {
+        vel.vel = vec2_scale(vel.vel, config.max_speed / sq_speed.sqrt());
+    }
+    vel.rot = vel.rot.max(-config.max_rot_speed).min(config.max_rot_speed);
+    true
+}
+
 /// Simulation system, updates positions from velocities.
+///
+/// This also enforces the speed limits from `PhysicsConfig`, and resets
+/// objects whose position or velocity became invalid (NaN), before that
+/// spreads through collisions and replication.
+///
+/// When authoritative, this only integrates the first physics sub-step, and
+/// `SysCollision` does the others.
 pub struct SysSimu;
 
 impl<'a> System<'a> for SysSimu {
     type SystemData = (
         Read<'a, DeltaTime>,
+        ReadExpect<'a, Role>,
+        Read<'a, PhysicsConfig>,
+        Read<'a, LazyUpdate>,
+        Entities<'a>,
         WriteStorage<'a, Position>,
-        ReadStorage<'a, Velocity>,
+        WriteStorage<'a, Velocity>,
+    );
+
+    fn run(
+        &mut self,
+        (dt, role, config, lazy, entities, mut pos, mut vel): Self::SystemData,
+    ) {
+        let dt = if role.authoritative() {
+            config.substeps(dt.0).1
+        } else {
+            dt.0
+        };
+        for (ent, pos, vel) in (&*entities, &mut pos, &mut vel).join() {
+            if !sanitize(pos, vel, &config) {
+                warn!("Entity {:?} had invalid position or velocity", ent);
+                #[cfg(feature = "network")]
+                lazy.insert(ent, net::Dirty);
+            }
+            integrate(pos, vel, dt);
+        }
+        #[cfg(not(feature = "network"))]
+        let _ = lazy;
+    }
+}
+
+/// Deletes entities at the end of their `Lifetime`.
+pub struct SysLifetime;
+
+impl<'a> System<'a> for SysLifetime {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        ReadExpect<'a, Role>,
+        Read<'a, LazyUpdate>,
+        Entities<'a>,
+        WriteStorage<'a, Lifetime>,
     );
 
-    fn run(&mut self, (dt, mut pos, vel): Self::SystemData) {
-        let dt = dt.0;
-        for (pos, vel) in (&mut pos, &vel).join() {
-            pos.pos = vec2_add(pos.pos, vec2_scale(vel.vel, dt));
-            pos.rot += vel.rot * dt;
-            pos.rot %= 2.0 * PI;
+    fn run(
+        &mut self,
+        (dt, role, lazy, entities, mut lifetime): Self::SystemData,
+    ) {
+        assert!(role.authoritative());
+
+        for (ent, lifetime) in (&*entities, &mut lifetime).join() {
+            lifetime.0 -= dt.0;
+            if lifetime.0 <= 0.0 {
+                delete_entity(*role, &entities, &lazy, ent);
+            }
         }
     }
 }
 
 /// Collision detection and response.
+///
+/// This goes over the physics sub-steps: it finds collisions at the
+/// positions `SysSimu` computed, then integrates the following sub-steps
+/// (if the frame is longer than `PhysicsConfig::max_step`), finding
+/// collisions after each of them. The other systems only run once.
 pub struct SysCollision;
 
 impl<'a> System<'a> for SysCollision {
     type SystemData = (
+        Read<'a, DeltaTime>,
         ReadExpect<'a, Role>,
+        Read<'a, PhysicsConfig>,
+        Read<'a, Rules>,
         Read<'a, LazyUpdate>,
+        Write<'a, CollisionEvents>,
         Entities<'a>,
         WriteStorage<'a, Position>,
         WriteStorage<'a, Velocity>,
         ReadStorage<'a, Blocky>,
         ReadStorage<'a, DetectCollision>,
+        ReadStorage<'a, CircleCollider>,
         WriteStorage<'a, Hits>,
+        ReadStorage<'a, Joint>,
+        ReadStorage<'a, Faction>,
     );
 
     fn run(
         &mut self,
         (
+            dt,
             role,
+            config,
+            rules,
             lazy,
+            mut events,
             entities,
             mut pos,
             mut vel,
             blocky,
             collision,
+            circles,
             mut hits,
+            joints,
+            faction,
         ): Self::SystemData,
 ){
         assert!(role.authoritative());
 
+        // Send out the hits of the last frame, before clearing them
+        for (ent, hits) in (&*entities, &hits).join() {
+            events.iter_write(hits.iter().map(|hit| CollisionEvent {
+                entity: ent,
+                hit: hit.clone(),
+            }));
+        }
         hits.clear();
 
-        // Detect collisions between Blocky objects
-        let mut block_hits = Vec::new();
-        for (e1, pos1, blocky1) in (&*entities, &pos, &blocky).join() {
-            for (e2, pos2, blocky2) in (&*entities, &pos, &blocky).join() {
-                if e2 >= e1 {
-                    break;
-                }
-                if blocky1.blocks.is_empty() || blocky2.blocks.is_empty() {
-                    continue;
-                }
-                let rad = blocky1.radius + blocky2.radius;
-                if vec2_square_len(vec2_sub(pos1.pos, pos2.pos)) > rad * rad {
-                    continue;
-                }
-                // Detect collisions using tree
-                if let Some(hit) = find_collision_tree(
-                    pos1,
-                    &blocky1.tree,
-                    0,
-                    pos2,
-                    &blocky2.tree,
-                    0,
-                ) {
-                    block_hits.push((e1, e2, hit));
+        let (substeps, step) = config.substeps(dt.0);
+        let mut detected = HashSet::new();
+        for substep in 0..substeps {
+            if substep > 0 {
+                for (pos, vel) in (&mut pos, &vel).join() {
+                    integrate(pos, vel, step);
                 }
             }
-        }
 
-        // Handle the detected collisions
-        for (e1, e2, hit) in block_hits {
-            handle_collision(
-                e1,
-                e2,
-                &mut pos,
-                &mut vel,
-                &blocky,
-                &mut hits,
-                &hit,
-                &lazy,
-            );
-        }
+            // Detect collisions between Blocky objects
+            let mut block_hits = Vec::new();
+            for (e1, pos1, blocky1) in (&*entities, &pos, &blocky).join() {
+                for (e2, pos2, blocky2) in (&*entities, &pos, &blocky).join() {
+                    if e2 >= e1 {
+                        break;
+                    }
+                    if blocky1.blocks.is_empty() || blocky2.blocks.is_empty() {
+                        continue;
+                    }
+                    // Objects tied together don't collide
+                    if jointed(&joints, e1, e2) {
+                        continue;
+                    }
+                    let rad = blocky1.radius + blocky2.radius;
+                    let sq_dist =
+                        vec2_square_len(vec2_sub(pos1.pos, pos2.pos));
+                    if sq_dist > rad * rad {
+                        continue;
+                    }
+                    // Detect collisions using tree
+                    if let Some(hit) = find_collision_tree(
+                        pos1,
+                        &blocky1.tree,
+                        0,
+                        pos2,
+                        &blocky2.tree,
+                        0,
+                    ) {
+                        block_hits.push((e1, e2, hit));
+                    }
+                }
+            }
 
-        // Detect collisions between Blocky and DetectCollision objects
-        for (e2, pos2, blocky2) in (&*entities, &pos, &blocky).join() {
-            if blocky2.blocks.is_empty() {
-                continue;
+            // Handle the detected collisions
+            for (e1, e2, hit) in block_hits {
+                handle_collision(
+                    (e1, e2),
+                    &mut pos,
+                    &mut vel,
+                    &blocky,
+                    &mut hits,
+                    &hit,
+                    (&config, &lazy),
+                );
             }
-            for (e1, pos1, col1) in (&*entities, &pos, &collision).join() {
-                if col1.ignore == Some(e2) {
-                    continue;
+
+            // Detect collisions between Blocky objects and circles
+            let mut circle_hits = Vec::new();
+            for (e1, pos1, circle) in (&*entities, &pos, &circles).join() {
+                for (e2, pos2, blocky2, _) in
+                    (&*entities, &pos, &blocky, &vel).join()
+                {
+                    if let Some(hit) = find_collision_circle(
+                        pos1.pos,
+                        circle.radius,
+                        pos2,
+                        blocky2,
+                    ) {
+                        circle_hits.push((e1, e2, hit));
+                    }
                 }
-                let rad = col1.radius + blocky2.radius;
-                if vec2_square_len(vec2_sub(pos1.pos, pos2.pos)) > rad * rad {
+            }
+            for (e1, e2, hit) in circle_hits {
+                let pos2 = pos.get_mut(e2).unwrap();
+                let impulse = handle_static_collision(
+                    pos2,
+                    vel.get_mut(e2).unwrap(),
+                    blocky.get(e2).unwrap(),
+                    &hit,
+                    &config,
+                );
+                store_collision(
+                    pos2,
+                    hit.location,
+                    HitEffect::Collision(impulse, e1),
+                    e2,
+                    &mut hits,
+                );
+                #[cfg(feature = "network")]
+                lazy.insert(e2, net::Dirty);
+            }
+
+            // Detect collisions between Blocky and DetectCollision objects
+            for (e2, pos2, blocky2) in (&*entities, &pos, &blocky).join() {
+                if blocky2.blocks.is_empty() {
                     continue;
                 }
-                // Detect collisions using tree
-                if let Some(hit) = find_collision_tree_box(
-                    pos1,
-                    &col1.bounding_box,
-                    pos2,
-                    &blocky2.tree,
-                    0,
-                ) {
-                    let vel1 = vel.get(e1).unwrap().vel;
-                    let vel2 = vel.get(e2).unwrap().vel;
-                    let momentum = vec2_sub(vel1, vel2);
-                    let momentum = vec2_len(momentum) * blocky2.mass;
-                    // Store collision on the DetectCollision entity
-                    store_collision(
+                for (e1, pos1, col1) in (&*entities, &pos, &collision).join() {
+                    if col1.ignore == Some(e2) {
+                        continue;
+                    }
+                    if rules.no_friendly_fire && !hostile(&faction, e1, e2) {
+                        continue;
+                    }
+                    // Only hit once over the sub-steps
+                    if detected.contains(&(e1, e2)) {
+                        continue;
+                    }
+                    let rad = col1.radius + blocky2.radius;
+                    let sq_dist =
+                        vec2_square_len(vec2_sub(pos1.pos, pos2.pos));
+                    if sq_dist > rad * rad {
+                        continue;
+                    }
+                    // Detect collisions using tree
+                    if let Some(hit) = find_collision_tree_box(
                         pos1,
-                        hit.location,
-                        HitEffect::Collision(momentum, e2),
-                        e1,
-                        &mut hits,
-                    );
-                    if let Some(mass1) = col1.mass {
-                        let impulse = vec2_scale(vel1, mass1);
-                        let vel2 = vel.get_mut(e2).unwrap();
-                        vel2.vel = vec2_add(
-                            vel2.vel,
-                            vec2_scale(impulse, 1.0 / blocky2.mass),
+                        &col1.bounding_box,
+                        pos2,
+                        &blocky2.tree,
+                        0,
+                    ) {
+                        detected.insert((e1, e2));
+                        let vel1 = vel.get(e1).unwrap().vel;
+                        let vel2 = vel.get(e2).unwrap().vel;
+                        let momentum = vec2_sub(vel1, vel2);
+                        let momentum = vec2_len(momentum) * blocky2.mass;
+                        // Store collision on the DetectCollision entity
+                        store_collision(
+                            pos1,
+                            hit.location,
+                            HitEffect::Collision(momentum, e2),
+                            e1,
+                            &mut hits,
                         );
-                        let rel = vec2_sub(hit.location, pos2.pos);
-                        vel2.rot += (rel[0] * impulse[1] - rel[1] * impulse[0])
-                            / blocky2.inertia;
+                        if let Some(mass1) = col1.mass {
+                            let impulse = vec2_scale(vel1, mass1);
+                            let vel2 = vel.get_mut(e2).unwrap();
+                            vel2.vel = vec2_add(
+                                vel2.vel,
+                                vec2_scale(impulse, 1.0 / blocky2.mass),
+                            );
+                            let rel = vec2_sub(hit.location, pos2.pos);
+                            vel2.rot += (rel[0] * impulse[1]
+                                - rel[1] * impulse[0])
+                                / blocky2.inertia;
+                        }
                     }
                 }
             }
@@ -486,7 +824,7 @@ fn store_collision<'a>(
     ent: Entity,
     hits: &mut WriteStorage<'a, Hits>,
 ) {
-    let (s, c) = pos.rot.sin_cos();
+    let (s, c) = sin_cos(pos.rot);
     let x = hit[0] - pos.pos[0];
     let y = hit[1] - pos.pos[1];
     let rel_loc = [x * c + y * s, -x * s + y * c];
@@ -501,8 +839,6 @@ fn store_collision<'a>(
     );
 }
 
-const ELASTICITY: f32 = 0.6;
-
 /// Cross-product of planar vector with orthogonal vector.
 fn cross(a: [f32; 2], b: f32) -> [f32; 2] {
     [a[1] * b, -a[0] * b]
@@ -514,15 +850,15 @@ fn cross_dot2(a: [f32; 2], b: [f32; 2]) -> f32 {
     c * c
 }
 
+/// Pushes two colliding objects apart, and records the hit on both.
 fn handle_collision<'a>(
-    ent: Entity,
-    o_ent: Entity,
+    (ent, o_ent): (Entity, Entity),
     position: &mut WriteStorage<'a, Position>,
     velocity: &mut WriteStorage<'a, Velocity>,
     blocky: &ReadStorage<'a, Blocky>,
     hits: &mut WriteStorage<'a, Hits>,
     hit: &sat::Collision,
-    lazy: &Read<'a, LazyUpdate>,
+    (config, lazy): (&PhysicsConfig, &LazyUpdate),
 ) {
     let blk = blocky.get(ent).unwrap();
     let o_blk = blocky.get(o_ent).unwrap();
@@ -545,15 +881,31 @@ fn handle_collision<'a>(
         let ia = blk.inertia;
         let ib = o_blk.inertia;
 
+        // Don't bounce at low speed, so objects can come to rest
+        let vn = vec2_dot(vab1, n);
+        let elasticity = if -vn < config.resting_speed {
+            0.0
+        } else {
+            config.elasticity
+        };
+
         (
-            (-(1.0 + ELASTICITY) * vec2_dot(vab1, n))
+            // Only push objects apart, if they are already separating the
+            // position correction is enough
+            ((-(1.0 + elasticity) * vn)
                 / (1.0 / ma + 1.0 / mb + cross_dot2(rap, n) / ia
-                    + cross_dot2(rbp, n) / ib),
+                    + cross_dot2(rbp, n) / ib))
+                .max(0.0),
             rap,
             rbp,
         )
     };
 
+    // Move objects out of collision, the lighter one moving more
+    let correction = (hit.depth - config.penetration_slop).max(0.0)
+        * config.correction
+        / (1.0 / blk.mass + 1.0 / o_blk.mass);
+
     {
         // Compute location in object space
         let pos = position.get_mut(ent).unwrap();
@@ -568,7 +920,7 @@ fn handle_collision<'a>(
         // Move object out of collision
         pos.pos = vec2_add(
             pos.pos,
-            vec2_scale(hit.direction, hit.depth * 0.5 + 0.05),
+            vec2_scale(hit.direction, correction / blk.mass),
         );
 
         // Update velocity
@@ -595,7 +947,7 @@ fn handle_collision<'a>(
         // Move object out of collision
         pos.pos = vec2_add(
             pos.pos,
-            vec2_scale(hit.direction, -(hit.depth * 0.5 + 0.05)),
+            vec2_scale(hit.direction, -correction / o_blk.mass),
         );
 
         // Update velocity
@@ -610,9 +962,117 @@ fn handle_collision<'a>(
     }
 
     #[cfg(feature = "network")]
-    lazy.insert(ent, net::Dirty);
+    {
+        lazy.insert(ent, net::Dirty);
+        lazy.insert(o_ent, net::Dirty);
+    }
 }
 
+/// Finds the deepest block of an object inside a `CircleCollider`.
+///
+/// Blocks are approximated by their inscribed circle. The direction of the
+/// collision points out of the circle.
+fn find_collision_circle(
+    center: [f32; 2],
+    radius: f32,
+    pos: &Position,
+    blocky: &Blocky,
+) -> Option<sat::Collision> {
+    let rad = radius + blocky.radius;
+    if vec2_square_len(vec2_sub(pos.pos, center)) > rad * rad {
+        return None;
+    }
+    let (s, c) = sin_cos(pos.rot);
+    let mut deepest: Option<sat::Collision> = None;
+    for &(loc, _) in &blocky.blocks {
+        let block_pos = vec2_add(
+            pos.pos,
+            [c * loc[0] - s * loc[1], s * loc[0] + c * loc[1]],
+        );
+        let diff = vec2_sub(block_pos, center);
+        let dist = vec2_len(diff);
+        let depth = radius + 0.5 - dist;
+        let deeper = match deepest {
+            Some(ref h) => depth > h.depth,
+            None => true,
+        };
+        if depth <= 0.0 || !deeper {
+            continue;
+        }
+        let direction = if dist > 0.0 {
+            vec2_scale(diff, 1.0 / dist)
+        } else {
+            [1.0, 0.0]
+        };
+        deepest = Some(sat::Collision {
+            direction,
+            depth,
+            location: vec2_add(center, vec2_scale(direction, radius)),
+        });
+    }
+    deepest
+}
+
+/// Collision response against a static obstacle, which doesn't move.
+///
+/// `hit.direction` points from the obstacle to the object. Returns the
+/// impulse for the `Hit`, which is none for objects landing.
+fn handle_static_collision(
+    pos: &mut Position,
+    vel: &mut Velocity,
+    blk: &Blocky,
+    hit: &sat::Collision,
+    config: &PhysicsConfig,
+) -> f32 {
+    let rap = vec2_sub(hit.location, pos.pos);
+    let n = hit.direction;
+
+    // Compute impulse, the obstacle having infinite mass
+    let vn = vec2_dot(vec2_add(vel.vel, cross(rap, -vel.rot)), n);
+    let landing = -vn < config.landing_speed;
+    let elasticity = if landing { 0.0 } else { config.elasticity };
+    let impulse = ((-(1.0 + elasticity) * vn)
+        / (1.0 / blk.mass + cross_dot2(rap, n) / blk.inertia))
+        .max(0.0);
+
+    // Move object out of collision, it does all the moving
+    let correction =
+        (hit.depth - config.penetration_slop).max(0.0) * config.correction;
+    pos.pos = vec2_add(pos.pos, vec2_scale(n, correction));
+
+    // Update velocity
+    vel.vel = vec2_add(vel.vel, vec2_scale(n, impulse / blk.mass));
+    vel.rot += impulse * (rap[0] * n[1] - rap[1] * n[0]) / blk.inertia;
+    if landing {
+        0.0
+    } else {
+        impulse
+    }
+}
+
+/// Finds the `Blocky` objects overlapping a circle.
+///
+/// This uses the bounding circle of the objects, so it can include some
+/// whose blocks are just outside.
+pub fn query_area<'a>(
+    entities: &Entities<'a>,
+    pos: &ReadStorage<'a, Position>,
+    blocky: &ReadStorage<'a, Blocky>,
+    center: [f32; 2],
+    radius: f32,
+) -> Vec<Entity> {
+    (&**entities, pos, blocky)
+        .join()
+        .filter(|&(_, pos, blk)| {
+            let dist = vec2_square_len(vec2_sub(pos.pos, center));
+            let rad = radius + blk.radius;
+            dist < rad * rad
+        })
+        .map(|(ent, _, _)| ent)
+        .collect()
+}
+
+/// Applies an effect to the `Blocky` objects overlapping a circle.
 pub fn affect_area<'a>(
     entities: &Entities<'a>,
     pos: &ReadStorage<'a, Position>,
@@ -622,12 +1082,96 @@ pub fn affect_area<'a>(
     radius: f32,
     effect: HitEffect,
 ) {
-    for (ent, pos, blk) in (&**entities, &*pos, &*blocky).join() {
-        let entity_radius = blk.radius;
-        let dist = vec2_square_len(vec2_sub(pos.pos, center));
-        let rad = radius + entity_radius;
-        if dist < rad * rad {
-            store_collision(pos, center, effect.clone(), ent, hits);
+    for ent in query_area(entities, pos, blocky, center, radius) {
+        let pos = pos.get(ent).unwrap();
+        store_collision(pos, center, effect.clone(), ent, hits);
+    }
+}
+
+/// Finds the blocks of an object crossed by a ray, in order.
+///
+/// The ray is in the object's coordinate system, and `dir` should be
+/// normalized. This samples points along the ray up to `length`, so it can
+/// miss the corners of blocks.
+pub fn trace_blocks(
+    blk: &Blocky,
+    start: [f32; 2],
+    dir: [f32; 2],
+    length: f32,
+) -> Vec<usize> {
+    let mut crossed = Vec::new();
+    let mut t = 0.0;
+    while t <= length {
+        let point = vec2_add(start, vec2_scale(dir, t));
+        if let Some(idx) = blk.tree.find(point) {
+            if !crossed.contains(&idx) {
+                crossed.push(idx);
+            }
+        }
+        t += 0.2;
+    }
+    crossed
+}
+
+/// A block hit by a ray, returned by `raycast()`.
+pub struct RayHit {
+    pub entity: Entity,
+    /// Distance from the start of the ray.
+    pub distance: f32,
+    /// Location of the hit, just inside the block, in the entity's
+    /// coordinate system.
+    pub rel_location: [f32; 2],
+    /// Index of the block in `Blocky::blocks`.
+    pub block: usize,
+}
+
+/// Casts a ray against the blocks of all `Blocky` entities.
+///
+/// Returns the closest block within `range` of `start`, ignoring the
+/// entities for which `filter` returns false. `dir` should be normalized.
+pub fn raycast<'a, F: Fn(Entity) -> bool>(
+    entities: &Entities<'a>,
+    pos: &ReadStorage<'a, Position>,
+    blocky: &ReadStorage<'a, Blocky>,
+    start: [f32; 2],
+    dir: [f32; 2],
+    range: f32,
+    filter: F,
+) -> Option<RayHit> {
+    let mut closest: Option<RayHit> = None;
+    for (ent, pos, blk) in (&**entities, pos, blocky).join() {
+        let rad = range + blk.radius;
+        if blk.blocks.is_empty()
+            || vec2_square_len(vec2_sub(pos.pos, start)) > rad * rad
+            || !filter(ent)
+        {
+            continue;
+        }
+        // Cast the ray in the entity's coordinate system
+        let (s, c) = sin_cos(pos.rot);
+        let to_local =
+            |v: [f32; 2]| [v[0] * c + v[1] * s, -v[0] * s + v[1] * c];
+        let lstart = to_local(vec2_sub(start, pos.pos));
+        let ldir = to_local(dir);
+        let (t, point) = match find_collision_tree_ray(lstart, ldir, &blk.tree)
+        {
+            Some(r) => r,
+            None => continue,
+        };
+        match closest {
+            Some(ref hit) if hit.distance <= t => continue,
+            _ if t > range => continue,
+            _ => {}
+        }
+        let inside = vec2_add(point, vec2_scale(ldir, 0.05));
+        if let Some(idx) = blk.tree.find(inside) {
+            closest = Some(RayHit {
+                entity: ent,
+                distance: t,
+                rel_location: inside,
+                block: idx,
+            });
         }
     }
+    closest
 }
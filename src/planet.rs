@@ -0,0 +1,132 @@
+//! Planets, large round bodies pulling everything towards them.
+//!
+//! A planet is an entity with a `Planet` marker and a `CircleCollider` for
+//! its surface (see `physics.rs`). Ships that hit the surface fast crash into
+//! it, taking damage as in any collision; those coming down slowly don't
+//! bounce, and rest on it, held by gravity.
+//!
+//! `SysGravity` pulls `Blocky` objects towards the planets. All planets have
+//! the same surface gravity, so the bigger ones reach farther.
+
+use specs::{Component, Entities, Entity, Join, LazyUpdate, NullStorage,
+            Read, ReadExpect, ReadStorage, System, WriteStorage};
+use vecmath::*;
+
+use crate::blocks::Blocky;
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{CircleCollider, DeltaTime, Position, Velocity};
+use crate::station::Station;
+use crate::Role;
+
+/// Acceleration at the surface of a planet.
+const SURFACE_GRAVITY: f32 = 4.0;
+
+/// Distance from the center of a planet past which it doesn't pull anymore,
+/// in planet radii.
+const GRAVITY_RANGE: f32 = 4.0;
+
+/// Marks a planet.
+#[derive(Default)]
+pub struct Planet;
+
+impl Component for Planet {
+    type Storage = NullStorage<Self>;
+}
+
+impl Planet {
+    /// Creates a planet, centered on `pos`.
+    pub fn create(
+        entities: &Entities,
+        lazy: &Read<LazyUpdate>,
+        pos: [f32; 2],
+        radius: f32,
+    ) -> Entity {
+        let entity = entities.create();
+        lazy.insert(entity, Position { pos, rot: 0.0 });
+        lazy.insert(entity, Planet);
+        lazy.insert(entity, CircleCollider { radius });
+        #[cfg(feature = "network")]
+        {
+            lazy.insert(entity, net::Replicated::new());
+            lazy.insert(entity, net::Dirty);
+        }
+        entity
+    }
+}
+
+/// Gravity system, pulls objects towards planets.
+pub struct SysGravity;
+
+impl<'a> System<'a> for SysGravity {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        ReadExpect<'a, Role>,
+        Read<'a, LazyUpdate>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        WriteStorage<'a, Velocity>,
+        ReadStorage<'a, Blocky>,
+        ReadStorage<'a, Station>,
+        ReadStorage<'a, Planet>,
+        ReadStorage<'a, CircleCollider>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            role,
+            lazy,
+            entities,
+            position,
+            mut velocity,
+            blocky,
+            station,
+            planet,
+            circle,
+        ): Self::SystemData,
+    ) {
+        assert!(role.authoritative());
+
+        let planets = (&position, &planet, &circle)
+            .join()
+            .map(|(pos, _, circle)| (pos.pos, circle.radius))
+            .collect::<Vec<_>>();
+        if planets.is_empty() {
+            return;
+        }
+
+        // Stations hold their position
+        for (ent, pos, vel, _, _) in
+            (&*entities, &position, &mut velocity, &blocky, !&station).join()
+        {
+            let mut pulled = false;
+            for &(center, radius) in &planets {
+                let diff = vec2_sub(center, pos.pos);
+                let sq_dist = vec2_square_len(diff);
+                let range = radius * GRAVITY_RANGE;
+                if sq_dist > range * range || sq_dist < 0.01 {
+                    continue;
+                }
+                // Falls off with the square of the distance, capped at the
+                // surface
+                let accel = SURFACE_GRAVITY * radius * radius
+                    / sq_dist.max(radius * radius);
+                let dir = vec2_scale(diff, 1.0 / sq_dist.sqrt());
+                vel.vel = vec2_add(vel.vel, vec2_scale(dir, accel * dt.0));
+                pulled = true;
+            }
+            #[cfg(feature = "network")]
+            {
+                if pulled {
+                    lazy.insert(ent, net::Dirty);
+                }
+            }
+            #[cfg(not(feature = "network"))]
+            let _ = (ent, pulled);
+        }
+        #[cfg(not(feature = "network"))]
+        let _ = lazy;
+    }
+}
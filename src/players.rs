@@ -0,0 +1,39 @@
+//! Names of the players, for scoreboards and name tags.
+//!
+//! Players give their name when they join a server, which keeps the
+//! `Players` table and sends it to the clients (see `net`). Standalone games
+//! have no names; `Players::name()` falls back to numbers.
+
+use std::collections::HashMap;
+
+use crate::utils::clean_text;
+
+/// Maximum length of a name, in bytes.
+pub const MAX_NAME_LEN: usize = 24;
+
+/// What is known of a player.
+#[derive(Debug, Clone)]
+pub struct PlayerInfo {
+    pub name: String,
+}
+
+/// Players of the game, by ID, available as a resource.
+#[derive(Debug, Clone, Default)]
+pub struct Players {
+    pub players: HashMap<u64, PlayerInfo>,
+}
+
+impl Players {
+    /// The name to show for a player.
+    pub fn name(&self, player: u64) -> String {
+        match self.players.get(&player) {
+            Some(info) => info.name.clone(),
+            None => format!("Player {}", player),
+        }
+    }
+}
+
+/// Cleans up a name, see `clean_text()`.
+pub fn sanitize_name(name: &str) -> Option<String> {
+    clean_text(name, MAX_NAME_LEN)
+}
@@ -0,0 +1,278 @@
+//! Player profiles, keeping progression across sessions.
+//!
+//! Clients introduce themselves with a profile key, a secret number they
+//! keep from one session to the next (see `GameBuilder::profile_key()`). The
+//! server loads the matching `Profile` from its `ProfileStore` and sends it
+//! back. At the end of each match, `SysProfiles` adds the players' statistics
+//! to their lifetime totals, grants new `Unlocks`, and saves the profiles;
+//! the server then sends them to their players again.
+
+use log::warn;
+use specs::shrev::ReaderId;
+use specs::{Read, System, World, Write};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::events::{GameEvent, GameEvents};
+use crate::ship::ShipClass;
+use crate::stats::PlayerStats;
+
+/// Things a player has unlocked, as bit flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Unlocks(pub u32);
+
+impl Unlocks {
+    pub const SCOUT: Unlocks = Unlocks(1 << 0);
+    pub const FREIGHTER: Unlocks = Unlocks(1 << 1);
+    pub const GUNSHIP: Unlocks = Unlocks(1 << 2);
+    pub const CHARGE_GUN: Unlocks = Unlocks(1 << 8);
+    pub const BEAM_LASER: Unlocks = Unlocks(1 << 9);
+    pub const DRONE_BAY: Unlocks = Unlocks(1 << 10);
+
+    /// What it takes to fly a class of ship; nothing for the `Fighter`.
+    pub fn class(class: ShipClass) -> Unlocks {
+        match class {
+            ShipClass::Fighter => Unlocks(0),
+            ShipClass::Scout => Unlocks::SCOUT,
+            ShipClass::Freighter => Unlocks::FREIGHTER,
+            ShipClass::Gunship => Unlocks::GUNSHIP,
+        }
+    }
+
+    pub fn contains(self, other: Unlocks) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Unlocks) {
+        self.0 |= other.0;
+    }
+}
+
+/// A player's progression.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// Statistics over all the matches played, with `largest_ship` the
+    /// largest ever.
+    pub stats: PlayerStats,
+    pub matches: u32,
+    /// Matches finished at the top of the scoreboard.
+    pub wins: u32,
+    pub unlocks: Unlocks,
+}
+
+impl Profile {
+    /// Adds the statistics of a finished match, and grants what they
+    /// unlock.
+    pub fn record_match(&mut self, stats: &PlayerStats, won: bool) {
+        let total = &mut self.stats;
+        total.shots_fired += stats.shots_fired;
+        total.shots_hit += stats.shots_hit;
+        total.damage_dealt += stats.damage_dealt;
+        total.damage_taken += stats.damage_taken;
+        total.kills += stats.kills;
+        total.deaths += stats.deaths;
+        total.largest_ship = total.largest_ship.max(stats.largest_ship);
+        self.matches += 1;
+        if won {
+            self.wins += 1;
+        }
+
+        let unlocked = [
+            (Unlocks::SCOUT, self.matches >= 1),
+            (Unlocks::FREIGHTER, self.matches >= 5),
+            (Unlocks::GUNSHIP, self.stats.kills >= 25),
+            (Unlocks::CHARGE_GUN, self.wins >= 1),
+            (Unlocks::BEAM_LASER, self.stats.damage_dealt >= 5000.0),
+            (Unlocks::DRONE_BAY, self.stats.largest_ship >= 40),
+        ];
+        for &(unlock, earned) in &unlocked {
+            if earned {
+                self.unlocks.insert(unlock);
+            }
+        }
+    }
+
+    /// Whether the player may fly this class of ship.
+    pub fn can_fly(&self, class: ShipClass) -> bool {
+        self.unlocks.contains(Unlocks::class(class))
+    }
+}
+
+/// Somewhere to keep profiles between sessions.
+pub trait ProfileStore: Send + Sync {
+    /// Loads a profile, `None` if there is no profile with this key yet.
+    fn load(&mut self, key: u64) -> io::Result<Option<Profile>>;
+
+    fn save(&mut self, key: u64, profile: &Profile) -> io::Result<()>;
+}
+
+/// Profile store keeping each profile in a text file, in a directory.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> FileStore {
+        FileStore { dir: dir.into() }
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.profile", key))
+    }
+}
+
+impl ProfileStore for FileStore {
+    fn load(&mut self, key: u64) -> io::Result<Option<Profile>> {
+        let text = match fs::read_to_string(self.path(key)) {
+            Ok(t) => t,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        let mut profile = Profile::default();
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let (name, value) = match (fields.next(), fields.next()) {
+                (Some(n), Some(v)) => (n, v),
+                _ => continue,
+            };
+            let int = || value.parse::<u32>().unwrap_or(0);
+            match name {
+                "shots_fired" => profile.stats.shots_fired = int(),
+                "shots_hit" => profile.stats.shots_hit = int(),
+                "damage_dealt" => {
+                    profile.stats.damage_dealt = value.parse().unwrap_or(0.0)
+                }
+                "damage_taken" => {
+                    profile.stats.damage_taken = value.parse().unwrap_or(0.0)
+                }
+                "kills" => profile.stats.kills = int(),
+                "deaths" => profile.stats.deaths = int(),
+                "largest_ship" => profile.stats.largest_ship = int(),
+                "matches" => profile.matches = int(),
+                "wins" => profile.wins = int(),
+                "unlocks" => profile.unlocks = Unlocks(int()),
+                _ => warn!("Unknown field {:?} in profile {:x}", name, key),
+            }
+        }
+        Ok(Some(profile))
+    }
+
+    fn save(&mut self, key: u64, profile: &Profile) -> io::Result<()> {
+        let stats = &profile.stats;
+        let text = format!(
+            "shots_fired {}\nshots_hit {}\ndamage_dealt {}\n\
+             damage_taken {}\nkills {}\ndeaths {}\nlargest_ship {}\n\
+             matches {}\nwins {}\nunlocks {}\n",
+            stats.shots_fired,
+            stats.shots_hit,
+            stats.damage_dealt,
+            stats.damage_taken,
+            stats.kills,
+            stats.deaths,
+            stats.largest_ship,
+            profile.matches,
+            profile.wins,
+            profile.unlocks.0,
+        );
+        // Write then rename, so a crash doesn't leave a truncated profile
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(tmp, path)
+    }
+}
+
+/// Profiles of the connected players, available as a resource on servers.
+#[derive(Default)]
+pub struct Profiles {
+    store: Option<Box<dyn ProfileStore>>,
+    /// Key and profile of each player, identified as in `physics::pilot()`.
+    players: HashMap<u64, (u64, Profile)>,
+}
+
+impl Profiles {
+    pub fn new(store: Box<dyn ProfileStore>) -> Profiles {
+        Profiles {
+            store: Some(store),
+            players: HashMap::new(),
+        }
+    }
+
+    /// Loads the profile of a player joining with this key.
+    ///
+    /// Players without a key, or when there is no store, get a blank
+    /// profile that isn't saved.
+    pub fn join(&mut self, player: u64, key: u64) -> &Profile {
+        let profile = match self.store {
+            Some(ref mut store) if key != 0 => match store.load(key) {
+                Ok(profile) => profile,
+                Err(e) => {
+                    warn!("Can't load profile {:x}: {}", key, e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let entry = (key, profile.unwrap_or_default());
+        &self.players.entry(player).or_insert(entry).1
+    }
+
+    pub fn get(&self, player: u64) -> Option<&Profile> {
+        self.players.get(&player).map(|(_, profile)| profile)
+    }
+
+    /// Records the results of a match, and saves the profiles.
+    fn record_match(&mut self, results: &[(u64, PlayerStats)]) {
+        for (rank, &(player, ref stats)) in results.iter().enumerate() {
+            let (key, profile) = match self.players.get_mut(&player) {
+                Some(&mut (key, ref mut profile)) => (key, profile),
+                None => continue,
+            };
+            profile.record_match(stats, rank == 0 && results.len() > 1);
+            match self.store {
+                Some(ref mut store) if key != 0 => {
+                    if let Err(e) = store.save(key, profile) {
+                        warn!("Can't save profile {:x}: {}", key, e);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The profile of the local player, available as a resource on clients.
+///
+/// This is `None` until the server sends it.
+#[derive(Default)]
+pub struct LocalProfile(pub Option<Profile>);
+
+/// Profiles system, adds match results to the players' profiles.
+pub struct SysProfiles {
+    reader: ReaderId<GameEvent>,
+}
+
+impl SysProfiles {
+    pub fn new(world: &World) -> SysProfiles {
+        SysProfiles {
+            reader: world.fetch_mut::<GameEvents>().register_reader(),
+        }
+    }
+}
+
+impl<'a> System<'a> for SysProfiles {
+    type SystemData = (Read<'a, GameEvents>, Write<'a, Profiles>);
+
+    fn run(&mut self, (events, mut profiles): Self::SystemData) {
+        for event in events.read(&mut self.reader) {
+            if let GameEvent::MatchEnd { ref summary } = *event {
+                profiles.record_match(&summary.players);
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Respawning players.
+//!
+//! When a ship loses its cockpit, its pilot keeps controlling the wreck. On
+//! authoritative machines, `SysRespawn` takes control of the wreck away, and
+//! gives the player a new ship after `RESPAWN_TIME`. Network clients are told
+//! about it by `SysNetServer`, like for any change of control.
+//!
+//! The `PlayerState` resource says whether the local player has a ship, on
+//! any machine, so that frontends can show it. With several local players,
+//! it is about the first one.
+
+use specs::shrev::ReaderId;
+use specs::{Entities, Entity, Join, Read, ReadExpect, LazyUpdate, ReadStorage,
+            System, Write, WriteExpect};
+
+use crate::Role;
+use crate::events::{GameEvent, GameEvents};
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{DeltaTime, LocalControl, RemoteControl};
+use crate::ship::{PlayerClasses, Ship};
+
+/// Time between losing a ship and getting a new one.
+pub const RESPAWN_TIME: f32 = 5.0;
+
+/// State of the local player, available as a resource.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerState {
+    /// Not controlling anything yet, for example before the server gives a
+    /// ship to a new client.
+    Waiting,
+    /// Controlling a ship.
+    Alive,
+    /// Lost their ship, and will get a new one in `respawn_in` seconds.
+    ///
+    /// On clients this is an estimate, the server decides.
+    Dead { respawn_in: f32 },
+}
+
+/// A player waiting for a ship.
+#[derive(Clone, Copy)]
+enum Pilot {
+    Local(LocalControl),
+    /// A network client, by client ID.
+    #[cfg(feature = "network")]
+    Remote(u64),
+}
+
+/// Respawn system, gives new ships to players who lost theirs.
+#[derive(Default)]
+pub struct SysRespawn {
+    /// Players waiting for a ship, with the time left.
+    pending: Vec<(Pilot, f32)>,
+    /// Entities players controlled last frame, to notice those destroyed
+    /// entirely.
+    controlled: Vec<(Entity, Pilot)>,
+    /// Reader for `GameEvents`, to forget the players who left, registered
+    /// on the first run.
+    events: Option<ReaderId<GameEvent>>,
+}
+
+impl<'a> System<'a> for SysRespawn {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        ReadExpect<'a, Role>,
+        Read<'a, LazyUpdate>,
+        Write<'a, GameEvents>,
+        WriteExpect<'a, PlayerState>,
+        Read<'a, PlayerClasses>,
+        Entities<'a>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            role,
+            lazy,
+            mut events,
+            mut state,
+            classes,
+            entities,
+            ship,
+            local,
+            remote,
+        ): Self::SystemData,
+    ) {
+        let dt = dt.0;
+
+        if role.authoritative() {
+            // Take control away from wrecks
+            for (ent, &ctrl, _) in (&*entities, &local, !&ship).join() {
+                lazy.remove::<LocalControl>(ent);
+                self.pending.push((Pilot::Local(ctrl), RESPAWN_TIME));
+            }
+            #[cfg(feature = "network")]
+            for (ent, ctrl, _) in (&*entities, &remote, !&ship).join() {
+                lazy.remove::<net::ClientControlled>(ent);
+                let pilot = Pilot::Remote(ctrl.client_id);
+                self.pending.push((pilot, RESPAWN_TIME));
+            }
+            #[cfg(not(feature = "network"))]
+            let () = remote;
+
+            // Players who left don't get a new ship
+            let reader = {
+                let events = &mut events;
+                self.events.get_or_insert_with(|| events.register_reader())
+            };
+            for event in events.read(reader) {
+                #[cfg(feature = "network")]
+                {
+                    if let GameEvent::PlayerLeft { player } = *event {
+                        self.pending.retain(|&(pilot, _)| match pilot {
+                            Pilot::Remote(client_id) => client_id != player,
+                            Pilot::Local(_) => true,
+                        });
+                    }
+                }
+                #[cfg(not(feature = "network"))]
+                let _ = event;
+            }
+
+            // Ships destroyed down to the last block leave no wreck
+            for &(ent, pilot) in &self.controlled {
+                if !entities.is_alive(ent) {
+                    self.pending.push((pilot, RESPAWN_TIME));
+                }
+            }
+            self.controlled = (&*entities, &local, &ship)
+                .join()
+                .map(|(ent, &ctrl, _)| (ent, Pilot::Local(ctrl)))
+                .collect();
+            #[cfg(feature = "network")]
+            self.controlled.extend(
+                (&*entities, &remote, &ship).join().map(|(ent, ctrl, _)| {
+                    (ent, Pilot::Remote(ctrl.client_id))
+                }),
+            );
+
+            // Give new ships
+            for &mut (_, ref mut timer) in &mut self.pending {
+                *timer -= dt;
+            }
+            for &(ref pilot, timer) in &self.pending {
+                if timer > 0.0 {
+                    continue;
+                }
+                match *pilot {
+                    Pilot::Local(ctrl) => {
+                        let class = classes.get(ctrl.0 as u64);
+                        let newship = Ship::create(&entities, &lazy, class);
+                        lazy.insert(newship, ctrl);
+                    }
+                    #[cfg(feature = "network")]
+                    Pilot::Remote(client_id) => {
+                        let class = classes.get(client_id);
+                        let newship = Ship::create(&entities, &lazy, class);
+                        lazy.insert(
+                            newship,
+                            net::ClientControlled { client_id },
+                        );
+                    }
+                }
+            }
+            self.pending.retain(|&(_, timer)| timer > 0.0);
+        }
+
+        // Update the state of the local player
+        let alive = (&ship, &local)
+            .join()
+            .any(|(_, &LocalControl(index))| index == 0);
+        *state = match *state {
+            _ if alive => PlayerState::Alive,
+            PlayerState::Waiting => PlayerState::Waiting,
+            PlayerState::Alive => PlayerState::Dead {
+                respawn_in: RESPAWN_TIME,
+            },
+            PlayerState::Dead { respawn_in } => PlayerState::Dead {
+                respawn_in: (respawn_in - dt).max(0.0),
+            },
+        };
+    }
+}
@@ -0,0 +1,169 @@
+//! Match rules.
+//!
+//! The `Rules` resource says how matches are played: for a set time, or until
+//! the pirates' dreadnought is destroyed, on top of the game mode's own win
+//! condition (see `modes.rs`). When a match is over, `end_match()` turns the
+//! `Scoreboard` into a `MatchSummary`, announces it with a
+//! `GameEvent::MatchEnd` (which the server sends to clients) and starts over
+//! with a clean scoreboard.
+//!
+//! Each match goes through the phases of `MatchState`: a warmup without
+//! firing, the match itself, and the results, before the next warmup.
+
+use specs::shrev::ReaderId;
+use specs::{Read, System, World, Write};
+
+use crate::events::{GameEvent, GameEvents};
+use crate::physics::DeltaTime;
+use crate::stats::{MatchSummary, Scoreboard};
+
+/// Rules for the game, available as a resource.
+///
+/// Set through `GameBuilder::rules()`.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    /// Duration of a match in seconds, `None` to play forever.
+    pub match_length: Option<f32>,
+    /// Whether projectiles go through ships of their own faction, see
+    /// `faction::Faction`.
+    pub no_friendly_fire: bool,
+    /// Whether destroying the pirates' dreadnought ends the match, see
+    /// `boss.rs`.
+    pub boss_ends_match: bool,
+    /// Duration of the warmup before each match in seconds, during which
+    /// players can't fire.
+    pub warmup: f32,
+    /// Time the results of a match are shown before the next warmup, in
+    /// seconds.
+    pub intermission: f32,
+    /// Whether the computer takes over the ships of network players who
+    /// leave, rather than them being removed.
+    pub ai_takeover: bool,
+}
+
+/// Where the current match is at, available as a resource.
+///
+/// `SysRules` moves it along on authoritative machines; the server sends it
+/// to clients, so they can show countdowns and results.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchState {
+    /// Waiting for the match to start, with the time left. Ships can fly
+    /// around but not fire.
+    Warmup(f32),
+    /// The match is on, with the time since it started.
+    Playing(f32),
+    /// The match is over and its results are up, with the time left until
+    /// the next warmup.
+    Finished(f32),
+}
+
+impl Default for MatchState {
+    fn default() -> MatchState {
+        MatchState::Playing(0.0)
+    }
+}
+
+impl MatchState {
+    /// Whether the match is on, as opposed to warming up or over.
+    pub fn playing(&self) -> bool {
+        matches!(*self, MatchState::Playing(_))
+    }
+
+    /// Whether the players' fire input goes through.
+    pub fn can_fire(&self) -> bool {
+        !matches!(*self, MatchState::Warmup(_))
+    }
+}
+
+/// Summary of the last match, available as a resource.
+///
+/// On clients, this is received from the server.
+#[derive(Default)]
+pub struct LastMatch(pub Option<MatchSummary>);
+
+/// Ends the current match, and starts a new one.
+pub fn end_match(
+    scoreboard: &mut Scoreboard,
+    last_match: &mut LastMatch,
+    events: &mut GameEvents,
+) {
+    let summary = scoreboard.summary();
+    *scoreboard = Default::default();
+    last_match.0 = Some(summary.clone());
+    events.single_write(GameEvent::MatchEnd { summary });
+}
+
+/// Rules system, moves the match through its phases, ending it when the
+/// time is up or the boss is dead.
+pub struct SysRules {
+    reader: ReaderId<GameEvent>,
+}
+
+impl SysRules {
+    pub fn new(world: &World) -> SysRules {
+        SysRules {
+            reader: world.fetch_mut::<GameEvents>().register_reader(),
+        }
+    }
+}
+
+impl<'a> System<'a> for SysRules {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        Read<'a, Rules>,
+        Write<'a, MatchState>,
+        Write<'a, Scoreboard>,
+        Write<'a, LastMatch>,
+        Write<'a, GameEvents>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            rules,
+            mut state,
+            mut scoreboard,
+            mut last_match,
+            mut events,
+        ): Self::SystemData,
+    ) {
+        let dt = dt.0;
+        let mut boss_destroyed = false;
+        let mut ended = false;
+        for event in events.read(&mut self.reader) {
+            match *event {
+                GameEvent::BossDestroyed => boss_destroyed = true,
+                GameEvent::MatchEnd { .. } => ended = true,
+                _ => {}
+            }
+        }
+        *state = match *state {
+            MatchState::Warmup(left) if left > dt => {
+                MatchState::Warmup(left - dt)
+            }
+            MatchState::Warmup(_) => MatchState::Playing(0.0),
+            // The game mode can end matches too
+            MatchState::Playing(_) if ended => {
+                MatchState::Finished(rules.intermission)
+            }
+            MatchState::Playing(elapsed) => {
+                let elapsed = elapsed + dt;
+                let time_up = match rules.match_length {
+                    Some(length) => elapsed >= length,
+                    None => false,
+                };
+                if time_up || (boss_destroyed && rules.boss_ends_match) {
+                    end_match(&mut scoreboard, &mut last_match, &mut events);
+                    MatchState::Finished(rules.intermission)
+                } else {
+                    MatchState::Playing(elapsed)
+                }
+            }
+            MatchState::Finished(left) if left > dt => {
+                MatchState::Finished(left - dt)
+            }
+            MatchState::Finished(_) => MatchState::Warmup(rules.warmup),
+        };
+    }
+}
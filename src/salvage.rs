@@ -0,0 +1,245 @@
+//! Salvaging blocks from wrecks.
+//!
+//! A ship with a `SalvageBeam` block can point it at a wreck (a `Blocky`
+//! object that is neither a ship nor an asteroid) while firing. The block
+//! under the beam gets deconstructed over a few seconds, then removed from
+//! the wreck and added to the ship's `Cargo`. Players' cargo then gets
+//! refined into ore, see `economy.rs`.
+
+use rand::Rng;
+use specs::{Component, Entities, Entity, Join, HashMapStorage, LazyUpdate,
+            ReadStorage, Read, System, WriteStorage};
+use vecmath::*;
+
+use crate::asteroid::Asteroid;
+use crate::blocks::{Block, BlockInner, Blocky};
+use crate::math::sin_cos;
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{raycast, DeltaTime, Hit, HitEffect, Hits, Position,
+                     Velocity};
+use crate::ship::Ship;
+
+/// Maximum distance from the beam to the salvaged block.
+const SALVAGE_RANGE: f32 = 15.0;
+
+/// Time it takes to deconstruct a block, per unit of mass.
+const SALVAGE_TIME_PER_MASS: f32 = 4.0;
+
+/// Speed at which spilled cargo flies off the ship.
+const SPILL_SPEED: f32 = 4.0;
+
+/// Blocks carried by a ship, that can be placed later, and ammunition.
+#[derive(Default)]
+pub struct Cargo {
+    pub blocks: Vec<BlockInner>,
+    /// Rounds of ammunition, used by guns to reload.
+    pub ammo: u32,
+}
+
+impl Component for Cargo {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Throws the blocks of a cargo hold out as loose blocks, for example when
+/// the ship carrying them gets destroyed.
+pub fn spill_cargo<R: Rng>(
+    entities: &Entities,
+    lazy: &Read<LazyUpdate>,
+    rng: &mut R,
+    pos: &Position,
+    vel: &Velocity,
+    cargo: &mut Cargo,
+) {
+    for inner in cargo.blocks.drain(..) {
+        let (blocky, _) = Blocky::new(vec![([0.0, 0.0], Block::new(inner))]);
+        let dir = rng.gen_range(0.0, 2.0 * ::std::f32::consts::PI);
+        let (s, c) = sin_cos(dir);
+        let dir = [c, s];
+        let newent = entities.create();
+        lazy.insert(
+            newent,
+            Position {
+                pos: vec2_add(pos.pos, vec2_scale(dir, 2.0)),
+                rot: pos.rot,
+            },
+        );
+        lazy.insert(
+            newent,
+            Velocity {
+                vel: vec2_add(vel.vel, vec2_scale(dir, SPILL_SPEED)),
+                rot: rng.gen_range(-1.0, 1.0),
+            },
+        );
+        lazy.insert(newent, blocky);
+        #[cfg(feature = "network")]
+        {
+            lazy.insert(newent, net::Replicated::new());
+            lazy.insert(newent, net::Dirty);
+        }
+    }
+}
+
+/// Salvage in progress, attached to the salvaging ship.
+pub struct Salvaging {
+    /// The wreck being salvaged.
+    pub target: Entity,
+    /// Location of the block, in the wreck's coordinate system.
+    pub block: [f32; 2],
+    /// Time spent deconstructing so far.
+    pub progress: f32,
+    /// Total time needed to deconstruct this block.
+    pub duration: f32,
+}
+
+impl Salvaging {
+    /// How far along the salvage is, from 0 to 1.
+    pub fn fraction(&self) -> f32 {
+        (self.progress / self.duration).min(1.0)
+    }
+}
+
+impl Component for Salvaging {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Salvage system, picks blocks under the beam and deconstructs them.
+///
+/// This records a `HitEffect::Salvage` on the wreck once done, so it needs to
+/// run after `SysCollision` clears the hits.
+pub struct SysSalvage;
+
+impl<'a> System<'a> for SysSalvage {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Blocky>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, Asteroid>,
+        WriteStorage<'a, Salvaging>,
+        WriteStorage<'a, Cargo>,
+        WriteStorage<'a, Hits>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            entities,
+            position,
+            blocky,
+            ships,
+            asteroid,
+            mut salvaging,
+            mut cargo,
+            mut hits,
+        ): Self::SystemData,
+    ) {
+        let dt = dt.0;
+
+        for (ent, pos, blk, ship) in
+            (&*entities, &position, &blocky, &ships).join()
+        {
+            // Find the beam
+            let beam = blk.blocks.iter().find(|(_, block)| {
+                match block.inner {
+                    BlockInner::SalvageBeam => {
+                        ship.fires(block) && !block.is_disabled()
+                    }
+                    _ => false,
+                }
+            });
+            let beam = match beam {
+                Some(&(loc, _)) => loc,
+                None => {
+                    salvaging.remove(ent);
+                    continue;
+                }
+            };
+            let (s, c) = sin_cos(pos.rot);
+            let beam = vec2_add(
+                pos.pos,
+                [beam[0] * c - beam[1] * s, beam[0] * s + beam[1] * c],
+            );
+            let dir = vec2_sub(vec2_add(pos.pos, ship.want_target), beam);
+            if vec2_square_len(dir) < 0.01 {
+                salvaging.remove(ent);
+                continue;
+            }
+            let dir = vec2_normalized(dir);
+
+            // Pick the closest wreck block under the beam
+            let hit = raycast(
+                &entities,
+                &position,
+                &blocky,
+                beam,
+                dir,
+                SALVAGE_RANGE,
+                |e| ships.get(e).is_none() && asteroid.get(e).is_none(),
+            );
+            let (wreck, loc, block) = match hit {
+                Some(hit) => {
+                    let (loc, ref block) =
+                        blocky.get(hit.entity).unwrap().blocks[hit.block];
+                    (hit.entity, loc, block.inner.clone())
+                }
+                None => {
+                    salvaging.remove(ent);
+                    continue;
+                }
+            };
+
+            // Deconstruct it
+            let done = match salvaging.get_mut(ent) {
+                Some(ref mut s) if s.target == wreck && s.block == loc => {
+                    s.progress += dt;
+                    s.progress >= s.duration
+                }
+                _ => {
+                    salvaging
+                        .insert(
+                            ent,
+                            Salvaging {
+                                target: wreck,
+                                block: loc,
+                                progress: 0.0,
+                                duration: block.mass()
+                                    * SALVAGE_TIME_PER_MASS,
+                            },
+                        )
+                        .unwrap();
+                    false
+                }
+            };
+            if !done {
+                continue;
+            }
+            salvaging.remove(ent);
+
+            // Remove the block from the wreck, put it in the cargo hold
+            Hits::record(
+                &mut hits,
+                wreck,
+                Hit {
+                    rel_location: loc,
+                    effect: HitEffect::Salvage,
+                },
+            );
+            if let Some(cargo) = cargo.get_mut(ent) {
+                cargo.blocks.push(block);
+                continue;
+            }
+            cargo
+                .insert(
+                    ent,
+                    Cargo {
+                        blocks: vec![block],
+                        ammo: 0,
+                    },
+                )
+                .unwrap();
+        }
+    }
+}
@@ -7,6 +7,7 @@
 use std::cmp::Ordering;
 use vecmath::*;
 
+use crate::math::sin_cos;
 use crate::physics::{AABox, Position};
 use crate::utils::IteratorExt;
 
@@ -43,7 +44,7 @@ fn check_sat_collision_dir(
     // It checks whether there is collision of the shape projected along it
 
     // Project rectangle 1
-    let (s, c) = pos1.rot.sin_cos();
+    let (s, c) = sin_cos(pos1.rot);
     let proj1 = size1
         .corners()
         .iter()
@@ -65,7 +66,7 @@ fn check_sat_collision_dir(
         .minmax()
         .unwrap();
     // Project rectangle 2
-    let (s, c) = pos2.rot.sin_cos();
+    let (s, c) = sin_cos(pos2.rot);
     let proj2 = size2
         .corners()
         .iter()
@@ -119,7 +120,7 @@ pub fn find(
     pos2: &Position,
     size2: &AABox,
 ) -> Option<Collision> {
-    let (s, c) = pos1.rot.sin_cos();
+    let (s, c) = sin_cos(pos1.rot);
     let mut res = check_sat_collision_dir(pos1, size1, pos2, size2, [c, s])?;
 
     let r = check_sat_collision_dir(pos1, size1, pos2, size2, [-s, c])?;
@@ -127,7 +128,7 @@ pub fn find(
         res = r;
     }
 
-    let (s, c) = pos2.rot.sin_cos();
+    let (s, c) = sin_cos(pos2.rot);
     let r = check_sat_collision_dir(pos2, size2, pos1, size1, [c, s])?;
     if r.depth < res.depth {
         res = r;
@@ -0,0 +1,959 @@
+//! Saving and loading standalone games.
+//!
+//! `Game::save()` writes the state of the world to a text file, one line
+//! per component or resource entry, and `Game::load()` replaces the world
+//! with the contents of such a file. This covers what lasts: ships with their
+//! blocks and cargo, wrecks, asteroids, stations, planets, nebulae, control
+//! points, loose ore, and the resources keeping track of the game (players'
+//! ore, scoreboard, match state, survival waves, teams, generated sectors).
+//! Short-lived things are left out: projectiles, beams, particles, and what
+//! only ties entities together for a moment (tractor beams, boarding, joints,
+//! AI targets).
+//!
+//! The same format is used to stream sectors out (see `sector.rs`): a sector
+//! file holds the entities that were in it, and the save file lists the
+//! sectors that are stored in such files.
+
+use specs::{Builder, Entity, Join, ReadStorage, World, WorldExt};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::str::SplitWhitespace;
+
+use crate::ai::{AiPilot, Personality, Wing};
+use crate::asteroid::Asteroid;
+use crate::autopilot::Autopilot;
+use crate::blocks::{Block, BlockInner, Blocky};
+use crate::capture::CaptureZone;
+use crate::drones::Drone;
+use crate::economy::{OrePickup, Wallet};
+use crate::faction::Faction;
+use crate::math::sin_cos;
+use crate::medium::MediumZone;
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{pilot, CircleCollider, Lifetime, LocalControl,
+                     Position, RemoteControl, Velocity};
+use crate::planet::Planet;
+use crate::rules::MatchState;
+use crate::salvage::Cargo;
+use crate::sector::{SectorId, SectorManager};
+use crate::ship::{Ship, ShipIntegrity};
+use crate::station::Station;
+use crate::stats::{PlayerStats, Scoreboard};
+use crate::survival::WaveState;
+use crate::teams::{Team, Teams, TEAMS};
+use crate::traffic::Trader;
+#[cfg(feature = "network")]
+use crate::Role;
+
+/// First line of a save file, with the version of the format.
+const HEADER: &str = "vigilant-steel-save 1";
+
+/// Writes the type and state of a block.
+fn write_inner<W: Write>(out: &mut W, inner: &BlockInner) -> io::Result<()> {
+    match *inner {
+        BlockInner::Cockpit => write!(out, "cockpit"),
+        BlockInner::Thruster { angle } => write!(out, "thruster {}", angle),
+        BlockInner::PlasmaGun {
+            angle,
+            cooldown,
+            ammo,
+        } => write!(out, "plasma {} {} {}", angle, cooldown, ammo),
+        BlockInner::RailGun {
+            angle,
+            cooldown,
+            ammo,
+        } => write!(out, "rail {} {} {}", angle, cooldown, ammo),
+        BlockInner::EmpGun {
+            angle,
+            cooldown,
+            ammo,
+        } => write!(out, "emp {} {} {}", angle, cooldown, ammo),
+        BlockInner::FlakCannon {
+            angle,
+            cooldown,
+            ammo,
+        } => write!(out, "flak {} {} {}", angle, cooldown, ammo),
+        BlockInner::ChargeGun {
+            angle,
+            cooldown,
+            ammo,
+            charge,
+        } => write!(out, "charge {} {} {} {}", angle, cooldown, ammo, charge),
+        BlockInner::BeamLaser { angle } => write!(out, "beam {}", angle),
+        BlockInner::BoardingClamp => write!(out, "clamp"),
+        BlockInner::SalvageBeam => write!(out, "salvage"),
+        BlockInner::DroneBay { drones, cooldown } => {
+            write!(out, "bay {} {}", drones, cooldown)
+        }
+        BlockInner::FuelTank { fuel } => write!(out, "tank {}", fuel),
+        BlockInner::DockingPort => write!(out, "port"),
+        BlockInner::Armor => write!(out, "armor"),
+        BlockInner::Rock => write!(out, "rock"),
+    }
+}
+
+fn personality_name(personality: Personality) -> &'static str {
+    match personality {
+        Personality::Interceptor => "interceptor",
+        Personality::Sniper => "sniper",
+        Personality::Miner => "miner",
+        Personality::Trader => "trader",
+        Personality::Drone => "drone",
+    }
+}
+
+/// Writes the world to a save file.
+pub fn save<W: Write>(world: &World, out: &mut W) -> io::Result<()> {
+    writeln!(out, "{}", HEADER)?;
+
+    // Resources
+    let sectors = world.read_resource::<SectorManager>();
+    writeln!(out, "seed {}", sectors.seed)?;
+    let mut generated = sectors
+        .sectors
+        .iter()
+        .filter(|(_, s)| s.layout.is_some())
+        .map(|(&id, _)| id)
+        .collect::<Vec<_>>();
+    generated.sort();
+    for id in generated {
+        if sectors.sectors[&id].stored {
+            writeln!(out, "sector {} {} stored", id.x, id.y)?;
+        } else {
+            writeln!(out, "sector {} {}", id.x, id.y)?;
+        }
+    }
+    for (player, ore) in &world.read_resource::<Wallet>().balances {
+        writeln!(out, "wallet {} {}", player, ore)?;
+    }
+    for (player, s) in &world.read_resource::<Scoreboard>().players {
+        writeln!(
+            out,
+            "score {} {} {} {} {} {} {} {}",
+            player,
+            s.shots_fired,
+            s.shots_hit,
+            s.damage_dealt,
+            s.damage_taken,
+            s.kills,
+            s.deaths,
+            s.largest_ship,
+        )?;
+    }
+    let (phase, time) = match *world.read_resource::<MatchState>() {
+        MatchState::Warmup(t) => ("warmup", t),
+        MatchState::Playing(t) => ("playing", t),
+        MatchState::Finished(t) => ("finished", t),
+    };
+    writeln!(out, "match {} {}", phase, time)?;
+    if let Some(waves) = world.try_fetch::<WaveState>() {
+        writeln!(
+            out,
+            "waves {} {} {} {}",
+            waves.wave,
+            waves.cleared,
+            waves.next_wave.unwrap_or(-1.0),
+            waves.enemies,
+        )?;
+    }
+    if let Some(teams) = world.try_fetch::<Teams>() {
+        for (team, score) in teams.scores.iter().enumerate() {
+            writeln!(out, "team_score {} {}", team, score)?;
+        }
+        for (player, team) in &teams.players {
+            writeln!(out, "team_player {} {}", player, team.0)?;
+        }
+    }
+
+    let saved = (&*world.entities(), &world.read_storage::<Position>())
+        .join()
+        .map(|(e, _)| e)
+        .filter(|&e| worth_saving(world, e))
+        .collect::<Vec<_>>();
+    write_entities(world, &saved, out)
+}
+
+/// Writes the entities of a sector to a file, to stream the sector out.
+///
+/// Returns all the entities in the sector, saved or not, for the caller to
+/// delete. Ships with a pilot are left alone.
+pub fn save_sector<W: Write>(
+    world: &World,
+    id: SectorId,
+    out: &mut W,
+) -> io::Result<Vec<Entity>> {
+    writeln!(out, "{}", HEADER)?;
+    let (local, remote) =
+        world.system_data::<(ReadStorage<LocalControl>, RemoteControl)>();
+    let in_sector = (&*world.entities(), &world.read_storage::<Position>())
+        .join()
+        .filter(|&(e, pos)| {
+            SectorId::at(pos.pos) == id && pilot(e, &local, &remote).is_none()
+        })
+        .map(|(e, _)| e)
+        .collect::<Vec<_>>();
+    let saved = in_sector
+        .iter()
+        .cloned()
+        .filter(|&e| worth_saving(world, e))
+        .collect::<Vec<_>>();
+    write_entities(world, &saved, out)?;
+    Ok(in_sector)
+}
+
+/// Whether an entity lasts, and should be saved.
+fn worth_saving(world: &World, e: Entity) -> bool {
+    world.read_storage::<Blocky>().get(e).is_some()
+        || world.read_storage::<CircleCollider>().get(e).is_some()
+        || world.read_storage::<MediumZone>().get(e).is_some()
+        || world.read_storage::<CaptureZone>().get(e).is_some()
+        || world.read_storage::<OrePickup>().get(e).is_some()
+}
+
+/// Writes entities, numbered in the order they are written.
+fn write_entities<W: Write>(
+    world: &World,
+    saved: &[Entity],
+    out: &mut W,
+) -> io::Result<()> {
+    let index = saved
+        .iter()
+        .enumerate()
+        .map(|(i, &e)| (e, i))
+        .collect::<HashMap<_, _>>();
+
+    let position = world.read_storage::<Position>();
+    let blocky = world.read_storage::<Blocky>();
+    let medium = world.read_storage::<MediumZone>();
+    let capture = world.read_storage::<CaptureZone>();
+    let ore = world.read_storage::<OrePickup>();
+    let circle = world.read_storage::<CircleCollider>();
+    let velocity = world.read_storage::<Velocity>();
+    let lifetime = world.read_storage::<Lifetime>();
+    let ship = world.read_storage::<Ship>();
+    let integrity = world.read_storage::<ShipIntegrity>();
+    let local = world.read_storage::<LocalControl>();
+    let faction = world.read_storage::<Faction>();
+    let team = world.read_storage::<Team>();
+    let asteroid = world.read_storage::<Asteroid>();
+    let trader = world.read_storage::<Trader>();
+    let ai = world.read_storage::<AiPilot>();
+    let autopilot = world.read_storage::<Autopilot>();
+    let wing = world.read_storage::<Wing>();
+    let drone = world.read_storage::<Drone>();
+    let cargo = world.read_storage::<Cargo>();
+    let station = world.read_storage::<Station>();
+    let planet = world.read_storage::<Planet>();
+    for &ent in saved {
+        writeln!(out, "entity")?;
+        let pos = position.get(ent).unwrap();
+        writeln!(out, "position {} {} {}", pos.pos[0], pos.pos[1], pos.rot)?;
+        if let Some(vel) = velocity.get(ent) {
+            writeln!(
+                out,
+                "velocity {} {} {}",
+                vel.vel[0], vel.vel[1], vel.rot,
+            )?;
+        }
+        if let Some(lifetime) = lifetime.get(ent) {
+            writeln!(out, "lifetime {}", lifetime.0)?;
+        }
+        if let Some(blk) = blocky.get(ent) {
+            for (loc, block) in &blk.blocks {
+                write!(
+                    out,
+                    "block {} {} {} {} {} {} ",
+                    loc[0],
+                    loc[1],
+                    block.health,
+                    block.disabled,
+                    block.group,
+                    block.auto as u8,
+                )?;
+                write_inner(out, &block.inner)?;
+                writeln!(out)?;
+            }
+        }
+        if let Some(ship) = ship.get(ent) {
+            let n = ship.nominal_thrust;
+            writeln!(
+                out,
+                "ship {} {} {} {}",
+                ship.dampeners as u8, n[0], n[1], n[2],
+            )?;
+        }
+        if let Some(integrity) = integrity.get(ent) {
+            writeln!(out, "integrity {}", integrity.nominal_health())?;
+        }
+        if let Some(local) = local.get(ent) {
+            writeln!(out, "local {}", local.0)?;
+        }
+        if let Some(faction) = faction.get(ent) {
+            writeln!(out, "faction {}", faction.0)?;
+        }
+        if let Some(team) = team.get(ent) {
+            writeln!(out, "team {}", team.0)?;
+        }
+        if asteroid.get(ent).is_some() {
+            writeln!(out, "asteroid")?;
+        }
+        if trader.get(ent).is_some() {
+            writeln!(out, "trader")?;
+        }
+        if let Some(ai) = ai.get(ent) {
+            writeln!(out, "ai {}", personality_name(ai.personality))?;
+        }
+        match autopilot.get(ent) {
+            Some(&Autopilot::FlyTo(to)) => {
+                writeln!(out, "fly_to {} {}", to[0], to[1])?
+            }
+            Some(&Autopilot::Follow { leader, offset }) => {
+                if let Some(leader) = index.get(&leader) {
+                    writeln!(
+                        out,
+                        "follow {} {} {}",
+                        leader, offset[0], offset[1],
+                    )?;
+                }
+            }
+            _ => {}
+        }
+        if let Some(wing) = wing.get(ent) {
+            if let Some(leader) = index.get(&wing.leader) {
+                writeln!(
+                    out,
+                    "wing {} {} {}",
+                    leader, wing.offset[0], wing.offset[1],
+                )?;
+            }
+        }
+        if let Some(drone) = drone.get(ent) {
+            if let Some(carrier) = index.get(&drone.carrier) {
+                writeln!(
+                    out,
+                    "drone {} {} {} {}",
+                    carrier, drone.dock[0], drone.dock[1], drone.orbit,
+                )?;
+            }
+        }
+        if let Some(cargo) = cargo.get(ent) {
+            writeln!(out, "cargo {}", cargo.ammo)?;
+            for inner in &cargo.blocks {
+                write!(out, "cargo_block ")?;
+                write_inner(out, inner)?;
+                writeln!(out)?;
+            }
+        }
+        if let Some(station) = station.get(ent) {
+            writeln!(out, "station {}", station.safe_radius)?;
+        }
+        if planet.get(ent).is_some() {
+            writeln!(out, "planet")?;
+        }
+        if let Some(circle) = circle.get(ent) {
+            writeln!(out, "circle {}", circle.radius)?;
+        }
+        if let Some(zone) = medium.get(ent) {
+            writeln!(out, "medium {} {}", zone.radius, zone.drag)?;
+        }
+        if let Some(zone) = capture.get(ent) {
+            let owner = zone.owner.map(|t| t.0 as i32).unwrap_or(-1);
+            let (capturing, progress) = match zone.capturing {
+                Some((t, p)) => (t.0 as i32, p),
+                None => (-1, 0.0),
+            };
+            writeln!(
+                out,
+                "zone {} {} {} {} {}",
+                zone.radius, owner, capturing, progress,
+                zone.contested as u8,
+            )?;
+        }
+        if let Some(ore) = ore.get(ent) {
+            writeln!(out, "ore {}", ore.amount)?;
+        }
+    }
+    Ok(())
+}
+
+/// The fields of a line, parsed on demand.
+struct Fields<'a> {
+    fields: SplitWhitespace<'a>,
+}
+
+impl<'a> Fields<'a> {
+    fn str(&mut self) -> Result<&'a str, String> {
+        self.fields.next().ok_or_else(|| "missing field".to_owned())
+    }
+
+    fn parse<T: std::str::FromStr>(&mut self) -> Result<T, String> {
+        let field = self.str()?;
+        field.parse().map_err(|_| format!("invalid field {:?}", field))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        self.parse()
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.parse()
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        self.parse()
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u32()? != 0)
+    }
+
+    /// A team number, or -1 for none.
+    fn team(&mut self) -> Result<Option<Team>, String> {
+        match self.parse::<i32>()? {
+            -1 => Ok(None),
+            t if t >= 0 && (t as usize) < TEAMS => Ok(Some(Team(t as u8))),
+            t => Err(format!("invalid team {}", t)),
+        }
+    }
+
+    fn inner(&mut self) -> Result<BlockInner, String> {
+        Ok(match self.str()? {
+            "cockpit" => BlockInner::Cockpit,
+            "thruster" => BlockInner::Thruster { angle: self.f32()? },
+            "plasma" => BlockInner::PlasmaGun {
+                angle: self.f32()?,
+                cooldown: self.f32()?,
+                ammo: self.u32()?,
+            },
+            "rail" => BlockInner::RailGun {
+                angle: self.f32()?,
+                cooldown: self.f32()?,
+                ammo: self.u32()?,
+            },
+            "emp" => BlockInner::EmpGun {
+                angle: self.f32()?,
+                cooldown: self.f32()?,
+                ammo: self.u32()?,
+            },
+            "flak" => BlockInner::FlakCannon {
+                angle: self.f32()?,
+                cooldown: self.f32()?,
+                ammo: self.u32()?,
+            },
+            "charge" => BlockInner::ChargeGun {
+                angle: self.f32()?,
+                cooldown: self.f32()?,
+                ammo: self.u32()?,
+                charge: self.f32()?,
+            },
+            "beam" => BlockInner::BeamLaser { angle: self.f32()? },
+            "clamp" => BlockInner::BoardingClamp,
+            "salvage" => BlockInner::SalvageBeam,
+            "bay" => BlockInner::DroneBay {
+                drones: self.u32()?,
+                cooldown: self.f32()?,
+            },
+            "tank" => BlockInner::FuelTank { fuel: self.f32()? },
+            "port" => BlockInner::DockingPort,
+            "armor" => BlockInner::Armor,
+            "rock" => BlockInner::Rock,
+            kind => return Err(format!("unknown block {:?}", kind)),
+        })
+    }
+}
+
+/// An entity read from a save file, before it gets created.
+#[derive(Default)]
+struct SavedEntity {
+    position: Option<Position>,
+    velocity: Option<Velocity>,
+    lifetime: Option<f32>,
+    blocks: Vec<([f32; 2], Block)>,
+    ship: Option<Ship>,
+    nominal_health: Option<f32>,
+    local: Option<usize>,
+    faction: Option<u32>,
+    team: Option<Team>,
+    asteroid: bool,
+    trader: bool,
+    ai: Option<Personality>,
+    fly_to: Option<[f32; 2]>,
+    follow: Option<(usize, [f32; 2])>,
+    wing: Option<(usize, [f32; 2])>,
+    drone: Option<(usize, [f32; 2], f32)>,
+    cargo: Option<Cargo>,
+    station: Option<Station>,
+    planet: bool,
+    circle: Option<f32>,
+    medium: Option<MediumZone>,
+    zone: Option<CaptureZone>,
+    ore: Option<u32>,
+}
+
+/// The contents of a save file.
+#[derive(Default)]
+struct SaveFile {
+    seed: u64,
+    /// Generated sectors, and whether they were streamed out.
+    sectors: Vec<(SectorId, bool)>,
+    wallet: Vec<(u64, u32)>,
+    scores: Vec<(u64, PlayerStats)>,
+    match_state: MatchState,
+    waves: Option<WaveState>,
+    team_scores: Vec<(usize, u32)>,
+    team_players: Vec<(u64, Team)>,
+    entities: Vec<SavedEntity>,
+}
+
+/// Parses one line of a save file.
+fn parse_line(file: &mut SaveFile, line: &str) -> Result<(), String> {
+    let mut f = Fields {
+        fields: line.split_whitespace(),
+    };
+    let name = match f.fields.next() {
+        Some(n) => n,
+        None => return Ok(()),
+    };
+    let ent = file.entities.last_mut();
+    let ent = || ent.ok_or_else(|| format!("{:?} outside entity", name));
+    match name {
+        "seed" => file.seed = f.u64()?,
+        "sector" => {
+            let id = SectorId {
+                x: f.parse()?,
+                y: f.parse()?,
+            };
+            let stored = f.fields.next() == Some("stored");
+            file.sectors.push((id, stored));
+        }
+        "wallet" => file.wallet.push((f.u64()?, f.u32()?)),
+        "score" => file.scores.push((
+            f.u64()?,
+            PlayerStats {
+                shots_fired: f.u32()?,
+                shots_hit: f.u32()?,
+                damage_dealt: f.f32()?,
+                damage_taken: f.f32()?,
+                kills: f.u32()?,
+                deaths: f.u32()?,
+                largest_ship: f.u32()?,
+            },
+        )),
+        "match" => {
+            file.match_state = match (f.str()?, f.f32()?) {
+                ("warmup", t) => MatchState::Warmup(t),
+                ("playing", t) => MatchState::Playing(t),
+                ("finished", t) => MatchState::Finished(t),
+                (phase, _) => return Err(format!("unknown phase {:?}", phase)),
+            }
+        }
+        "waves" => {
+            let wave = f.u32()?;
+            let cleared = f.u32()?;
+            let next_wave = Some(f.f32()?).filter(|&t| t >= 0.0);
+            file.waves = Some(WaveState {
+                wave,
+                cleared,
+                next_wave,
+                enemies: f.parse()?,
+            });
+        }
+        "team_score" => {
+            let team = f.team()?.ok_or_else(|| "missing team".to_owned())?;
+            file.team_scores.push((team.0 as usize, f.u32()?));
+        }
+        "team_player" => {
+            let player = f.u64()?;
+            let team = f.team()?.ok_or_else(|| "missing team".to_owned())?;
+            file.team_players.push((player, team));
+        }
+        "entity" => file.entities.push(Default::default()),
+        "position" => {
+            ent()?.position = Some(Position {
+                pos: [f.f32()?, f.f32()?],
+                rot: f.f32()?,
+            })
+        }
+        "velocity" => {
+            ent()?.velocity = Some(Velocity {
+                vel: [f.f32()?, f.f32()?],
+                rot: f.f32()?,
+            })
+        }
+        "lifetime" => ent()?.lifetime = Some(f.f32()?),
+        "block" => {
+            let loc = [f.f32()?, f.f32()?];
+            let health = f.f32()?;
+            let disabled = f.f32()?;
+            let group = f.parse()?;
+            let auto = f.bool()?;
+            let mut block = Block::new(f.inner()?);
+            block.health = health;
+            block.disabled = disabled;
+            block.group = group;
+            block.auto = auto;
+            ent()?.blocks.push((loc, block));
+        }
+        "ship" => {
+            let mut ship = Ship::new();
+            ship.dampeners = f.bool()?;
+            ship.nominal_thrust = [f.f32()?, f.f32()?, f.f32()?];
+            ent()?.ship = Some(ship);
+        }
+        "integrity" => ent()?.nominal_health = Some(f.f32()?),
+        "local" => ent()?.local = Some(f.parse()?),
+        "faction" => ent()?.faction = Some(f.u32()?),
+        "team" => ent()?.team = f.team()?,
+        "asteroid" => ent()?.asteroid = true,
+        "trader" => ent()?.trader = true,
+        "ai" => {
+            ent()?.ai = Some(match f.str()? {
+                "interceptor" => Personality::Interceptor,
+                "sniper" => Personality::Sniper,
+                "miner" => Personality::Miner,
+                "trader" => Personality::Trader,
+                "drone" => Personality::Drone,
+                p => return Err(format!("unknown personality {:?}", p)),
+            })
+        }
+        "fly_to" => ent()?.fly_to = Some([f.f32()?, f.f32()?]),
+        "follow" => {
+            ent()?.follow = Some((f.parse()?, [f.f32()?, f.f32()?]))
+        }
+        "wing" => ent()?.wing = Some((f.parse()?, [f.f32()?, f.f32()?])),
+        "drone" => {
+            ent()?.drone =
+                Some((f.parse()?, [f.f32()?, f.f32()?], f.f32()?))
+        }
+        "cargo" => {
+            ent()?.cargo = Some(Cargo {
+                blocks: Vec::new(),
+                ammo: f.u32()?,
+            })
+        }
+        "cargo_block" => {
+            let inner = f.inner()?;
+            match ent()?.cargo {
+                Some(ref mut cargo) => cargo.blocks.push(inner),
+                None => return Err("cargo block without cargo".to_owned()),
+            }
+        }
+        "station" => {
+            ent()?.station = Some(Station {
+                safe_radius: f.f32()?,
+                ports: 0,
+            })
+        }
+        "planet" => ent()?.planet = true,
+        "circle" => ent()?.circle = Some(f.f32()?),
+        "medium" => {
+            ent()?.medium = Some(MediumZone {
+                radius: f.f32()?,
+                drag: f.f32()?,
+            })
+        }
+        "zone" => {
+            let mut zone = CaptureZone::new(f.f32()?);
+            zone.owner = f.team()?;
+            let capturing = f.team()?;
+            let progress = f.f32()?;
+            zone.capturing = capturing.map(|t| (t, progress));
+            zone.contested = f.bool()?;
+            ent()?.zone = Some(zone);
+        }
+        "ore" => ent()?.ore = Some(f.u32()?),
+        _ => return Err(format!("unknown entry {:?}", name)),
+    }
+    Ok(())
+}
+
+/// Reads a save file, without touching the world.
+fn parse(text: &str) -> Result<SaveFile, String> {
+    let mut lines = text.lines().enumerate();
+    match lines.next() {
+        Some((_, HEADER)) => {}
+        _ => return Err("not a save file".to_owned()),
+    }
+    let mut file = SaveFile::default();
+    for (num, line) in lines {
+        parse_line(&mut file, line)
+            .map_err(|e| format!("line {}: {}", num + 1, e))?;
+    }
+    let count = file.entities.len();
+    for ent in &file.entities {
+        if ent.position.is_none() {
+            return Err("entity without a position".to_owned());
+        }
+        let refs = [
+            ent.follow.map(|f| f.0),
+            ent.wing.map(|w| w.0),
+            ent.drone.map(|d| d.0),
+        ];
+        if refs.iter().filter_map(|&r| r).any(|i| i >= count) {
+            return Err("reference to a missing entity".to_owned());
+        }
+    }
+    Ok(file)
+}
+
+/// Replaces the contents of the world with a save file.
+///
+/// The world is left untouched if the file can't be read.
+pub fn load(world: &mut World, text: &str) -> Result<(), String> {
+    let file = parse(text)?;
+
+    world.delete_all();
+    world.maintain();
+
+    // Resources
+    let dir = world.read_resource::<SectorManager>().dir.clone();
+    let mut sectors = SectorManager::new(file.seed);
+    sectors.dir = dir;
+    for (id, stored) in file.sectors {
+        sectors.restore(id);
+        sectors.sectors.get_mut(&id).unwrap().stored = stored;
+    }
+    world.insert(sectors);
+    world.insert(Wallet {
+        balances: file.wallet.into_iter().collect(),
+    });
+    world.insert(Scoreboard {
+        players: file.scores.into_iter().collect(),
+    });
+    world.insert(file.match_state);
+    if let Some(waves) = file.waves {
+        world.insert(waves);
+    }
+    if world.has_value::<Teams>() {
+        let mut teams = Teams::default();
+        for (team, score) in file.team_scores {
+            teams.scores[team] = score;
+        }
+        teams.players = file.team_players.into_iter().collect();
+        world.insert(teams);
+    }
+
+    create_entities(world, file.entities);
+    Ok(())
+}
+
+/// Loads back the entities of a sector that was streamed out.
+pub fn load_sector(world: &mut World, text: &str) -> Result<(), String> {
+    let file = parse(text)?;
+    create_entities(world, file.entities);
+    Ok(())
+}
+
+/// Creates entities read from a file.
+///
+/// On servers, they get replicated to the clients as new entities.
+fn create_entities(world: &mut World, entities: Vec<SavedEntity>) {
+    let created = entities
+        .iter()
+        .map(|_| world.create_entity().build())
+        .collect::<Vec<Entity>>();
+    #[cfg(feature = "network")]
+    {
+        if world.read_resource::<Role>().networked() {
+            for &e in &created {
+                world
+                    .write_storage()
+                    .insert(e, net::Replicated::new())
+                    .unwrap();
+                world.write_storage().insert(e, net::Dirty).unwrap();
+            }
+        }
+    }
+    for (&e, saved) in created.iter().zip(entities) {
+        let mut pos = saved.position.unwrap();
+        if !saved.blocks.is_empty() {
+            let (blocky, center) = Blocky::new(saved.blocks);
+            // Blocky::new() centers the blocks on their center of mass
+            // again, move the entity to match
+            let (s, c) = sin_cos(pos.rot);
+            pos.pos[0] += center[0] * c - center[1] * s;
+            pos.pos[1] += center[0] * s + center[1] * c;
+            if let Some(nominal) = saved.nominal_health {
+                world
+                    .write_storage()
+                    .insert(e, ShipIntegrity::with_nominal_health(
+                        &blocky, nominal,
+                    ))
+                    .unwrap();
+            }
+            world.write_storage().insert(e, blocky).unwrap();
+        }
+        world.write_storage().insert(e, pos).unwrap();
+        if let Some(vel) = saved.velocity {
+            world.write_storage().insert(e, vel).unwrap();
+        }
+        if let Some(t) = saved.lifetime {
+            world.write_storage().insert(e, Lifetime(t)).unwrap();
+        }
+        if let Some(ship) = saved.ship {
+            world.write_storage().insert(e, ship).unwrap();
+        }
+        if let Some(index) = saved.local {
+            world.write_storage().insert(e, LocalControl(index)).unwrap();
+        }
+        if let Some(faction) = saved.faction {
+            world.write_storage().insert(e, Faction(faction)).unwrap();
+        }
+        if let Some(team) = saved.team {
+            world.write_storage().insert(e, team).unwrap();
+        }
+        if saved.asteroid {
+            world.write_storage().insert(e, Asteroid).unwrap();
+        }
+        if saved.trader {
+            world.write_storage().insert(e, Trader).unwrap();
+        }
+        if let Some(personality) = saved.ai {
+            world
+                .write_storage()
+                .insert(e, AiPilot::new(personality))
+                .unwrap();
+        }
+        if let Some(to) = saved.fly_to {
+            world.write_storage().insert(e, Autopilot::FlyTo(to)).unwrap();
+        }
+        if let Some((leader, offset)) = saved.follow {
+            let follow = Autopilot::Follow {
+                leader: created[leader],
+                offset,
+            };
+            world.write_storage().insert(e, follow).unwrap();
+        }
+        if let Some((leader, offset)) = saved.wing {
+            let wing = Wing {
+                leader: created[leader],
+                offset,
+            };
+            world.write_storage().insert(e, wing).unwrap();
+        }
+        if let Some((carrier, dock, orbit)) = saved.drone {
+            let drone = Drone {
+                carrier: created[carrier],
+                dock,
+                orbit,
+            };
+            world.write_storage().insert(e, drone).unwrap();
+        }
+        if let Some(cargo) = saved.cargo {
+            world.write_storage().insert(e, cargo).unwrap();
+        }
+        if let Some(station) = saved.station {
+            world.write_storage().insert(e, station).unwrap();
+        }
+        if saved.planet {
+            world.write_storage().insert(e, Planet).unwrap();
+        }
+        if let Some(radius) = saved.circle {
+            world
+                .write_storage()
+                .insert(e, CircleCollider { radius })
+                .unwrap();
+        }
+        if let Some(zone) = saved.medium {
+            world.write_storage().insert(e, zone).unwrap();
+        }
+        if let Some(zone) = saved.zone {
+            world.write_storage().insert(e, zone).unwrap();
+        }
+        if let Some(amount) = saved.ore {
+            world.write_storage().insert(e, OrePickup { amount }).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{Join, WorldExt};
+
+    use super::{load, save};
+    use crate::Game;
+
+    /// A game that ran for a bit, with a save file of it.
+    fn saved_game() -> (Game, String) {
+        let mut game = Game::new_standalone();
+        for _ in 0..20 {
+            game.update(0.05);
+        }
+        let mut out = Vec::new();
+        save(&game.world, &mut out).unwrap();
+        (game, String::from_utf8(out).unwrap())
+    }
+
+    fn count_entities(game: &Game) -> usize {
+        game.world.entities().join().count()
+    }
+
+    /// Whether two save files match, up to the order of the entities and
+    /// rounding errors from recentering the ships' blocks.
+    fn same_save(a: &str, b: &str) -> bool {
+        let close = |a: &str, b: &str| {
+            let a = a.split_whitespace().collect::<Vec<_>>();
+            let b = b.split_whitespace().collect::<Vec<_>>();
+            a.len() == b.len()
+                && a.iter().zip(&b).all(|(x, y)| {
+                    x == y
+                        || match (x.parse::<f32>(), y.parse::<f32>()) {
+                            (Ok(x), Ok(y)) => (x - y).abs() < 1e-4,
+                            _ => false,
+                        }
+                })
+        };
+        let mut a = a.split("\nentity\n");
+        let mut b = b.split("\nentity\n");
+        if a.next() != b.next() {
+            return false;
+        }
+        let mut b = b.collect::<Vec<_>>();
+        for ent in a {
+            match b.iter().position(|&other| close(ent, other)) {
+                Some(i) => {
+                    b.swap_remove(i);
+                }
+                None => return false,
+            }
+        }
+        b.is_empty()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (game, text) = saved_game();
+        let mut loaded = Game::new_standalone();
+        load(&mut loaded.world, &text).unwrap();
+        loaded.world.maintain();
+        let mut out = Vec::new();
+        save(&loaded.world, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(same_save(&out, &text));
+        assert_eq!(count_entities(&loaded), count_entities(&game));
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        let (_, text) = saved_game();
+        let mut game = Game::new_standalone();
+        let count = count_entities(&game);
+
+        // Truncated in the middle of a line
+        let cut = text.find("\nposition ").unwrap() + 12;
+        assert!(load(&mut game.world, &text[..cut]).is_err());
+        // Corrupt number
+        let corrupt = text.replacen("\nposition ", "\nposition x", 1);
+        assert!(load(&mut game.world, &corrupt).is_err());
+        // Unknown entry
+        let corrupt = text.replacen("\nposition ", "\nteleport ", 1);
+        assert!(load(&mut game.world, &corrupt).is_err());
+        // Not a save file
+        assert!(load(&mut game.world, "").is_err());
+        assert!(load(&mut game.world, "hello\nentity\n").is_err());
+
+        // The world was left alone
+        assert_eq!(count_entities(&game), count);
+    }
+}
@@ -0,0 +1,535 @@
+//! Sectors, splitting space into a grid of squares.
+//!
+//! Each sector is `SECTOR_SIZE` across; sector (0, 0) is centered on the
+//! origin, and is where players start. `SysSector` gives every entity with a
+//! `Position` the `SectorId` it is in, updating it as the entity moves, and
+//! keeps track of the sectors in the `SectorManager` resource. It runs
+//! everywhere, since the sectors follow from the positions.
+//!
+//! On authoritative machines, the contents of a sector (its `Layout`) are
+//! generated from the world seed when a player gets close to it, so the same
+//! seed always gives the same map. Asteroids are kept around players, in the
+//! sectors they are in and the ones next to them, see `asteroid.rs`.
+//!
+//! Each sector also gets a `Backdrop`, the sun and the distant planets seen
+//! from it, for frontends to draw behind everything. Servers send it to
+//! clients when they get near the sector.
+//!
+//! Servers only send each client the entities in the sector its ship is in
+//! and the ones next to it.
+//!
+//! If the `SectorManager` has a directory to store sectors in (see
+//! `GameBuilder::sector_dir()`), sectors that no player is close to are
+//! streamed out: `Game` writes their entities to a file, in the format of
+//! `save.rs`, and deletes them. They are loaded back when a player comes into
+//! or next to them. The starting sector always stays loaded.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use specs::{Component, Entities, Join, LazyUpdate, Read, ReadExpect,
+            ReadStorage, System, VecStorage, Write, WriteStorage};
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use vecmath::*;
+
+use crate::medium::MediumZone;
+use crate::physics::{pilot, LocalControl, Position, RemoteControl};
+use crate::planet::Planet;
+use crate::station::Station;
+use crate::Role;
+
+/// Width and height of a sector.
+pub const SECTOR_SIZE: f32 = 300.0;
+
+/// Distance to the edge of a sector under which a player gets the sector on
+/// the other side generated.
+const GENERATE_DISTANCE: f32 = 75.0;
+
+/// Distance, in sectors, past which players get a sector streamed out.
+const STORE_DISTANCE: i32 = 2;
+
+/// Coordinates of a sector in the grid.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SectorId {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl Component for SectorId {
+    type Storage = VecStorage<Self>;
+}
+
+impl SectorId {
+    /// The sector containing a point.
+    pub fn at(pos: [f32; 2]) -> SectorId {
+        let coord = |v: f32| (v / SECTOR_SIZE + 0.5).floor() as i32;
+        SectorId {
+            x: coord(pos[0]),
+            y: coord(pos[1]),
+        }
+    }
+
+    pub fn center(self) -> [f32; 2] {
+        [self.x as f32 * SECTOR_SIZE, self.y as f32 * SECTOR_SIZE]
+    }
+
+    /// Whether a sector is this one or one of its neighbors.
+    pub fn is_near(self, other: SectorId) -> bool {
+        self.distance(other) <= 1
+    }
+
+    /// Number of sectors between this one and another, diagonals counting
+    /// as one.
+    pub fn distance(self, other: SectorId) -> i32 {
+        (self.x - other.x).abs().max((self.y - other.y).abs())
+    }
+
+    /// The 8 sectors around this one.
+    pub fn neighbors(self) -> impl Iterator<Item = SectorId> {
+        (-1..=1)
+            .flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+            .filter(|&d| d != (0, 0))
+            .map(move |(dx, dy)| SectorId {
+                x: self.x + dx,
+                y: self.y + dy,
+            })
+    }
+}
+
+/// A nebula slowing ships down, see `medium.rs`.
+#[derive(Debug, Clone)]
+pub struct Nebula {
+    pub pos: [f32; 2],
+    pub radius: f32,
+    pub drag: f32,
+}
+
+/// How a sector looks from afar.
+///
+/// Positions and sizes are relative to the view rather than to the world,
+/// since these are too far to move on screen: -1 to 1 along each axis, and
+/// sizes as a fraction of the height of the view.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Backdrop {
+    pub sun_pos: [f32; 2],
+    pub sun_size: f32,
+    pub sun_color: [f32; 3],
+    pub planets: Vec<DistantPlanet>,
+}
+
+/// A planet in the backdrop of a sector, that can't be reached.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DistantPlanet {
+    pub pos: [f32; 2],
+    pub size: f32,
+    pub color: [f32; 3],
+}
+
+/// Maximum number of planets in a backdrop.
+pub const MAX_DISTANT_PLANETS: usize = 4;
+
+/// Colors suns come in, from hot to cold.
+const SUN_COLORS: [[f32; 3]; 4] = [
+    [0.7, 0.8, 1.0],
+    [1.0, 0.95, 0.8],
+    [1.0, 0.75, 0.4],
+    [1.0, 0.5, 0.3],
+];
+
+impl Backdrop {
+    /// Picks a random backdrop.
+    fn generate<R: Rng>(rng: &mut R) -> Backdrop {
+        let sun_pos = [rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0)];
+        let planets = (0..rng.gen_range(0, MAX_DISTANT_PLANETS + 1))
+            .map(|_| DistantPlanet {
+                pos: [rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0)],
+                size: rng.gen_range(0.02, 0.1),
+                color: [
+                    rng.gen_range(0.2, 0.8),
+                    rng.gen_range(0.2, 0.8),
+                    rng.gen_range(0.2, 0.8),
+                ],
+            })
+            .collect();
+        Backdrop {
+            sun_pos,
+            sun_size: rng.gen_range(0.03, 0.12),
+            sun_color: SUN_COLORS[rng.gen_range(0, SUN_COLORS.len())],
+            planets,
+        }
+    }
+}
+
+/// The contents of a sector, picked when it gets generated.
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    /// Number of asteroids kept in the sector while players are around.
+    pub asteroids: u32,
+    pub nebulae: Vec<Nebula>,
+    /// Where stations go, in world coordinates.
+    pub stations: Vec<[f32; 2]>,
+    /// Planets, as their center and radius.
+    pub planets: Vec<([f32; 2], f32)>,
+    pub backdrop: Backdrop,
+}
+
+impl Layout {
+    /// The starting sector, always the same: plenty of asteroids, a couple
+    /// of nebulae, a station and a small planet away from where ships
+    /// spawn.
+    fn home() -> Layout {
+        Layout {
+            asteroids: 60,
+            nebulae: vec![
+                Nebula {
+                    pos: [45.0, 30.0],
+                    radius: 25.0,
+                    drag: 0.3,
+                },
+                Nebula {
+                    pos: [-50.0, -35.0],
+                    radius: 20.0,
+                    drag: 0.5,
+                },
+            ],
+            stations: vec![[70.0, 70.0]],
+            planets: vec![([-75.0, 75.0], 14.0)],
+            backdrop: Backdrop {
+                sun_pos: [0.7, 0.6],
+                sun_size: 0.08,
+                sun_color: SUN_COLORS[1],
+                planets: vec![DistantPlanet {
+                    pos: [-0.5, -0.4],
+                    size: 0.05,
+                    color: [0.6, 0.4, 0.3],
+                }],
+            },
+        }
+    }
+
+    /// Picks the contents of a sector, from the world seed.
+    pub fn generate(seed: u64, id: SectorId) -> Layout {
+        if id == SectorId::default() {
+            return Layout::home();
+        }
+        let coords = (id.x as u32 as u64) << 32 | id.y as u32 as u64;
+        let mut rng = StdRng::seed_from_u64(seed ^ coords);
+        let center = id.center();
+        let point = |rng: &mut StdRng, margin: f32| {
+            let half = 0.5 * SECTOR_SIZE - margin;
+            [
+                center[0] + rng.gen_range(-half, half),
+                center[1] + rng.gen_range(-half, half),
+            ]
+        };
+
+        // Mostly sparse sectors, with a few dense asteroid fields
+        let density: f32 = rng.gen_range(0.0, 1.0);
+        let asteroids = (density * density * 90.0) as u32;
+        let nebulae = (0..rng.gen_range(0, 4))
+            .map(|_| Nebula {
+                pos: point(&mut rng, 30.0),
+                radius: rng.gen_range(10.0, 35.0),
+                drag: rng.gen_range(0.2, 0.8),
+            })
+            .collect();
+        let stations = if rng.gen_range(0.0, 1.0) < 0.25 {
+            vec![point(&mut rng, 60.0)]
+        } else {
+            Vec::new()
+        };
+        let mut planets = Vec::new();
+        if rng.gen_range(0.0, 1.0) < 0.2 {
+            let radius = rng.gen_range(15.0, 40.0);
+            let pos = point(&mut rng, radius + 20.0);
+            // Leave room around stations
+            let clear = stations.iter().all(|&station| {
+                let room = radius + 25.0;
+                vec2_square_len(vec2_sub(pos, station)) > room * room
+            });
+            if clear {
+                planets.push((pos, radius));
+            }
+        }
+        Layout {
+            asteroids,
+            nebulae,
+            stations,
+            planets,
+            backdrop: Backdrop::generate(&mut rng),
+        }
+    }
+}
+
+/// What is known about a sector.
+#[derive(Debug, Clone, Default)]
+pub struct Sector {
+    /// Number of entities in the sector, as of the last tick.
+    pub entities: u32,
+    /// Number of players' ships in the sector, as of the last tick.
+    pub players: u32,
+    /// The contents of the sector, once generated. This is only known on
+    /// authoritative machines.
+    pub layout: Option<Layout>,
+    /// How the sector looks, known everywhere once the sector is generated
+    /// and, on clients, sent by the server.
+    pub backdrop: Option<Backdrop>,
+    /// Whether the entities of the sector were streamed out to a file.
+    pub stored: bool,
+}
+
+/// The sectors entities have been in, available as a resource.
+#[derive(Debug, Default)]
+pub struct SectorManager {
+    /// Seed the sectors are generated from.
+    pub seed: u64,
+    pub sectors: HashMap<SectorId, Sector>,
+    /// Directory sectors get streamed out to, if any.
+    pub dir: Option<PathBuf>,
+}
+
+impl SectorManager {
+    pub fn new(seed: u64) -> SectorManager {
+        SectorManager {
+            seed,
+            sectors: HashMap::new(),
+            dir: None,
+        }
+    }
+
+    pub fn get(&self, id: SectorId) -> Option<&Sector> {
+        self.sectors.get(&id)
+    }
+
+    /// Generates a sector and creates its nebulae, stations and planets,
+    /// unless it was generated already.
+    pub fn generate(
+        &mut self,
+        id: SectorId,
+        entities: &Entities,
+        lazy: &Read<LazyUpdate>,
+    ) {
+        let sector = self.sectors.entry(id).or_default();
+        if sector.layout.is_some() {
+            return;
+        }
+        let layout = Layout::generate(self.seed, id);
+        sector.backdrop = Some(layout.backdrop.clone());
+        for nebula in &layout.nebulae {
+            MediumZone::create(
+                entities,
+                lazy,
+                nebula.pos,
+                nebula.radius,
+                nebula.drag,
+            );
+        }
+        for &pos in &layout.stations {
+            Station::create(entities, lazy, pos);
+        }
+        for &(pos, radius) in &layout.planets {
+            Planet::create(entities, lazy, pos, radius);
+        }
+        sector.layout = Some(layout);
+    }
+
+    /// Marks a sector as generated without creating its contents, for
+    /// example when they are loaded from a save file.
+    pub fn restore(&mut self, id: SectorId) {
+        let layout = Layout::generate(self.seed, id);
+        let sector = self.sectors.entry(id).or_default();
+        sector.backdrop = Some(layout.backdrop.clone());
+        sector.layout = Some(layout);
+    }
+
+    /// The file a sector gets streamed out to.
+    pub fn path(&self, id: SectorId) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("sector_{}_{}.txt", id.x, id.y)))
+    }
+
+    /// The sectors players are in.
+    fn occupied(&self) -> Vec<SectorId> {
+        self.sectors
+            .iter()
+            .filter(|(_, s)| s.players > 0)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// The loaded sectors to stream out: those no player is close to,
+    /// except the starting sector.
+    pub fn to_store(&self) -> Vec<SectorId> {
+        if self.dir.is_none() {
+            return Vec::new();
+        }
+        let occupied = self.occupied();
+        let mut ids = self
+            .sectors
+            .iter()
+            .filter(|(&id, s)| {
+                s.layout.is_some()
+                    && !s.stored
+                    && id != SectorId::default()
+                    && occupied
+                        .iter()
+                        .all(|&p| p.distance(id) > STORE_DISTANCE)
+            })
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    /// The stored sectors to load back: those players are in or next to.
+    pub fn to_load(&self) -> Vec<SectorId> {
+        let occupied = self.occupied();
+        let mut ids = self
+            .sectors
+            .iter()
+            .filter(|(&id, s)| {
+                s.stored && occupied.iter().any(|&p| p.is_near(id))
+            })
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    /// The generated sectors where asteroids are kept: the starting sector,
+    /// and those players are in or next to.
+    pub fn active(&self) -> BTreeSet<SectorId> {
+        let mut active = BTreeSet::new();
+        active.insert(SectorId::default());
+        for (&id, sector) in &self.sectors {
+            if sector.players > 0 {
+                active.insert(id);
+                active.extend(id.neighbors());
+            }
+        }
+        active.retain(|id| match self.sectors.get(id) {
+            Some(s) => s.layout.is_some() && !s.stored,
+            None => false,
+        });
+        active
+    }
+}
+
+/// Sector system, assigns entities to sectors and counts them.
+pub struct SysSector;
+
+impl<'a> System<'a> for SysSector {
+    type SystemData = (
+        ReadExpect<'a, Role>,
+        Read<'a, LazyUpdate>,
+        Write<'a, SectorManager>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        WriteStorage<'a, SectorId>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            role,
+            lazy,
+            mut manager,
+            entities,
+            position,
+            mut sector,
+            local,
+            remote,
+        ): Self::SystemData,
+    ) {
+        for s in manager.sectors.values_mut() {
+            s.entities = 0;
+            s.players = 0;
+        }
+
+        let mut players = Vec::new();
+        for (ent, pos) in (&*entities, &position).join() {
+            let id = SectorId::at(pos.pos);
+            if sector.get(ent) != Some(&id) {
+                sector.insert(ent, id).unwrap();
+            }
+            let s = manager.sectors.entry(id).or_default();
+            s.entities += 1;
+            if pilot(ent, &local, &remote).is_some() {
+                s.players += 1;
+                players.push((id, pos.pos));
+            }
+        }
+
+        if !role.authoritative() {
+            return;
+        }
+
+        // Generate the sectors players are in, and those they are getting
+        // close to
+        for (id, pos) in players {
+            let center = id.center();
+            let side = |v: f32| {
+                if v < GENERATE_DISTANCE - 0.5 * SECTOR_SIZE {
+                    -1
+                } else if v > 0.5 * SECTOR_SIZE - GENERATE_DISTANCE {
+                    1
+                } else {
+                    0
+                }
+            };
+            let dx = side(pos[0] - center[0]);
+            let dy = side(pos[1] - center[1]);
+            for &(dx, dy) in &[(0, 0), (dx, 0), (0, dy), (dx, dy)] {
+                let id = SectorId {
+                    x: id.x + dx,
+                    y: id.y + dy,
+                };
+                manager.generate(id, &entities, &lazy);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{Join, WorldExt};
+
+    use super::SectorId;
+    use crate::physics::{LocalControl, Position, Velocity};
+    use crate::GameBuilder;
+
+    #[test]
+    fn test_fly_to_next_sector() {
+        // Seeded, so no asteroid gets in the way
+        let mut game =
+            GameBuilder::new().world_seed(1).rng_seed(1).standalone();
+        let ship = {
+            let entities = game.world.entities();
+            let local = game.world.read_storage::<LocalControl>();
+            (&*entities, &local).join().next().unwrap().0
+        };
+        {
+            let mut pos = game.world.write_storage::<Position>();
+            pos.get_mut(ship).unwrap().pos = [120.0, 0.0];
+            let mut vel = game.world.write_storage::<Velocity>();
+            vel.get_mut(ship).unwrap().vel = [60.0, 0.0];
+        }
+        game.update(0.05);
+        assert_eq!(
+            game.world.read_storage::<SectorId>().get(ship),
+            Some(&SectorId { x: 0, y: 0 }),
+        );
+        for _ in 0..30 {
+            game.update(0.05);
+        }
+        let pos = game.world.read_storage::<Position>().get(ship).unwrap().pos;
+        assert!(pos[0] > 150.0);
+        assert_eq!(
+            game.world.read_storage::<SectorId>().get(ship),
+            Some(&SectorId { x: 1, y: 0 }),
+        );
+    }
+}
@@ -0,0 +1,51 @@
+//! Sensors, and the nebulae blinding them.
+//!
+//! Ships see other ships up to `SENSOR_RANGE` away. Nebulae (`MediumZone`s)
+//! degrade sensors both ways: from inside one, a ship only sees as far as
+//! `NEBULA_SENSOR_RANGE`, and a ship inside one can only be seen from that
+//! close. AI pilots go by this when picking targets, and servers only send
+//! each client the ships its own ship can see, so players can hide in
+//! nebulae.
+
+use vecmath::*;
+
+use crate::medium::MediumZone;
+
+/// Distance at which ships see each other.
+pub const SENSOR_RANGE: f32 = 150.0;
+
+/// Distance at which ships see each other when either is in a nebula.
+pub const NEBULA_SENSOR_RANGE: f32 = 20.0;
+
+/// Whether a point is inside one of the zones.
+///
+/// `zones` lists the zones with the position of their center, as for
+/// `medium::drag_at()`.
+pub fn in_nebula(pos: [f32; 2], zones: &[([f32; 2], MediumZone)]) -> bool {
+    zones.iter().any(|(center, zone)| {
+        vec2_square_len(vec2_sub(pos, *center)) <= zone.radius * zone.radius
+    })
+}
+
+/// How far sensors reach between two points.
+pub fn sensor_range(
+    observer: [f32; 2],
+    target: [f32; 2],
+    zones: &[([f32; 2], MediumZone)],
+) -> f32 {
+    if in_nebula(observer, zones) || in_nebula(target, zones) {
+        NEBULA_SENSOR_RANGE
+    } else {
+        SENSOR_RANGE
+    }
+}
+
+/// Whether a ship at `observer` can see something at `target`.
+pub fn can_see(
+    observer: [f32; 2],
+    target: [f32; 2],
+    zones: &[([f32; 2], MediumZone)],
+) -> bool {
+    let range = sensor_range(observer, target, zones);
+    vec2_square_len(vec2_sub(target, observer)) <= range * range
+}
@@ -4,139 +4,211 @@
 //! gets tacked on to store controls and thruster state.
 // TODO: Take some behavior out of SysShip and into blocks.rs
 //
-use rand::{self, Rng};
+use rand::Rng;
 use specs::{Component, Entities, Entity, Read, ReadExpect, Join, LazyUpdate,
-            ReadStorage, System, VecStorage, WriteStorage};
+            ReadStorage, System, VecStorage, Write, WriteStorage};
+use std::collections::HashMap;
 use std::f32::consts::PI;
 use vecmath::*;
 
 use crate::asteroid::Asteroid;
-use crate::blocks::{Block, BlockInner, Blocky};
+use crate::autopilot::Autopilot;
+use crate::blocks::{Block, BlockInner, Blocky, BAY_CAPACITY,
+                    TANK_CAPACITY};
+use crate::economy;
+use crate::events::{GameEvent, GameEvents};
+use crate::faction::{hostile, Faction};
 use crate::guns::{Projectile, ProjectileType};
-use crate::input::{Input, Press};
+use crate::input::{Inputs, Press};
+use crate::math::{atan2, sin_cos};
+use crate::medium::{drag_at, MediumZone};
 #[cfg(feature = "network")]
 use crate::net;
-use crate::particles::{Effect, EffectInner, Particle, ParticleType};
-use crate::physics::{find_collision_tree_ray, DeltaTime, HitEffect, Hits,
-                     LocalControl, Position, Velocity};
-use crate::utils::angle_wrap;
-use crate::{Clock, Role};
+use crate::particles::{Effect, EffectInner, EffectRng, Particle,
+                       ParticleType};
+use crate::physics::{find_collision_tree_ray, pilot, DeltaTime, HitEffect,
+                     Hits, LocalControl, PhysicsConfig, Position,
+                     RemoteControl, Velocity};
+use crate::rules::MatchState;
+use crate::salvage::Cargo;
+use crate::station::InSafeZone;
+use crate::teams::Team;
+use crate::utils::{angle_wrap, clamp};
+use crate::{Clock, GameRng, Role};
+
+/// Collision impulse under which blocks don't get damaged.
+const COLLISION_DAMAGE_THRESHOLD: f32 = 30.0;
+
+/// Damage dealt to blocks per unit of collision impulse over the threshold.
+const COLLISION_DAMAGE: f32 = 0.01;
+
+/// Number of weapon groups, that the pilot can fire independently.
+pub const WEAPON_GROUPS: usize = 3;
+
+/// Time it takes a `ChargeGun` to charge fully.
+const CHARGE_TIME: f32 = 2.0;
+
+/// Range at which guns in automatic mode pick their targets.
+const AUTO_RANGE: f32 = 30.0;
+
+/// How close to its target a gun in automatic mode needs to aim to fire.
+const AUTO_AIM: f32 = 0.1;
+
+/// How much wider the spread of a gun gets as it gets damaged, up to
+/// `1 + GUN_WEAR_SPREAD` times its normal spread.
+const GUN_WEAR_SPREAD: f32 = 2.0;
+
+/// Rounds of ammunition in the cargo hold of new ships.
+pub const STARTING_AMMO: u32 = 200;
+
+/// Speed under which the inertia dampeners leave the ship alone.
+const DAMPENER_SPEED: f32 = 0.5;
+
+/// Rotation speed under which the inertia dampeners leave the ship alone.
+const DAMPENER_ROT_SPEED: f32 = 0.1;
+
+/// Fuel burned per second by a thruster firing at full power.
+const FUEL_RATE: f32 = 1.0;
+
+/// Factor applied to the thrust while boosting.
+const BOOST_THRUST: f32 = 2.0;
+
+/// Factor applied to the fuel consumption while boosting.
+const BOOST_FUEL: f32 = 3.0;
+
+/// Heat at which the thrusters overheat, one unit per second of boost.
+const BOOST_MAX_HEAT: f32 = 2.0;
+
+/// Heat lost per second when not boosting.
+const BOOST_COOLING: f32 = 0.5;
+
+/// Number of passes of the thrust allocation solver.
+const THRUST_SOLVER_ITERATIONS: usize = 12;
+
+/// Radius around the contact point in which blocks get damaged by
+/// collisions.
+const COLLISION_DAMAGE_RADIUS: f32 = 1.5;
 
 /// A ship.
 ///
 /// A ship has thrusters allowing it to rotate and move forward, and can fire
 /// projectiles.
 pub struct Ship {
-    pub want_fire: bool,
+    /// Whether the pilot wants to fire, for each weapon group.
+    pub want_fire: [bool; WEAPON_GROUPS],
     pub want_thrust: [f32; 2],
     pub want_thrust_rot: f32,
     pub want_target: [f32; 2],
+    /// Whether the pilot wants the tractor beam on.
+    pub want_tractor: bool,
+    /// Whether the inertia dampeners are on: thrusters then kill the ship's
+    /// motion on the axes the pilot isn't using.
+    pub dampeners: bool,
+    /// Whether the pilot wants to boost the thrusters.
+    pub want_boost: bool,
+    /// Whether the pilot wants to match the velocity of the object under
+    /// `want_target`, see `autopilot.rs`.
+    pub want_match: bool,
     pub thrust: [f32; 2],
     pub thrust_rot: f32,
+    /// Time until all blocks disabled by an EMP are functional again.
+    pub disabled: f32,
+    /// Blocks disabled by an EMP, by location in the ship, with the time
+    /// until they work again. Clients get this rather than the blocks.
+    pub disabled_blocks: Vec<([f32; 2], f32)>,
+    /// Highest charge of the ship's `ChargeGun`s, from 0 to 1.
+    pub charge: f32,
+    /// Whether the tractor beam is holding a piece.
+    pub tractor: bool,
+    /// Fuel left in the ship's tanks.
+    pub fuel: f32,
+    /// Whether the thrusters are boosted right now.
+    pub boosting: bool,
+    /// Heat of the thrusters from boosting, the boost stops at
+    /// `BOOST_MAX_HEAT`.
+    pub heat: f32,
+    /// Whether the thrusters overheated, they can't boost again until they
+    /// cooled down completely.
+    pub overheated: bool,
+    /// Control authority on each axis (forward/backward, sideways,
+    /// rotation): the fraction of the ship's original thrust still
+    /// available, in the weakest direction.
+    pub authority: [f32; 3],
+    /// Thrust available on each axis when the ship was at its best, see
+    /// `thrust_capacity()`.
+    pub nominal_thrust: [f32; 3],
 }
 
 impl Ship {
     pub fn new() -> Ship {
         Ship {
-            want_fire: false,
+            want_fire: [false; WEAPON_GROUPS],
             want_thrust: [0.0, 0.0],
             want_thrust_rot: 0.0,
             want_target: [0.0, 0.0],
+            want_tractor: false,
+            dampeners: false,
+            want_boost: false,
+            want_match: false,
             thrust: [0.0, 0.0],
             thrust_rot: 0.0,
+            disabled: 0.0,
+            disabled_blocks: Vec::new(),
+            charge: 0.0,
+            tractor: false,
+            fuel: 0.0,
+            boosting: false,
+            heat: 0.0,
+            overheated: false,
+            authority: [1.0; 3],
+            nominal_thrust: [0.0; 3],
         }
     }
 
-    pub fn create(entities: &Entities, lazy: &Read<LazyUpdate>) -> Entity {
-        use self::BlockInner::*;
-        let blocks = &[
-            ([0, 0], Cockpit),
-            ([-3, -2], Armor),
-            ([-3, -1], Thruster { angle: 0.0 }),
-            ([-3, 0], Thruster { angle: 0.0 }),
-            ([-3, 1], Thruster { angle: 0.0 }),
-            ([-3, 2], Armor),
-            (
-                [-2, -2],
-                Thruster {
-                    angle: 0.5 * PI,
-                },
-            ),
-            ([-2, -1], Armor),
-            ([-2, 0], Armor),
-            ([-2, 1], Armor),
-            (
-                [-2, 2],
-                Thruster {
-                    angle: -0.5 * PI,
-                },
-            ),
-            ([-1, -2], Thruster { angle: PI }),
-            ([-1, -1], Armor),
-            ([-1, 0], Armor),
-            ([-1, 1], Armor),
-            ([-1, 2], Thruster { angle: PI }),
-            ([-0, -1], Armor),
-            ([-0, 1], Armor),
-            ([1, -1], Armor),
-            ([1, 0], Armor),
-            ([1, 1], Armor),
-            (
-                [2, -1],
-                Thruster {
-                    angle: 0.5 * PI,
-                },
-            ),
-            ([2, 0], Armor),
-            (
-                [2, 1],
-                Thruster {
-                    angle: -0.5 * PI,
-                },
-            ),
-            (
-                [3, -1],
-                PlasmaGun {
-                    angle: 0.0,
-                    cooldown: -1.0,
-                },
-            ),
-            (
-                [3, 0],
-                RailGun {
-                    angle: 0.0,
-                    cooldown: -1.0,
-                },
-            ),
-            (
-                [3, 1],
-                PlasmaGun {
-                    angle: 0.0,
-                    cooldown: -1.0,
-                },
-            ),
-        ];
-        let blocks = blocks
-            .iter()
-            .map(|&(ref p, ref b)| {
-                (
-                    [p[0] as f32, p[1] as f32],
-                    Block::new(b.clone()),
-                )
-            })
-            .collect();
+    /// Whether the pilot wants a block to fire, from its weapon group.
+    pub fn fires(&self, block: &Block) -> bool {
+        self.want_fire[block.group]
+    }
+
+    pub fn create(
+        entities: &Entities,
+        lazy: &Read<LazyUpdate>,
+        class: ShipClass,
+    ) -> Entity {
+        Ship::create_at(entities, lazy, class, [0.0, 0.0], 0.0)
+    }
+
+    /// Creates a ship with its blueprint's origin at `pos`, facing `angle`.
+    pub fn create_at(
+        entities: &Entities,
+        lazy: &Read<LazyUpdate>,
+        class: ShipClass,
+        pos: [f32; 2],
+        angle: f32,
+    ) -> Entity {
+        let blocks = build_blocks(class.blueprint());
+        Ship::create_from(entities, lazy, blocks, pos, angle)
+    }
+
+    /// Creates a ship from its blocks, see `build_blocks()`.
+    pub fn create_from(
+        entities: &Entities,
+        lazy: &Read<LazyUpdate>,
+        blocks: Vec<([f32; 2], Block)>,
+        pos: [f32; 2],
+        angle: f32,
+    ) -> Entity {
         let (blocky, center) = Blocky::new(blocks);
         let entity = entities.create();
-        let angle: f32 = 0.0;
-        let (s, c) = angle.sin_cos();
+        let (s, c) = sin_cos(angle);
         let center = [
             center[0] * c - center[1] * s,
-            center[0] * s + center[1] * s,
+            center[0] * s + center[1] * c,
         ];
         lazy.insert(
             entity,
             Position {
-                pos: center,
+                pos: vec2_add(pos, center),
                 rot: angle,
             },
         );
@@ -148,7 +220,15 @@ impl Ship {
             },
         );
         lazy.insert(entity, Ship::new());
+        lazy.insert(entity, ShipIntegrity::new(&blocky));
         lazy.insert(entity, blocky);
+        lazy.insert(
+            entity,
+            Cargo {
+                blocks: Vec::new(),
+                ammo: STARTING_AMMO,
+            },
+        );
         #[cfg(feature = "network")]
         {
             lazy.insert(entity, net::Replicated::new());
@@ -158,10 +238,379 @@ impl Ship {
     }
 }
 
+/// Makes the blocks of a ship from a blueprint, like
+/// `ShipClass::blueprint()`, assigning weapon groups.
+pub fn build_blocks(
+    blueprint: Vec<([i32; 2], BlockInner)>,
+) -> Vec<([f32; 2], Block)> {
+    use self::BlockInner::*;
+    blueprint
+        .into_iter()
+        .map(|(p, b)| {
+            // Light guns fire with the first group, heavy guns with the
+            // second, and support weapons with the third
+            let group = match b {
+                RailGun { .. } | ChargeGun { .. } => 1,
+                EmpGun { .. }
+                | FlakCannon { .. }
+                | SalvageBeam
+                | DroneBay { .. } => 2,
+                _ => 0,
+            };
+            let mut block = Block::new(b);
+            block.group = group;
+            ([p[0] as f32, p[1] as f32], block)
+        })
+        .collect()
+}
+
+/// The hulls players can pick for their ship.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShipClass {
+    /// Well-rounded ship, with a plasma gun, a rail gun and a salvage beam.
+    Fighter,
+    /// Small and agile, but lightly armed and armored.
+    Scout,
+    /// Large and armored, to salvage blocks.
+    Freighter,
+    /// Slow, with heavy guns and a drone bay.
+    Gunship,
+}
+
+impl ShipClass {
+    /// The blocks of a new ship of this class, at integer positions.
+    pub fn blueprint(self) -> Vec<([i32; 2], BlockInner)> {
+        use self::BlockInner::*;
+        match self {
+            ShipClass::Fighter => vec![
+                ([0, 0], Cockpit),
+                ([-3, -2], Armor),
+                ([-3, -1], Thruster { angle: 0.0 }),
+                ([-3, 0], Thruster { angle: 0.0 }),
+                ([-3, 1], Thruster { angle: 0.0 }),
+                ([-3, 2], Armor),
+                (
+                    [-2, -2],
+                    Thruster {
+                        angle: 0.5 * PI,
+                    },
+                ),
+                ([-2, -1], Armor),
+                ([-2, 0], Armor),
+                ([-2, 1], Armor),
+                (
+                    [-2, 2],
+                    Thruster {
+                        angle: -0.5 * PI,
+                    },
+                ),
+                ([-1, -2], Thruster { angle: PI }),
+                ([-1, -1], FuelTank { fuel: TANK_CAPACITY }),
+                ([-1, 0], Armor),
+                ([-1, 1], FuelTank { fuel: TANK_CAPACITY }),
+                ([-1, 2], Thruster { angle: PI }),
+                ([-0, -1], Armor),
+                ([-0, 1], Armor),
+                ([1, -2], BoardingClamp),
+                ([1, -1], Armor),
+                ([1, 0], Armor),
+                ([1, 1], Armor),
+                ([1, 2], BoardingClamp),
+                (
+                    [2, -1],
+                    Thruster {
+                        angle: 0.5 * PI,
+                    },
+                ),
+                ([2, 0], SalvageBeam),
+                (
+                    [2, 1],
+                    Thruster {
+                        angle: -0.5 * PI,
+                    },
+                ),
+                (
+                    [3, -1],
+                    PlasmaGun {
+                        angle: 0.0,
+                        cooldown: -1.0,
+                        ammo: 20,
+                    },
+                ),
+                (
+                    [3, 0],
+                    RailGun {
+                        angle: 0.0,
+                        cooldown: -1.0,
+                        ammo: 4,
+                    },
+                ),
+                (
+                    [3, 1],
+                    PlasmaGun {
+                        angle: 0.0,
+                        cooldown: -1.0,
+                        ammo: 20,
+                    },
+                ),
+            ],
+            ShipClass::Scout => vec![
+                ([0, 0], Cockpit),
+                ([-2, -1], Thruster { angle: 0.5 * PI }),
+                ([-2, 1], Thruster { angle: -0.5 * PI }),
+                ([-1, -1], Thruster { angle: 0.0 }),
+                ([-1, 0], Thruster { angle: 0.0 }),
+                ([-1, 1], Thruster { angle: 0.0 }),
+                ([0, -1], Thruster { angle: PI }),
+                ([0, 1], Thruster { angle: PI }),
+                ([1, -1], Thruster { angle: 0.5 * PI }),
+                ([1, 1], Thruster { angle: -0.5 * PI }),
+                ([-2, 0], FuelTank { fuel: TANK_CAPACITY }),
+                (
+                    [1, 0],
+                    PlasmaGun {
+                        angle: 0.0,
+                        cooldown: -1.0,
+                        ammo: 20,
+                    },
+                ),
+                ([2, 0], BoardingClamp),
+            ],
+            ShipClass::Freighter => {
+                let mut blocks = vec![
+                    ([0, 0], Cockpit),
+                    ([-5, -1], Thruster { angle: 0.0 }),
+                    ([-5, 0], Thruster { angle: 0.0 }),
+                    ([-5, 1], Thruster { angle: 0.0 }),
+                    ([-4, -2], Thruster { angle: 0.5 * PI }),
+                    ([-4, 2], Thruster { angle: -0.5 * PI }),
+                    ([-1, -2], Thruster { angle: PI }),
+                    ([-1, 2], Thruster { angle: PI }),
+                    ([3, -2], Thruster { angle: 0.5 * PI }),
+                    ([3, 2], Thruster { angle: -0.5 * PI }),
+                    ([4, -1], BoardingClamp),
+                    ([4, 0], SalvageBeam),
+                    ([4, 1], BoardingClamp),
+                    (
+                        [1, -2],
+                        PlasmaGun {
+                            angle: 0.0,
+                            cooldown: -1.0,
+                            ammo: 20,
+                        },
+                    ),
+                    (
+                        [1, 2],
+                        FlakCannon {
+                            angle: 0.0,
+                            cooldown: -1.0,
+                            ammo: 8,
+                        },
+                    ),
+                ];
+                // Armored hull, with tanks at the back
+                for x in -4..4 {
+                    for y in -1..2 {
+                        if x == -3 {
+                            let tank = FuelTank {
+                                fuel: TANK_CAPACITY,
+                            };
+                            blocks.push(([x, y], tank));
+                        } else if x != 0 || y != 0 {
+                            blocks.push(([x, y], Armor));
+                        }
+                    }
+                }
+                blocks
+            }
+            ShipClass::Gunship => vec![
+                ([0, 0], Cockpit),
+                ([-3, -1], Thruster { angle: 0.0 }),
+                ([-3, 0], Thruster { angle: 0.0 }),
+                ([-3, 1], Thruster { angle: 0.0 }),
+                ([-2, -2], Thruster { angle: 0.5 * PI }),
+                ([-2, -1], Armor),
+                ([-2, 0], Armor),
+                ([-2, 1], Armor),
+                ([-2, 2], Thruster { angle: -0.5 * PI }),
+                ([-1, -2], Thruster { angle: PI }),
+                ([-1, -1], FuelTank { fuel: TANK_CAPACITY }),
+                ([-1, 0], Armor),
+                ([-1, 1], FuelTank { fuel: TANK_CAPACITY }),
+                ([-1, 2], Thruster { angle: PI }),
+                ([0, -2], Armor),
+                ([0, -1], Armor),
+                ([0, 1], Armor),
+                (
+                    [0, 2],
+                    DroneBay {
+                        drones: BAY_CAPACITY,
+                        cooldown: 0.0,
+                    },
+                ),
+                (
+                    [1, -2],
+                    EmpGun {
+                        angle: 0.0,
+                        cooldown: -1.0,
+                        ammo: 2,
+                    },
+                ),
+                ([1, -1], Armor),
+                ([1, 0], Armor),
+                ([1, 1], Armor),
+                (
+                    [1, 2],
+                    FlakCannon {
+                        angle: 0.0,
+                        cooldown: -1.0,
+                        ammo: 8,
+                    },
+                ),
+                ([2, -1], Thruster { angle: 0.5 * PI }),
+                (
+                    [2, 0],
+                    ChargeGun {
+                        angle: 0.0,
+                        cooldown: -1.0,
+                        ammo: 6,
+                        charge: 0.0,
+                    },
+                ),
+                ([2, 1], Thruster { angle: -0.5 * PI }),
+                (
+                    [3, -1],
+                    PlasmaGun {
+                        angle: 0.0,
+                        cooldown: -1.0,
+                        ammo: 20,
+                    },
+                ),
+                (
+                    [3, 0],
+                    RailGun {
+                        angle: 0.0,
+                        cooldown: -1.0,
+                        ammo: 4,
+                    },
+                ),
+                (
+                    [3, 1],
+                    PlasmaGun {
+                        angle: 0.0,
+                        cooldown: -1.0,
+                        ammo: 20,
+                    },
+                ),
+            ],
+        }
+    }
+}
+
 impl Component for Ship {
     type Storage = VecStorage<Self>;
 }
 
+/// The class of ship each player picked, available as a resource.
+///
+/// Players are identified as in `physics::pilot()`. Players who didn't pick
+/// get a `Fighter`.
+#[derive(Default)]
+pub struct PlayerClasses(pub HashMap<u64, ShipClass>);
+
+impl PlayerClasses {
+    pub fn get(&self, player: u64) -> ShipClass {
+        self.0.get(&player).cloned().unwrap_or(ShipClass::Fighter)
+    }
+}
+
+/// Summary of the state of a ship's blocks, for display.
+///
+/// This is updated by `SysShip` when the ship gets hit, and replicated, so
+/// that frontends don't have to go over all the blocks.
+#[derive(Debug, Clone)]
+pub struct ShipIntegrity {
+    /// Total health of the blocks, as a fraction of the total when the ship
+    /// was at its best.
+    pub health: f32,
+    /// Number of blocks.
+    pub blocks: u32,
+    /// Health of the cockpit, as a fraction of its maximum.
+    pub cockpit: f32,
+    /// Total maximum health of the blocks when the ship was at its best.
+    nominal_health: f32,
+}
+
+impl ShipIntegrity {
+    pub fn new(blocky: &Blocky) -> ShipIntegrity {
+        let mut integrity = ShipIntegrity {
+            health: 1.0,
+            blocks: 0,
+            cockpit: 1.0,
+            nominal_health: 0.0,
+        };
+        integrity.update(blocky);
+        integrity
+    }
+
+    /// Makes the summary of a ship that was at its best with a total
+    /// maximum health of `nominal_health`, for example when loading a game.
+    pub fn with_nominal_health(
+        blocky: &Blocky,
+        nominal_health: f32,
+    ) -> ShipIntegrity {
+        let mut integrity = ShipIntegrity::new(blocky);
+        integrity.nominal_health = nominal_health;
+        integrity.update(blocky);
+        integrity
+    }
+
+    /// Total maximum health of the blocks when the ship was at its best.
+    pub fn nominal_health(&self) -> f32 {
+        self.nominal_health
+    }
+
+    /// Recomputes the summary from the blocks.
+    pub fn update(&mut self, blocky: &Blocky) {
+        let mut health = 0.0;
+        let mut max_health = 0.0;
+        self.cockpit = 0.0;
+        for (_, block) in &blocky.blocks {
+            health += block.health.max(0.0);
+            max_health += block.inner.max_health();
+            if let BlockInner::Cockpit = block.inner {
+                self.cockpit = block.health.max(0.0)
+                    / block.inner.max_health();
+            }
+        }
+        self.nominal_health = self.nominal_health.max(max_health);
+        self.health = if self.nominal_health > 0.0 {
+            health / self.nominal_health
+        } else {
+            0.0
+        };
+        self.blocks = blocky.blocks.len() as u32;
+    }
+
+    /// Makes a summary from replicated values.
+    pub fn from_values(
+        health: f32,
+        blocks: u32,
+        cockpit: f32,
+    ) -> ShipIntegrity {
+        ShipIntegrity {
+            health,
+            blocks,
+            cockpit,
+            nominal_health: 0.0,
+        }
+    }
+}
+
+impl Component for ShipIntegrity {
+    type Storage = VecStorage<Self>;
+}
+
 /// Ship physics and keyboard control.
 ///
 /// This computes the ship's state from the keyboard if `LocalControl`, updates
@@ -172,17 +621,30 @@ impl<'a> System<'a> for SysShip {
     type SystemData = (
         Read<'a, DeltaTime>,
         ReadExpect<'a, Role>,
+        Read<'a, PhysicsConfig>,
         Read<'a, LazyUpdate>,
-        Read<'a, Input>,
+        Read<'a, Inputs>,
         Read<'a, Clock>,
+        Read<'a, MatchState>,
+        Write<'a, GameRng>,
+        Write<'a, EffectRng>,
+        Write<'a, GameEvents>,
         Entities<'a>,
         WriteStorage<'a, Position>,
         WriteStorage<'a, Velocity>,
         ReadStorage<'a, Hits>,
         WriteStorage<'a, Ship>,
+        WriteStorage<'a, ShipIntegrity>,
         WriteStorage<'a, Blocky>,
         ReadStorage<'a, Asteroid>,
+        ReadStorage<'a, MediumZone>,
+        ReadStorage<'a, Projectile>,
+        WriteStorage<'a, Cargo>,
+        ReadStorage<'a, Autopilot>,
+        ReadStorage<'a, Faction>,
+        ReadStorage<'a, InSafeZone>,
         ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
     );
 
     fn run(
@@ -190,43 +652,104 @@ impl<'a> System<'a> for SysShip {
         (
             dt,
             role,
+            config,
             lazy,
-            input,
+            inputs,
             clock,
+            match_state,
+            mut game_rng,
+            mut effect_rng,
+            mut events,
             entities,
             mut pos,
             mut vel,
             hits,
             mut ship,
+            mut integrity,
             mut blocky,
             asteroid,
+            medium,
+            projectile,
+            mut cargo,
+            autopilot,
+            faction,
+            safe,
             local,
+            remote,
         ): Self::SystemData,
     ) {
         let dt = dt.0;
-        let mut rng = rand::thread_rng();
 
         if role.authoritative() {
+            // The player credited for damage, none if done to an ally
+            let attacker_of = |source: Entity, victim: Entity| {
+                if hostile(&faction, source, victim) {
+                    pilot(source, &local, &remote)
+                } else {
+                    None
+                }
+            };
+
             // Handle collisions
             for (ent, mut pos, blk, hits) in
                 (&*entities, &mut pos, &mut blocky, &hits).join()
             {
-                let (s, c) = pos.rot.sin_cos();
+                let (s, c) = sin_cos(pos.rot);
                 let mut deleted = false;
+                let mut attacker = None;
                 for hit in &**hits {
                     match hit.effect {
-                        HitEffect::Collision(_, _) => {}
-                        HitEffect::Explosion(size) => {
+                        HitEffect::Collision(impulse, other) => {
+                            let damage = (impulse
+                                - COLLISION_DAMAGE_THRESHOLD)
+                                * COLLISION_DAMAGE;
+                            if damage <= 0.0 {
+                                continue;
+                            }
+
+                            // Hurt the blocks near the contact point
+                            let radius = COLLISION_DAMAGE_RADIUS;
+                            let mut total = 0.0;
+                            for &mut (loc, ref mut block) in &mut blk.blocks {
+                                let diff = vec2_sub(hit.rel_location, loc);
+                                let sq_dist = vec2_square_len(diff);
+                                if sq_dist <= radius * radius {
+                                    let hurt = damage
+                                        * (1.0 - sq_dist / (radius * radius));
+                                    block.health -= hurt;
+                                    total += hurt;
+                                    if block.health < 0.0 {
+                                        deleted = true;
+                                    }
+                                }
+                            }
+
+                            // Ramming counts as an attack
+                            let by = attacker_of(other, ent);
+                            if by.is_some() {
+                                attacker = by;
+                            }
+                            if total > 0.0 {
+                                events.single_write(GameEvent::Damage {
+                                    attacker: by,
+                                    victim: pilot(ent, &local, &remote),
+                                    amount: total,
+                                });
+                            }
+                        }
+                        HitEffect::Explosion(size, source) => {
                             let mut impulse = [0.0, 0.0];
                             let mut rot = 0.0;
+                            let mut damage = 0.0;
 
                             // Hurt some blocks
                             for &mut (loc, ref mut block) in &mut blk.blocks {
                                 let diff = vec2_sub(hit.rel_location, loc);
                                 let sq_dist = vec2_square_len(diff);
                                 if sq_dist <= size {
-                                    block.health -=
-                                        1.0 - sq_dist / (size * size);
+                                    let hurt = 1.0 - sq_dist / (size * size);
+                                    block.health -= hurt;
+                                    damage += hurt;
                                     if block.health < 0.0 {
                                         deleted = true;
                                     }
@@ -249,26 +772,100 @@ impl<'a> System<'a> for SysShip {
                                 vec2_scale(impulse, 1.0 / blk.mass),
                             );
                             vel.rot += rot / blk.inertia;
+
+                            // Keep track of who did it
+                            let by =
+                                source.and_then(|e| attacker_of(e, ent));
+                            if by.is_some() {
+                                attacker = by;
+                            }
+                            if damage > 0.0 {
+                                events.single_write(GameEvent::Damage {
+                                    attacker: by,
+                                    victim: pilot(ent, &local, &remote),
+                                    amount: damage,
+                                });
+                            }
+                        }
+                        HitEffect::Emp(size, duration) => {
+                            // Disable blocks in range
+                            for &mut (loc, ref mut block) in &mut blk.blocks {
+                                let diff = vec2_sub(hit.rel_location, loc);
+                                if vec2_square_len(diff) <= size * size {
+                                    block.disabled =
+                                        block.disabled.max(duration);
+                                }
+                            }
+                        }
+                        HitEffect::Pierce(amount, source) => {
+                            // Hurt the block under the hit
+                            let idx = match blk.tree.find(hit.rel_location) {
+                                Some(i) => i,
+                                None => continue,
+                            };
+                            let block = &mut blk.blocks[idx].1;
+                            block.health -= amount;
+                            if block.health < 0.0 {
+                                deleted = true;
+                            }
+
+                            // Keep track of who did it
+                            let by = attacker_of(source, ent);
+                            if by.is_some() {
+                                attacker = by;
+                            }
+                            events.single_write(GameEvent::Damage {
+                                attacker: by,
+                                victim: pilot(ent, &local, &remote),
+                                amount,
+                            });
+                        }
+                        HitEffect::Salvage => {
+                            // Remove the salvaged block
+                            for &mut (loc, ref mut block) in &mut blk.blocks {
+                                let diff = vec2_sub(hit.rel_location, loc);
+                                if vec2_square_len(diff) < 0.01 {
+                                    block.health = -1.0;
+                                    deleted = true;
+                                }
+                            }
                         }
                     }
                 }
 
                 if deleted {
                     let (dead_blocks, center, pieces) = blk.maintain();
+                    if !dead_blocks.is_empty() {
+                        events.single_write(GameEvent::BlocksDestroyed {
+                            attacker,
+                            count: dead_blocks.len() as u32,
+                        });
+                    }
+
+                    for (loc, block) in dead_blocks {
+                        let block_pos = vec2_add(
+                            pos.pos,
+                            [c * loc[0] - s * loc[1], s * loc[0] + c * loc[1]],
+                        );
+
+                        // Rock leaves ore behind
+                        if let BlockInner::Rock = block.inner {
+                            let ent_vel = vel.get(ent).unwrap().vel;
+                            economy::spawn_ore(
+                                &entities,
+                                &lazy,
+                                &mut *game_rng,
+                                block_pos,
+                                ent_vel,
+                            );
+                        }
 
-                    for (loc, blk) in dead_blocks {
                         // Spawn particle effects for dead blocks
                         let new_effect = entities.create();
                         lazy.insert(
                             new_effect,
                             Position {
-                                pos: vec2_add(
-                                    pos.pos,
-                                    [
-                                        c * loc[0] - s * loc[1],
-                                        s * loc[0] + c * loc[1],
-                                    ],
-                                ),
+                                pos: block_pos,
                                 rot: 0.0,
                             },
                         );
@@ -279,11 +876,19 @@ impl<'a> System<'a> for SysShip {
                                 lifetime: -1.0,
                             },
                         );
+                    }
 
-                        // If a cockpit died then this is no longer a ship
-                        if let BlockInner::Cockpit = blk.inner {
-                            lazy.remove::<Ship>(ent);
-                        }
+                    // If the cockpit died or broke off, this is no longer a
+                    // ship
+                    if !blk.has_cockpit() && ship.get(ent).is_some() {
+                        lazy.remove::<Ship>(ent);
+                        lazy.remove::<ShipIntegrity>(ent);
+                        lazy.remove::<Faction>(ent);
+                        lazy.remove::<Team>(ent);
+                        events.single_write(GameEvent::ShipDestroyed {
+                            player: pilot(ent, &local, &remote),
+                            killer: attacker,
+                        });
                     }
 
                     // If there is no block remaining, delete the entity
@@ -315,6 +920,11 @@ impl<'a> System<'a> for SysShip {
                                 rot: vel.rot,
                             },
                         );
+                        // A piece that got the cockpit is a derelict ship
+                        if blocky.has_cockpit() {
+                            lazy.insert(newent, Ship::new());
+                            lazy.insert(newent, ShipIntegrity::new(&blocky));
+                        }
                         lazy.insert(newent, blocky);
                         // Asteroids stay asteroids
                         if is_asteroid {
@@ -335,41 +945,100 @@ impl<'a> System<'a> for SysShip {
                     pos.pos = vec2_add(pos.pos, center);
                 }
 
+                if let Some(integrity) = integrity.get_mut(ent) {
+                    integrity.update(blk);
+                }
+
                 #[cfg(feature = "network")]
                 lazy.insert(ent, net::Dirty);
             }
-
-            // Prevent leaving the screen
-            for (ent, pos, vel, _) in
-                (&*entities, &pos, &mut vel, &ship).join()
-            {
-                if pos.pos[0] < -100.0 || pos.pos[0] > 100.0
-                    || pos.pos[1] < -100.0
-                    || pos.pos[1] > 100.0
-                {
-                    vel.vel = vec2_sub([0.0, 0.0], pos.pos);
-                    vel.vel =
-                        vec2_scale(vel.vel, 60.0 * vec2_inv_len(vel.vel));
-                    #[cfg(feature = "network")]
-                    lazy.insert(ent, net::Dirty);
-                }
-            }
         }
 
-        // Set ship controls from local input
-        for (ent, mut ship, _) in (&*entities, &mut ship, &local).join() {
-            ship.want_thrust = input.movement;
-            ship.want_thrust_rot = input.rotation;
+        // Set ship controls from local input, unless on autopilot
+        for (ent, mut ship, &LocalControl(index)) in
+            (&*entities, &mut ship, &local).join()
+        {
+            let input = match inputs.get(index) {
+                Some(input) => input,
+                None => continue,
+            };
+            if autopilot.get(ent).is_none() {
+                ship.want_thrust = input.movement;
+                ship.want_thrust_rot = input.rotation;
+            }
             ship.want_target = input.mouse;
-            match input.fire {
-                Press::UP => ship.want_fire = false,
-                Press::PRESSED => ship.want_fire = true,
-                _ => {}
+            for (want, fire) in ship.want_fire.iter_mut().zip(&input.fire) {
+                match *fire {
+                    Press::UP => *want = false,
+                    Press::PRESSED => *want = true,
+                    _ => {}
+                }
+            }
+            if !match_state.can_fire() {
+                ship.want_fire = [false; WEAPON_GROUPS];
+            }
+            if input.tractor_beam == Press::PRESSED {
+                ship.want_tractor = !ship.want_tractor;
+            }
+            if input.dampeners == Press::PRESSED {
+                ship.dampeners = !ship.dampeners;
             }
+            ship.want_boost = input.boost != Press::UP;
+            ship.want_match = input.match_velocity != Press::UP;
             #[cfg(feature = "network")]
             lazy.insert(ent, net::Dirty);
         }
 
+        let zones = (&pos, &medium)
+            .join()
+            .map(|(pos, zone)| (pos.pos, zone.clone()))
+            .collect::<Vec<_>>();
+
+        // Things guns in automatic mode can shoot at, with their owner
+        let mut targets = (&pos, &projectile)
+            .join()
+            .map(|(pos, proj)| (proj.shooter, pos.pos))
+            .collect::<Vec<_>>();
+        targets.extend(
+            (&*entities, &pos, &ship)
+                .join()
+                .filter(|&(e, _, _)| pilot(e, &local, &remote).is_some())
+                .map(|(e, pos, _)| (e, pos.pos)),
+        );
+
+        // Recover from EMP, on ships and on anything else with blocks
+        if role.authoritative() {
+            for (ent, blocky) in (&*entities, &mut blocky).join() {
+                let mut disabled = Vec::new();
+                for &mut (loc, ref mut block) in &mut blocky.blocks {
+                    if block.is_disabled() {
+                        block.disabled -= dt;
+                        if block.is_disabled() {
+                            disabled.push((loc, block.disabled));
+                        }
+                    }
+                }
+                if let Some(ship) = ship.get_mut(ent) {
+                    ship.disabled =
+                        disabled.iter().fold(0.0, |a, &(_, t)| a.max(t));
+                    // Tell clients as soon as blocks go off or come back
+                    let changed = disabled
+                        .iter()
+                        .map(|&(loc, _)| loc)
+                        .ne(ship.disabled_blocks.iter().map(|&(loc, _)| loc));
+                    ship.disabled_blocks = disabled;
+                    #[cfg(feature = "network")]
+                    {
+                        if changed {
+                            lazy.insert(ent, net::Dirty);
+                        }
+                    }
+                    #[cfg(not(feature = "network"))]
+                    let _ = changed;
+                }
+            }
+        }
+
         for (ent, pos, mut vel, mut ship, blocky) in (
             &*entities,
             &pos,
@@ -378,16 +1047,67 @@ impl<'a> System<'a> for SysShip {
             &mut blocky,
         ).join()
         {
-            let (s, c) = pos.rot.sin_cos();
+            let (s, c) = sin_cos(pos.rot);
 
-            // Action thrusters from controls
+            // Find out how much control is left
             if role.authoritative() {
-                let (thrust, rot) = compute_thrust(
-                    blocky.blocks.iter().enumerate(),
-                    |_, _| {},
-                    ship.want_thrust,
-                    ship.want_thrust_rot,
-                );
+                let capacity = thrust_capacity(blocky, config.thrust);
+                let mut changed = false;
+                for (i, &cap) in capacity.iter().enumerate() {
+                    let nominal = ship.nominal_thrust[i].max(cap);
+                    let authority = if nominal > 0.0 {
+                        cap / nominal
+                    } else {
+                        1.0
+                    };
+                    changed |= authority != ship.authority[i];
+                    ship.nominal_thrust[i] = nominal;
+                    ship.authority[i] = authority;
+                }
+                #[cfg(feature = "network")]
+                {
+                    if changed {
+                        lazy.insert(ent, net::Dirty);
+                    }
+                }
+                #[cfg(not(feature = "network"))]
+                let _ = changed;
+            }
+
+            // Boost, until the thrusters overheat
+            if role.authoritative() {
+                ship.boosting = ship.want_boost && !ship.overheated;
+                if ship.boosting {
+                    ship.heat += dt;
+                    ship.overheated = ship.heat >= BOOST_MAX_HEAT;
+                } else {
+                    ship.heat = (ship.heat - BOOST_COOLING * dt).max(0.0);
+                    ship.overheated &= ship.heat > 0.0;
+                }
+            }
+            let (force, fuel_rate) = if ship.boosting {
+                (config.thrust * BOOST_THRUST, FUEL_RATE * BOOST_FUEL)
+            } else {
+                (config.thrust, FUEL_RATE)
+            };
+
+            // Action thrusters from controls, coast without fuel
+            let (want_thrust, want_thrust_rot) = dampen(ship, pos, vel);
+            if role.authoritative() {
+                let mut burn = 0.0;
+                let (thrust, rot) = if blocky.fuel() > 0.0 {
+                    compute_thrust(
+                        blocky.blocks.iter().enumerate(),
+                        |_, activation| burn += activation,
+                        want_thrust,
+                        want_thrust_rot,
+                        force,
+                    )
+                } else {
+                    ([0.0, 0.0], 0.0)
+                };
+                blocky.burn_fuel(burn * fuel_rate * dt);
+                ship.fuel = blocky.fuel();
                 ship.thrust = thrust;
                 ship.thrust_rot = rot;
             }
@@ -398,14 +1118,40 @@ impl<'a> System<'a> for SysShip {
                 -ship.want_target[0] * s + ship.want_target[1] * c,
             ];
             for &mut (rel, ref mut block) in &mut blocky.blocks {
+                if block.is_disabled() {
+                    continue;
+                }
+                let arc = match block.inner.firing_arc() {
+                    Some(a) => a,
+                    None => continue,
+                };
+                let auto = if block.auto {
+                    auto_target(ent, pos, rel, arc, &targets)
+                } else {
+                    None
+                };
                 match &mut block.inner {
                     &mut BlockInner::PlasmaGun {
                         ref mut angle, ..
-                    } => {
+                    }
+                    | &mut BlockInner::FlakCannon {
+                        ref mut angle, ..
+                    }
+                    | &mut BlockInner::ChargeGun {
+                        ref mut angle, ..
+                    }
+                    | &mut BlockInner::BeamLaser { ref mut angle } => {
                         let target_rel = vec2_sub(target_rel, rel);
-                        let bearing = target_rel[1].atan2(target_rel[0]);
+                        let bearing = match auto {
+                            Some(b) => b,
+                            None => atan2(target_rel[1], target_rel[0]),
+                        };
                         let chg = angle_wrap(bearing - *angle);
-                        *angle += angle_wrap(chg.min(3.0 * dt).max(-3.0 * dt));
+                        *angle = clamp(
+                            *angle + clamp(chg, -3.0 * dt, 3.0 * dt),
+                            -arc,
+                            arc,
+                        );
                     }
                     _ => {}
                 }
@@ -427,7 +1173,13 @@ impl<'a> System<'a> for SysShip {
             );
 
             // Spawn Exhaust particles
-            if role.graphical() {
+            if role.graphical() && ship.fuel > 0.0 {
+                let exhaust = if ship.boosting {
+                    ParticleType::BoostExhaust
+                } else {
+                    ParticleType::Exhaust
+                };
+                let rng = &mut *effect_rng;
                 let spawn_thrust_exhaust = |idx, thrust| {
                     let &(rel, ref block): &(
                         [f32; 2],
@@ -442,7 +1194,7 @@ impl<'a> System<'a> for SysShip {
                         - ((**clock - dt) / rate) as i32;
                     for _ in 0..num {
                         let thrust_dir = {
-                            let (ts, tc) = (pos.rot + angle).sin_cos();
+                            let (ts, tc) = sin_cos(pos.rot + angle);
                             [tc, ts]
                         };
                         let thrust_pos = [
@@ -474,7 +1226,7 @@ impl<'a> System<'a> for SysShip {
                             p,
                             Particle {
                                 lifetime: 0.5,
-                                which: ParticleType::Exhaust,
+                                which: exhaust,
                             },
                         );
                     }
@@ -482,133 +1234,372 @@ impl<'a> System<'a> for SysShip {
                 compute_thrust(
                     blocky.blocks.iter().enumerate(),
                     spawn_thrust_exhaust,
-                    ship.want_thrust,
-                    ship.want_thrust_rot,
+                    want_thrust,
+                    want_thrust_rot,
+                    force,
                 );
             }
 
-            // Apply friction
+            // Apply friction, more inside a nebula
+            let friction = config.friction + drag_at(pos.pos, &zones);
             vel.vel = vec2_add(
                 vel.vel,
-                vec2_scale(vel.vel, -0.04 * dt * vec2_len(vel.vel)),
+                vec2_scale(vel.vel, -friction * dt * vec2_len(vel.vel)),
             );
-            vel.rot -= vel.rot * vel.rot.abs() * 2.0 * dt;
+            vel.rot -= vel.rot * vel.rot.abs() * config.rot_friction * dt;
 
             // Fire
             if role.authoritative() {
-                let mut fired = false;
+                let mut changed = false;
+                let mut charge: f32 = 0.0;
+                // Guns hold fire near stations
+                let holding = safe.get(ent).is_some();
                 let mass = blocky.mass;
+                let ship_vel = vel.vel;
                 for &mut (rel, ref mut block) in &mut blocky.blocks {
-                    let (angle, cooldown) = match block.inner {
+                    let magazine = block.inner.magazine();
+                    let (angle, cooldown, ammo) = match block.inner {
                         BlockInner::PlasmaGun {
                             angle,
                             ref mut cooldown,
-                        } => (angle, cooldown),
+                            ref mut ammo,
+                        } => (angle, cooldown, ammo),
                         BlockInner::RailGun {
                             angle,
                             ref mut cooldown,
-                        } => (angle, cooldown),
+                            ref mut ammo,
+                        } => (angle, cooldown, ammo),
+                        BlockInner::EmpGun {
+                            angle,
+                            ref mut cooldown,
+                            ref mut ammo,
+                        } => (angle, cooldown, ammo),
+                        BlockInner::FlakCannon {
+                            angle,
+                            ref mut cooldown,
+                            ref mut ammo,
+                        } => (angle, cooldown, ammo),
+                        BlockInner::ChargeGun {
+                            angle,
+                            ref mut cooldown,
+                            ref mut ammo,
+                            ..
+                        } => (angle, cooldown, ammo),
                         _ => continue,
                     };
                     if *cooldown > 0.0 {
                         *cooldown -= dt;
                         continue;
                     }
+                    if block.disabled > 0.0 {
+                        continue;
+                    }
+                    // Reload empty magazines from the cargo hold
+                    if *ammo == 0 {
+                        let (size, reload_time) = magazine.unwrap();
+                        let rounds = match cargo.get_mut(ent) {
+                            Some(cargo) => {
+                                let rounds = cargo.ammo.min(size);
+                                cargo.ammo -= rounds;
+                                rounds
+                            }
+                            None => 0,
+                        };
+                        if rounds > 0 {
+                            *ammo = rounds;
+                            *cooldown = reload_time;
+                        }
+                        continue;
+                    }
                     let cooldown = *cooldown;
-                    if ship.want_fire && cooldown <= 0.0 {
+                    // Guns in automatic mode fire once aimed at a target
+                    let firing = if block.auto {
+                        let arc = block.inner.firing_arc().unwrap_or(AUTO_AIM);
+                        match auto_target(ent, pos, rel, arc, &targets) {
+                            Some(b) => angle_wrap(b - angle).abs() < AUTO_AIM,
+                            None => false,
+                        }
+                    } else {
+                        ship.fires(block)
+                    };
+                    // Charge guns charge while firing, and shoot on release
+                    let firing = match block.inner {
+                        BlockInner::ChargeGun {
+                            charge: ref mut gun_charge,
+                            ..
+                        } => {
+                            if firing {
+                                *gun_charge =
+                                    (*gun_charge + dt / CHARGE_TIME).min(1.0);
+                                charge = charge.max(*gun_charge);
+                                false
+                            } else {
+                                *gun_charge > 0.0
+                            }
+                        }
+                        _ => firing,
+                    };
+                    if firing && cooldown <= 0.0 && !holding {
                         let fire_dir = {
-                            let (fs, fc) = (pos.rot + angle).sin_cos();
+                            let (fs, fc) = sin_cos(pos.rot + angle);
                             [fc, fs]
                         };
                         let fire_pos = vec2_add(
                             pos.pos,
                             [rel[0] * c - rel[1] * s, rel[0] * s + rel[1] * c],
                         );
-                        match block.inner {
+                        // Don't fire into our own blocks
+                        let fire_dir_loc = {
+                            let (ps, pc) = sin_cos(angle);
+                            [pc, ps]
+                        };
+                        let proj_loc =
+                            vec2_add(rel, vec2_scale(fire_dir_loc, 1.6));
+                        if find_collision_tree_ray(
+                            proj_loc,
+                            fire_dir_loc,
+                            &blocky.tree,
+                        ).is_some()
+                        {
+                            continue;
+                        }
+                        // Shots spread, more so from damaged guns
+                        let wear = 1.0
+                            - block.health / block.inner.max_health();
+                        let spread = block.inner.spread()
+                            * (1.0 + GUN_WEAR_SPREAD * wear.max(0.0));
+                        let rot = pos.rot + angle;
+                        let rot = if spread > 0.0 {
+                            rot + game_rng.gen_range(-spread, spread)
+                        } else {
+                            rot
+                        };
+                        let kind = match block.inner {
                             BlockInner::PlasmaGun {
                                 ref mut cooldown,
+                                ref mut ammo,
                                 ..
                             } => {
-                                let fire_dir_loc = {
-                                    let (ps, pc) = angle.sin_cos();
-                                    [pc, ps]
-                                };
-                                let proj_loc = vec2_add(
-                                    rel,
-                                    vec2_scale(fire_dir_loc, 1.6),
-                                );
-                                if find_collision_tree_ray(
-                                    proj_loc,
-                                    fire_dir_loc,
-                                    &blocky.tree,
-                                ).is_some()
-                                {
-                                    continue;
-                                }
-                                Projectile::create(
-                                    &entities,
-                                    &lazy,
-                                    vec2_add(
-                                        fire_pos,
-                                        vec2_scale(fire_dir, 1.6),
-                                    ),
-                                    pos.rot + angle,
-                                    ProjectileType::Plasma,
-                                    ent,
-                                );
-                                *cooldown = rng.gen_range(0.3, 0.4);
+                                *cooldown = game_rng.gen_range(0.3, 0.4);
+                                *ammo -= 1;
+                                ProjectileType::Plasma
                             }
                             BlockInner::RailGun {
                                 ref mut cooldown,
+                                ref mut ammo,
                                 ..
                             } => {
-                                Projectile::create(
-                                    &entities,
-                                    &lazy,
-                                    vec2_add(
-                                        fire_pos,
-                                        vec2_scale(fire_dir, 1.6),
-                                    ),
-                                    pos.rot + angle,
-                                    ProjectileType::Rail,
-                                    ent,
-                                );
-                                *cooldown = rng.gen_range(1.4, 1.6);
+                                *cooldown = game_rng.gen_range(1.4, 1.6);
+                                *ammo -= 1;
+                                ProjectileType::Rail
                             }
-                            _ => {}
+                            BlockInner::EmpGun {
+                                ref mut cooldown,
+                                ref mut ammo,
+                                ..
+                            } => {
+                                *cooldown = game_rng.gen_range(2.8, 3.2);
+                                *ammo -= 1;
+                                ProjectileType::Emp
+                            }
+                            BlockInner::FlakCannon {
+                                ref mut cooldown,
+                                ref mut ammo,
+                                ..
+                            } => {
+                                *cooldown = game_rng.gen_range(0.8, 1.0);
+                                *ammo -= 1;
+                                ProjectileType::Flak
+                            }
+                            BlockInner::ChargeGun {
+                                ref mut cooldown,
+                                ref mut ammo,
+                                ref mut charge,
+                                ..
+                            } => {
+                                let kind = ProjectileType::Charge(*charge);
+                                *charge = 0.0;
+                                *cooldown = game_rng.gen_range(0.9, 1.1);
+                                *ammo -= 1;
+                                kind
+                            }
+                            _ => continue,
+                        };
+                        let proj = Projectile::create(
+                            &entities,
+                            &lazy,
+                            vec2_add(fire_pos, vec2_scale(fire_dir, 1.6)),
+                            rot,
+                            ship_vel,
+                            kind,
+                            ent,
+                        );
+                        // Projectiles are on their shooter's side
+                        if let Some(side) = faction.get(ent) {
+                            lazy.insert(proj, *side);
+                        }
+                        if let Some(player) = pilot(ent, &local, &remote) {
+                            events.single_write(GameEvent::ShotFired {
+                                player,
+                            });
                         }
                         // Recoil
                         vel.vel = vec2_add(
                             vel.vel,
-                            vec2_scale(fire_dir, -10.0 / mass),
+                            vec2_scale(fire_dir, -config.recoil / mass),
                         );
-                        fired = true;
+                        changed = true;
                     }
                 }
+                // The charge is replicated, so clients can show it
+                if ship.charge != charge {
+                    ship.charge = charge;
+                    changed = true;
+                }
                 #[cfg(feature = "network")]
                 {
-                    if fired {
+                    if changed {
                         lazy.insert(ent, net::Dirty);
                     }
                 }
+                #[cfg(not(feature = "network"))]
+                let _ = changed;
             }
         }
     }
 }
 
+/// Picks the target of a gun in automatic mode.
+///
+/// This is the closest of `targets` within range and the firing arc, that
+/// isn't owned by the ship. Returns its bearing from the gun, in the ship's
+/// coordinate system.
+fn auto_target(
+    ent: Entity,
+    pos: &Position,
+    rel: [f32; 2],
+    arc: f32,
+    targets: &[(Entity, [f32; 2])],
+) -> Option<f32> {
+    let (s, c) = sin_cos(pos.rot);
+    let mut best: Option<(f32, f32)> = None;
+    for &(owner, target) in targets {
+        if owner == ent {
+            continue;
+        }
+        let diff = vec2_sub(target, pos.pos);
+        let diff = vec2_sub(
+            [diff[0] * c + diff[1] * s, -diff[0] * s + diff[1] * c],
+            rel,
+        );
+        let sq_dist = vec2_square_len(diff);
+        if sq_dist > AUTO_RANGE * AUTO_RANGE {
+            continue;
+        }
+        let bearing = atan2(diff[1], diff[0]);
+        if bearing.abs() > arc {
+            continue;
+        }
+        match best {
+            Some((closest, _)) if closest <= sq_dist => {}
+            _ => best = Some((sq_dist, bearing)),
+        }
+    }
+    best.map(|(_, bearing)| bearing)
+}
+
+/// Computes the thrust available on each axis.
+///
+/// This is the thrust the ship gets when trying to move forward or backward,
+/// sideways, or to rotate, taking the weakest of the two directions.
+fn thrust_capacity(blocky: &Blocky, force: f32) -> [f32; 3] {
+    let axis = |dir, rot| {
+        compute_thrust(
+            blocky.blocks.iter().enumerate(),
+            |_, _| {},
+            dir,
+            rot,
+            force,
+        )
+    };
+    let forward = axis([1.0, 0.0], 0.0).0[0];
+    let backward = -axis([-1.0, 0.0], 0.0).0[0];
+    let left = axis([0.0, 1.0], 0.0).0[1];
+    let right = -axis([0.0, -1.0], 0.0).0[1];
+    let ccw = axis([0.0, 0.0], 1.0).1;
+    let cw = -axis([0.0, 0.0], -1.0).1;
+    [
+        forward.min(backward).max(0.0),
+        left.min(right).max(0.0),
+        ccw.min(cw).max(0.0),
+    ]
+}
+
+/// The controls to give the thrusters, with the inertia dampeners.
+///
+/// If the dampeners are on, the axes the pilot isn't using are set to slow
+/// the ship down.
+fn dampen(ship: &Ship, pos: &Position, vel: &Velocity) -> ([f32; 2], f32) {
+    let mut thrust = ship.want_thrust;
+    let mut rot = ship.want_thrust_rot;
+    if !ship.dampeners {
+        return (thrust, rot);
+    }
+    if vec2_len(thrust) < 0.1 && vec2_len(vel.vel) > DAMPENER_SPEED {
+        let (s, c) = sin_cos(pos.rot);
+        thrust = [
+            -(vel.vel[0] * c + vel.vel[1] * s),
+            -(-vel.vel[0] * s + vel.vel[1] * c),
+        ];
+    }
+    if rot == 0.0 && vel.rot.abs() > DAMPENER_ROT_SPEED {
+        rot = -vel.rot.signum();
+    }
+    (thrust, rot)
+}
+
+/// Estimates the thrust of a ship from its controls, without its blocks.
+///
+/// This is used by clients to predict the movement of their ship (see
+/// `net/predict.rs`). It assumes the thrusters push as hard in every
+/// direction as on the weakest side of each axis, and ignores boost.
+pub fn predict_thrust(
+    ship: &Ship,
+    pos: &Position,
+    vel: &Velocity,
+) -> ([f32; 2], f32) {
+    if ship.fuel <= 0.0 {
+        return ([0.0, 0.0], 0.0);
+    }
+    let (dir, rot) = dampen(ship, pos, vel);
+    let dir = if vec2_len(dir) > 1.0 {
+        vec2_normalized(dir)
+    } else {
+        dir
+    };
+    let capacity = |i: usize| ship.nominal_thrust[i] * ship.authority[i];
+    (
+        [dir[0] * capacity(0), dir[1] * capacity(1)],
+        clamp(rot, -1.0, 1.0) * capacity(2),
+    )
+}
+
 /// Computes the thrust generated by thrusters.
 ///
-/// Goes over the iterator of blocks, computing the maximu thrust that can be
-/// generated in a specific direction. The callback function gets called with
-/// thrust generated by each individual thruster.
+/// This is a small allocation solver: it picks how much to fire each
+/// thruster, from 0 to 1, to get as close as possible to the most thrust the
+/// ship can generate in direction `dir` and rotation `rot`, while avoiding
+/// unwanted translation or torque. The callback function gets called with
+/// the activation of each thruster that fires, each of which generates up to
+/// `force`.
 fn compute_thrust<'a, T, B, F>(
     blocks: B,
     mut cb: F,
     dir: [f32; 2],
     rot: f32,
+    force: f32,
 ) -> ([f32; 2], f32)
 where
-    T: Clone,
     B: Iterator<Item = (T, &'a ([f32; 2], Block))>,
     F: FnMut(T, f32),
 {
@@ -620,26 +1611,69 @@ where
             [0.0, 0.0]
         }
     };
+    let rot = clamp(rot, -1.0, 1.0);
+    let rot_sign = if rot > 0.0 {
+        1.0
+    } else if rot < 0.0 {
+        -1.0
+    } else {
+        0.0
+    };
 
+    // Force and torque of each working thruster
+    let mut thrusters = Vec::new();
+    let mut sq_dist = 0.0;
+    for (udata, &(loc, ref block)) in blocks {
+        if block.is_disabled() {
+            continue;
+        }
+        if let BlockInner::Thruster { angle } = block.inner {
+            let (s, c) = sin_cos(angle);
+            let torque = (loc[0] * s - loc[1] * c) * force;
+            thrusters.push((udata, [c * force, s * force], torque));
+            sq_dist += vec2_square_len(loc);
+        }
+    }
+    if thrusters.is_empty() {
+        return ([0.0, 0.0], 0.0);
+    }
+    // Weight of torque errors, relative to force errors
+    let rot_weight = thrusters.len() as f32 / sq_dist.max(1.0);
+
+    // Aim for everything the thrusters can give in the wanted direction
+    let mut target = [0.0, 0.0];
+    let mut target_rot = 0.0;
+    for &(_, f, torque) in &thrusters {
+        target = vec2_add(target, vec2_scale(dir, vec2_dot(f, dir).max(0.0)));
+        target_rot += rot * (torque * rot_sign).max(0.0) * rot_sign;
+    }
+
+    // Least-squares with activations in [0, 1], by coordinate descent
+    let mut activations = vec![0.0; thrusters.len()];
     let mut thrust = [0.0, 0.0];
     let mut thrust_rot = 0.0;
-
-    for (ref udata, &(loc, ref block)) in blocks {
-        match block.inner {
-            BlockInner::Thruster { angle } => {
-                let (s, c) = angle.sin_cos();
-                let torque = loc[0] * s - loc[1] * c;
-                // If this takes us forward, or rotating the right way
-                if vec2_dot([c, s], dir) >= 0.5 || (torque > 1.0 && rot > 0.1)
-                    || (torque < -1.0 && rot < -0.1)
-                {
-                    // Fire thruster
-                    thrust = vec2_add(thrust, vec2_scale([c, s], 60.0));
-                    thrust_rot += torque * 60.0;
-                    cb(udata.clone(), 1.0);
-                }
+    for _ in 0..THRUST_SOLVER_ITERATIONS {
+        for (&(_, f, torque), act) in
+            thrusters.iter().zip(activations.iter_mut())
+        {
+            let error = vec2_sub(thrust, target);
+            let error_rot = thrust_rot - target_rot;
+            let gradient =
+                vec2_dot(error, f) + rot_weight * error_rot * torque;
+            let curvature = vec2_dot(f, f) + rot_weight * torque * torque;
+            if curvature <= 0.0 {
+                continue;
             }
-            _ => {}
+            let new = clamp(*act - gradient / curvature, 0.0, 1.0);
+            thrust = vec2_add(thrust, vec2_scale(f, new - *act));
+            thrust_rot += torque * (new - *act);
+            *act = new;
+        }
+    }
+
+    for ((udata, _, _), act) in thrusters.into_iter().zip(activations) {
+        if act > 0.01 {
+            cb(udata, act);
         }
     }
     (thrust, thrust_rot)
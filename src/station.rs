@@ -0,0 +1,286 @@
+//! Space stations, where ships dock to get repaired and refueled.
+//!
+//! A station is a large `Blocky` entity that doesn't move, with a `Station`
+//! component. Ships that come to rest next to one of its `DockingPort` blocks
+//! are docked: their fuel tanks get filled, and their damaged blocks get
+//! repaired one at a time, paid for with their pilot's ore (see
+//! `economy.rs`). No one can fire near a station; `SysStation` marks the ships
+//! in range with `InSafeZone`, and the guns hold fire.
+//!
+//! Stations take damage like any other blocks. One that lost all its docking
+//! ports no longer offers any service, though it remains a safe zone.
+
+use specs::{Component, Entities, Entity, Join, LazyUpdate, NullStorage,
+            Read, ReadExpect, ReadStorage, System, VecStorage, Write,
+            WriteStorage};
+use std::collections::HashMap;
+use vecmath::*;
+
+use crate::blocks::{Block, BlockInner, Blocky, TANK_CAPACITY};
+use crate::economy::Wallet;
+use crate::math::sin_cos;
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{pilot, DeltaTime, LocalControl, Position,
+                     RemoteControl, Velocity};
+use crate::ship::{Ship, ShipIntegrity};
+use crate::Role;
+
+/// Radius of the zone around a station where guns can't fire.
+const SAFE_RADIUS: f32 = 20.0;
+
+/// Distance from a docking port under which a ship can dock, counted from
+/// the edge of the ship.
+const DOCK_RANGE: f32 = 2.0;
+
+/// Speed under which a ship near a docking port is docked.
+const DOCK_SPEED: f32 = 1.0;
+
+/// Fuel put into a docked ship's tanks each second.
+const REFUEL_RATE: f32 = 40.0;
+
+/// Time it takes to repair a block of a docked ship.
+const REPAIR_TIME: f32 = 0.5;
+
+/// A space station.
+#[derive(Debug, Clone)]
+pub struct Station {
+    /// Radius of the zone around the station where guns can't fire.
+    pub safe_radius: f32,
+    /// Number of docking ports still working, kept up to date by
+    /// `SysStation`.
+    pub ports: u32,
+}
+
+impl Component for Station {
+    type Storage = VecStorage<Self>;
+}
+
+impl Station {
+    /// Creates a station, centered on `pos`.
+    pub fn create(
+        entities: &Entities,
+        lazy: &Read<LazyUpdate>,
+        pos: [f32; 2],
+    ) -> Entity {
+        // A square hull, with an arm on each side ending in a docking port
+        let mut blocks = Vec::new();
+        for y in -2..=2 {
+            for x in -2..=2 {
+                let loc = [x as f32, y as f32];
+                blocks.push((loc, Block::new(BlockInner::Armor)));
+            }
+        }
+        for &(dx, dy) in &[(1.0, 0.0), (0.0, 1.0), (-1.0, 0.0), (0.0, -1.0)]
+        {
+            let arm = [3.0 * dx, 3.0 * dy];
+            blocks.push((arm, Block::new(BlockInner::Armor)));
+            let port = [4.0 * dx, 4.0 * dy];
+            blocks.push((port, Block::new(BlockInner::DockingPort)));
+        }
+        let (blocky, _) = Blocky::new(blocks);
+
+        let entity = entities.create();
+        lazy.insert(entity, Position { pos, rot: 0.0 });
+        lazy.insert(
+            entity,
+            Velocity {
+                vel: [0.0, 0.0],
+                rot: 0.0,
+            },
+        );
+        lazy.insert(entity, blocky);
+        lazy.insert(
+            entity,
+            Station {
+                safe_radius: SAFE_RADIUS,
+                ports: 4,
+            },
+        );
+        #[cfg(feature = "network")]
+        {
+            lazy.insert(entity, net::Replicated::new());
+            lazy.insert(entity, net::Dirty);
+        }
+        entity
+    }
+}
+
+/// Marks ships in the safe zone of a station, whose guns can't fire.
+#[derive(Default)]
+pub struct InSafeZone;
+
+impl Component for InSafeZone {
+    type Storage = NullStorage<Self>;
+}
+
+/// Station system, keeps stations in place and services docked ships.
+#[derive(Default)]
+pub struct SysStation {
+    /// Time spent repairing the current block, for each docked ship.
+    repairing: HashMap<Entity, f32>,
+}
+
+impl<'a> System<'a> for SysStation {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        ReadExpect<'a, Role>,
+        Read<'a, LazyUpdate>,
+        Write<'a, Wallet>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        WriteStorage<'a, Velocity>,
+        WriteStorage<'a, Blocky>,
+        WriteStorage<'a, Station>,
+        ReadStorage<'a, Ship>,
+        WriteStorage<'a, ShipIntegrity>,
+        WriteStorage<'a, InSafeZone>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            role,
+            lazy,
+            mut wallet,
+            entities,
+            position,
+            mut velocity,
+            mut blocky,
+            mut station,
+            ship,
+            mut integrity,
+            mut safe,
+            local,
+            remote,
+        ): Self::SystemData,
+    ) {
+        assert!(role.authoritative());
+
+        // Keep stations from drifting after collisions, and find where
+        // their docking ports are
+        let mut zones = Vec::new();
+        let mut ports = Vec::new();
+        for (ent, pos, vel, blk, station) in (
+            &*entities,
+            &position,
+            &mut velocity,
+            &blocky,
+            &mut station,
+        ).join()
+        {
+            let mut changed = false;
+            if vel.vel != [0.0, 0.0] || vel.rot != 0.0 {
+                vel.vel = [0.0, 0.0];
+                vel.rot = 0.0;
+                changed = true;
+            }
+            zones.push((pos.pos, station.safe_radius));
+            let (s, c) = sin_cos(pos.rot);
+            let before = ports.len();
+            for &(loc, ref block) in &blk.blocks {
+                if let BlockInner::DockingPort = block.inner {
+                    if !block.is_disabled() {
+                        ports.push(vec2_add(
+                            pos.pos,
+                            [c * loc[0] - s * loc[1], s * loc[0] + c * loc[1]],
+                        ));
+                    }
+                }
+            }
+            let working = (ports.len() - before) as u32;
+            if station.ports != working {
+                station.ports = working;
+                changed = true;
+            }
+            #[cfg(feature = "network")]
+            {
+                if changed {
+                    lazy.insert(ent, net::Dirty);
+                }
+            }
+            #[cfg(not(feature = "network"))]
+            let _ = (ent, changed);
+        }
+
+        // Mark the ships in safe zones
+        for (ent, pos, _) in (&*entities, &position, &ship).join() {
+            let inside = zones.iter().any(|&(center, radius)| {
+                vec2_square_len(vec2_sub(pos.pos, center)) < radius * radius
+            });
+            if inside {
+                if safe.get(ent).is_none() {
+                    safe.insert(ent, InSafeZone).unwrap();
+                }
+            } else {
+                safe.remove(ent);
+            }
+        }
+
+        // Service the docked ships
+        self.repairing.retain(|&e, _| entities.is_alive(e));
+        for (ent, pos, vel, blk, _) in
+            (&*entities, &position, &velocity, &mut blocky, &ship).join()
+        {
+            let range = blk.radius + DOCK_RANGE;
+            let docked = vec2_square_len(vel.vel) < DOCK_SPEED * DOCK_SPEED
+                && ports.iter().any(|&port| {
+                    vec2_square_len(vec2_sub(pos.pos, port)) < range * range
+                });
+            if !docked {
+                self.repairing.remove(&ent);
+                continue;
+            }
+
+            // Refuel
+            let mut changed = false;
+            let mut fuel = REFUEL_RATE * dt.0;
+            for (_, block) in &mut blk.blocks {
+                if let BlockInner::FuelTank { fuel: ref mut tank } =
+                    block.inner
+                {
+                    let added = fuel.min(TANK_CAPACITY - *tank);
+                    if added > 0.0 {
+                        *tank += added;
+                        fuel -= added;
+                        changed = true;
+                    }
+                }
+            }
+
+            // Repair the most damaged block, if the pilot can pay for it
+            let progress = self.repairing.entry(ent).or_insert(0.0);
+            *progress += dt.0;
+            if *progress >= REPAIR_TIME {
+                *progress = 0.0;
+                let damaged = blk
+                    .blocks
+                    .iter_mut()
+                    .map(|(_, block)| block)
+                    .filter(|block| block.repair_cost() > 0)
+                    .max_by_key(|block| block.repair_cost());
+                if let (Some(block), Some(player)) =
+                    (damaged, pilot(ent, &local, &remote))
+                {
+                    if wallet.spend(player, block.repair_cost()) {
+                        block.health = block.inner.max_health();
+                        changed = true;
+                    }
+                }
+            }
+
+            if changed {
+                if let Some(integrity) = integrity.get_mut(ent) {
+                    integrity.update(blk);
+                }
+                #[cfg(feature = "network")]
+                lazy.insert(ent, net::Dirty);
+            }
+        }
+        #[cfg(not(feature = "network"))]
+        let _ = lazy;
+    }
+}
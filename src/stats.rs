@@ -0,0 +1,158 @@
+//! Player statistics.
+//!
+//! `SysStats` follows the game events to fill the `Scoreboard` resource:
+//! shots, damage, kills, and the size of the ships players control. At the
+//! end of a match, the rules turn it into a `MatchSummary`.
+
+use specs::shrev::ReaderId;
+use specs::{Entities, Join, Read, ReadStorage, System, World, Write};
+use std::collections::HashMap;
+
+use crate::blocks::Blocky;
+use crate::events::{GameEvent, GameEvents};
+use crate::physics::{pilot, LocalControl, RemoteControl};
+use crate::ship::Ship;
+
+/// Statistics for one player.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "webhook", derive(serde::Serialize))]
+pub struct PlayerStats {
+    pub shots_fired: u32,
+    pub shots_hit: u32,
+    pub damage_dealt: f32,
+    pub damage_taken: f32,
+    pub kills: u32,
+    pub deaths: u32,
+    /// Number of blocks of the largest ship this player controlled.
+    pub largest_ship: u32,
+}
+
+impl PlayerStats {
+    /// Fraction of the shots that hit something.
+    pub fn accuracy(&self) -> f32 {
+        if self.shots_fired == 0 {
+            0.0
+        } else {
+            self.shots_hit as f32 / self.shots_fired as f32
+        }
+    }
+}
+
+/// Statistics for the current match, available as a resource.
+#[derive(Default)]
+pub struct Scoreboard {
+    pub players: HashMap<u64, PlayerStats>,
+}
+
+impl Scoreboard {
+    fn player(&mut self, player: u64) -> &mut PlayerStats {
+        self.players.entry(player).or_default()
+    }
+
+    /// Makes a summary, with players ranked by kills then damage dealt.
+    pub fn summary(&self) -> MatchSummary {
+        let mut players = self
+            .players
+            .iter()
+            .map(|(&p, s)| (p, s.clone()))
+            .collect::<Vec<_>>();
+        players.sort_by(|a, b| {
+            b.1.kills.cmp(&a.1.kills).then(
+                b.1.damage_dealt
+                    .partial_cmp(&a.1.damage_dealt)
+                    .unwrap_or(::std::cmp::Ordering::Equal),
+            )
+        });
+        MatchSummary { players }
+    }
+}
+
+/// Results of a match, best player first.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "webhook", derive(serde::Serialize))]
+pub struct MatchSummary {
+    pub players: Vec<(u64, PlayerStats)>,
+}
+
+/// Statistics system, updates the `Scoreboard` from game events.
+pub struct SysStats {
+    reader: ReaderId<GameEvent>,
+}
+
+impl SysStats {
+    pub fn new(world: &World) -> SysStats {
+        SysStats {
+            reader: world.fetch_mut::<GameEvents>().register_reader(),
+        }
+    }
+}
+
+impl<'a> System<'a> for SysStats {
+    type SystemData = (
+        Read<'a, GameEvents>,
+        Write<'a, Scoreboard>,
+        Entities<'a>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, Blocky>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            events,
+            mut scoreboard,
+            entities,
+            ship,
+            blocky,
+            local,
+            remote,
+        ): Self::SystemData,
+    ) {
+        for event in events.read(&mut self.reader) {
+            match *event {
+                GameEvent::ShotFired { player } => {
+                    scoreboard.player(player).shots_fired += 1;
+                }
+                GameEvent::ShotHit { player } => {
+                    scoreboard.player(player).shots_hit += 1;
+                }
+                GameEvent::Damage {
+                    attacker,
+                    victim,
+                    amount,
+                } => {
+                    if let Some(attacker) = attacker {
+                        if victim != Some(attacker) {
+                            scoreboard.player(attacker).damage_dealt += amount;
+                        }
+                    }
+                    if let Some(victim) = victim {
+                        scoreboard.player(victim).damage_taken += amount;
+                    }
+                }
+                GameEvent::ShipDestroyed { player, killer } => {
+                    if let Some(player) = player {
+                        scoreboard.player(player).deaths += 1;
+                    }
+                    if let Some(killer) = killer {
+                        if Some(killer) != player {
+                            scoreboard.player(killer).kills += 1;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Record ship sizes
+        for (ent, _, blk) in (&*entities, &ship, &blocky).join() {
+            if let Some(player) = pilot(ent, &local, &remote) {
+                let stats = scoreboard.player(player);
+                stats.largest_ship =
+                    stats.largest_ship.max(blk.blocks.len() as u32);
+            }
+        }
+    }
+}
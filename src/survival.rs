@@ -0,0 +1,215 @@
+//! Survival game mode, holding out against waves of enemies.
+//!
+//! With `modes::Survival`, `SysSurvival` sends waves of pirate ships and
+//! asteroid showers at the players instead of the director's raids. Each wave
+//! is bigger than the last; once all its ships are destroyed, the players get
+//! `BUILD_TIME` seconds to salvage the wrecks and restock before the next one.
+//! When every player has lost their ship at once, the game is over: the match
+//! ends, the number of waves cleared is announced with
+//! `GameEvent::SurvivalOver`, and a new run starts once the players respawn.
+
+use rand::prelude::*;
+use specs::shrev::ReaderId;
+use specs::{Entities, Entity, Join, LazyUpdate, Read, ReadExpect,
+            ReadStorage, System, World, Write, WriteStorage};
+use std::f32::consts::PI;
+use vecmath::*;
+
+use crate::{asteroid, GameRng, Role};
+use crate::ai::{AiPilot, Personality};
+use crate::director::PIRATES;
+use crate::events::{GameEvent, GameEvents};
+use crate::math::sin_cos;
+use crate::physics::{delete_entity, pilot, DeltaTime, LocalControl,
+                     RemoteControl};
+use crate::rules::MatchState;
+use crate::salvage::Cargo;
+use crate::ship::{Ship, ShipClass, STARTING_AMMO};
+
+/// Time between two waves, to salvage and restock.
+const BUILD_TIME: f32 = 20.0;
+
+const MAX_WAVE_SHIPS: usize = 12;
+
+const MAX_WAVE_ASTEROIDS: usize = 20;
+
+/// Distance from the center of the map where enemy ships appear.
+const WAVE_EDGE: f32 = 95.0;
+
+/// Distance from the center of the map where asteroid showers start.
+const SHOWER_EDGE: f32 = 140.0;
+
+/// Wave from which gunships join the waves.
+const GUNSHIP_WAVE: u32 = 4;
+
+/// State of the survival game, available as a resource.
+#[derive(Debug, Clone, Default)]
+pub struct WaveState {
+    /// The current wave, 0 before the first one.
+    pub wave: u32,
+    /// Number of waves the players got through.
+    pub cleared: u32,
+    /// Time until the next wave, `None` while fighting one.
+    pub next_wave: Option<f32>,
+    /// Ships of the current wave still flying.
+    pub enemies: usize,
+}
+
+/// Survival system, sends the waves and keeps the score.
+pub struct SysSurvival {
+    /// The ships of the current wave.
+    ships: Vec<Entity>,
+    reader: ReaderId<GameEvent>,
+}
+
+impl SysSurvival {
+    pub fn new(world: &World) -> SysSurvival {
+        SysSurvival {
+            ships: Vec::new(),
+            reader: world.fetch_mut::<GameEvents>().register_reader(),
+        }
+    }
+}
+
+impl<'a> System<'a> for SysSurvival {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        ReadExpect<'a, Role>,
+        Read<'a, LazyUpdate>,
+        Write<'a, GameRng>,
+        Write<'a, GameEvents>,
+        Read<'a, MatchState>,
+        Write<'a, WaveState>,
+        Entities<'a>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
+        WriteStorage<'a, Cargo>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            role,
+            lazy,
+            mut rng,
+            mut events,
+            match_state,
+            mut survival,
+            entities,
+            ship,
+            local,
+            remote,
+            mut cargo,
+        ): Self::SystemData,
+    ) {
+        let rng = &mut *rng;
+        let match_over = events
+            .read(&mut self.reader)
+            .any(|e| matches!(*e, GameEvent::MatchEnd { .. }));
+        if match_over {
+            // Everyone went down, or the time is up: start a new run
+            if survival.wave > 0 {
+                events.single_write(GameEvent::SurvivalOver {
+                    waves: survival.cleared,
+                });
+            }
+            for &ent in &self.ships {
+                if entities.is_alive(ent) {
+                    delete_entity(*role, &entities, &lazy, ent);
+                }
+            }
+            self.ships.clear();
+            *survival = Default::default();
+        }
+
+        let players = (&*entities, &ship)
+            .join()
+            .filter(|&(e, _)| pilot(e, &local, &remote).is_some())
+            .map(|(e, _)| e)
+            .collect::<Vec<_>>();
+
+        if players.is_empty() || !match_state.playing() {
+            // Wait for players and for the warmup to be over;
+            // `modes::Survival` ends the match if the players are all gone
+            return;
+        }
+
+        let next_wave = match survival.next_wave {
+            Some(t) => t - dt.0,
+            None => {
+                self.ships.retain(|&e| {
+                    entities.is_alive(e) && ship.get(e).is_some()
+                });
+                survival.enemies = self.ships.len();
+                if !self.ships.is_empty() {
+                    return;
+                }
+
+                // Wave cleared, restock the players for the next one
+                if survival.wave > 0 {
+                    survival.cleared = survival.wave;
+                }
+                for &ent in &players {
+                    if let Some(cargo) = cargo.get_mut(ent) {
+                        cargo.ammo = cargo.ammo.max(STARTING_AMMO);
+                    }
+                }
+                BUILD_TIME
+            }
+        };
+        if next_wave > 0.0 {
+            survival.next_wave = Some(next_wave);
+            return;
+        }
+
+        survival.wave += 1;
+        survival.next_wave = None;
+        let wave = survival.wave;
+        events.single_write(GameEvent::WaveStart { wave });
+
+        // Enemy ships, coming in from one side
+        let count = 1 + wave as usize + players.len() / 2;
+        let count = count.min(MAX_WAVE_SHIPS);
+        let dir = rng.gen_range(0.0, 2.0 * PI);
+        for i in 0..count {
+            let angle = dir + (i as f32 - (count - 1) as f32 * 0.5) * 0.15;
+            let (s, c) = sin_cos(angle);
+            let pos = [WAVE_EDGE * c, WAVE_EDGE * s];
+            let class = if wave >= GUNSHIP_WAVE && i % 4 == 3 {
+                ShipClass::Gunship
+            } else {
+                ShipClass::Scout
+            };
+            let personality = if i % 3 == 2 {
+                Personality::Sniper
+            } else {
+                Personality::Interceptor
+            };
+            let ent = AiPilot::spawn(
+                &entities,
+                &lazy,
+                class,
+                pos,
+                angle + PI,
+                personality,
+            );
+            lazy.insert(ent, PIRATES);
+            self.ships.push(ent);
+        }
+        survival.enemies = count;
+
+        // Asteroid shower, aimed at the middle of the map
+        let count = (2 * wave as usize).min(MAX_WAVE_ASTEROIDS);
+        for _ in 0..count {
+            let (s, c) = sin_cos(rng.gen_range(0.0, 2.0 * PI));
+            let pos = [SHOWER_EDGE * c, SHOWER_EDGE * s];
+            let aim = [rng.gen_range(-30.0, 30.0), rng.gen_range(-30.0, 30.0)];
+            let speed = rng.gen_range(8.0, 14.0);
+            let vel =
+                vec2_scale(vec2_normalized(vec2_sub(aim, pos)), speed);
+            asteroid::create(&entities, &lazy, rng, pos, vel);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! Teams, for team deathmatch.
+//!
+//! With `modes::TeamDeathmatch`, each player is put in one of `TEAMS` teams
+//! when joining, keeping the teams balanced. A player's ships carry their
+//! `Team`, along with the matching `Faction`: teammates are allies, so their
+//! damage doesn't score and, with `Rules::no_friendly_fire`, their projectiles
+//! go through each other. The team is replicated to clients, which can color
+//! ships with it. In team deathmatch, kills of players from the other team
+//! count towards the `Teams` scores, and the first team to the mode's
+//! `score_limit` wins the match.
+
+use specs::shrev::ReaderId;
+use specs::{Component, Entities, Entity, Join, LazyUpdate, Read,
+            ReadStorage, System, VecStorage, World, Write};
+use std::collections::HashMap;
+
+use crate::events::{GameEvent, GameEvents};
+use crate::faction::Faction;
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::{pilot, LocalControl, RemoteControl};
+use crate::ship::Ship;
+
+/// Number of teams.
+pub const TEAMS: usize = 2;
+
+/// First faction used for teams, after the AI ones.
+const TEAM_FACTIONS: u32 = 16;
+
+/// The team an entity plays for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Team(pub u8);
+
+impl Component for Team {
+    type Storage = VecStorage<Self>;
+}
+
+impl Team {
+    /// The faction of the team's ships.
+    pub fn faction(self) -> Faction {
+        Faction(TEAM_FACTIONS + self.0 as u32)
+    }
+}
+
+/// Puts a ship in a team, which also makes it part of the team's faction.
+pub fn join(lazy: &LazyUpdate, ent: Entity, team: Team) {
+    lazy.insert(ent, team);
+    lazy.insert(ent, team.faction());
+    #[cfg(feature = "network")]
+    lazy.insert(ent, net::Dirty);
+}
+
+/// Team of each player and team scores, available as a resource.
+#[derive(Debug, Default)]
+pub struct Teams {
+    /// Team of each player, identified as in `physics::pilot()`.
+    pub players: HashMap<u64, Team>,
+    /// Score of each team in the current match: kills in team deathmatch,
+    /// or points from control points, see `capture.rs`.
+    pub scores: [u32; TEAMS],
+}
+
+impl Teams {
+    /// Gets the team of a player, picking one if they don't have one yet.
+    ///
+    /// New players go to the team with the fewest players, or on a tie, the
+    /// one with the lowest score.
+    pub fn assign(&mut self, player: u64) -> Team {
+        if let Some(&team) = self.players.get(&player) {
+            return team;
+        }
+        let mut sizes = [0; TEAMS];
+        for team in self.players.values() {
+            sizes[team.0 as usize] += 1;
+        }
+        let team = (0..TEAMS)
+            .min_by_key(|&t| (sizes[t], self.scores[t]))
+            .unwrap();
+        let team = Team(team as u8);
+        self.players.insert(player, team);
+        team
+    }
+
+    /// Whether a team got to the score limit, if there is one.
+    pub fn reached(&self, limit: Option<u32>) -> bool {
+        match limit {
+            Some(limit) => self.scores.iter().any(|&s| s >= limit),
+            None => false,
+        }
+    }
+}
+
+/// Teams system, puts players' ships in their team and keeps the score.
+pub struct SysTeams {
+    reader: ReaderId<GameEvent>,
+    /// Whether kills score, as opposed to control points.
+    kills_score: bool,
+}
+
+impl SysTeams {
+    pub fn new(world: &World, kills_score: bool) -> SysTeams {
+        SysTeams {
+            reader: world.fetch_mut::<GameEvents>().register_reader(),
+            kills_score,
+        }
+    }
+}
+
+impl<'a> System<'a> for SysTeams {
+    type SystemData = (
+        Read<'a, LazyUpdate>,
+        Read<'a, GameEvents>,
+        Write<'a, Teams>,
+        Entities<'a>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, Team>,
+        ReadStorage<'a, LocalControl>,
+        RemoteControl<'a>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            lazy,
+            events,
+            mut teams,
+            entities,
+            ship,
+            team,
+            local,
+            remote,
+        ): Self::SystemData,
+    ) {
+        for event in events.read(&mut self.reader) {
+            match *event {
+                GameEvent::ShipDestroyed {
+                    player: Some(player),
+                    killer: Some(killer),
+                } if self.kills_score => {
+                    let victim = teams.players.get(&player).cloned();
+                    let killer = teams.players.get(&killer).cloned();
+                    if let (Some(victim), Some(killer)) = (victim, killer) {
+                        if victim != killer {
+                            teams.scores[killer.0 as usize] += 1;
+                        }
+                    }
+                }
+                GameEvent::MatchEnd { .. } => {
+                    teams.scores = [0; TEAMS];
+                }
+                _ => {}
+            }
+        }
+
+        // New ships, respawned or captured, join their pilot's team
+        for (ent, _) in (&*entities, &ship).join() {
+            if let Some(player) = pilot(ent, &local, &remote) {
+                let player_team = teams.assign(player);
+                if team.get(ent) != Some(&player_team) {
+                    join(&lazy, ent, player_team);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Tractor beam.
+//!
+//! A ship whose pilot turns its tractor beam on grabs the nearest loose piece
+//! (a `Blocky` object that is neither a ship nor an asteroid) and holds it in
+//! front of it with a spring `Joint`. Turning it off releases the piece.
+
+use specs::{Component, Entities, Entity, Read, Join, HashMapStorage,
+            LazyUpdate, ReadStorage, System, WriteStorage};
+use vecmath::*;
+
+use crate::asteroid::Asteroid;
+use crate::blocks::Blocky;
+use crate::joints::{Joint, JointKind};
+#[cfg(feature = "network")]
+use crate::net;
+use crate::physics::Position;
+use crate::ship::Ship;
+
+/// Maximum distance between the hull and a piece it can grab.
+const TRACTOR_RANGE: f32 = 15.0;
+
+/// Space left between the hull and the piece it holds.
+const TRACTOR_GAP: f32 = 1.0;
+
+const TRACTOR_STIFFNESS: f32 = 20.0;
+
+const TRACTOR_DAMPING: f32 = 10.0;
+
+/// A piece held by a ship's tractor beam, attached to the ship.
+///
+/// The piece holds the `Joint` to the ship.
+pub struct Tractor {
+    pub target: Entity,
+}
+
+impl Component for Tractor {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Tractor beam system, grabs and releases pieces as pilots ask.
+pub struct SysTractor;
+
+impl<'a> System<'a> for SysTractor {
+    type SystemData = (
+        Read<'a, LazyUpdate>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Blocky>,
+        ReadStorage<'a, Asteroid>,
+        WriteStorage<'a, Ship>,
+        WriteStorage<'a, Tractor>,
+        WriteStorage<'a, Joint>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            lazy,
+            entities,
+            pos,
+            blocky,
+            asteroid,
+            mut ship,
+            mut tractor,
+            mut joints,
+        ): Self::SystemData,
+    ) {
+        let ships = (&*entities, &ship, &pos, &blocky)
+            .join()
+            .map(|(ent, s, p, blk)| (ent, s.want_tractor, p.pos, blk.radius))
+            .collect::<Vec<_>>();
+
+        for (ent, want, center, radius) in ships {
+            let holding = if let Some(&Tractor { target }) = tractor.get(ent)
+            {
+                // Release the piece, or notice that it's gone
+                let held = match joints.get(target) {
+                    Some(joint) => joint.other == ent,
+                    None => false,
+                };
+                if want && held {
+                    continue;
+                }
+                if held {
+                    joints.remove(target);
+                }
+                tractor.remove(ent);
+                false
+            } else if want {
+                // Grab the nearest piece in range
+                let piece = (&*entities, &pos, &blocky, !&ship, !&asteroid)
+                    .join()
+                    .filter(|&(e, _, _, _, _)| joints.get(e).is_none())
+                    .map(|(e, p, blk, _, _)| {
+                        (e, vec2_len(vec2_sub(p.pos, center)), blk.radius)
+                    })
+                    .filter(|&(_, dist, r)| {
+                        dist - r - radius <= TRACTOR_RANGE
+                    })
+                    .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+                let (target, piece_radius) = match piece {
+                    Some((e, _, r)) => (e, r),
+                    None => continue,
+                };
+                joints
+                    .insert(
+                        target,
+                        Joint {
+                            other: ent,
+                            anchor: [0.0, 0.0],
+                            other_anchor: [
+                                radius + piece_radius + TRACTOR_GAP,
+                                0.0,
+                            ],
+                            kind: JointKind::Spring {
+                                length: 0.0,
+                                stiffness: TRACTOR_STIFFNESS,
+                                damping: TRACTOR_DAMPING,
+                            },
+                        },
+                    )
+                    .unwrap();
+                tractor.insert(ent, Tractor { target }).unwrap();
+                true
+            } else {
+                continue;
+            };
+
+            ship.get_mut(ent).unwrap().tractor = holding;
+            #[cfg(feature = "network")]
+            lazy.insert(ent, net::Dirty);
+        }
+        #[cfg(not(feature = "network"))]
+        let _ = lazy;
+    }
+}
@@ -0,0 +1,160 @@
+//! Neutral traders, crossing the map.
+//!
+//! `SysTraffic` regularly sends a freighter from one edge of the map to the
+//! opposite one, flown by its `Autopilot`, and removes it once there. Traders
+//! don't fight back, but pirates and players can attack them: a trader that
+//! loses its cockpit spills its cargo as loose blocks, that can be salvaged or
+//! towed away.
+
+use rand::prelude::*;
+use specs::{Component, Entities, Join, LazyUpdate, NullStorage, Read,
+            ReadExpect, ReadStorage, System, Write, WriteStorage};
+use vecmath::*;
+
+use crate::{GameRng, Role};
+use crate::ai::{AiPilot, Personality};
+use crate::autopilot::Autopilot;
+use crate::blocks::{BlockInner, TANK_CAPACITY};
+use crate::math::atan2;
+use crate::physics::{delete_entity, DeltaTime, Position, Velocity};
+use crate::salvage::{spill_cargo, Cargo};
+use crate::ship::{Ship, ShipClass, STARTING_AMMO};
+
+/// Time between traders.
+const TRADER_INTERVAL: f32 = 40.0;
+
+/// No trader is sent while this many are still around.
+const MAX_TRADERS: usize = 3;
+
+/// Distance from the center of the map where traders come and go.
+const TRADE_EDGE: f32 = 95.0;
+
+/// Marks a ship as a neutral trader.
+#[derive(Default)]
+pub struct Trader;
+
+impl Component for Trader {
+    type Storage = NullStorage<Self>;
+}
+
+/// Traffic system, sends traders across the map.
+pub struct SysTraffic {
+    /// Time until the next trader.
+    next_trader: f32,
+}
+
+impl Default for SysTraffic {
+    fn default() -> SysTraffic {
+        SysTraffic {
+            next_trader: TRADER_INTERVAL * 0.5,
+        }
+    }
+}
+
+impl<'a> System<'a> for SysTraffic {
+    type SystemData = (
+        Read<'a, DeltaTime>,
+        ReadExpect<'a, Role>,
+        Read<'a, LazyUpdate>,
+        Write<'a, GameRng>,
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        ReadStorage<'a, Ship>,
+        ReadStorage<'a, Autopilot>,
+        ReadStorage<'a, Trader>,
+        WriteStorage<'a, Cargo>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            dt,
+            role,
+            lazy,
+            mut rng,
+            entities,
+            pos,
+            vel,
+            ship,
+            autopilot,
+            trader,
+            mut cargo,
+        ): Self::SystemData,
+    ) {
+        let rng = &mut *rng;
+
+        let mut count = 0;
+        for (ent, pos, vel, _, cargo) in
+            (&*entities, &pos, &vel, &trader, &mut cargo).join()
+        {
+            if ship.get(ent).is_some() {
+                // Traders that made it across leave
+                if autopilot.get(ent).is_none() {
+                    delete_entity(*role, &entities, &lazy, ent);
+                } else {
+                    count += 1;
+                }
+                continue;
+            }
+
+            // Destroyed, spill the cargo
+            spill_cargo(&entities, &lazy, rng, pos, vel, cargo);
+            lazy.remove::<Trader>(ent);
+        }
+
+        self.next_trader -= dt.0;
+        if self.next_trader > 0.0 || count >= MAX_TRADERS {
+            return;
+        }
+        self.next_trader = TRADER_INTERVAL;
+
+        // Cross from one edge to the opposite one
+        let &(xpos, ypos) = [
+            (-1.0, 0.0), // left
+            (1.0, 0.0),  // right
+            (0.0, -1.0), // bottom
+            (0.0, 1.0),  // top
+        ].choose(rng).unwrap();
+        let from = rng.gen_range(-60.0, 60.0);
+        let to = rng.gen_range(-60.0, 60.0);
+        let start = [
+            xpos * TRADE_EDGE + ypos * from,
+            ypos * TRADE_EDGE + xpos * from,
+        ];
+        let end = [
+            -xpos * TRADE_EDGE + ypos * to,
+            -ypos * TRADE_EDGE + xpos * to,
+        ];
+        let dir = vec2_sub(end, start);
+        let ent = AiPilot::spawn(
+            &entities,
+            &lazy,
+            ShipClass::Freighter,
+            start,
+            atan2(dir[1], dir[0]),
+            Personality::Trader,
+        );
+        lazy.insert(ent, Autopilot::FlyTo(end));
+        lazy.insert(ent, Trader);
+
+        // Some goods to carry
+        let goods = [
+            BlockInner::Armor,
+            BlockInner::FuelTank {
+                fuel: TANK_CAPACITY,
+            },
+            BlockInner::Thruster { angle: 0.0 },
+        ];
+        let blocks = (0..rng.gen_range(3, 7))
+            .map(|_| goods.choose(rng).unwrap().clone())
+            .collect();
+        lazy.insert(
+            ent,
+            Cargo {
+                blocks,
+                ammo: STARTING_AMMO,
+            },
+        );
+    }
+}
@@ -104,9 +104,37 @@ pub fn angle_wrap(a: f32) -> f32 {
     (a + 9.0 * PI) % (2.0 * PI) - PI
 }
 
+/// Restricts a value to an interval (`f32::clamp()` needs Rust 1.50).
+pub fn clamp(v: f32, min: f32, max: f32) -> f32 {
+    if v < min {
+        min
+    } else if v > max {
+        max
+    } else {
+        v
+    }
+}
+
+/// Cleans up text from players: drops control characters and surrounding
+/// spaces, and cuts it to `max_len` bytes. Returns `None` if nothing is left.
+pub fn clean_text(text: &str, max_len: usize) -> Option<String> {
+    let mut clean = String::new();
+    for c in text.trim().chars().filter(|c| !c.is_control()) {
+        if clean.len() + c.len_utf8() > max_len {
+            break;
+        }
+        clean.push(c);
+    }
+    if clean.is_empty() {
+        None
+    } else {
+        Some(clean)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::IteratorExt;
+    use super::{clean_text, IteratorExt};
 
     #[test]
     fn test_minmax() {
@@ -121,4 +149,14 @@ mod tests {
         let r: Option<(&i32, &i32)> = [].iter().minmax();
         assert_eq!(r, None);
     }
+
+    #[test]
+    fn test_clean_text() {
+        assert_eq!(
+            clean_text(" hi\x07 there\n", 20),
+            Some("hi there".into()),
+        );
+        assert_eq!(clean_text("h\u{e9}h\u{e9}", 4), Some("h\u{e9}h".into()));
+        assert_eq!(clean_text(" \t\x1b", 20), None);
+    }
 }
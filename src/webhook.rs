@@ -0,0 +1,161 @@
+//! Webhook integration, posting game events to a URL.
+//!
+//! This is meant for chat notifications (e.g. Discord): the server posts a
+//! JSON message for the events worth telling players about, such as someone
+//! joining or a ship being destroyed. Requests are sent from a background
+//! thread so they never stall the simulation, and are rate-limited, events
+//! that happen in the meantime get grouped in a single message.
+
+use log::warn;
+use specs::shrev::ReaderId;
+use specs::{Read, System, World};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::events::{GameEvent, GameEvents};
+
+/// Minimum time between two requests.
+const MIN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Maximum number of events waiting to be sent, more get dropped.
+const MAX_PENDING: usize = 64;
+
+/// Text for the events that get posted, `None` for the others.
+fn describe(event: &GameEvent) -> Option<String> {
+    match *event {
+        GameEvent::PlayerJoined { player } => {
+            Some(format!("Player {} joined", player))
+        }
+        GameEvent::PlayerLeft { player } => {
+            Some(format!("Player {} left", player))
+        }
+        GameEvent::ShipDestroyed {
+            player: Some(player),
+            killer: Some(killer),
+        } if killer != player => Some(format!(
+            "Player {} destroyed player {}'s ship",
+            killer, player
+        )),
+        GameEvent::ShipDestroyed {
+            player: Some(player),
+            ..
+        } => Some(format!("Player {}'s ship was destroyed", player)),
+        GameEvent::ShipDestroyed { player: None, .. } => None,
+        GameEvent::ShipCaptured { player } => {
+            Some(format!("Player {} captured a derelict ship", player))
+        }
+        GameEvent::BossDestroyed => {
+            Some("The pirate dreadnought was destroyed".to_owned())
+        }
+        GameEvent::WaveStart { wave } => {
+            Some(format!("Wave {} incoming", wave))
+        }
+        GameEvent::SurvivalOver { waves } => {
+            Some(format!("Survival over after {} waves", waves))
+        }
+        GameEvent::ZoneCaptured { team } => {
+            Some(format!("Team {} captured a control point", team + 1))
+        }
+        GameEvent::Achievement {
+            player,
+            achievement,
+        } => Some(format!(
+            "Player {} earned an achievement: {}",
+            player,
+            achievement.describe()
+        )),
+        GameEvent::ShotFired { .. }
+        | GameEvent::ShotHit { .. }
+        | GameEvent::BlocksDestroyed { .. }
+        | GameEvent::Damage { .. } => None,
+        GameEvent::MatchEnd { ref summary } => {
+            Some(match summary.players.first() {
+                Some(&(player, ref stats)) => format!(
+                    "Match over, player {} wins with {} kills",
+                    player, stats.kills
+                ),
+                None => "Match over".to_owned(),
+            })
+        }
+    }
+}
+
+/// Background thread posting the events.
+fn sender_thread(url: String, events: Receiver<(String, GameEvent)>) {
+    let mut last_post: Option<Instant> = None;
+    loop {
+        // Wait for an event
+        let first = match events.recv() {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+
+        // Respect the rate limit
+        if let Some(last) = last_post {
+            let elapsed = last.elapsed();
+            if elapsed < MIN_INTERVAL {
+                thread::sleep(MIN_INTERVAL - elapsed);
+            }
+        }
+
+        // Group with whatever arrived meanwhile
+        let mut batch = vec![first];
+        loop {
+            match events.try_recv() {
+                Ok(e) => batch.push(e),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        let content = batch
+            .iter()
+            .map(|(text, _)| text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let body = serde_json::json!({
+            "content": content,
+            "events": batch.iter().map(|(_, e)| e).collect::<Vec<_>>(),
+        });
+        if let Err(e) = ureq::post(&url)
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string())
+        {
+            warn!("Error posting to webhook: {}", e);
+        }
+        last_post = Some(Instant::now());
+    }
+}
+
+/// Webhook system, forwards events to the background thread.
+pub struct SysWebhook {
+    sender: SyncSender<(String, GameEvent)>,
+    reader: ReaderId<GameEvent>,
+}
+
+impl SysWebhook {
+    /// Create the system, starting the thread that will post to `url`.
+    pub fn new(url: String, world: &World) -> SysWebhook {
+        let (sender, receiver) = mpsc::sync_channel(MAX_PENDING);
+        thread::spawn(move || sender_thread(url, receiver));
+        SysWebhook {
+            sender,
+            reader: world.fetch_mut::<GameEvents>().register_reader(),
+        }
+    }
+}
+
+impl<'a> System<'a> for SysWebhook {
+    type SystemData = Read<'a, GameEvents>;
+
+    fn run(&mut self, events: Self::SystemData) {
+        for event in events.read(&mut self.reader) {
+            if let Some(text) = describe(event) {
+                if self.sender.try_send((text, event.clone())).is_err() {
+                    warn!("Too many webhook events pending, dropping");
+                }
+            }
+        }
+    }
+}